@@ -0,0 +1,14 @@
+use std::env;
+
+/// Targets with two cores sharing the heap
+const DUAL_CORE_TARGETS: &[&str] = &["xtensa-esp32-none-elf", "xtensa-esp32s3-none-elf"];
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(esp_alloc_dual_core)");
+
+    let target = env::var("TARGET").unwrap_or_default();
+    if DUAL_CORE_TARGETS.contains(&target.as_str()) {
+        println!("cargo:rustc-cfg=esp_alloc_dual_core");
+    }
+}
@@ -0,0 +1,111 @@
+//! Measures how long allocating and freeing a small block takes, to compare
+//! builds with and without the `inline-hot-path` feature
+//!
+//! With the `free-cache` feature, the same alloc/free ping-pong is measured
+//! once more with the region's free cache turned on.
+//!
+//! On a chip, call [`measure`] with a function that reads the cycle counter,
+//! like `xtensa_lx::timer::get_cycle_count` or the `mcycle` CSR on RISC-V,
+//! and print what it returns. The heap is set up with a few holes in front
+//! of the block, as it is in an application that has been running for a
+//! while.
+//!
+//! On the host, `cargo run --release --example alloc_latency` measures with
+//! the `std` clock instead, in nanoseconds.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::addr_of_mut,
+};
+use std::time::Instant;
+
+use esp_alloc::{EspHeap, MemoryCapability};
+
+static HEAP: EspHeap = EspHeap::empty();
+
+const HEAP_SIZE: usize = 32 * 1024;
+static mut MEMORY: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+
+const ROUNDS: u64 = 100_000;
+const HOLES: usize = 8;
+
+/// The average time to allocate and to free one block
+struct Latency {
+    alloc: u64,
+    dealloc: u64,
+}
+
+/// Allocates and frees a 32 byte block `ROUNDS` times, timing each with
+/// `now`, and calls `ready` once the holes are in place.
+fn measure(now: impl Fn() -> u64, ready: impl FnOnce()) -> Latency {
+    // Small holes in front of the block, which each allocation walks past.
+    let small = Layout::from_size_align(8, 4).unwrap();
+    let mut pinned = [core::ptr::null_mut(); 2 * HOLES];
+    for ptr in &mut pinned {
+        *ptr = unsafe { HEAP.alloc(small) };
+    }
+    for ptr in pinned.iter().step_by(2) {
+        unsafe { HEAP.dealloc(*ptr, small) };
+    }
+    ready();
+
+    let layout = Layout::from_size_align(32, 4).unwrap();
+    let (mut alloc, mut dealloc) = (0, 0);
+    for _ in 0..ROUNDS {
+        let start = now();
+        let ptr = unsafe { HEAP.alloc(layout) };
+        let allocated = now();
+        unsafe { HEAP.dealloc(ptr, layout) };
+        let freed = now();
+        assert!(!ptr.is_null());
+        alloc += allocated - start;
+        dealloc += freed - allocated;
+    }
+
+    for ptr in pinned.iter().skip(1).step_by(2) {
+        unsafe { HEAP.dealloc(*ptr, small) };
+    }
+    Latency {
+        alloc: alloc / ROUNDS,
+        dealloc: dealloc / ROUNDS,
+    }
+}
+
+fn main() {
+    let region = unsafe {
+        HEAP.add_region(
+            addr_of_mut!(MEMORY) as *mut u8,
+            HEAP_SIZE,
+            MemoryCapability::INTERNAL,
+        )
+    };
+    #[cfg(not(feature = "free-cache"))]
+    let _ = region;
+
+    let epoch = Instant::now();
+    let now = || epoch.elapsed().as_nanos() as u64;
+    let latency = measure(now, || {});
+    assert_eq!(HEAP.used(), 0, "blocks were lost");
+    println!(
+        "alloc {} ns, dealloc {} ns (inline-hot-path {})",
+        latency.alloc,
+        latency.dealloc,
+        if cfg!(feature = "inline-hot-path") {
+            "on"
+        } else {
+            "off"
+        },
+    );
+
+    #[cfg(feature = "free-cache")]
+    {
+        // Turned on behind the holes, so that they stay on the free list.
+        let latency = measure(now, || HEAP.set_free_cache(region, 4));
+        HEAP.set_free_cache(region, 0);
+        assert_eq!(HEAP.used(), 0, "blocks were lost");
+        println!(
+            "alloc {} ns, dealloc {} ns with the free cache",
+            latency.alloc, latency.dealloc
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Hammers one heap from two cores at once, to validate the
+//! `critical-section` implementation a dual-core application uses
+//!
+//! On a dual-core chip like the ESP32 or ESP32-S3, run [`hammer`] on both
+//! cores, for instance by starting it on the second core with esp-hal's
+//! `CpuControl::start_app_core` and calling it on the first one. With an
+//! implementation that doesn't exclude the other core, the heap soon panics
+//! because both cores are in it at once, or a corrupted block is reported.
+//! A correct setup finishes and leaves the heap empty.
+//!
+//! On the host, `cargo run --example dual_core_stress` runs the same loop on
+//! two threads, with the `std` implementation of `critical-section`.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::addr_of_mut,
+};
+
+use esp_alloc::{EspHeap, MemoryCapability};
+
+static HEAP: EspHeap = EspHeap::empty();
+
+const HEAP_SIZE: usize = 32 * 1024;
+static mut MEMORY: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+
+const ROUNDS: usize = 20_000;
+const SLOTS: usize = 16;
+
+/// Allocates, fills, checks and frees blocks of varying sizes, tagged with
+/// `core`, and returns the number of allocations that failed.
+fn hammer(core: u8) -> usize {
+    let mut slots: [Option<(*mut u8, Layout)>; SLOTS] = [None; SLOTS];
+    let mut seed = 0x9e37_79b9_u32 ^ u32::from(core);
+    let mut failures = 0;
+
+    for _ in 0..ROUNDS {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        let slot = &mut slots[seed as usize % SLOTS];
+
+        match slot.take() {
+            Some((ptr, layout)) => unsafe {
+                let block = core::slice::from_raw_parts(ptr, layout.size());
+                assert!(
+                    block.iter().all(|&byte| byte == core),
+                    "block at {ptr:?} was overwritten"
+                );
+                HEAP.dealloc(ptr, layout);
+            },
+            None => {
+                let layout = Layout::from_size_align(8 + (seed >> 8) as usize % 256, 4).unwrap();
+                let ptr = unsafe { HEAP.alloc(layout) };
+                if ptr.is_null() {
+                    failures += 1;
+                } else {
+                    unsafe { ptr.write_bytes(core, layout.size()) };
+                    *slot = Some((ptr, layout));
+                }
+            }
+        }
+    }
+
+    for (ptr, layout) in slots.into_iter().flatten() {
+        unsafe { HEAP.dealloc(ptr, layout) };
+    }
+    failures
+}
+
+fn main() {
+    unsafe {
+        HEAP.add_region(
+            addr_of_mut!(MEMORY) as *mut u8,
+            HEAP_SIZE,
+            MemoryCapability::INTERNAL,
+        );
+    }
+
+    let other = std::thread::spawn(|| hammer(1));
+    let failures = hammer(0) + other.join().unwrap();
+
+    assert_eq!(HEAP.used(), 0, "blocks were lost");
+    println!("{ROUNDS} rounds per core, {failures} allocations failed");
+}
@@ -0,0 +1,105 @@
+//! Preference for the regions a core reaches fastest
+
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{EspHeap, Region, RegionId, MAX_REGIONS};
+
+/// The maximum number of cores region access costs can be given for
+pub const MAX_CORES: usize = 2;
+
+/// The source of the allocating core, readable without entering a critical
+/// section
+pub(crate) struct CoreSource {
+    /// The source as a `fn() -> usize`, `0` if none is set
+    source: AtomicUsize,
+}
+
+impl CoreSource {
+    pub(crate) const fn new() -> Self {
+        Self {
+            source: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes over the source of `other`.
+    pub(crate) fn reset(&self, other: Self) {
+        self.source
+            .store(other.source.into_inner(), Ordering::Relaxed);
+    }
+
+    /// Returns the core the caller runs on, if known.
+    #[inline]
+    pub(crate) fn current(&self) -> Option<usize> {
+        let source = self.source.load(Ordering::Relaxed);
+        if source == 0 {
+            return None;
+        }
+
+        // SAFETY: non-zero values are only ever stored from a `fn() -> usize`.
+        let source: fn() -> usize = unsafe { mem::transmute(source) };
+        Some(source()).filter(|&core| core < MAX_CORES)
+    }
+}
+
+/// Returns the indices of the regions in the order they should be tried:
+/// cheapest for the allocating core first, and in the order they were added
+/// among equally cheap ones, or in reverse for a `large` allocation.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+pub(crate) fn region_order(
+    regions: &[Region],
+    core: Option<usize>,
+    large: bool,
+) -> [usize; MAX_REGIONS] {
+    let mut order = [0; MAX_REGIONS];
+    for (index, slot) in order.iter_mut().enumerate() {
+        *slot = index;
+    }
+    let rank = |index: usize| if large { MAX_REGIONS - index } else { index };
+    match core {
+        Some(core) => order[..regions.len()]
+            .sort_unstable_by_key(|&index| (regions[index].core_costs[core], rank(index))),
+        None if large => order[..regions.len()].reverse(),
+        None => {}
+    }
+    order
+}
+
+impl EspHeap {
+    /// Tells the heap which core an allocation comes from
+    ///
+    /// `current_core` is called before every allocation, outside of the
+    /// critical section, so it should be cheap, like reading the processor
+    /// ID. Values of [`MAX_CORES`] or more are treated as unknown. The
+    /// costs given with
+    /// [`set_region_core_affinity`](struct.EspHeap.html#method.set_region_core_affinity)
+    /// only take effect with a source set.
+    pub fn set_core_id_source(&self, current_core: fn() -> usize) {
+        self.core_source
+            .source
+            .store(current_core as usize, Ordering::Relaxed);
+    }
+
+    /// Sets how costly it is for `core` to access the given region
+    ///
+    /// Allocations from `core` try the regions with the lowest cost first,
+    /// and fall back to costlier ones as usual when those are full. Regions
+    /// with the same cost, `0` by default, are tried in the order they were
+    /// added. This is a best-effort hint for chips where some memory is
+    /// closer to one core than the other: nothing is moved when the costs
+    /// change, and allocations whose core isn't known ignore the costs.
+    ///
+    /// It has no effect on non-existent regions or cores of [`MAX_CORES`] or
+    /// more.
+    pub fn set_region_core_affinity(&self, region: RegionId, core: usize, cost: u8) {
+        self.locked(|cs| {
+            if let Some(region) = self.heap.borrow(cs).borrow_mut().get_mut(region.index) {
+                if let Some(slot) = region.core_costs.get_mut(core) {
+                    *slot = cost;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,142 @@
+//! Reporting the state of the heap when an allocation error ends the program
+//!
+//! The default allocation error handler panics with nothing but the size of
+//! the allocation, and by the time the panic handler runs, what the heap
+//! looked like is gone. [`handle_alloc_error`](struct.EspHeap.html#method.handle_alloc_error)
+//! takes a snapshot of every region first and hands it to the sink set with
+//! [`set_oom_sink`](struct.EspHeap.html#method.set_oom_sink), before it
+//! panics.
+//!
+//! On nightly, [`alloc_error_handler!`](macro.alloc_error_handler.html)
+//! makes it the application's `#[alloc_error_handler]`. Stable Rust has no
+//! way to replace the handler, but the out-of-memory handler set with
+//! [`set_oom_handler`](struct.EspHeap.html#method.set_oom_handler) runs on
+//! the same failures, right before the allocation returns null, and can
+//! call it.
+
+use core::{
+    alloc::Layout,
+    mem,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{EspHeap, HeapStats};
+
+/// Gets the usage of the heap and the layout of the allocation that failed,
+/// see [`set_oom_sink`](struct.EspHeap.html#method.set_oom_sink)
+pub type OomSink = fn(&HeapStats, Layout);
+
+/// The sink set with `set_oom_sink`, readable without a critical section
+pub(crate) struct OomSinkCell {
+    /// The sink as an `OomSink`, `0` if none is set
+    sink: AtomicUsize,
+    /// Whether the sink is running, during which it isn't called again
+    running: AtomicBool,
+}
+
+impl OomSinkCell {
+    pub(crate) const fn new() -> Self {
+        Self {
+            sink: AtomicUsize::new(0),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes over the sink of `other`.
+    pub(crate) fn reset(&self, other: Self) {
+        self.sink.store(other.sink.into_inner(), Ordering::Relaxed);
+        self.running
+            .store(other.running.into_inner(), Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Option<OomSink> {
+        let sink = self.sink.load(Ordering::Relaxed);
+        if sink == 0 {
+            return None;
+        }
+
+        // SAFETY: non-zero values are only ever stored from an `OomSink`.
+        Some(unsafe { mem::transmute::<usize, OomSink>(sink) })
+    }
+}
+
+impl EspHeap {
+    /// Calls `sink` with the state of the heap before
+    /// [`handle_alloc_error`](struct.EspHeap.html#method.handle_alloc_error)
+    /// panics
+    ///
+    /// The sink gets the usage of every region, with its watermark, its
+    /// largest free block and its fragmentation (see
+    /// [`stats`](struct.EspHeap.html#method.stats)), and the layout of the
+    /// allocation that failed, for writing them to a UART, `defmt`, RTT or
+    /// anything else that still works when the program is about to end.
+    ///
+    /// The snapshot is taken first and the heap's lock released before the
+    /// sink runs, so it may read the heap, but it shouldn't allocate: the
+    /// heap has just run out of memory. An allocation error while it runs,
+    /// its own or one on the other core, panics without calling it again.
+    pub fn set_oom_sink(&self, sink: OomSink) {
+        self.oom_sink.sink.store(sink as usize, Ordering::Relaxed);
+    }
+
+    /// Stops calling the sink set with `set_oom_sink`
+    pub fn clear_oom_sink(&self) {
+        self.oom_sink.sink.store(0, Ordering::Relaxed);
+    }
+
+    /// Reports an allocation of `layout` that failed to the sink set with
+    /// [`set_oom_sink`](struct.EspHeap.html#method.set_oom_sink), then
+    /// panics
+    ///
+    /// Meant as the allocation error handler of the program, which is what
+    /// [`alloc_error_handler!`](macro.alloc_error_handler.html) makes it on
+    /// nightly. On stable, call it from the out-of-memory handler:
+    ///
+    /// ```
+    /// use core::alloc::Layout;
+    ///
+    /// use esp_alloc::{EspHeap, HeapStats, OomAction};
+    ///
+    /// static HEAP: EspHeap = EspHeap::empty();
+    ///
+    /// fn out_of_memory(layout: Layout, _: &HeapStats) -> OomAction {
+    ///     HEAP.handle_alloc_error(layout)
+    /// }
+    ///
+    /// fn log(stats: &HeapStats, layout: Layout) {
+    ///     // Write `layout` and `stats` to the console, without allocating.
+    /// }
+    ///
+    /// HEAP.set_oom_sink(log);
+    /// HEAP.set_oom_handler(out_of_memory);
+    /// ```
+    ///
+    /// That way allocations that could handle the failure, like
+    /// `Vec::try_reserve`, panic as well.
+    ///
+    /// This must be called outside of the heap's lock, which is where the
+    /// allocation error handler and the out-of-memory handler run.
+    pub fn handle_alloc_error(&self, layout: Layout) -> ! {
+        let running = &self.oom_sink.running;
+        // Not every target has compare-and-swap atomics, so the lock makes
+        // this a single step.
+        let first = self.locked(|_| {
+            let first = !running.load(Ordering::Relaxed);
+            running.store(true, Ordering::Relaxed);
+            first
+        });
+        if first {
+            if let Some(sink) = self.oom_sink.get() {
+                let stats = self.stats();
+                sink(&stats, layout);
+            }
+            running.store(false, Ordering::Relaxed);
+        }
+        fail!(
+            "memory allocation failed",
+            "memory allocation of {} bytes aligned to {} failed",
+            layout.size(),
+            layout.align()
+        )
+    }
+}
@@ -0,0 +1,112 @@
+//! A box placed in memory with given capabilities
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+use crate::{EspHeap, MemoryCapability};
+
+/// An owned value on an [`EspHeap`], in a region with the requested
+/// capabilities
+///
+/// Works on the stable channel, where `Box::new_in` isn't available. The
+/// value is dropped and its memory returned to the heap it came from when
+/// the box is dropped.
+pub struct EspBox<'a, T> {
+    ptr: NonNull<T>,
+    heap: &'a EspHeap,
+    _owns: PhantomData<T>,
+}
+
+// SAFETY: the box owns its value, and the heap is `Sync`.
+unsafe impl<T: Send> Send for EspBox<'_, T> {}
+// SAFETY: as above.
+unsafe impl<T: Sync> Sync for EspBox<'_, T> {}
+
+impl<'a, T> EspBox<'a, T> {
+    /// Moves `value` into a region of `heap` with `capabilities`
+    ///
+    /// Gives `value` back if no such region has room for it.
+    pub fn new_caps(
+        heap: &'a EspHeap,
+        capabilities: MemoryCapability,
+        value: T,
+    ) -> Result<Self, T> {
+        let layout = Layout::new::<T>();
+        let ptr: NonNull<T> = if layout.size() == 0 {
+            NonNull::dangling()
+        } else {
+            match NonNull::new(heap.alloc_from(capabilities, layout)) {
+                Some(ptr) => ptr.cast(),
+                None => return Err(value),
+            }
+        };
+
+        // SAFETY: `ptr` is valid for writes of a `T`.
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(Self {
+            ptr,
+            heap,
+            _owns: PhantomData,
+        })
+    }
+
+    /// Moves the value out of the box, freeing its memory
+    pub fn into_inner(this: Self) -> T {
+        // SAFETY: the value is initialized and the box is forgotten below, so
+        // it isn't dropped twice.
+        let value = unsafe { this.ptr.as_ptr().read() };
+        // SAFETY: the memory was allocated with this layout from `heap`.
+        unsafe { free(this.heap, this.ptr) };
+        mem::forget(this);
+        value
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must have been allocated from `heap` as a `T`, or be dangling if `T`
+/// is zero-sized.
+unsafe fn free<T>(heap: &EspHeap, ptr: NonNull<T>) {
+    let layout = Layout::new::<T>();
+    if layout.size() != 0 {
+        heap.dealloc(ptr.as_ptr().cast(), layout);
+    }
+}
+
+impl<T> Deref for EspBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the value is initialized for as long as the box lives.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for EspBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: as above, and the box is borrowed mutably.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for EspBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for EspBox<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: the value is initialized and the memory came from `heap`.
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            free(self.heap, self.ptr);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Per-subsystem allocation quotas
+
+#[cfg(feature = "nightly")]
+use core::alloc::{AllocError, Allocator};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ptr::{self, NonNull},
+};
+
+use crate::{EspHeap, MemoryCapability};
+
+/// The maximum number of budgets a single [`EspHeap`] can hold
+pub const MAX_BUDGETS: usize = 8;
+
+#[derive(Clone, Copy)]
+pub(crate) struct Budget {
+    name: &'static str,
+    max_bytes: usize,
+    used: usize,
+}
+
+impl Budget {
+    pub(crate) fn usage(&self) -> BudgetUsage {
+        BudgetUsage {
+            name: self.name,
+            used: self.used,
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+/// Why an allocation through a [`BudgetHandle`] failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum BudgetError {
+    /// The allocation would have exceeded the budget
+    BudgetExceeded,
+    /// The budget had room, but the heap didn't
+    OutOfMemory,
+}
+
+impl fmt::Display for BudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BudgetExceeded => f.write_str("allocation budget exceeded"),
+            Self::OutOfMemory => f.write_str("out of memory"),
+        }
+    }
+}
+
+/// The state of a budget, see
+/// [`budgets`](struct.EspHeap.html#method.budgets) and
+/// [`stats`](struct.EspHeap.html#method.stats)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct BudgetUsage {
+    /// Name the budget was created with
+    pub name: &'static str,
+    /// Bytes currently allocated through the budget
+    pub used: usize,
+    /// Maximum number of bytes the budget allows
+    pub max_bytes: usize,
+}
+
+/// Allocates from an [`EspHeap`] within a budget
+///
+/// Created by [`budget`](struct.EspHeap.html#method.budget). The budget is
+/// only accounting: allocations are served from the heap's shared regions,
+/// and whatever a budget doesn't use stays available to everyone.
+#[derive(Clone, Copy)]
+pub struct BudgetHandle<'a> {
+    heap: &'a EspHeap,
+    index: usize,
+}
+
+impl EspHeap {
+    /// Creates a budget that caps the bytes allocated through its handle at
+    /// `max_bytes`
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`MAX_BUDGETS`] budgets have already been created.
+    pub fn budget(&self, name: &'static str, max_bytes: usize) -> BudgetHandle<'_> {
+        let index = self.locked(|cs| {
+            let mut budgets = self.budgets.borrow(cs).borrow_mut();
+            let (index, slot) = budgets
+                .iter_mut()
+                .enumerate()
+                .find(|(_, slot)| slot.is_none())
+                .unwrap_or_else(|| {
+                    fail!(
+                        "too many budgets",
+                        "Exceeded the maximum of {MAX_BUDGETS} budgets"
+                    )
+                });
+            *slot = Some(Budget {
+                name,
+                max_bytes,
+                used: 0,
+            });
+            index
+        });
+
+        BudgetHandle { heap: self, index }
+    }
+
+    /// Returns the usage of every budget
+    pub fn budgets(&self) -> impl Iterator<Item = BudgetUsage> {
+        let budgets = self.locked(|cs| *self.budgets.borrow(cs).borrow());
+        budgets.into_iter().flatten().map(|budget| budget.usage())
+    }
+}
+
+impl BudgetHandle<'_> {
+    /// Allocates a block, if the budget and the heap allow it
+    pub fn try_alloc(&self, layout: Layout) -> Result<NonNull<u8>, BudgetError> {
+        let context = self.heap.context(layout.size());
+        let result = self.heap.locked(|cs| {
+            let mut budgets = self.heap.budgets.borrow(cs).borrow_mut();
+            let budget = budgets[self.index].as_mut().unwrap();
+            if layout.size() > budget.max_bytes - budget.used {
+                return Err(BudgetError::BudgetExceeded);
+            }
+
+            let mut regions = self.heap.heap.borrow(cs).borrow_mut();
+            let capabilities = MemoryCapability::empty();
+            let Some(allocation) =
+                self.heap
+                    .allocate_locked(cs, &mut regions[..], context, capabilities, layout)
+            else {
+                self.heap
+                    .record_failed(cs, &mut regions[..], context, capabilities, layout);
+                return Err(BudgetError::OutOfMemory);
+            };
+            budget.used += layout.size();
+
+            Ok(allocation.ptr)
+        });
+        match result {
+            Ok(ptr) => self.heap.report_allocation(layout, ptr.as_ptr()),
+            Err(BudgetError::OutOfMemory) => self.heap.report_allocation(layout, ptr::null_mut()),
+            Err(BudgetError::BudgetExceeded) => return result,
+        }
+        self.heap.indicate_usage();
+        result
+    }
+
+    /// Frees a block and credits it back to the budget
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated through this budget with `layout`.
+    pub unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.heap.locked(|cs| {
+            if let Some(budget) = self.heap.budgets.borrow(cs).borrow_mut()[self.index].as_mut() {
+                budget.used = budget.used.saturating_sub(layout.size());
+            }
+        });
+        self.heap.dealloc(ptr.as_ptr(), layout);
+    }
+
+    /// Name the budget was created with
+    pub fn name(&self) -> &'static str {
+        self.usage().name
+    }
+
+    /// Returns how much of the budget is used
+    pub fn usage(&self) -> BudgetUsage {
+        self.heap.locked(|cs| {
+            // Budgets are never removed, so the handle's slot is always set.
+            self.heap.budgets.borrow(cs).borrow()[self.index]
+                .unwrap()
+                .usage()
+        })
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl Allocator for BudgetHandle<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            // SAFETY: the alignment is never zero.
+            return Ok(unsafe { crate::slice(layout.align() as *mut u8, 0) });
+        }
+
+        let ptr = self.try_alloc(layout).map_err(|_| AllocError)?;
+        // SAFETY: `ptr` is a new allocation of `layout.size()` bytes.
+        Ok(unsafe { crate::slice(ptr.as_ptr(), layout.size()) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.dealloc(ptr, layout);
+        }
+    }
+}
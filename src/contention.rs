@@ -0,0 +1,102 @@
+//! Allocation that gives up instead of waiting for the heap
+
+use core::{
+    alloc::Layout,
+    fmt,
+    ptr::{self, NonNull},
+};
+
+use crate::{EspHeap, MemoryCapability};
+
+/// Why [`try_alloc`](struct.EspHeap.html#method.try_alloc) failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum TryAllocError {
+    /// The heap was in use: trying again later may succeed
+    Contended,
+    /// No region has room for the allocation
+    OutOfMemory,
+}
+
+impl fmt::Display for TryAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Contended => f.write_str("heap in use"),
+            Self::OutOfMemory => f.write_str("out of memory"),
+        }
+    }
+}
+
+impl EspHeap {
+    /// Allocates memory, failing instead of waiting if the heap is in use
+    ///
+    /// The heap is in use while another operation on it is in progress on
+    /// the same core, like one interrupted by the caller or one running a
+    /// hook that allocates, with the `multicore` feature on the other core,
+    /// or while the lock set with
+    /// [`set_lock`](struct.EspHeap.html#method.set_lock) can't be taken
+    /// right away. Unlike the `GlobalAlloc` methods, this never calls the
+    /// out-of-memory handler, so it only ever takes as long as a single
+    /// search of the regions.
+    pub fn try_alloc(&self, layout: Layout) -> Result<NonNull<u8>, TryAllocError> {
+        if layout.size() == 0 {
+            // SAFETY: the alignment is never zero.
+            return Ok(unsafe { NonNull::new_unchecked(layout.align() as *mut u8) });
+        }
+
+        let context = self.context(layout.size());
+        let result = self
+            .try_locked(|cs| {
+                let Ok(mut regions) = self.heap.borrow(cs).try_borrow_mut() else {
+                    #[cfg(feature = "stats")]
+                    self.record_contention(cs);
+                    return Err(TryAllocError::Contended);
+                };
+                #[cfg(esp_alloc_dual_core)]
+                let _entered = self.core_guard.enter();
+                let capabilities = MemoryCapability::empty();
+                match self.allocate_locked(cs, &mut regions[..], context, capabilities, layout) {
+                    Some(allocation) => Ok(allocation.ptr),
+                    None => {
+                        self.record_failed(cs, &mut regions[..], context, capabilities, layout);
+                        Err(TryAllocError::OutOfMemory)
+                    }
+                }
+            })
+            .unwrap_or(Err(TryAllocError::Contended));
+        match result {
+            Ok(ptr) => self.report_allocation(layout, ptr.as_ptr()),
+            Err(TryAllocError::OutOfMemory) => self.report_allocation(layout, ptr::null_mut()),
+            Err(TryAllocError::Contended) => return result,
+        }
+        self.indicate_usage();
+        result
+    }
+
+    /// Allocates memory, trying up to `max_attempts` times while the heap is
+    /// in use
+    ///
+    /// `spin_hint` is called between attempts, outside of the critical
+    /// section, to pause for as long as the caller can afford, like a few
+    /// `nop`s or waiting for an interrupt. Running out of memory is never
+    /// retried. See [`try_alloc`](struct.EspHeap.html#method.try_alloc).
+    pub fn try_alloc_bounded(
+        &self,
+        layout: Layout,
+        max_attempts: usize,
+        spin_hint: fn(),
+    ) -> Result<NonNull<u8>, TryAllocError> {
+        let mut result = Err(TryAllocError::Contended);
+        for attempt in 0..max_attempts {
+            if attempt > 0 {
+                spin_hint();
+            }
+            result = self.try_alloc(layout);
+            if result != Err(TryAllocError::Contended) {
+                break;
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,117 @@
+//! Restrictions on large allocations during early boot
+
+use core::{
+    alloc::Layout,
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use critical_section::{CriticalSection, Mutex};
+
+use crate::EspHeap;
+
+/// What happens to large allocations before
+/// [`finalize`](struct.EspHeap.html#method.finalize), see
+/// [`set_early_limit`](struct.EspHeap.html#method.set_early_limit)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EarlyPolicy {
+    /// Fail the allocation
+    Deny,
+    /// Count and report the allocation, then serve it as usual
+    Report,
+}
+
+/// Receives every large allocation made before `finalize`, see
+/// [`set_early_hook`](struct.EspHeap.html#method.set_early_hook)
+pub(crate) type EarlyHook = fn(Layout);
+
+/// The limit and policy are readable without entering a critical section,
+/// so the common case of no limit costs a single load.
+pub(crate) struct EarlyLimit {
+    /// Allocations above this many bytes are restricted
+    limit: AtomicUsize,
+    deny: AtomicBool,
+    count: Mutex<Cell<usize>>,
+    hook: Mutex<Cell<Option<EarlyHook>>>,
+}
+
+impl EarlyLimit {
+    pub(crate) const fn new() -> Self {
+        Self {
+            limit: AtomicUsize::new(usize::MAX),
+            deny: AtomicBool::new(false),
+            count: Mutex::new(Cell::new(0)),
+            hook: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Takes over the state of `other`.
+    pub(crate) fn reset(&self, cs: CriticalSection<'_>, other: Self) {
+        self.limit
+            .store(other.limit.into_inner(), Ordering::Relaxed);
+        self.deny.store(other.deny.into_inner(), Ordering::Relaxed);
+        self.count.borrow(cs).set(other.count.into_inner().get());
+        self.hook.borrow(cs).set(other.hook.into_inner().get());
+    }
+
+    /// Returns whether an allocation of `size` bytes is restricted.
+    #[inline]
+    pub(crate) fn applies(&self, size: usize) -> bool {
+        size > self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Counts and reports a restricted allocation, returning whether it may
+    /// go ahead.
+    pub(crate) fn admit(&self, cs: CriticalSection<'_>, layout: Layout) -> bool {
+        let count = self.count.borrow(cs);
+        count.set(count.get() + 1);
+        if let Some(hook) = self.hook.borrow(cs).get() {
+            hook(layout);
+        }
+        !self.deny.load(Ordering::Relaxed)
+    }
+}
+
+impl EspHeap {
+    /// Restricts allocations of more than `bytes` until
+    /// [`finalize`](struct.EspHeap.html#method.finalize) is called
+    ///
+    /// Meant for the boot phase between initializing the internal memory
+    /// and adding external memory like PSRAM: large allocations made then,
+    /// often by lazily initialized statics, would otherwise stay in internal
+    /// memory for good. Depending on `policy` they fail or are let through,
+    /// and either way they are counted by
+    /// [`early_allocations`](struct.EspHeap.html#method.early_allocations)
+    /// and passed to the hook set with
+    /// [`set_early_hook`](struct.EspHeap.html#method.set_early_hook).
+    pub fn set_early_limit(&self, bytes: usize, policy: EarlyPolicy) {
+        self.early
+            .deny
+            .store(policy == EarlyPolicy::Deny, Ordering::Relaxed);
+        self.early.limit.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Calls `hook` with the layout of every allocation restricted by
+    /// [`set_early_limit`](struct.EspHeap.html#method.set_early_limit)
+    ///
+    /// The hook runs inside a critical section and must not use the heap, so
+    /// it should only record the layout, or log it through a non-allocating
+    /// logger.
+    pub fn set_early_hook(&self, hook: fn(Layout)) {
+        self.locked(|cs| self.early.hook.borrow(cs).set(Some(hook)));
+    }
+
+    /// Ends the boot phase, lifting the restriction set with
+    /// [`set_early_limit`](struct.EspHeap.html#method.set_early_limit)
+    pub fn finalize(&self) {
+        self.early.limit.store(usize::MAX, Ordering::Relaxed);
+    }
+
+    /// Returns the number of allocations restricted by
+    /// [`set_early_limit`](struct.EspHeap.html#method.set_early_limit),
+    /// whether they were denied or only reported
+    pub fn early_allocations(&self) -> usize {
+        self.locked(|cs| self.early.count.borrow(cs).get())
+    }
+}
@@ -0,0 +1,147 @@
+//! Regions that are expected to become available after boot
+
+use core::sync::atomic::Ordering;
+
+use crate::{check_overlap, EspHeap, MemoryCapability, RegionId, RegionStatus, MAX_REGIONS};
+
+/// Describes a region before its memory is known to be usable
+///
+/// Used with [`register_expected`](struct.EspHeap.html#method.register_expected)
+/// for memory like PSRAM that has to be probed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct RegionDescriptor {
+    /// Name of the region, for diagnostics
+    pub name: &'static str,
+    /// Capabilities the region will have
+    pub capabilities: MemoryCapability,
+}
+
+impl RegionDescriptor {
+    /// Describes a region named `name`
+    pub const fn new(name: &'static str, capabilities: MemoryCapability) -> Self {
+        Self { name, capabilities }
+    }
+}
+
+/// An expected region that isn't available, see
+/// [`missing_regions`](struct.EspHeap.html#method.missing_regions)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct MissingRegion {
+    /// The region
+    pub region: RegionId,
+    /// Capabilities the region would have had
+    pub capabilities: MemoryCapability,
+    /// Why the region is unavailable, or `None` if it hasn't been probed yet
+    pub reason: Option<&'static str>,
+}
+
+impl EspHeap {
+    /// Reserves a region slot for memory that still has to be probed
+    ///
+    /// The region takes no part in allocations until
+    /// [`mark_available`](struct.EspHeap.html#method.mark_available) is
+    /// called. Until then, or once
+    /// [`mark_unavailable`](struct.EspHeap.html#method.mark_unavailable) has
+    /// been called, it is reported by
+    /// [`missing_regions`](struct.EspHeap.html#method.missing_regions), so
+    /// a failed PSRAM probe shows up as a hardware fault rather than as an
+    /// allocation failure later on.
+    ///
+    /// Returns the id of the region.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all [`MAX_REGIONS`] region slots are in use.
+    pub fn register_expected(&self, descriptor: RegionDescriptor) -> RegionId {
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            let (index, region) = regions
+                .iter_mut()
+                .enumerate()
+                .find(|(_, region)| region.status == RegionStatus::Unused)
+                .unwrap_or_else(|| {
+                    fail!(
+                        "too many heap regions",
+                        "Exceeded the maximum of {MAX_REGIONS} heap regions"
+                    )
+                });
+            region.status = RegionStatus::Expected;
+            region.name = descriptor.name;
+            region.capabilities = descriptor.capabilities;
+            region.id(index)
+        })
+    }
+
+    /// Provides the memory of an expected region
+    ///
+    /// # Panics
+    ///
+    /// Panics if `region` isn't a region registered with
+    /// [`register_expected`](struct.EspHeap.html#method.register_expected)
+    /// that hasn't been marked available yet, or if the memory overlaps
+    /// another region.
+    ///
+    /// # Safety
+    ///
+    /// See [`add_region`](struct.EspHeap.html#method.add_region).
+    pub unsafe fn mark_available(&self, region: RegionId, heap_bottom: *mut u8, size: usize) {
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            check_overlap(&regions[..], heap_bottom, size);
+            let slot = regions
+                .get_mut(region.index)
+                .filter(|region| {
+                    matches!(
+                        region.status,
+                        RegionStatus::Expected | RegionStatus::Unavailable(_)
+                    )
+                })
+                .unwrap_or_else(|| {
+                    fail!(
+                        "not an expected region",
+                        "{region} is not an expected region"
+                    )
+                });
+            slot.activate(heap_bottom, size);
+        });
+        self.initialized.store(true, Ordering::Relaxed);
+    }
+
+    /// Records that the memory of an expected region is unavailable
+    ///
+    /// Does nothing if `region` isn't a region that is still expected.
+    pub fn mark_unavailable(&self, region: RegionId, reason: &'static str) {
+        self.locked(|cs| {
+            if let Some(region) = self.heap.borrow(cs).borrow_mut().get_mut(region.index) {
+                if region.status == RegionStatus::Expected {
+                    region.status = RegionStatus::Unavailable(reason);
+                }
+            }
+        });
+    }
+
+    /// Returns the expected regions that are not available
+    pub fn missing_regions(&self) -> impl Iterator<Item = MissingRegion> {
+        let mut missing = [None; MAX_REGIONS];
+        self.locked(|cs| {
+            let regions = self.heap.borrow(cs).borrow();
+            for (index, region) in regions.iter().enumerate() {
+                let reason = match region.status {
+                    RegionStatus::Expected => None,
+                    RegionStatus::Unavailable(reason) => Some(reason),
+                    _ => continue,
+                };
+                missing[index] = Some(MissingRegion {
+                    region: region.id(index),
+                    capabilities: region.capabilities,
+                    reason,
+                });
+            }
+        });
+        missing.into_iter().flatten()
+    }
+}
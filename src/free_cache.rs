@@ -0,0 +1,202 @@
+//! Per-region cache of recently freed blocks
+
+use core::{alloc::Layout, ptr::NonNull};
+
+use crate::{holes, EspHeap, RegionId};
+
+/// Maximum number of blocks the free-block cache of a region can hold
+pub const FREE_CACHE_CAPACITY: usize = 16;
+
+/// Usage of a region's free-block cache, see
+/// [`free_cache_stats`](struct.EspHeap.html#method.free_cache_stats)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct FreeCacheStats {
+    /// Allocations served from the cache
+    pub hits: usize,
+    /// Allocations the cache had no fitting block for
+    pub misses: usize,
+    /// Blocks currently in the cache
+    pub blocks: usize,
+    /// Bytes currently in the cache
+    pub bytes: usize,
+}
+
+/// How a region picks the block for an allocation, see
+/// [`set_alloc_strategy`](struct.EspHeap.html#method.set_alloc_strategy)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AllocStrategy {
+    /// The lowest free block that fits
+    #[default]
+    FirstFit,
+    /// The most recently freed block of the same size class, if there is
+    /// one, and the lowest free block that fits otherwise
+    RecentlyFreed,
+}
+
+/// Freed blocks kept out of the free list, so that allocations of the same
+/// size class can take them back without searching
+///
+/// The blocks form a stack, most recently freed on top.
+#[derive(Clone, Copy)]
+pub(crate) struct FreeCache {
+    blocks: [Option<(usize, Layout)>; FREE_CACHE_CAPACITY],
+    len: usize,
+    pub(crate) capacity: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl FreeCache {
+    pub(crate) const fn new() -> Self {
+        Self {
+            blocks: [None; FREE_CACHE_CAPACITY],
+            len: 0,
+            capacity: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Takes the block at `index` out of the stack.
+    fn remove(&mut self, index: usize) -> Option<(usize, Layout)> {
+        let block = self.blocks[index].take();
+        self.blocks[index..self.len].rotate_left(1);
+        self.len -= 1;
+        block
+    }
+
+    /// Takes the most recently cached block the backing allocator would have
+    /// made just as big for `layout`, and that is aligned for it.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    pub(crate) fn take(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let size = holes::block_size(layout.size());
+        let index = self.blocks[..self.len].iter().rposition(|slot| {
+            matches!(slot, Some((ptr, cached)) if holes::block_size(cached.size()) == size
+                && ptr % layout.align() == 0)
+        });
+        match index.and_then(|index| self.remove(index)) {
+            Some((ptr, _)) => {
+                self.hits += 1;
+                // SAFETY: only non-null block addresses are cached.
+                Some(unsafe { NonNull::new_unchecked(ptr as *mut u8) })
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches a freed block, returning `false` if the cache is full.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    pub(crate) fn put(&mut self, ptr: NonNull<u8>, layout: Layout) -> bool {
+        if self.len >= self.capacity {
+            return false;
+        }
+        self.blocks[self.len] = Some((ptr.as_ptr() as usize, layout));
+        self.len += 1;
+        true
+    }
+
+    /// Removes the least recently cached block.
+    pub(crate) fn pop(&mut self) -> Option<(NonNull<u8>, Layout)> {
+        if self.len == 0 {
+            return None;
+        }
+        let (ptr, layout) = self.remove(0)?;
+        // SAFETY: only non-null block addresses are cached.
+        Some((unsafe { NonNull::new_unchecked(ptr as *mut u8) }, layout))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the number of bytes the backing allocator holds for cached
+    /// blocks.
+    pub(crate) fn bytes(&self) -> usize {
+        self.blocks
+            .iter()
+            .flatten()
+            .map(|(_, layout)| holes::block_size(layout.size()))
+            .sum()
+    }
+}
+
+impl EspHeap {
+    /// Keeps up to `blocks` freed blocks of the given region in a cache
+    ///
+    /// Workloads that free and re-allocate the same few sizes, like packet
+    /// buffers, then get their blocks back without a free-list search: a
+    /// deallocation parks the block in the cache if there is room, and an
+    /// allocation first looks for a cached block of the same size class.
+    /// Cached blocks go back to the free list when the region would
+    /// otherwise run out of memory, when the cache is shrunk and on
+    /// [`flush_free_cache`](struct.EspHeap.html#method.flush_free_cache).
+    ///
+    /// `blocks` is capped at [`FREE_CACHE_CAPACITY`]; `0`, the default,
+    /// disables the cache.
+    pub fn set_free_cache(&self, region: RegionId, blocks: usize) {
+        self.locked(|cs| {
+            if let Some(region) = self.heap.borrow(cs).borrow_mut().get_mut(region.index) {
+                region.cache.capacity = blocks.min(FREE_CACHE_CAPACITY);
+                while region.cache.len() > region.cache.capacity {
+                    region.spill_one();
+                }
+            }
+        });
+    }
+
+    /// Sets how the given region picks blocks for allocations
+    ///
+    /// [`AllocStrategy::RecentlyFreed`] keeps the last
+    /// [`FREE_CACHE_CAPACITY`] freed blocks of the region on a stack and
+    /// serves an allocation from the most recently freed one of the same
+    /// size class. Its memory is likely still in the data cache, which pays
+    /// off for cached external memory like PSRAM on the ESP32-S3, where a
+    /// first-fit allocation may land on a block that has long been evicted.
+    /// This is the free-block cache of
+    /// [`set_free_cache`](struct.EspHeap.html#method.set_free_cache) at its
+    /// full capacity; [`AllocStrategy::FirstFit`], the default, disables it.
+    pub fn set_alloc_strategy(&self, region: RegionId, strategy: AllocStrategy) {
+        let blocks = match strategy {
+            AllocStrategy::FirstFit => 0,
+            AllocStrategy::RecentlyFreed => FREE_CACHE_CAPACITY,
+        };
+        self.set_free_cache(region, blocks);
+    }
+
+    /// Returns all blocks in the free-block cache of the given region to its
+    /// free list
+    pub fn flush_free_cache(&self, region: RegionId) {
+        self.locked(|cs| {
+            if let Some(region) = self.heap.borrow(cs).borrow_mut().get_mut(region.index) {
+                region.flush_cache();
+            }
+        });
+    }
+
+    /// Returns the usage of the free-block cache of the given region
+    ///
+    /// `hits` against `misses` tells whether the cache earns its keep.
+    pub fn free_cache_stats(&self, region: RegionId) -> FreeCacheStats {
+        self.locked(|cs| {
+            self.heap.borrow(cs).borrow().get(region.index).map_or(
+                FreeCacheStats::default(),
+                |region| FreeCacheStats {
+                    hits: region.cache.hits,
+                    misses: region.cache.misses,
+                    blocks: region.cache.len(),
+                    bytes: region.cache.bytes(),
+                },
+            )
+        })
+    }
+}
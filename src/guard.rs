@@ -0,0 +1,223 @@
+//! Canaries around every block, to catch overruns where they happen
+//!
+//! In front of every block is a header of its size, the size of the header
+//! itself and a canary word, padded so that the block keeps its alignment.
+//! Another canary word follows right behind the block. The header makes the
+//! used parts of a region walkable block by block, which is how
+//! [`check_integrity`](struct.EspHeap.html#method.check_integrity) finds
+//! every live block.
+
+use core::{alloc::Layout, fmt, mem::size_of, ptr::NonNull};
+
+use crate::{holes, isolate, EspHeap, Region, RegionId, RegionStatus};
+
+const WORD: usize = size_of::<usize>();
+
+const FRONT_CANARY: usize = 0x5afe_c0de;
+const BACK_CANARY: usize = 0xc0de_5afe;
+
+/// A block with damaged canaries, found by
+/// [`check_integrity`](struct.EspHeap.html#method.check_integrity)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct CorruptionInfo {
+    /// The region the block is in
+    pub region: RegionId,
+    /// Address of the block, or of its header if the header is damaged, in
+    /// which case nothing behind it in the region could be checked
+    pub address: usize,
+    /// Size of the block, `0` if its header is damaged
+    pub size: usize,
+}
+
+impl fmt::Display for CorruptionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.size == 0 {
+            write!(f, "damaged block header at {:#x}", self.address)?;
+        } else {
+            write!(
+                f,
+                "overrun around the block of {} bytes at {:#x}",
+                self.size, self.address
+            )?;
+        }
+        write!(f, " in {}", self.region)
+    }
+}
+
+/// Returns the size of the header in front of a block with `layout`.
+pub(crate) fn front(layout: Layout) -> usize {
+    (3 * WORD + layout.align() - 1) & !(layout.align() - 1)
+}
+
+/// Bytes the canaries add to a block aligned for `usize`.
+pub(crate) const OVERHEAD: usize = 4 * WORD;
+
+/// Returns the layout of a block with `layout` and its canaries.
+pub(crate) fn outer(layout: Layout) -> Option<Layout> {
+    let size = front(layout)
+        .checked_add(layout.size())?
+        .checked_add(WORD)?;
+    Layout::from_size_align(size, layout.align().max(WORD)).ok()
+}
+
+/// Returns the size of [`outer`], which must exist.
+pub(crate) fn outer_size(layout: Layout) -> usize {
+    front(layout) + layout.size() + WORD
+}
+
+/// Writes the header and canaries of a block with `layout` into the outer
+/// block at `outer`, returning the block.
+///
+/// # Safety
+///
+/// `outer` must be valid for writes of [`outer`] bytes.
+pub(crate) unsafe fn arm(outer: NonNull<u8>, layout: Layout) -> NonNull<u8> {
+    let front = front(layout);
+    let header = outer.as_ptr() as *mut usize;
+    header.write(layout.size());
+    header.add(1).write(front);
+    let ptr = outer.as_ptr().add(front);
+    (ptr as *mut usize).sub(1).write(FRONT_CANARY);
+    (ptr.add(layout.size()) as *mut usize).write_unaligned(BACK_CANARY);
+    NonNull::new_unchecked(ptr)
+}
+
+/// Returns whether the header and canaries of the block of `size` bytes at
+/// `ptr`, behind a header of `front` bytes, are intact.
+///
+/// # Safety
+///
+/// The header and canaries must be readable.
+unsafe fn intact(ptr: *const u8, front: usize, size: usize) -> bool {
+    let header = ptr.sub(front) as *const usize;
+    header.read() == size
+        && header.add(1).read() == front
+        && (ptr as *const usize).sub(1).read() == FRONT_CANARY
+        && (ptr.add(size) as *const usize).read_unaligned() == BACK_CANARY
+}
+
+/// Checks the canaries of the block at `ptr`, returning its outer block.
+///
+/// # Panics
+///
+/// Panics if the canaries are damaged.
+///
+/// # Safety
+///
+/// `ptr` must have been returned by [`arm`] with `layout`.
+pub(crate) unsafe fn check(ptr: NonNull<u8>, layout: Layout) -> NonNull<u8> {
+    let front = front(layout);
+    if !intact(ptr.as_ptr(), front, layout.size()) {
+        fail!(
+            "heap corruption",
+            "Heap corruption around the block of {} bytes at {:p}",
+            layout.size(),
+            ptr
+        );
+    }
+    NonNull::new_unchecked(ptr.as_ptr().sub(front))
+}
+
+impl Region {
+    /// Checks the canaries of every block in the region, returning the
+    /// address and size of the first damaged one.
+    fn check_blocks(&mut self) -> Result<(), (usize, usize)> {
+        let bottom = self.heap.bottom() as usize;
+        let start = (bottom + holes::BLOCK_ALIGN - 1) & !(holes::BLOCK_ALIGN - 1);
+        let end = bottom + self.size();
+        let headroom = (self.headroom_block, self.headroom_held);
+        let line = self.cache_line;
+
+        // Walking writes into the free blocks.
+        self.untouched = self.heap.top() as usize;
+        self.replay = None;
+        let mut cursor = start;
+        let mut result = Ok(());
+        holes::walk(&mut self.heap, |hole, size| {
+            if result.is_ok() {
+                // SAFETY: everything between two holes is allocated blocks.
+                result = unsafe { check_span(cursor, hole as usize, headroom, line) };
+            }
+            cursor = hole as usize + size;
+        });
+        result?;
+        // SAFETY: as above, up to the end of the region.
+        unsafe { check_span(cursor, end, headroom, line) }
+    }
+}
+
+/// Checks every block in `[start, end)`, which must be allocated blocks
+/// only, apart from the headroom block and less than a minimum block of
+/// slack at the end.
+///
+/// # Safety
+///
+/// The blocks must be readable and not be written to while this runs.
+unsafe fn check_span(
+    start: usize,
+    end: usize,
+    (headroom, headroom_size): (usize, usize),
+    line: usize,
+) -> Result<(), (usize, usize)> {
+    let mut cursor = start;
+    while cursor + holes::MIN_BLOCK <= end {
+        if cursor == headroom {
+            cursor += headroom_size;
+            continue;
+        }
+
+        let header = cursor as *const usize;
+        let (size, front) = (header.read(), header.add(1).read());
+        let room = end - cursor;
+        if front < 3 * WORD || front % WORD != 0 || front > room || size > room - front - WORD {
+            return Err((cursor, 0));
+        }
+        if !intact((cursor + front) as *const u8, front, size) {
+            return Err((cursor + front, size));
+        }
+
+        let outer = Layout::from_size_align_unchecked(front + size + WORD, WORD);
+        let outer = isolate(outer, line).unwrap_or(outer);
+        cursor += holes::block_size(outer.size());
+    }
+    Ok(())
+}
+
+impl EspHeap {
+    /// Checks the canaries around every live block (the `heap-guard`
+    /// feature)
+    ///
+    /// With the feature, every allocation is framed by canary words, which
+    /// are checked whenever the block is freed, and by this method for all
+    /// blocks at once. Call it periodically, say from a watchdog task, to
+    /// catch a buffer overrun close to where it happened, instead of
+    /// through a crash in the allocator much later. Returns the first
+    /// damaged block found.
+    ///
+    /// The [watched ranges](struct.EspHeap.html#method.watch_range) are
+    /// checked as well, and any change reported to their handlers.
+    ///
+    /// This walks the free list of every region, which is `O(n²)` in the
+    /// number of free blocks, in a single critical section.
+    pub fn check_integrity(&self) -> Result<(), CorruptionInfo> {
+        self.locked(|cs| {
+            self.check_watches_locked(cs);
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            for (index, region) in regions.iter_mut().enumerate() {
+                if region.status != RegionStatus::Available {
+                    continue;
+                }
+                region
+                    .check_blocks()
+                    .map_err(|(address, size)| CorruptionInfo {
+                        region: region.id(index),
+                        address,
+                        size,
+                    })?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,135 @@
+//! Contiguous memory kept back for allocations that must not fail
+
+use core::{alloc::Layout, ptr::NonNull};
+
+use crate::{holes, Allocation, EspHeap, MemoryCapability, Region, RegionId};
+
+impl Region {
+    /// Takes the headroom out of the free list, as a single block, if it
+    /// isn't already held as a whole. Returns whether it is held afterwards.
+    ///
+    /// A part of it that is held while an allocation uses the rest is traded
+    /// for the whole headroom once that is free again.
+    pub(crate) fn reserve_headroom(&mut self) -> bool {
+        let bytes = holes::block_size(self.headroom);
+        if self.headroom == 0 || self.headroom_held == bytes {
+            return self.headroom_held != 0;
+        }
+
+        let held = self.headroom_held;
+        self.release_headroom();
+        if self.hold_headroom(bytes) {
+            return true;
+        }
+        self.hold_headroom(held);
+        false
+    }
+
+    /// Takes a block of `size` bytes out of the free list as the headroom,
+    /// returning whether that was possible.
+    fn hold_headroom(&mut self, size: usize) -> bool {
+        if size < holes::MIN_BLOCK {
+            return false;
+        }
+        let Ok(layout) = Layout::from_size_align(size, holes::BLOCK_ALIGN) else {
+            return false;
+        };
+        match self.heap.allocate_first_fit(layout) {
+            Ok(block) => {
+                let end = block.as_ptr() as usize + layout.size();
+                // The allocator may have placed a hole header right behind
+                // the block.
+                self.untouched = self.untouched.max(end + holes::MIN_BLOCK);
+                self.headroom_block = block.as_ptr() as usize;
+                self.headroom_held = size;
+                true
+            }
+            Err(()) => false,
+        }
+    }
+
+    /// Returns the headroom block to the free list.
+    pub(crate) fn release_headroom(&mut self) {
+        if let Some(block) = NonNull::new(self.headroom_block as *mut u8) {
+            // SAFETY: the block was allocated with this size, and nothing but
+            // the region knows about it.
+            unsafe {
+                let layout =
+                    Layout::from_size_align_unchecked(self.headroom_held, holes::BLOCK_ALIGN);
+                self.heap.deallocate(block, layout);
+            }
+        }
+        self.headroom_block = 0;
+        self.headroom_held = 0;
+    }
+
+    /// Serves `layout` with the headroom given back to the free list, then
+    /// takes back whatever the allocation left of it.
+    pub(crate) fn allocate_in_headroom(&mut self, layout: Layout) -> Option<Allocation> {
+        let (held, size) = match self.region_layout(layout) {
+            Some(region_layout) if self.headroom_held != 0 => {
+                (self.headroom_held, holes::block_size(region_layout.size()))
+            }
+            _ => return None,
+        };
+
+        self.release_headroom();
+        let allocation = self.allocate(layout);
+        // Nothing else had room for the allocation, so it was served from
+        // the headroom.
+        let left = if allocation.is_some() {
+            held.saturating_sub(size)
+        } else {
+            held
+        };
+        if !self.hold_headroom(holes::block_size(self.headroom)) {
+            self.hold_headroom(left);
+        }
+        allocation
+    }
+}
+
+impl EspHeap {
+    /// Keeps `bytes` of contiguous memory in the given region out of reach
+    /// of ordinary allocations
+    ///
+    /// Meant for a large buffer that must always be available on demand,
+    /// like a camera frame: the headroom is held as a single free block, so
+    /// no amount of fragmentation by other allocations can eat into it, and
+    /// only [`alloc_from_headroom`](struct.EspHeap.html#method.alloc_from_headroom)
+    /// may use it. Ordinary allocations that don't fit next to it are served
+    /// from other regions or fail. While held, the headroom counts as used in
+    /// [`used`](struct.EspHeap.html#method.used) and
+    /// [`free`](struct.EspHeap.html#method.free).
+    ///
+    /// Returns whether the headroom could be set aside right away. If there is
+    /// no contiguous free block that large yet, it is taken as soon as one
+    /// becomes free. Passing `0` removes the headroom.
+    pub fn set_headroom(&self, region: RegionId, bytes: usize) -> bool {
+        self.locked(
+            |cs| match self.heap.borrow(cs).borrow_mut().get_mut(region.index) {
+                Some(region) if region.is_initialized() => {
+                    region.release_headroom();
+                    region.headroom = bytes;
+                    bytes == 0 || region.reserve_headroom()
+                }
+                _ => false,
+            },
+        )
+    }
+
+    /// Allocates from a region with `capabilities`, using its headroom if
+    /// nothing else fits
+    ///
+    /// The headroom set with
+    /// [`set_headroom`](struct.EspHeap.html#method.set_headroom) is only
+    /// touched if no region could serve the allocation otherwise. Whatever
+    /// part of the headroom the allocation doesn't use stays reserved, and
+    /// the rest is reserved again once the allocation is freed as usual.
+    pub fn alloc_from_headroom(&self, capabilities: MemoryCapability, layout: Layout) -> *mut u8 {
+        let mut context = self.context(layout.size());
+        context.headroom = true;
+        self.allocate_with(context, capabilities, layout)
+            .map_or(core::ptr::null_mut(), |allocation| allocation.ptr.as_ptr())
+    }
+}
@@ -0,0 +1,266 @@
+//! Free-list introspection
+//!
+//! `linked_list_allocator` doesn't expose its list of holes, so the free list
+//! is walked by probing it through its public API: the first hole is located
+//! with a minimum-sized allocation, its size is found by binary search and the
+//! whole hole is then taken out of the list, which makes the next hole the
+//! first one. Once the walk is done every captured hole is handed back, which
+//! leaves the heap as it was - apart from merging any neighbouring holes that
+//! were not merged before.
+//!
+//! Walking is `O(n²)` in the number of holes, so it is meant for diagnostics
+//! and not for the allocation path.
+
+use core::{
+    alloc::Layout,
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+};
+
+use linked_list_allocator::Heap;
+
+/// Smallest block the backing allocator hands out.
+pub(crate) const MIN_BLOCK: usize = 2 * size_of::<usize>();
+
+/// Granularity of block sizes in the backing allocator.
+pub(crate) const BLOCK_ALIGN: usize = align_of::<usize>();
+
+/// Returns the number of bytes the backing allocator sets aside for an
+/// allocation of `size` bytes.
+pub(crate) const fn block_size(size: usize) -> usize {
+    let size = if size < MIN_BLOCK { MIN_BLOCK } else { size };
+    (size + BLOCK_ALIGN - 1) & !(BLOCK_ALIGN - 1)
+}
+
+fn block_layout(size: usize) -> Layout {
+    // SAFETY: `BLOCK_ALIGN` is a power of two and `size` never exceeds the
+    // size of the heap.
+    unsafe { Layout::from_size_align_unchecked(size, BLOCK_ALIGN) }
+}
+
+/// Allocates `size` bytes and releases them again, returning whether the
+/// allocation was served from `addr`.
+fn served_at(heap: &mut Heap, addr: NonNull<u8>, size: usize) -> bool {
+    let layout = block_layout(size);
+    match heap.allocate_first_fit(layout) {
+        Ok(ptr) => {
+            // SAFETY: `ptr` was just allocated with `layout`.
+            unsafe { heap.deallocate(ptr, layout) };
+            ptr == addr
+        }
+        Err(()) => false,
+    }
+}
+
+/// Finds the size of the hole starting at `addr`, which must be the first hole
+/// in the list and able to serve `MIN_BLOCK` bytes.
+///
+/// A hole of size `h` can serve any size up to `h - MIN_BLOCK` (the remainder
+/// becomes a new hole) and exactly `h`, but nothing in between. The binary
+/// search therefore ends on either `h - MIN_BLOCK` or `h`, which one more probe
+/// tells apart.
+fn hole_size(heap: &mut Heap, addr: NonNull<u8>) -> usize {
+    let mut lo = MIN_BLOCK;
+    let mut hi = heap.free() + BLOCK_ALIGN;
+
+    while hi - lo > BLOCK_ALIGN {
+        let mid = lo + (hi - lo) / 2 / BLOCK_ALIGN * BLOCK_ALIGN;
+        if served_at(heap, addr, mid) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    if served_at(heap, addr, lo + MIN_BLOCK) {
+        lo + MIN_BLOCK
+    } else {
+        lo
+    }
+}
+
+/// Takes the first hole out of the list, returning its address and size.
+///
+/// A minimum-sized probe skips holes of more than `MIN_BLOCK` but less than
+/// twice that, which can't spare a hole for the rest. Those can only serve
+/// their exact size, so each of these sizes is probed as well and the lowest
+/// address wins.
+fn capture_first(heap: &mut Heap) -> Option<(NonNull<u8>, usize)> {
+    let mut first: Option<(NonNull<u8>, usize)> = None;
+    let mut size = MIN_BLOCK;
+    while size < 2 * MIN_BLOCK {
+        if let Ok(addr) = heap.allocate_first_fit(block_layout(size)) {
+            // SAFETY: `addr` was just allocated with this layout.
+            unsafe { heap.deallocate(addr, block_layout(size)) };
+            if first.map_or(true, |(first, _)| addr < first) {
+                first = Some((addr, size));
+            }
+        }
+        size += BLOCK_ALIGN;
+    }
+
+    let (addr, probe) = first?;
+    let size = if probe == MIN_BLOCK {
+        hole_size(heap, addr)
+    } else {
+        probe
+    };
+    match heap.allocate_first_fit(block_layout(size)) {
+        Ok(captured) if captured == addr => Some((addr, size)),
+        Ok(captured) => {
+            // SAFETY: `captured` was just allocated with this layout.
+            unsafe { heap.deallocate(captured, block_layout(size)) };
+            None
+        }
+        Err(()) => None,
+    }
+}
+
+/// Holes taken out of the list, chained through their first two words: the
+/// address and size of the previously captured hole.
+struct Captured {
+    last: Option<(NonNull<u8>, usize)>,
+}
+
+impl Captured {
+    /// # Safety
+    ///
+    /// The hole must have been captured with [`capture_first`].
+    unsafe fn push(&mut self, addr: NonNull<u8>, size: usize) {
+        // The hole is at least `MIN_BLOCK` bytes and aligned for `usize`.
+        let link = addr.as_ptr() as *mut usize;
+        let (prev, prev_size) = self
+            .last
+            .map_or((ptr::null_mut(), 0), |(p, s)| (p.as_ptr(), s));
+        link.write(prev as usize);
+        link.add(1).write(prev_size);
+        self.last = Some((addr, size));
+    }
+
+    /// Hands every captured hole back to `heap`.
+    fn release(mut self, heap: &mut Heap) {
+        while let Some((addr, size)) = self.last {
+            // SAFETY: every link was written by `push` and the hole is still
+            // captured.
+            unsafe {
+                let link = addr.as_ptr() as *const usize;
+                let prev = link.read() as *mut u8;
+                let prev_size = link.add(1).read();
+                self.last = NonNull::new(prev).map(|p| (p, prev_size));
+
+                heap.deallocate(addr, block_layout(size));
+            }
+        }
+    }
+}
+
+/// Calls `f` with the address and size of every hole in `heap`, in address
+/// order.
+///
+/// `f` runs while the walk is in progress, so it must not touch `heap`.
+pub(crate) fn walk(heap: &mut Heap, f: impl FnMut(*mut u8, usize)) {
+    walk_from(heap, 0, usize::MAX, f);
+}
+
+/// Like [`walk`], but starts at `start` and stops after `max_holes` holes,
+/// returning where to go on from if there may be more.
+///
+/// This splits a walk over several critical sections. A hole that reaches
+/// past `start` is reported from `start` on, so one that got merged with
+/// holes already reported in between isn't counted twice. The holes in
+/// front of `start` still have to be taken out of the list to get past
+/// them, which makes each part more expensive than the one before.
+pub(crate) fn walk_from(
+    heap: &mut Heap,
+    start: usize,
+    max_holes: usize,
+    mut f: impl FnMut(*mut u8, usize),
+) -> Option<usize> {
+    let mut captured = Captured { last: None };
+    let mut reported = 0;
+    let mut next = None;
+    while let Some((addr, size)) = capture_first(heap) {
+        // SAFETY: the hole was just captured.
+        unsafe { captured.push(addr, size) };
+        let hole = addr.as_ptr() as usize;
+        let end = hole + size;
+        if end <= start {
+            continue;
+        }
+
+        let from = hole.max(start);
+        f(from as *mut u8, end - from);
+        reported += 1;
+        if reported == max_holes {
+            next = Some(end);
+            break;
+        }
+    }
+    captured.release(heap);
+    next
+}
+
+/// Takes `[start, end)` out of the free list for good, where `end` is the
+/// end of the last hole, returning whether that was possible.
+///
+/// The range must lie in a single hole, which is what walking the list
+/// first makes sure of, and any part of the hole in front of it must be big
+/// enough to still be a hole. The range is then accounted as used, and can
+/// be handed back with a deallocation of its size.
+pub(crate) fn take_tail(heap: &mut Heap, start: usize, end: usize) -> bool {
+    let mut captured = Captured { last: None };
+    let mut taken = false;
+    while let Some((addr, size)) = capture_first(heap) {
+        let hole = addr.as_ptr() as usize;
+        if hole + size != end {
+            // SAFETY: the hole was just captured.
+            unsafe { captured.push(addr, size) };
+            continue;
+        }
+
+        let front = start.wrapping_sub(hole);
+        if start < hole || (front != 0 && front < MIN_BLOCK) {
+            // SAFETY: the hole was just captured with this size.
+            unsafe { heap.deallocate(addr, block_layout(size)) };
+        } else {
+            if front != 0 {
+                // SAFETY: the front is part of the captured hole, and big
+                // enough to be a hole of its own.
+                unsafe { heap.deallocate(addr, block_layout(front)) };
+            }
+            taken = true;
+        }
+        break;
+    }
+    captured.release(heap);
+    taken
+}
+
+/// Returns the size of the largest block the backing allocator can carve out
+/// of the hole at `addr` that is aligned to `align`, a multiple of `align` in
+/// size and doesn't reach past `limit`. Returns `0` if there is none.
+///
+/// A block that isn't at the very start of the hole needs at least
+/// `MIN_BLOCK` bytes of front padding, and one that doesn't end the hole
+/// needs at least `MIN_BLOCK` bytes behind it, which become new holes.
+pub(crate) fn largest_fit(addr: usize, size: usize, align: usize, limit: usize) -> usize {
+    let aligned = if addr % align == 0 {
+        addr
+    } else {
+        (addr + MIN_BLOCK + align - 1) & !(align - 1)
+    };
+    let end = addr + size;
+
+    let exact = end.saturating_sub(aligned);
+    let fit = if end <= limit && exact % align == 0 {
+        exact
+    } else {
+        let end = limit.min(end.saturating_sub(MIN_BLOCK));
+        end.saturating_sub(aligned) / align * align
+    };
+    if fit < MIN_BLOCK {
+        0
+    } else {
+        fit
+    }
+}
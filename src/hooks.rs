@@ -0,0 +1,82 @@
+//! Hooks for reporting allocations to external heap tracing
+
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::EspHeap;
+
+/// Called with the address and size of a block
+type BlockHook = fn(*mut u8, usize);
+
+/// The hooks, readable without entering a critical section
+pub(crate) struct AllocHooks {
+    /// The hooks as `fn(*mut u8, usize)`, `0` if none is set
+    on_alloc: AtomicUsize,
+    on_free: AtomicUsize,
+}
+
+impl AllocHooks {
+    pub(crate) const fn new() -> Self {
+        Self {
+            on_alloc: AtomicUsize::new(0),
+            on_free: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes over the hooks of `other`.
+    pub(crate) fn reset(&self, other: Self) {
+        self.on_alloc
+            .store(other.on_alloc.into_inner(), Ordering::Relaxed);
+        self.on_free
+            .store(other.on_free.into_inner(), Ordering::Relaxed);
+    }
+
+    /// Reports a block that was just allocated.
+    #[inline]
+    pub(crate) fn allocated(&self, ptr: *mut u8, size: usize) {
+        call(&self.on_alloc, ptr, size);
+    }
+
+    /// Reports a block that is about to be freed.
+    #[inline]
+    pub(crate) fn freeing(&self, ptr: *mut u8, size: usize) {
+        call(&self.on_free, ptr, size);
+    }
+}
+
+#[inline]
+fn call(hook: &AtomicUsize, ptr: *mut u8, size: usize) {
+    let hook = hook.load(Ordering::Relaxed);
+    if hook != 0 {
+        // SAFETY: non-zero values are only ever stored from a `BlockHook`.
+        let hook: BlockHook = unsafe { mem::transmute(hook) };
+        hook(ptr, size);
+    }
+}
+
+impl EspHeap {
+    /// Reports every allocation and deallocation to an external tracer, like
+    /// ESP-IDF's heap tracing
+    ///
+    /// `on_alloc` is called with the address and requested size of every
+    /// successful allocation, and `on_free` with those of every block about
+    /// to be freed, so a block is always reported freed before its memory can
+    /// be handed out again. Both run outside of the critical section, and may
+    /// take locks of their own, but must not use this heap.
+    pub fn set_alloc_hooks(&self, on_alloc: fn(*mut u8, usize), on_free: fn(*mut u8, usize)) {
+        self.alloc_hooks
+            .on_alloc
+            .store(on_alloc as usize, Ordering::Relaxed);
+        self.alloc_hooks
+            .on_free
+            .store(on_free as usize, Ordering::Relaxed);
+    }
+
+    /// Stops reporting allocations and deallocations
+    pub fn clear_alloc_hooks(&self) {
+        self.alloc_hooks.on_alloc.store(0, Ordering::Relaxed);
+        self.alloc_hooks.on_free.store(0, Ordering::Relaxed);
+    }
+}
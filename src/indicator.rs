@@ -0,0 +1,144 @@
+//! Heap usage levels, for boards without a console
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use critical_section::{CriticalSection, Mutex};
+
+use crate::EspHeap;
+
+/// The maximum number of thresholds a usage indicator can have
+pub const MAX_USAGE_LEVELS: usize = 8;
+
+/// Receives the new usage level, see
+/// [`usage_indicator`](struct.EspHeap.html#method.usage_indicator)
+pub(crate) type LevelHandler = fn(u8);
+
+#[derive(Clone, Copy)]
+struct Bands {
+    /// Ascending, only the first `len` are used
+    thresholds: [usize; MAX_USAGE_LEVELS],
+    len: usize,
+    level: u8,
+    handler: LevelHandler,
+}
+
+impl Bands {
+    /// Returns the level for `used` bytes, coming from the current level.
+    ///
+    /// A level is entered once usage reaches its threshold, but only left
+    /// again once usage drops a sixteenth below it, so that usage hovering
+    /// around a threshold doesn't toggle the level on every operation.
+    fn level(&self, used: usize) -> u8 {
+        let thresholds = &self.thresholds[..self.len];
+        let mut level = usize::from(self.level);
+        while level < thresholds.len() && used >= thresholds[level] {
+            level += 1;
+        }
+        while level > 0 && used < thresholds[level - 1] - thresholds[level - 1] / 16 {
+            level -= 1;
+        }
+        level as u8
+    }
+}
+
+/// Whether an indicator is set is readable without entering a critical
+/// section, so heaps without one only pay for a single load.
+pub(crate) struct UsageIndicator {
+    active: AtomicBool,
+    bands: Mutex<Cell<Option<Bands>>>,
+}
+
+impl UsageIndicator {
+    pub(crate) const fn new() -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            bands: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Takes over the state of `other`.
+    pub(crate) fn reset(&self, cs: CriticalSection<'_>, other: Self) {
+        self.active
+            .store(other.active.into_inner(), Ordering::Relaxed);
+        self.bands.borrow(cs).set(other.bands.into_inner().get());
+    }
+}
+
+impl EspHeap {
+    /// Calls `callback` whenever the bytes in use move to another band
+    /// between `thresholds`
+    ///
+    /// Meant for bring-up on boards with an LED but no console: the level
+    /// is the number of thresholds usage has reached, from `0` below the
+    /// first one to `N` at or above the last one, and the application maps
+    /// it to a blink pattern or an output voltage. A level is only left once
+    /// usage drops a sixteenth below its threshold, so usage hovering around
+    /// a threshold doesn't flood the callback.
+    ///
+    /// Usage is checked after every allocation and deallocation, and the
+    /// callback runs outside of the critical section, so it may use the heap
+    /// itself. Returns the current level, for which the callback isn't
+    /// called.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than [`MAX_USAGE_LEVELS`] thresholds, or if
+    /// they aren't ascending.
+    pub fn usage_indicator<const N: usize>(&self, thresholds: [usize; N], callback: fn(u8)) -> u8 {
+        assert!(N <= MAX_USAGE_LEVELS, "too many usage indicator thresholds");
+        assert!(
+            thresholds.windows(2).all(|pair| pair[0] < pair[1]),
+            "usage indicator thresholds must be ascending"
+        );
+
+        let mut bands = Bands {
+            thresholds: [0; MAX_USAGE_LEVELS],
+            len: N,
+            level: 0,
+            handler: callback,
+        };
+        bands.thresholds[..N].copy_from_slice(&thresholds);
+        self.locked(|cs| {
+            bands.level = bands.level(self.used_locked(cs));
+            self.indicator.bands.borrow(cs).set(Some(bands));
+            self.indicator.active.store(true, Ordering::Relaxed);
+            bands.level
+        })
+    }
+
+    /// Stops calling the usage indicator callback
+    pub fn clear_usage_indicator(&self) {
+        self.locked(|cs| {
+            self.indicator.active.store(false, Ordering::Relaxed);
+            self.indicator.bands.borrow(cs).set(None);
+        });
+    }
+
+    /// Calls the usage indicator callback if usage moved to another band.
+    ///
+    /// This must be called outside of a critical section.
+    #[inline]
+    pub(crate) fn indicate_usage(&self) {
+        if !self.indicator.active.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let change = self.locked(|cs| {
+            let cell = self.indicator.bands.borrow(cs);
+            let mut bands = cell.get()?;
+            let level = bands.level(self.used_locked(cs));
+            if level == bands.level {
+                return None;
+            }
+            bands.level = level;
+            cell.set(Some(bands));
+            Some((bands.handler, level))
+        });
+        if let Some((handler, level)) = change {
+            handler(level);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Detection of heap use from interrupt handlers
+
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::EspHeap;
+
+/// What to do when the heap is used from an interrupt handler, see
+/// [`set_isr_guard`](struct.EspHeap.html#method.set_isr_guard)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IsrPolicy {
+    /// Panic, naming the offending operation
+    Panic,
+    /// Count the operation and carry on as usual
+    Allow,
+    /// Count the operation and only try the regions themselves: anything that
+    /// may take long before giving up, like flushing the quarantine, is
+    /// skipped and the allocation fails instead
+    TryOnly,
+}
+
+impl IsrPolicy {
+    const fn from_usize(value: usize) -> Self {
+        match value {
+            0 => Self::Panic,
+            1 => Self::Allow,
+            _ => Self::TryOnly,
+        }
+    }
+}
+
+/// The probe and policy, readable without entering a critical section
+pub(crate) struct IsrGuard {
+    /// The probe as a `fn() -> bool`, `0` if none is set
+    probe: AtomicUsize,
+    policy: AtomicUsize,
+}
+
+impl IsrGuard {
+    pub(crate) const fn new() -> Self {
+        Self {
+            probe: AtomicUsize::new(0),
+            policy: AtomicUsize::new(IsrPolicy::Panic as usize),
+        }
+    }
+
+    /// Takes over the probe and policy of `other`.
+    pub(crate) fn reset(&self, other: Self) {
+        self.policy
+            .store(other.policy.into_inner(), Ordering::Relaxed);
+        self.probe
+            .store(other.probe.into_inner(), Ordering::Relaxed);
+    }
+
+    /// Returns the policy to apply if the caller runs in an interrupt handler.
+    ///
+    /// This must be called outside of a critical section, which may look like
+    /// interrupt context to the probe.
+    #[inline]
+    pub(crate) fn check(&self) -> Option<IsrPolicy> {
+        let probe = self.probe.load(Ordering::Relaxed);
+        if probe == 0 {
+            return None;
+        }
+
+        // SAFETY: non-zero values are only ever stored from a `fn() -> bool`.
+        let probe: fn() -> bool = unsafe { mem::transmute(probe) };
+        if probe() {
+            Some(IsrPolicy::from_usize(self.policy.load(Ordering::Relaxed)))
+        } else {
+            None
+        }
+    }
+}
+
+impl EspHeap {
+    /// Checks every allocation and deallocation for interrupt context
+    ///
+    /// `in_interrupt` is called on every heap operation and must return
+    /// `true` when running in an interrupt handler, so it should be cheap,
+    /// like reading the current interrupt level. What happens then is decided
+    /// by `policy`; the operations are counted by
+    /// [`isr_allocations`](struct.EspHeap.html#method.isr_allocations)
+    /// unless the policy is to panic.
+    pub fn set_isr_guard(&self, in_interrupt: fn() -> bool, policy: IsrPolicy) {
+        self.isr_guard
+            .policy
+            .store(policy as usize, Ordering::Relaxed);
+        self.isr_guard
+            .probe
+            .store(in_interrupt as usize, Ordering::Relaxed);
+    }
+
+    /// Stops checking for interrupt context
+    pub fn clear_isr_guard(&self) {
+        self.isr_guard.probe.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns the number of allocations and deallocations made from
+    /// interrupt handlers
+    pub fn isr_allocations(&self) -> usize {
+        self.locked(|cs| self.isr_allocations.borrow(cs).get())
+    }
+}
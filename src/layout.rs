@@ -0,0 +1,306 @@
+//! Heap configuration from the linker's memory layout
+
+use crate::{holes, EspHeap, MemoryCapability, RegionId, MAX_REGIONS};
+
+/// A range of memory the linker left free, between `start` and `end`
+///
+/// Usually built by
+/// [`configure_from_layout!`](macro.configure_from_layout.html) from linker
+/// symbols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MemoryRange {
+    /// First byte of the range
+    pub start: *mut u8,
+    /// One past the last byte of the range
+    pub end: *mut u8,
+    /// Capabilities of the memory in the range
+    pub capabilities: MemoryCapability,
+}
+
+impl MemoryRange {
+    /// Describes the memory in `[start, end)`
+    pub const fn new(start: *mut u8, end: *mut u8, capabilities: MemoryCapability) -> Self {
+        Self {
+            start,
+            end,
+            capabilities,
+        }
+    }
+}
+
+/// A region of a memory map known at compile time, see [`validate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HeapRegion {
+    /// Address of the first byte, `None` if the linker places the region,
+    /// as it does for a static buffer
+    pub start: Option<usize>,
+    /// Size of the region in bytes
+    pub size: usize,
+    /// Capabilities of the memory in the region
+    pub capabilities: MemoryCapability,
+}
+
+impl HeapRegion {
+    /// Describes a region at a fixed address, like external memory mapped
+    /// by the cache
+    pub const fn fixed(start: usize, size: usize, capabilities: MemoryCapability) -> Self {
+        Self {
+            start: Some(start),
+            size,
+            capabilities,
+        }
+    }
+
+    /// Describes a static buffer, whose address is up to the linker
+    pub const fn buffer(size: usize, capabilities: MemoryCapability) -> Self {
+        Self {
+            start: None,
+            size,
+            capabilities,
+        }
+    }
+
+    /// Returns the end of a fixed region, `None` if it has no fixed address
+    /// or wraps around the address space.
+    const fn end(&self) -> Option<usize> {
+        match self.start {
+            Some(start) => start.checked_add(self.size),
+            None => None,
+        }
+    }
+}
+
+/// Checks a memory map that is known at compile time
+///
+/// Rejects maps with more than [`MAX_REGIONS`] regions, regions too small to
+/// allocate from, regions claiming to be both internal and external memory,
+/// fixed regions that wrap around the address space and fixed regions that
+/// overlap. Static buffers can't overlap anything and only have their size
+/// and capabilities checked.
+///
+/// Being a `const fn`, this can run at compile time, which turns a bad map
+/// into a build error; [`check_memory_map!`](macro.check_memory_map.html)
+/// does just that. Regions whose addresses come from the linker are checked
+/// at runtime by
+/// [`configure_from_layout`](struct.EspHeap.html#method.configure_from_layout)
+/// instead.
+pub const fn validate(regions: &[HeapRegion]) -> Result<(), &'static str> {
+    if regions.len() > MAX_REGIONS {
+        return Err("the memory map has more than MAX_REGIONS regions");
+    }
+
+    let mut index = 0;
+    while index < regions.len() {
+        let region = &regions[index];
+        if region.size == 0 {
+            return Err("a region of the memory map is empty");
+        }
+        if region.size < MIN_REGION_SIZE {
+            return Err("a region of the memory map is too small to allocate from");
+        }
+        if region.capabilities.contains(MemoryCapability::INTERNAL)
+            && region.capabilities.contains(MemoryCapability::EXTERNAL)
+        {
+            return Err("a region of the memory map is both internal and external");
+        }
+
+        if let Some(start) = region.start {
+            let end = match region.end() {
+                Some(end) => end,
+                None => return Err("a region of the memory map wraps around the address space"),
+            };
+            let mut other = 0;
+            while other < index {
+                if let (Some(other_start), Some(other_end)) =
+                    (regions[other].start, regions[other].end())
+                {
+                    if start < other_end && other_start < end {
+                        return Err("two regions of the memory map overlap");
+                    }
+                }
+                other += 1;
+            }
+        }
+        index += 1;
+    }
+
+    Ok(())
+}
+
+/// A region registered by
+/// [`configure_from_layout`](struct.EspHeap.html#method.configure_from_layout)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConfiguredRegion {
+    /// The region
+    pub id: RegionId,
+    /// Address of the bottom of the region
+    pub bottom: usize,
+    /// Size of the region in bytes
+    pub size: usize,
+    /// Capabilities of the region
+    pub capabilities: MemoryCapability,
+}
+
+/// What [`configure_from_layout`](struct.EspHeap.html#method.configure_from_layout)
+/// did with the ranges it was given
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LayoutSummary {
+    /// The registered regions, in registration order
+    pub regions: [Option<ConfiguredRegion>; MAX_REGIONS],
+    /// Ranges that were empty, too small or overlapped another range
+    pub rejected: usize,
+    /// Viable ranges that didn't fit into the [`MAX_REGIONS`] regions
+    pub dropped: usize,
+}
+
+impl LayoutSummary {
+    /// Total number of bytes registered
+    pub fn total_size(&self) -> usize {
+        self.regions
+            .iter()
+            .flatten()
+            .map(|region| region.size)
+            .sum()
+    }
+}
+
+/// Ranges smaller than this can't hold the backing allocator's metadata and
+/// a useful allocation.
+const MIN_REGION_SIZE: usize = 2 * holes::MIN_BLOCK;
+
+/// Shrinks `range` to the allocator's granularity, returning `(bottom, end)`.
+fn align_range(range: &MemoryRange) -> (usize, usize) {
+    let bottom =
+        (range.start as usize).saturating_add(holes::BLOCK_ALIGN - 1) & !(holes::BLOCK_ALIGN - 1);
+    let end = range.end as usize & !(holes::BLOCK_ALIGN - 1);
+    (bottom, end)
+}
+
+impl EspHeap {
+    /// Registers every viable range of `ranges` as a heap region
+    ///
+    /// Each range is shrunk to the allocator's alignment, and rejected if it
+    /// ends up empty, too small or overlapping another range. The first
+    /// registered region is the one next to the stack: `stack_reserve` bytes
+    /// at its top are reserved as with
+    /// [`set_reserved_for_stack`](struct.EspHeap.html#method.set_reserved_for_stack),
+    /// and it is rejected as well if that leaves nothing to allocate from.
+    ///
+    /// Returns a summary of what was registered, which is handy to log at
+    /// boot.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a region has already been added to this heap.
+    ///
+    /// # Safety
+    ///
+    /// The memory in the ranges must be valid for the entire program and not
+    /// used for anything else, see
+    /// [`add_region`](struct.EspHeap.html#method.add_region).
+    pub unsafe fn configure_from_layout(
+        &self,
+        ranges: &[MemoryRange],
+        stack_reserve: usize,
+    ) -> LayoutSummary {
+        assert!(
+            self.locked(|cs| {
+                !self
+                    .heap
+                    .borrow(cs)
+                    .borrow()
+                    .iter()
+                    .any(|region| region.is_initialized())
+            }),
+            "configure_from_layout must be called on an empty heap"
+        );
+
+        let mut summary = LayoutSummary::default();
+        let mut registered = 0;
+
+        for (index, range) in ranges.iter().enumerate() {
+            let (bottom, end) = align_range(range);
+            let overlaps = ranges.iter().enumerate().any(|(other_index, other)| {
+                let (other_bottom, other_end) = align_range(other);
+                other_index != index
+                    && other_bottom < other_end
+                    && bottom < other_end
+                    && other_bottom < end
+            });
+            let reserve = if registered == 0 { stack_reserve } else { 0 };
+
+            if overlaps || end < bottom || end - bottom < MIN_REGION_SIZE.saturating_add(reserve) {
+                summary.rejected += 1;
+                continue;
+            }
+            if registered == MAX_REGIONS {
+                summary.dropped += 1;
+                continue;
+            }
+
+            let id = self.add_region(bottom as *mut u8, end - bottom, range.capabilities);
+            summary.regions[registered] = Some(ConfiguredRegion {
+                id,
+                bottom,
+                size: end - bottom,
+                capabilities: range.capabilities,
+            });
+            registered += 1;
+        }
+
+        if registered > 0 {
+            self.set_reserved_for_stack(stack_reserve);
+        }
+
+        summary
+    }
+    /// Adds the memory between two linker symbols as a heap region
+    ///
+    /// Meant for the range the linker script leaves free, like `_heap_start`
+    /// to `_heap_end` on esp-hal projects, so that the heap size doesn't have
+    /// to be kept in sync with `memory.x` by hand; see
+    /// [`init_heap_from_linker!`](macro.init_heap_from_linker.html). `start`
+    /// is rounded up and `end` down to the allocator's alignment, so an odd
+    /// placement by the linker can't misalign the heap's metadata.
+    ///
+    /// Every call adds a region of its own, like
+    /// [`add_region`](struct.EspHeap.html#method.add_region), which panics if
+    /// the range overlaps memory that was added before, such as the same
+    /// range added a second time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `end` isn't past `start`, if the aligned range is too small
+    /// to allocate from, or if it overlaps another region.
+    ///
+    /// # Safety
+    ///
+    /// The memory in `[start, end)` must be valid for the entire program and
+    /// not used for anything else, see
+    /// [`add_region`](struct.EspHeap.html#method.add_region).
+    pub unsafe fn init_from_symbols(&self, start: *mut u8, end: *mut u8) -> RegionId {
+        if end <= start {
+            fail!(
+                "heap end before its start",
+                "Heap end {:p} is not past its start {:p}",
+                end,
+                start
+            );
+        }
+        let range = MemoryRange::new(start, end, MemoryCapability::empty());
+        let (bottom, end) = align_range(&range);
+        if end < bottom || end - bottom < MIN_REGION_SIZE {
+            fail!(
+                "heap range too small",
+                "Heap range {:p}..{:p} is too small to allocate from",
+                start,
+                range.end
+            );
+        }
+        self.add_region(bottom as *mut u8, end - bottom, MemoryCapability::empty())
+    }
+}
@@ -5,20 +5,802 @@
 //! greater, or the `nightly` release channel.
 
 #![no_std]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+/// Panics with the detailed message, or just the terse one without the
+/// `verbose-errors` feature, which keeps the formatting code out of the
+/// binary.
+#[cfg(feature = "verbose-errors")]
+macro_rules! fail {
+    ($terse:literal, $($verbose:tt)+) => {
+        panic!($($verbose)+)
+    };
+}
+
+#[cfg(not(feature = "verbose-errors"))]
+macro_rules! fail {
+    ($terse:literal, $($verbose:tt)+) => {
+        panic!($terse)
+    };
+}
+
+mod affinity;
+#[cfg(feature = "oom-handler")]
+mod alloc_error;
+mod boxed;
+mod budget;
+mod contention;
+mod early;
+mod expected;
+#[cfg(feature = "free-cache")]
+mod free_cache;
+#[cfg(feature = "heap-guard")]
+mod guard;
+mod headroom;
+mod holes;
+#[cfg(feature = "alloc-hooks")]
+mod hooks;
+mod indicator;
+#[cfg(feature = "isr-guard")]
+mod isr;
+mod layout;
+mod lock;
+#[cfg(feature = "defmt")]
+mod logged;
 pub mod macros;
+mod map;
+#[cfg(esp_alloc_dual_core)]
+mod multicore;
+mod offset;
+mod oom;
+#[cfg(feature = "poison")]
+mod poison;
+pub mod prelude;
+#[cfg(feature = "quarantine")]
+mod quarantine;
+#[cfg(feature = "registry")]
+mod registry;
+mod report;
+mod retained;
+mod scoped;
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(feature = "test-util")]
+mod test_util;
+#[cfg(feature = "trace")]
+mod trace;
+mod usage;
+mod vec;
+mod watch;
+mod wire;
 
+#[cfg(feature = "nightly")]
+use core::alloc::{AllocError, Allocator};
 use core::{
     alloc::{GlobalAlloc, Layout},
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    fmt,
+    ops::{BitOr, BitOrAssign},
     ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
-use critical_section::Mutex;
+use critical_section::{CriticalSection, Mutex};
 use linked_list_allocator::Heap;
 
+pub use affinity::MAX_CORES;
+#[cfg(feature = "oom-handler")]
+pub use alloc_error::OomSink;
+pub use boxed::EspBox;
+pub use budget::{BudgetError, BudgetHandle, BudgetUsage, MAX_BUDGETS};
+pub use contention::TryAllocError;
+pub use early::EarlyPolicy;
+pub use expected::{MissingRegion, RegionDescriptor};
+#[cfg(feature = "free-cache")]
+pub use free_cache::{AllocStrategy, FreeCacheStats, FREE_CACHE_CAPACITY};
+#[cfg(feature = "heap-guard")]
+pub use guard::CorruptionInfo;
+pub use indicator::MAX_USAGE_LEVELS;
+#[cfg(feature = "isr-guard")]
+pub use isr::IsrPolicy;
+pub use layout::{validate, ConfiguredRegion, HeapRegion, LayoutSummary, MemoryRange};
+pub use lock::{HeapLock, TryHeapLock};
+#[cfg(feature = "defmt")]
+pub use logged::Logged;
+pub use offset::{OffsetHeap, DEFAULT_FREE_RANGES};
+pub use oom::OomAction;
+#[cfg(feature = "poison")]
+pub use poison::{ALLOC_POISON, DEFAULT_POISON_LIMIT, FREE_POISON};
+#[cfg(feature = "quarantine")]
+pub use quarantine::QUARANTINE_CAPACITY;
+#[cfg(feature = "registry")]
+pub use registry::{TrackedAlloc, REGISTRY_CAPACITY};
+pub use report::RegionConfig;
+pub use scoped::ScopedHeap;
+#[cfg(feature = "trace")]
+pub use trace::{
+    replay, LiveSummary, ReplayReport, SizeClass, TraceError, TraceEvent, TraceOp, MAX_EVENT_SIZE,
+    REPLAY_SLOTS, SIZE_CLASSES,
+};
+pub use usage::{HeapStats, RegionStats};
+pub use vec::EspVec;
+pub use watch::{WatchHit, MAX_WATCHES};
+pub use wire::{STATS_BYTES_LEN, STATS_FORMAT_VERSION};
+
+/// The maximum number of memory regions a single [`EspHeap`] can manage
+pub const MAX_REGIONS: usize = 4;
+
+/// The cache line size assumed until
+/// [`set_cache_line_size`](struct.EspHeap.html#method.set_cache_line_size) is
+/// called
+///
+/// 32 bytes is the default data cache line size of external memory on all
+/// current chips. The ESP32-S3 can be configured for 64 byte lines, which
+/// then needs to be set explicitly.
+pub const DEFAULT_CACHE_LINE_SIZE: usize = 32;
+
+/// The alignment of [`alloc_dma`](struct.EspHeap.html#method.alloc_dma)
+/// buffers until
+/// [`set_dma_alignment`](struct.EspHeap.html#method.set_dma_alignment) is
+/// called
+///
+/// 32 bytes covers the descriptors and the internal cache lines of all
+/// current chips.
+pub const DEFAULT_DMA_ALIGNMENT: usize = 32;
+
+/// The region that sits next to the stack, see
+/// [`set_reserved_for_stack`](struct.EspHeap.html#method.set_reserved_for_stack)
+const STACK_REGION: usize = 0;
+
+/// Free blocks `coalesce` walks in each critical section, which bounds how
+/// long it keeps interrupts waiting
+const COALESCE_HOLES: usize = 8;
+
+const EMPTY_REGION: Region = Region {
+    heap: Heap::empty(),
+    status: RegionStatus::Unused,
+    name: "",
+    capabilities: MemoryCapability::empty(),
+    reserved: 0,
+    tail_guard: 0,
+    headroom: 0,
+    headroom_block: 0,
+    headroom_held: 0,
+    cache_line: 0,
+    padding: 0,
+    live: 0,
+    zeroed: false,
+    untouched: 0,
+    trimmed: 0,
+    min_free: 0,
+    core_costs: [0; MAX_CORES],
+    #[cfg(feature = "free-cache")]
+    cache: free_cache::FreeCache::new(),
+    #[cfg(feature = "trace")]
+    allocations: 0,
+    #[cfg(feature = "trace")]
+    deallocations: 0,
+    replay: None,
+};
+
+/// Describes the properties of a memory region
+///
+/// Capabilities can be combined with `|`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MemoryCapability(u32);
+
+impl MemoryCapability {
+    /// Memory is internal to the chip
+    pub const INTERNAL: Self = Self(1 << 0);
+    /// Memory is external (PSRAM) and accessed through the cache
+    pub const EXTERNAL: Self = Self(1 << 1);
+    /// Memory can be accessed by DMA
+    pub const DMA: Self = Self(1 << 2);
+    /// Memory keeps its contents in deep sleep, like RTC fast memory
+    pub const RTC_RETAINED: Self = Self(1 << 3);
+
+    /// No capabilities at all
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns `true` if all capabilities in `other` are also in `self`
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for MemoryCapability {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for MemoryCapability {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Identifies a region of an [`EspHeap`]
+///
+/// Returned when a region is added, and taken by every method that operates
+/// on a single region. Displays as the region's name if it has one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegionId {
+    index: usize,
+    name: &'static str,
+}
+
+impl RegionId {
+    /// Position of the region in the heap, starting at `0` for the region
+    /// added first
+    pub const fn index(self) -> usize {
+        self.index
+    }
+
+    /// Name of the region, empty if it has none
+    pub const fn name(self) -> &'static str {
+        self.name
+    }
+}
+
+impl fmt::Display for RegionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "region {}", self.index)
+        } else {
+            f.write_str(self.name)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RegionStatus {
+    /// The slot is free
+    Unused,
+    /// Registered as expected, waiting for its memory to be probed
+    Expected,
+    /// Expected, but its memory turned out to be unavailable
+    Unavailable(&'static str),
+    /// The region's heap is initialized
+    Available,
+}
+
+struct Region {
+    heap: Heap,
+    status: RegionStatus,
+    name: &'static str,
+    capabilities: MemoryCapability,
+    /// Bytes at the top of the region no allocation may reach into
+    reserved: usize,
+    /// Bytes at the top of the region kept clear of DMA over-reads
+    tail_guard: usize,
+    /// Contiguous bytes kept free for allocations that may use the headroom
+    headroom: usize,
+    /// Address of the block holding the headroom, `0` if it isn't held
+    headroom_block: usize,
+    /// Size of the block holding the headroom, less than the headroom while
+    /// an allocation uses the rest
+    headroom_held: usize,
+    /// Cache line every allocation is padded to, `0` if lines aren't isolated
+    cache_line: usize,
+    /// Bytes currently spent on padding allocations to `cache_line`
+    padding: usize,
+    /// Sum of the requested sizes of the live allocations
+    live: usize,
+    /// Whether memory above `untouched` is known to be zero-filled
+    zeroed: bool,
+    /// Lowest address the allocator has never written to or handed out
+    untouched: usize,
+    /// Bytes at the top handed back by `shrink_region`, held as used
+    trimmed: usize,
+    /// Fewest free bytes seen after an allocation since the region was added
+    /// or the watermark reset
+    min_free: usize,
+    /// How costly each core's accesses to the region are
+    core_costs: [u8; MAX_CORES],
+    #[cfg(feature = "free-cache")]
+    cache: free_cache::FreeCache,
+    /// Allocations served by the region so far, for trace events
+    #[cfg(feature = "trace")]
+    allocations: usize,
+    /// Deallocations routed to the region so far, for trace events
+    #[cfg(feature = "trace")]
+    deallocations: usize,
+    /// Progress in handing out the blocks of a retained region again, see
+    /// `reinit_retained_preserving`
+    replay: Option<retained::Replay>,
+}
+
+/// A block handed out by a region
+struct Allocation {
+    ptr: NonNull<u8>,
+    /// Usable size of the block
+    #[cfg(feature = "nightly")]
+    size: usize,
+    /// Number of leading bytes of the block that may not be zero
+    dirty: usize,
+}
+
+impl Region {
+    fn is_initialized(&self) -> bool {
+        !self.heap.bottom().is_null()
+    }
+
+    fn id(&self, index: usize) -> RegionId {
+        RegionId {
+            index,
+            name: self.name,
+        }
+    }
+
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn contains(&self, ptr: *mut u8) -> bool {
+        self.is_initialized() && self.heap.bottom() <= ptr && ptr < self.top()
+    }
+
+    /// Returns the address right behind the region.
+    fn top(&self) -> *mut u8 {
+        if self.trimmed == 0 {
+            self.heap.top()
+        } else {
+            self.heap.bottom().wrapping_add(self.size())
+        }
+    }
+
+    /// Returns the number of bytes the region allocates from.
+    fn size(&self) -> usize {
+        self.heap.size() - self.trimmed
+    }
+
+    /// Returns the number of bytes in use.
+    fn used(&self) -> usize {
+        self.heap.used() - self.trimmed
+    }
+
+    /// # Safety
+    ///
+    /// See [`EspHeap::add_region`].
+    unsafe fn activate(&mut self, heap_bottom: *mut u8, size: usize) {
+        self.heap.init(heap_bottom, size);
+        self.min_free = self.heap.free();
+        self.status = RegionStatus::Available;
+        self.untouched = self.heap.bottom() as usize + holes::MIN_BLOCK;
+    }
+
+    /// Returns the number of bytes at the top of the region no allocation may
+    /// reach into.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn reserved_top(&self) -> usize {
+        self.reserved.max(self.tail_guard)
+    }
+
+    /// Returns the address no allocation may reach past.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn limit(&self) -> usize {
+        // `top` may lie a few bytes above the end of the last usable block.
+        (self.heap.bottom() as usize + self.size()).saturating_sub(self.reserved_top())
+    }
+
+    /// Returns whether allocations asking for `capabilities` may use the
+    /// region.
+    ///
+    /// Retained regions only serve allocations that ask for retention.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn serves(&self, capabilities: MemoryCapability) -> bool {
+        self.capabilities.contains(capabilities)
+            && (capabilities.contains(MemoryCapability::RTC_RETAINED)
+                || !self.capabilities.contains(MemoryCapability::RTC_RETAINED))
+    }
+
+    /// Returns whether `layout` could be served if the region were empty.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn could_fit(&self, layout: Layout) -> bool {
+        let usable = self.size().saturating_sub(self.reserved_top());
+        self.is_initialized()
+            && self
+                .region_layout(layout)
+                .map_or(false, |layout| holes::block_size(layout.size()) <= usable)
+    }
+
+    /// Returns the layout actually allocated for `layout` in this region.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn region_layout(&self, layout: Layout) -> Option<Layout> {
+        #[cfg(feature = "heap-guard")]
+        let layout = guard::outer(layout)?;
+        isolate(layout, self.cache_line)
+    }
+
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn allocate(&mut self, layout: Layout) -> Option<Allocation> {
+        let region_layout = self.region_layout(layout)?;
+
+        #[cfg(feature = "free-cache")]
+        if let Some(ptr) = self.cache.take(region_layout) {
+            let size = holes::block_size(region_layout.size());
+            self.padding += region_layout.size() - guarded_size(layout);
+            self.live += layout.size();
+            // SAFETY: the block was allocated with `region_layout`.
+            return Some(unsafe { allocation(ptr, layout, size, size) });
+        }
+
+        let ptr = if self.replay.is_some() {
+            self.allocate_replayed(region_layout)
+        } else {
+            self.heap.allocate_first_fit(region_layout).ok()
+        };
+        #[cfg(feature = "free-cache")]
+        let ptr = ptr.or_else(|| {
+            if self.flush_cache() {
+                self.heap.allocate_first_fit(region_layout).ok()
+            } else {
+                None
+            }
+        });
+        let ptr = ptr?;
+        let start = ptr.as_ptr() as usize;
+        let end = start + holes::block_size(region_layout.size());
+
+        // The allocator may have placed a hole header right behind the block.
+        let untouched = self.untouched;
+        self.untouched = untouched.max(end + holes::MIN_BLOCK);
+
+        if end > self.limit() {
+            self.replay = None;
+            // SAFETY: `ptr` was just allocated with `region_layout`.
+            unsafe { self.heap.deallocate(ptr, region_layout) };
+            return None;
+        }
+
+        self.padding += region_layout.size() - guarded_size(layout);
+        self.live += layout.size();
+        let dirty = if self.zeroed {
+            untouched.saturating_sub(start).min(end - start)
+        } else {
+            end - start
+        };
+        // SAFETY: the block was just allocated with `region_layout`.
+        Some(unsafe { allocation(ptr, layout, end - start, dirty) })
+    }
+
+    /// Returns whether the free list can serve `layout` right now, which is
+    /// found out with a trial allocation.
+    fn can_serve(&mut self, layout: Layout) -> bool {
+        let Some(region_layout) = self.region_layout(layout) else {
+            return false;
+        };
+        self.replay = None;
+        let Ok(ptr) = self.heap.allocate_first_fit(region_layout) else {
+            return false;
+        };
+        let end = ptr.as_ptr() as usize + holes::block_size(region_layout.size());
+        // The allocator may have placed a hole header right behind the block.
+        self.untouched = self.untouched.max(end + holes::MIN_BLOCK);
+        // SAFETY: `ptr` was just allocated with `region_layout`.
+        unsafe { self.heap.deallocate(ptr, region_layout) };
+        end <= self.limit()
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `allocate` with the same `layout`.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        #[cfg(feature = "heap-guard")]
+        let ptr = guard::check(ptr, layout);
+        let region_layout = self.region_layout(layout).unwrap_or(layout);
+        self.padding = self
+            .padding
+            .saturating_sub(region_layout.size().saturating_sub(guarded_size(layout)));
+        self.live = self.live.saturating_sub(layout.size());
+        self.replay = None;
+
+        #[cfg(feature = "free-cache")]
+        if self.cache.put(ptr, region_layout) {
+            return;
+        }
+
+        self.heap.deallocate(ptr, region_layout);
+        self.reserve_headroom();
+    }
+
+    /// Returns whether a block with `layout` can shrink to the smaller
+    /// `new_layout` without moving.
+    #[cfg(feature = "poison")]
+    fn shrinks_in_place(&self, layout: Layout, new_layout: Layout) -> bool {
+        match (self.region_layout(layout), self.region_layout(new_layout)) {
+            (Some(old), Some(new)) => {
+                let tail = holes::block_size(old.size()) - holes::block_size(new.size());
+                tail == 0 || tail >= holes::MIN_BLOCK
+            }
+            _ => false,
+        }
+    }
+
+    /// Resizes the block at `ptr` from `layout` to `new_layout` without
+    /// moving it, returning whether that was possible.
+    ///
+    /// A block grows by taking the hole right behind it and handing back
+    /// what it doesn't need, and shrinks by handing back its tail. Whatever
+    /// is handed back must be big enough to be a hole.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `allocate` with `layout`, and
+    /// `new_layout` must have the same alignment.
+    unsafe fn resize_in_place(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_layout: Layout,
+    ) -> bool {
+        let (Some(old), Some(new)) = (self.region_layout(layout), self.region_layout(new_layout))
+        else {
+            return false;
+        };
+        #[cfg(feature = "heap-guard")]
+        let ptr = guard::check(ptr, layout);
+        let start = ptr.as_ptr() as usize;
+        let end = start + holes::block_size(old.size());
+        let new_end = start + holes::block_size(new.size());
+        self.replay = None;
+
+        if new_end > end {
+            if new_end > self.limit() {
+                return false;
+            }
+            // Searching for the hole writes into the free blocks.
+            self.untouched = self.heap.top() as usize;
+            let mut behind = None;
+            holes::walk_from(&mut self.heap, end, 1, |hole, size| {
+                behind = Some((hole as usize, hole as usize + size));
+            });
+            let Some((hole, hole_end)) = behind else {
+                return false;
+            };
+            // The whole hole is taken, and what the block doesn't need of it
+            // handed back, which must be big enough to be a hole.
+            let back = hole_end.wrapping_sub(new_end);
+            if hole != end
+                || hole_end < new_end
+                || (back != 0 && back < holes::MIN_BLOCK)
+                || !holes::take_tail(&mut self.heap, end, hole_end)
+            {
+                return false;
+            }
+            if back != 0 {
+                let back_layout = Layout::from_size_align_unchecked(back, holes::BLOCK_ALIGN);
+                self.heap
+                    .deallocate(NonNull::new_unchecked(new_end as *mut u8), back_layout);
+            }
+        } else if new_end < end {
+            if end - new_end < holes::MIN_BLOCK {
+                return false;
+            }
+            let tail = Layout::from_size_align_unchecked(end - new_end, holes::BLOCK_ALIGN);
+            self.heap
+                .deallocate(NonNull::new_unchecked(new_end as *mut u8), tail);
+            self.reserve_headroom();
+        }
+
+        #[cfg(feature = "heap-guard")]
+        guard::arm(ptr, new_layout);
+        self.padding = (self.padding + (new.size() - guarded_size(new_layout)))
+            .saturating_sub(old.size() - guarded_size(layout));
+        self.live = self.live.saturating_sub(layout.size()) + new_layout.size();
+        true
+    }
+
+    /// Returns one cached block to the free list.
+    #[cfg(feature = "free-cache")]
+    fn spill_one(&mut self) -> bool {
+        match self.cache.pop() {
+            Some((ptr, layout)) => {
+                // SAFETY: cached blocks are allocated blocks nobody uses.
+                unsafe { self.heap.deallocate(ptr, layout) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns every cached block to the free list, returning whether there
+    /// were any.
+    #[cfg(feature = "free-cache")]
+    fn flush_cache(&mut self) -> bool {
+        let mut flushed = false;
+        while self.spill_one() {
+            flushed = true;
+        }
+        flushed
+    }
+}
+
+/// Returns the current time, for timestamps
+#[cfg(any(feature = "stats", feature = "registry"))]
+type Clock = fn() -> u64;
+
+/// What an allocation may do, decided before entering the critical section
+#[derive(Clone, Copy)]
+struct Context {
+    /// The policy to apply if called from an interrupt handler
+    #[cfg(feature = "isr-guard")]
+    in_isr: Option<IsrPolicy>,
+    /// Whether the headroom of regions may be used
+    headroom: bool,
+    /// Whether the allocation is restricted by the early limit
+    early: bool,
+    /// The core the allocation comes from, if known
+    core: Option<usize>,
+    /// Whether the allocation is at least the large allocation threshold
+    large: bool,
+    /// The only region the allocation may be served from, if restricted
+    region: Option<usize>,
+}
+
+impl Context {
+    /// Whether slow ways to make room may be tried if the regions are full
+    fn fallback(&self) -> bool {
+        #[cfg(feature = "isr-guard")]
+        return self.in_isr != Some(IsrPolicy::TryOnly);
+        #[cfg(not(feature = "isr-guard"))]
+        return true;
+    }
+}
+
+/// Result of a [`coalesce`](struct.EspHeap.html#method.coalesce) pass
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CoalesceReport {
+    /// Number of adjacent free blocks that had not been merged
+    pub merges: usize,
+    /// Number of free blocks after the pass
+    pub free_blocks: usize,
+    /// Size of the largest free block after the pass, in bytes
+    pub largest_free_block: usize,
+}
+
+/// Why [`shrink_region`](struct.EspHeap.html#method.shrink_region) failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ShrinkError {
+    /// The region doesn't exist or isn't initialized
+    NoSuchRegion,
+    /// The region is smaller than the requested size
+    TooLarge,
+    /// The range to take away is smaller than the backing allocator's
+    /// smallest block
+    TooSmall,
+    /// Part of the range to take away is allocated, or the free memory left
+    /// in front of it would be too small to allocate from
+    Occupied,
+}
+
+impl fmt::Display for ShrinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuchRegion => f.write_str("no such region"),
+            Self::TooLarge => f.write_str("region smaller than the requested size"),
+            Self::TooSmall => f.write_str("range to take away too small"),
+            Self::Occupied => f.write_str("range to take away not free"),
+        }
+    }
+}
+
+/// The configuration an [`EspHeap`] runs with, see
+/// [`config_summary`](struct.EspHeap.html#method.config_summary)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct ConfigSummary {
+    /// Version of this crate
+    pub version: &'static str,
+    /// Number of regions that have been added
+    pub regions: usize,
+    /// Maximum number of regions
+    pub max_regions: usize,
+    /// Cache line size used for DMA buffers and cache line isolation
+    pub cache_line_size: usize,
+    /// Alignment of buffers from `alloc_dma`
+    pub dma_alignment: usize,
+    /// Number of regions with cache line isolation enabled
+    pub isolated_regions: usize,
+    /// Bytes reserved for stack growth
+    pub stack_reserve: usize,
+    /// Number of expected regions that aren't available (yet)
+    pub missing_regions: usize,
+    /// Whether allocations can be reported to external tracing (the
+    /// `alloc-hooks` feature)
+    pub alloc_hooks: bool,
+    /// Whether failed allocations are logged over `defmt` (the
+    /// `defmt-failures` feature)
+    pub defmt_failures: bool,
+    /// Whether freed blocks may be cached (the `free-cache` feature)
+    pub free_cache: bool,
+    /// Whether blocks are framed by canaries (the `heap-guard` feature)
+    pub heap_guard: bool,
+    /// Whether heap use from interrupt handlers can be checked (the
+    /// `isr-guard` feature)
+    pub isr_guard: bool,
+    /// Whether critical sections also exclude the other core (the
+    /// `multicore` feature)
+    pub multicore: bool,
+    /// Whether allocation errors report the heap before panicking (the
+    /// `oom-handler` feature)
+    pub oom_handler: bool,
+    /// Whether blocks are filled with known patterns (the `poison` feature)
+    pub poison: bool,
+    /// Whether freed blocks are quarantined (the `quarantine` feature)
+    pub quarantine: bool,
+    /// Whether live allocations are tracked (the `registry` feature)
+    pub registry: bool,
+    /// Whether allocation failures are counted (the `stats` feature)
+    pub stats: bool,
+    /// Whether heap operations can be traced (the `trace` feature)
+    pub trace: bool,
+    /// Whether layouts are validated (the `validate-layout` feature)
+    pub validate_layout: bool,
+}
+
 pub struct EspHeap {
-    heap: Mutex<RefCell<Heap>>,
+    heap: Mutex<RefCell<[Region; MAX_REGIONS]>>,
+    /// Set once any region is initialized, so stats can skip the critical
+    /// section before that
+    initialized: AtomicBool,
+    cache_line_size: AtomicUsize,
+    dma_alignment: AtomicUsize,
+    /// Allocations of at least this many bytes try the regions in reverse
+    large_threshold: AtomicUsize,
+    /// Allocations of more than this many bytes fail
+    max_alloc_size: AtomicUsize,
+    largest_allocation: Mutex<Cell<usize>>,
+    /// Number of allocations made so far
+    sequence: Mutex<Cell<usize>>,
+    watches: Mutex<RefCell<watch::Watches>>,
+    budgets: Mutex<RefCell<[Option<budget::Budget>; MAX_BUDGETS]>>,
+    early: early::EarlyLimit,
+    oom_handler: Mutex<Cell<oom::OomState>>,
+    indicator: indicator::UsageIndicator,
+    core_source: affinity::CoreSource,
+    custom_lock: lock::CustomLock,
+    #[cfg(feature = "quarantine")]
+    quarantine: Mutex<RefCell<quarantine::Quarantine>>,
+    #[cfg(feature = "registry")]
+    registry: Mutex<RefCell<registry::Registry>>,
+    #[cfg(feature = "stats")]
+    counters: Mutex<RefCell<stats::Counters>>,
+    /// Source of the timestamps of failures and live allocations
+    #[cfg(any(feature = "stats", feature = "registry"))]
+    clock: Mutex<Cell<Option<Clock>>>,
+    #[cfg(feature = "trace")]
+    trace_sink: Mutex<Cell<Option<trace::TraceSink>>>,
+    #[cfg(feature = "trace")]
+    trace_hook: Mutex<Cell<Option<trace::TraceHook>>>,
+    #[cfg(feature = "trace")]
+    trace_ring: Mutex<RefCell<trace::TraceRing>>,
+    #[cfg(feature = "isr-guard")]
+    isr_guard: isr::IsrGuard,
+    #[cfg(feature = "isr-guard")]
+    isr_allocations: Mutex<Cell<usize>>,
+    #[cfg(esp_alloc_dual_core)]
+    core_guard: multicore::CoreGuard,
+    #[cfg(feature = "multicore")]
+    spin: lock::SpinLock,
+    #[cfg(feature = "alloc-hooks")]
+    alloc_hooks: hooks::AllocHooks,
+    #[cfg(feature = "poison")]
+    poison_limit: AtomicUsize,
+    #[cfg(feature = "oom-handler")]
+    oom_sink: alloc_error::OomSinkCell,
 }
 
 impl EspHeap {
@@ -29,10 +811,186 @@ impl EspHeap {
     /// allocator.
     pub const fn empty() -> EspHeap {
         EspHeap {
-            heap: Mutex::new(RefCell::new(Heap::empty())),
+            heap: Mutex::new(RefCell::new([EMPTY_REGION; MAX_REGIONS])),
+            initialized: AtomicBool::new(false),
+            cache_line_size: AtomicUsize::new(DEFAULT_CACHE_LINE_SIZE),
+            dma_alignment: AtomicUsize::new(DEFAULT_DMA_ALIGNMENT),
+            large_threshold: AtomicUsize::new(usize::MAX),
+            max_alloc_size: AtomicUsize::new(usize::MAX),
+            largest_allocation: Mutex::new(Cell::new(0)),
+            sequence: Mutex::new(Cell::new(0)),
+            watches: Mutex::new(RefCell::new(watch::Watches::new())),
+            budgets: Mutex::new(RefCell::new([None; MAX_BUDGETS])),
+            early: early::EarlyLimit::new(),
+            oom_handler: Mutex::new(Cell::new(oom::OomState::new())),
+            indicator: indicator::UsageIndicator::new(),
+            core_source: affinity::CoreSource::new(),
+            custom_lock: lock::CustomLock::new(),
+            #[cfg(feature = "quarantine")]
+            quarantine: Mutex::new(RefCell::new(quarantine::Quarantine::new())),
+            #[cfg(feature = "registry")]
+            registry: Mutex::new(RefCell::new(registry::Registry::new())),
+            #[cfg(feature = "stats")]
+            counters: Mutex::new(RefCell::new(stats::Counters::new())),
+            #[cfg(any(feature = "stats", feature = "registry"))]
+            clock: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "trace")]
+            trace_sink: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "trace")]
+            trace_hook: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "trace")]
+            trace_ring: Mutex::new(RefCell::new(trace::TraceRing::new())),
+            #[cfg(feature = "isr-guard")]
+            isr_guard: isr::IsrGuard::new(),
+            #[cfg(feature = "isr-guard")]
+            isr_allocations: Mutex::new(Cell::new(0)),
+            #[cfg(esp_alloc_dual_core)]
+            core_guard: multicore::CoreGuard::new(),
+            #[cfg(feature = "multicore")]
+            spin: lock::SpinLock::new(),
+            #[cfg(feature = "alloc-hooks")]
+            alloc_hooks: hooks::AllocHooks::new(),
+            #[cfg(feature = "poison")]
+            poison_limit: AtomicUsize::new(poison::DEFAULT_POISON_LIMIT),
+            #[cfg(feature = "oom-handler")]
+            oom_sink: alloc_error::OomSinkCell::new(),
         }
     }
 
+    /// Returns the heap to the state of a freshly created
+    /// [`empty`](struct.EspHeap.html#method.empty) heap
+    ///
+    /// Every region, counter, hook, budget and watch is forgotten, so the heap
+    /// can be initialized again, for instance between tests sharing a static
+    /// heap or when restarting a runtime that owns the heap.
+    ///
+    /// # Safety
+    ///
+    /// No allocation made from this heap may be live, or used afterwards, and
+    /// nothing may use the heap concurrently with the reset.
+    pub unsafe fn reset(&self) {
+        // Destructured so that a new field can't be forgotten here.
+        let EspHeap {
+            heap,
+            initialized,
+            cache_line_size,
+            dma_alignment,
+            large_threshold,
+            max_alloc_size,
+            largest_allocation,
+            sequence,
+            watches,
+            budgets,
+            early,
+            oom_handler,
+            indicator,
+            core_source,
+            custom_lock,
+            #[cfg(feature = "quarantine")]
+            quarantine,
+            #[cfg(feature = "registry")]
+            registry,
+            #[cfg(feature = "stats")]
+            counters,
+            #[cfg(any(feature = "stats", feature = "registry"))]
+            clock,
+            #[cfg(feature = "trace")]
+            trace_sink,
+            #[cfg(feature = "trace")]
+            trace_hook,
+            #[cfg(feature = "trace")]
+            trace_ring,
+            #[cfg(feature = "isr-guard")]
+            isr_guard,
+            #[cfg(feature = "isr-guard")]
+            isr_allocations,
+            #[cfg(esp_alloc_dual_core)]
+            core_guard,
+            // Free whenever the heap isn't in use.
+            #[cfg(feature = "multicore")]
+                spin: _,
+            #[cfg(feature = "alloc-hooks")]
+            alloc_hooks,
+            #[cfg(feature = "poison")]
+            poison_limit,
+            #[cfg(feature = "oom-handler")]
+            oom_sink,
+        } = EspHeap::empty();
+
+        self.locked(|cs| {
+            self.heap.borrow(cs).replace(heap.into_inner().into_inner());
+            self.largest_allocation
+                .borrow(cs)
+                .set(largest_allocation.into_inner().get());
+            self.sequence.borrow(cs).set(sequence.into_inner().get());
+            self.watches
+                .borrow(cs)
+                .replace(watches.into_inner().into_inner());
+            self.budgets
+                .borrow(cs)
+                .replace(budgets.into_inner().into_inner());
+            self.early.reset(cs, early);
+            self.oom_handler
+                .borrow(cs)
+                .set(oom_handler.into_inner().get());
+            self.indicator.reset(cs, indicator);
+            #[cfg(feature = "quarantine")]
+            self.quarantine
+                .borrow(cs)
+                .replace(quarantine.into_inner().into_inner());
+            #[cfg(feature = "registry")]
+            self.registry
+                .borrow(cs)
+                .replace(registry.into_inner().into_inner());
+            #[cfg(feature = "stats")]
+            self.counters
+                .borrow(cs)
+                .replace(counters.into_inner().into_inner());
+            #[cfg(any(feature = "stats", feature = "registry"))]
+            self.clock.borrow(cs).set(clock.into_inner().get());
+            #[cfg(feature = "trace")]
+            self.trace_sink
+                .borrow(cs)
+                .set(trace_sink.into_inner().get());
+            #[cfg(feature = "trace")]
+            self.trace_hook
+                .borrow(cs)
+                .set(trace_hook.into_inner().get());
+            #[cfg(feature = "trace")]
+            self.trace_ring
+                .borrow(cs)
+                .replace(trace_ring.into_inner().into_inner());
+            #[cfg(feature = "isr-guard")]
+            self.isr_allocations
+                .borrow(cs)
+                .set(isr_allocations.into_inner().get());
+        });
+
+        #[cfg(feature = "isr-guard")]
+        self.isr_guard.reset(isr_guard);
+        self.core_source.reset(core_source);
+        self.custom_lock.reset(custom_lock);
+        #[cfg(esp_alloc_dual_core)]
+        self.core_guard.reset(core_guard);
+        #[cfg(feature = "alloc-hooks")]
+        self.alloc_hooks.reset(alloc_hooks);
+        #[cfg(feature = "oom-handler")]
+        self.oom_sink.reset(oom_sink);
+        self.cache_line_size
+            .store(cache_line_size.into_inner(), Ordering::Relaxed);
+        self.dma_alignment
+            .store(dma_alignment.into_inner(), Ordering::Relaxed);
+        self.large_threshold
+            .store(large_threshold.into_inner(), Ordering::Relaxed);
+        self.max_alloc_size
+            .store(max_alloc_size.into_inner(), Ordering::Relaxed);
+        #[cfg(feature = "poison")]
+        self.poison_limit
+            .store(poison_limit.into_inner(), Ordering::Relaxed);
+        self.initialized
+            .store(initialized.into_inner(), Ordering::Relaxed);
+    }
+
     /// Initializes the heap
     ///
     /// This function must be called BEFORE you run any code that makes use of
@@ -50,45 +1008,1817 @@ impl EspHeap {
     /// - The size of the heap is `(end_addr as usize) - (start_addr as usize)`.
     ///   The allocator won't use the byte at `end_addr`.
     ///
+    /// Further memory is added with
+    /// [`add_region`](struct.EspHeap.html#method.add_region).
+    ///
+    /// # Panics
+    ///
+    /// Panics if memory has already been added to the heap, so that a second
+    /// call can't hand out memory that is in use.
+    ///
     /// # Safety
     ///
     /// Obey these or Bad Stuff will happen.
     ///
-    /// - This function must be called exactly ONCE.
+    /// - The memory must be valid for the entire program and not used for
+    ///   anything else.
     /// - `size > 0`
-    pub unsafe fn init(&self, heap_bottom: *mut u8, size: usize) {
-        critical_section::with(|cs| self.heap.borrow(cs).borrow_mut().init(heap_bottom, size));
+    pub unsafe fn init(&self, heap_bottom: *mut u8, size: usize) -> RegionId {
+        self.add(heap_bottom, size, MemoryCapability::empty(), true)
     }
 
-    /// Returns an estimate of the amount of bytes in use.
-    pub fn used(&self) -> usize {
-        critical_section::with(|cs| self.heap.borrow(cs).borrow_mut().used())
+    /// Adds a memory region to the heap
+    ///
+    /// Regions are used in the order they were added: an allocation is served
+    /// from the first region that can satisfy it. `capabilities` describes the
+    /// memory so that allocations with special requirements can be placed
+    /// correctly.
+    ///
+    /// Returns the [`RegionId`] of the new region.
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`MAX_REGIONS`] regions have already been added, or if the
+    /// memory overlaps a region that was added before, which catches adding
+    /// the same memory twice.
+    ///
+    /// # Safety
+    ///
+    /// - The memory in `[heap_bottom, heap_bottom + size)` must be valid for
+    ///   the entire program and not used for anything else.
+    /// - `size > 0`
+    pub unsafe fn add_region(
+        &self,
+        heap_bottom: *mut u8,
+        size: usize,
+        capabilities: MemoryCapability,
+    ) -> RegionId {
+        self.add(heap_bottom, size, capabilities, false)
     }
 
-    /// Returns an estimate of the amount of bytes available.
-    pub fn free(&self) -> usize {
-        critical_section::with(|cs| self.heap.borrow(cs).borrow_mut().free())
+    /// Adds a region, checking in the same critical section that it doesn't
+    /// overlap another one, and with `first` that there is no other one.
+    unsafe fn add(
+        &self,
+        heap_bottom: *mut u8,
+        size: usize,
+        capabilities: MemoryCapability,
+        first: bool,
+    ) -> RegionId {
+        let id = self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            if first && regions.iter().any(|region| region.is_initialized()) {
+                panic!("heap initialized twice");
+            }
+            check_overlap(&regions[..], heap_bottom, size);
+
+            let (index, region) = regions
+                .iter_mut()
+                .enumerate()
+                .find(|(_, region)| region.status == RegionStatus::Unused)
+                .unwrap_or_else(|| {
+                    fail!(
+                        "too many heap regions",
+                        "Exceeded the maximum of {MAX_REGIONS} heap regions"
+                    )
+                });
+            region.capabilities = capabilities;
+            region.activate(heap_bottom, size);
+            region.id(index)
+        });
+        self.initialized.store(true, Ordering::Relaxed);
+        id
     }
-}
 
-unsafe impl GlobalAlloc for EspHeap {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        critical_section::with(|cs| {
+    /// Returns whether the memory of the given region has been added
+    ///
+    /// Regions that were only registered as expected, see
+    /// [`register_expected`](struct.EspHeap.html#method.register_expected), aren't
+    /// initialized until their memory is probed. Lets code like a PSRAM
+    /// driver find out whether it still has to add its memory.
+    pub fn is_region_initialized(&self, region: RegionId) -> bool {
+        self.locked(|cs| {
             self.heap
                 .borrow(cs)
-                .borrow_mut()
-                .allocate_first_fit(layout)
-                .ok()
-                .map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+                .borrow()
+                .get(region.index)
+                .map_or(false, |region| region.status == RegionStatus::Available)
         })
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        critical_section::with(|cs| {
+    /// Returns the ids of all regions that have been added or registered, in
+    /// the order they were added
+    pub fn region_ids(&self) -> impl Iterator<Item = RegionId> {
+        let mut ids = [None; MAX_REGIONS];
+        self.locked(|cs| {
+            let regions = self.heap.borrow(cs).borrow();
+            for (index, region) in regions.iter().enumerate() {
+                if region.status != RegionStatus::Unused {
+                    ids[index] = Some(region.id(index));
+                }
+            }
+        });
+        ids.into_iter().flatten()
+    }
+
+    /// Returns the id of the region at `index`, if there is one
+    ///
+    /// An escape hatch for code that has to deal with raw indices; prefer
+    /// keeping the [`RegionId`] returned when the region was added.
+    pub fn region_id_by_index(&self, index: usize) -> Option<RegionId> {
+        self.locked(|cs| {
             self.heap
                 .borrow(cs)
-                .borrow_mut()
-                .deallocate(NonNull::new_unchecked(ptr), layout)
+                .borrow()
+                .get(index)
+                .filter(|region| region.status != RegionStatus::Unused)
+                .map(|region| region.id(index))
+        })
+    }
+
+    /// Returns `true` once a region has been added to the heap
+    ///
+    /// Cheap enough to call from anywhere: it doesn't enter a critical
+    /// section.
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Relaxed)
+    }
+
+    /// Declares that the memory of the given region is zero-filled
+    ///
+    /// Zeroed allocations then skip clearing memory that the allocator has
+    /// never handed out or written to, which makes them cheap in regions that
+    /// start out cleared, like `.bss` or freshly powered RTC memory. Memory
+    /// that has been allocated or used by the allocator's own bookkeeping
+    /// before this call is tracked and still cleared.
+    ///
+    /// # Safety
+    ///
+    /// Every byte of the region that hasn't been handed out yet must be zero.
+    pub unsafe fn assume_zeroed(&self, region: RegionId) {
+        self.locked(|cs| {
+            if let Some(region) = self.heap.borrow(cs).borrow_mut().get_mut(region.index) {
+                region.zeroed = true;
+            }
+        });
+    }
+
+    /// Returns the address right behind the end of the given region
+    ///
+    /// This is where memory given to
+    /// [`extend_region`](struct.EspHeap.html#method.extend_region) has to
+    /// start, like the next bank of external memory to map. Returns `None`
+    /// for non-existent and uninitialized regions.
+    pub fn region_top(&self, region: RegionId) -> Option<*mut u8> {
+        self.locked(|cs| {
+            self.heap
+                .borrow(cs)
+                .borrow()
+                .get(region.index)
+                .filter(|region| region.is_initialized())
+                .map(|region| region.top())
+        })
+    }
+
+    /// Grows the given region by `by` bytes of memory right behind its top
+    ///
+    /// Hands memory that is no longer needed for anything else to the heap,
+    /// like a static buffer only used while booting, as long as it directly
+    /// follows the region. The new memory is available to allocations, and
+    /// counted by [`free`](struct.EspHeap.html#method.free),
+    /// [`stats`](struct.EspHeap.html#method.stats) and
+    /// [`largest_free_block`](struct.EspHeap.html#method.largest_free_block),
+    /// as soon as this returns. Memory anywhere else has to be added as a
+    /// region of its own with
+    /// [`add_region`](struct.EspHeap.html#method.add_region).
+    ///
+    /// The new memory is not assumed to be zero-filled, even if the region
+    /// was declared so. The tail taken by
+    /// [`shrink_region`](struct.EspHeap.html#method.shrink_region) can only
+    /// be given back as a whole: `by` must then be at least its size. Does
+    /// nothing for non-existent and uninitialized regions, or if `by` is
+    /// too small.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the new memory overlaps another region.
+    ///
+    /// # Safety
+    ///
+    /// The memory in `[top, top + by)`, with `top` as returned by
+    /// [`region_top`](struct.EspHeap.html#method.region_top), must be valid
+    /// for the entire program, not used for anything else and not overlap
+    /// any other region.
+    pub unsafe fn extend_region(&self, region: RegionId, by: usize) {
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            let Some(top) = regions
+                .get(region.index)
+                .filter(|region| region.is_initialized())
+                .map(|region| region.top())
+            else {
+                return;
+            };
+            check_overlap(&regions[..], top, by);
+
+            // A trimmed tail lies between the region's top and the backing
+            // allocator's, and comes first in the new memory.
+            let region = &mut regions[region.index];
+            let Some(by) = (top as usize + by).checked_sub(region.heap.top() as usize) else {
+                return;
+            };
+            if region.trimmed != 0 {
+                let tail = Layout::from_size_align_unchecked(region.trimmed, holes::BLOCK_ALIGN);
+                region.trimmed = 0;
+                region.heap.deallocate(NonNull::new_unchecked(top), tail);
+            }
+            region.heap.extend(by);
+            region.untouched = region.untouched.max(region.heap.top() as usize);
+            region.reserve_headroom();
         });
     }
+
+    /// Takes the free memory at the top of the given region away from it,
+    /// leaving `new_size` bytes
+    ///
+    /// Returns the start of the `[start, top)` range that was taken, with
+    /// `top` as returned by
+    /// [`region_top`](struct.EspHeap.html#method.region_top) before, for the
+    /// caller to use as it sees fit, like for a framebuffer carved from the
+    /// same memory. `new_size` is rounded up to a multiple of the backing
+    /// allocator's granularity. Blocks in the free-block cache and the
+    /// headroom are returned to the free list first, but any live or
+    /// quarantined allocation in the range makes this fail, and nothing
+    /// changes.
+    ///
+    /// The range can be given back with
+    /// [`extend_region`](struct.EspHeap.html#method.extend_region).
+    ///
+    /// # Safety
+    ///
+    /// The returned range must not be used after it has been given back.
+    pub unsafe fn shrink_region(
+        &self,
+        region: RegionId,
+        new_size: usize,
+    ) -> Result<*mut u8, ShrinkError> {
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            let region = regions
+                .get_mut(region.index)
+                .filter(|region| region.is_initialized())
+                .ok_or(ShrinkError::NoSuchRegion)?;
+
+            let bottom = region.heap.bottom() as usize;
+            let new_size = (new_size + holes::BLOCK_ALIGN - 1) & !(holes::BLOCK_ALIGN - 1);
+            let end = bottom + region.size();
+            if new_size >= region.size() {
+                return if new_size == region.size() {
+                    Ok(end as *mut u8)
+                } else {
+                    Err(ShrinkError::TooLarge)
+                };
+            }
+            if region.size() - new_size < holes::MIN_BLOCK {
+                return Err(ShrinkError::TooSmall);
+            }
+
+            #[cfg(feature = "free-cache")]
+            region.flush_cache();
+            region.release_headroom();
+            // Walking merges neighbouring holes and writes into them.
+            region.untouched = region.heap.top() as usize;
+            region.replay = None;
+            holes::walk(&mut region.heap, |_, _| {});
+
+            let start = bottom + new_size;
+            let taken = holes::take_tail(&mut region.heap, start, end);
+            if taken {
+                region.trimmed += end - start;
+                region.min_free = region.min_free.min(region.heap.free());
+            }
+            region.reserve_headroom();
+            if taken {
+                Ok(start as *mut u8)
+            } else {
+                Err(ShrinkError::Occupied)
+            }
+        })
+    }
+
+    /// Returns an estimate of the amount of bytes in use.
+    ///
+    /// This is the backing allocator's figure: it includes the rounding of
+    /// every block to the allocator's granularity, cache line padding and
+    /// blocks held in quarantine, which is what matters for capacity
+    /// planning. For the bytes the application actually holds, see
+    /// [`live_bytes`](struct.EspHeap.html#method.live_bytes).
+    ///
+    /// This is zero, without entering a critical section, before any region
+    /// has been added.
+    pub fn used(&self) -> usize {
+        self.usage(false).used
+    }
+
+    /// Returns the sum of the sizes requested by all live allocations
+    ///
+    /// Unlike [`used`](struct.EspHeap.html#method.used) this is maintained by
+    /// this crate on every allocation and deallocation, so it returns to
+    /// exactly the same value once everything allocated since has been freed.
+    pub fn live_bytes(&self) -> usize {
+        self.usage(false).live_bytes
+    }
+
+    /// Returns an estimate of the amount of bytes available.
+    ///
+    /// This is the sum over all regions, see
+    /// [`stats`](struct.EspHeap.html#method.stats) for each of them. It is
+    /// zero, without entering a critical section, before any region
+    /// has been added.
+    pub fn free(&self) -> usize {
+        self.usage(false).free
+    }
+
+    /// Returns the number of free bytes ordinary allocations can actually use
+    ///
+    /// [`free`](struct.EspHeap.html#method.free) is the raw figure of every
+    /// free byte in every region. This one leaves out the space a plain
+    /// allocation is kept from, which is the stack reserve, and adds the
+    /// quarantined blocks that are freed for it if need be. Size general
+    /// purpose buffers like a `Vec` by this one.
+    ///
+    /// Like `free`, it doesn't account for fragmentation.
+    pub fn free_general(&self) -> usize {
+        if !self.is_initialized() {
+            return 0;
+        }
+
+        self.locked(|cs| self.free_general_locked(cs))
+    }
+
+    fn free_general_locked(&self, cs: CriticalSection<'_>) -> usize {
+        let free: usize = self
+            .heap
+            .borrow(cs)
+            .borrow()
+            .iter()
+            .map(|region| {
+                let free = region.heap.free().saturating_sub(region.reserved_top());
+                #[cfg(feature = "free-cache")]
+                let free = free + region.cache.bytes();
+                free
+            })
+            .sum();
+
+        #[cfg(feature = "quarantine")]
+        let free = free + self.quarantine.borrow(cs).borrow().bytes;
+
+        free
+    }
+
+    /// Returns the size of the largest single allocation that has succeeded
+    ///
+    /// This is the smallest largest-free-block the heap has to sustain for
+    /// the application's biggest request to keep succeeding.
+    pub fn largest_allocation(&self) -> usize {
+        if !self.is_initialized() {
+            return 0;
+        }
+
+        self.locked(|cs| self.largest_allocation.borrow(cs).get())
+    }
+
+    /// Walks the free list of the given region and merges adjacent free
+    /// blocks
+    ///
+    /// The backing allocator merges neighbouring blocks on every
+    /// deallocation, so `merges` is expected to be zero: a non-zero value
+    /// means the free list was not in the state it should be in. Either way
+    /// the resulting `largest_free_block` reflects the memory that is really
+    /// contiguous, which tells fragmentation apart from bookkeeping artifacts.
+    ///
+    /// The walk is `O(n²)` in the number of free blocks, so don't call this
+    /// from latency sensitive code. It takes the region's critical section
+    /// for a few free blocks at a time, which keeps interrupts from waiting
+    /// on it for long, but allocations in between can change the figures. An
+    /// uninitialized or non-existent region reports all zeros.
+    pub fn coalesce(&self, region: RegionId) -> CoalesceReport {
+        if !self.is_initialized() {
+            return CoalesceReport::default();
+        }
+
+        let mut report = CoalesceReport::default();
+        let mut run_end = ptr::null_mut();
+        let mut run_size = 0;
+        let mut start = Some(0);
+        while let Some(from) = start {
+            start = self.locked(|cs| {
+                let mut regions = self.heap.borrow(cs).borrow_mut();
+                let region = match regions.get_mut(region.index) {
+                    Some(region) if region.is_initialized() => region,
+                    _ => return None,
+                };
+
+                // Walking writes into the free blocks.
+                region.untouched = region.heap.top() as usize;
+                region.replay = None;
+
+                holes::walk_from(&mut region.heap, from, COALESCE_HOLES, |addr, size| {
+                    if addr == run_end {
+                        report.merges += 1;
+                        run_size += size;
+                    } else {
+                        report.free_blocks += 1;
+                        run_size = size;
+                    }
+                    run_end = addr.wrapping_add(size);
+                    report.largest_free_block = report.largest_free_block.max(run_size);
+                })
+            });
+        }
+
+        report
+    }
+
+    /// Returns the size of the largest allocation the given region can serve
+    /// right now
+    ///
+    /// This is the largest size for which an allocation with an alignment of
+    /// up to that of `usize` from this region succeeds, taking the stack
+    /// reserve, the DMA tail guard, cache line isolation and the canaries of
+    /// the `heap-guard` feature into account, so it is the figure to size
+    /// "the largest possible buffer" by. It can be less than the largest free
+    /// block reported by
+    /// [`coalesce`](struct.EspHeap.html#method.coalesce), since the backing
+    /// allocator can't split off a remainder smaller than its minimum block.
+    /// For the same reason a size slightly below it may fail, when it would
+    /// leave such a remainder of the block it fills. Blocks in the free cache
+    /// or the quarantine are not counted.
+    ///
+    /// Like `coalesce` this walks the free list, which is `O(n²)` in the
+    /// number of free blocks.
+    pub fn largest_free_block(&self, region: RegionId) -> usize {
+        if !self.is_initialized() {
+            return 0;
+        }
+
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            let region = match regions.get_mut(region.index) {
+                Some(region) if region.is_initialized() => region,
+                _ => return 0,
+            };
+
+            region.free_blocks().largest_fit
+        })
+    }
+
+    /// Returns the size of the largest allocation any region can serve right
+    /// now
+    ///
+    /// The heap-wide [`largest_free_block`](struct.EspHeap.html#method.largest_free_block),
+    /// for deciding up front whether one big buffer fits or data has to be
+    /// streamed in chunks: while [`free`](struct.EspHeap.html#method.free)
+    /// may report plenty of memory, fragmentation can keep any single
+    /// allocation of that size from succeeding. Regions that aren't
+    /// initialized count as `0`.
+    ///
+    /// Each region is walked in a critical section of its own.
+    pub fn max_free_block(&self) -> usize {
+        self.region_ids()
+            .map(|region| self.largest_free_block(region))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the region an allocation of `layout` would be served from
+    /// right now, or `None` if it would fail
+    ///
+    /// Unlike comparing `layout.size()` to
+    /// [`largest_free_block`](struct.EspHeap.html#method.largest_free_block),
+    /// this accounts for the padding the alignment of `layout` needs within a
+    /// free block, and considers the regions in the order an allocation
+    /// would. Blocks in the free cache or the quarantine, which an allocation
+    /// may fall back on, are not counted.
+    pub fn available_for(&self, layout: Layout) -> Option<RegionId> {
+        if !self.is_initialized() {
+            return None;
+        }
+
+        let core = self.core_source.current();
+        let large = self.is_large(layout.size());
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            let order = affinity::region_order(&regions[..], core, large);
+            order[..regions.len()].iter().find_map(|&index| {
+                let region = &mut regions[index];
+                (region.serves(MemoryCapability::empty())
+                    && region.could_fit(layout)
+                    && region.can_serve(layout))
+                .then(|| region.id(index))
+            })
+        })
+    }
+
+    /// Returns the number of contiguous free bytes beyond `layout.size()` an
+    /// allocation of `layout` from the given region needs at most
+    ///
+    /// Any free block of at least `layout.size()` plus this many bytes,
+    /// outside of the stack reserve and the DMA tail guard, can serve the
+    /// allocation, wherever it lies. This covers rounding up to the backing
+    /// allocator's block size and to cache lines, padding for the alignment,
+    /// and the room for the remainder of the block to remain a free block.
+    /// Returns `usize::MAX` if `layout` can't be served at all.
+    pub fn worst_case_overhead(&self, region: RegionId, layout: Layout) -> usize {
+        let region_layout =
+            self.locked(|cs| match self.heap.borrow(cs).borrow().get(region.index) {
+                Some(region) => region.region_layout(layout),
+                None => Some(layout),
+            });
+        let Some(region_layout) = region_layout else {
+            return usize::MAX;
+        };
+
+        let padding = if region_layout.align() > holes::BLOCK_ALIGN {
+            holes::MIN_BLOCK + region_layout.align() - holes::BLOCK_ALIGN
+        } else {
+            0
+        };
+        holes::block_size(region_layout.size()) - layout.size() + padding + holes::MIN_BLOCK
+    }
+
+    /// Reserves the top `bytes` of the region next to the stack for stack
+    /// growth
+    ///
+    /// The stack usually grows downwards towards the end of the region added
+    /// first (by [`init`](struct.EspHeap.html#method.init)), so allocations
+    /// from that region that would reach into its top `bytes` are refused and
+    /// served from other regions instead, if possible. Allocations already
+    /// living in the reserved zone are not affected.
+    ///
+    /// Passing `0` removes the reservation.
+    pub fn set_reserved_for_stack(&self, bytes: usize) {
+        self.locked(|cs| {
+            self.heap.borrow(cs).borrow_mut()[STACK_REGION].reserved = bytes;
+        });
+    }
+
+    /// Grows the stack reservation to at least `high_water` bytes
+    ///
+    /// Meant to be called periodically with the measured stack high-water
+    /// mark (plus whatever margin is appropriate). The reservation never
+    /// shrinks through this call, see
+    /// [`set_reserved_for_stack`](struct.EspHeap.html#method.set_reserved_for_stack).
+    pub fn note_stack_high_water(&self, high_water: usize) {
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            let region = &mut regions[STACK_REGION];
+            region.reserved = region.reserved.max(high_water);
+        });
+    }
+
+    /// Allocates memory from a region with all of `capabilities`
+    ///
+    /// The counterpart of ESP-IDF's `heap_caps_malloc`: regions are tried in
+    /// the usual order, but those missing any of `capabilities` are skipped
+    /// even if they have room, so for example a DMA descriptor asked for with
+    /// [`MemoryCapability::DMA`] `|` [`MemoryCapability::INTERNAL`] never
+    /// lands in PSRAM. [`MemoryCapability::empty()`] accepts any region, like
+    /// `GlobalAlloc::alloc` does.
+    ///
+    /// Returns null if no such region has room. The block is freed with
+    /// `GlobalAlloc::dealloc`, which finds its region by address.
+    pub fn alloc_caps(&self, capabilities: MemoryCapability, layout: Layout) -> *mut u8 {
+        self.alloc_from(capabilities, layout)
+    }
+
+    /// Allocates memory from the given region only
+    ///
+    /// For buffers that must be in one particular region whatever the
+    /// others have room for, like a frame buffer in PSRAM. There is no
+    /// fallback: returns null if that region can't serve the allocation,
+    /// including when it doesn't exist or its memory hasn't been added yet.
+    /// The block is freed with `GlobalAlloc::dealloc`, which finds its region
+    /// by address, so it can back a `Vec` built with `Vec::from_raw_parts`.
+    pub fn alloc_in_region(&self, region: RegionId, layout: Layout) -> *mut u8 {
+        let mut context = self.context(layout.size());
+        context.region = Some(region.index);
+        self.allocate_with(context, MemoryCapability::empty(), layout)
+            .map_or(ptr::null_mut(), |allocation| allocation.ptr.as_ptr())
+    }
+
+    /// Allocates memory from an internal region, see
+    /// [`alloc_caps`](struct.EspHeap.html#method.alloc_caps)
+    pub fn alloc_internal(&self, layout: Layout) -> *mut u8 {
+        self.alloc_caps(MemoryCapability::INTERNAL, layout)
+    }
+
+    /// Allocates memory from an external region, see
+    /// [`alloc_caps`](struct.EspHeap.html#method.alloc_caps)
+    pub fn alloc_external(&self, layout: Layout) -> *mut u8 {
+        self.alloc_caps(MemoryCapability::EXTERNAL, layout)
+    }
+
+    /// Allocates a block for every layout in `layouts` in a single critical
+    /// section
+    ///
+    /// Meant for subsystems that grab many buffers at once during
+    /// initialization: interrupts are only held off once instead of for every
+    /// buffer. The pointer to the block for `layouts[i]` is stored in
+    /// `out[i]`, or null if that allocation failed.
+    ///
+    /// A partial failure doesn't roll anything back: the successful
+    /// allocations stay live and must be freed by the caller.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `layouts` and `out` have different lengths.
+    pub fn alloc_batch(&self, layouts: &[Layout], out: &mut [*mut u8]) {
+        assert_eq!(
+            layouts.len(),
+            out.len(),
+            "alloc_batch needs one output slot per layout"
+        );
+
+        let size = layouts
+            .iter()
+            .fold(0usize, |size, layout| size.saturating_add(layout.size()));
+        let context = self.context(size);
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            #[cfg(esp_alloc_dual_core)]
+            let _entered = self.core_guard.enter();
+            for (layout, out) in layouts.iter().zip(out.iter_mut()) {
+                let context = self.resize_context(context, layout.size());
+                let capabilities = MemoryCapability::empty();
+                *out = match self.allocate_locked(
+                    cs,
+                    &mut regions[..],
+                    context,
+                    capabilities,
+                    *layout,
+                ) {
+                    Some(allocation) => allocation.ptr.as_ptr(),
+                    None => {
+                        self.record_failed(cs, &mut regions[..], context, capabilities, *layout);
+                        ptr::null_mut()
+                    }
+                };
+            }
+        });
+        for (layout, ptr) in layouts.iter().zip(out) {
+            self.report_allocation(*layout, *ptr);
+        }
+        self.indicate_usage();
+    }
+
+    /// Sets the cache line size used by
+    /// [`alloc_dma_cached`](struct.EspHeap.html#method.alloc_dma_cached) and
+    /// [`set_isolate_cache_lines`](struct.EspHeap.html#method.set_isolate_cache_lines)
+    ///
+    /// `bytes` must be a power of two. Change it before allocating any cached
+    /// DMA buffers or isolating any region.
+    pub fn set_cache_line_size(&self, bytes: usize) {
+        assert!(
+            bytes.is_power_of_two(),
+            "cache line size must be a power of two"
+        );
+        self.cache_line_size.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Makes allocations of at least `bytes` try the regions in reverse
+    /// order
+    ///
+    /// Regions are normally tried in the order they were added, so large
+    /// buffers fill up the first region, typically scarce internal RAM, even
+    /// when a big PSRAM region was added after it. Above the threshold the
+    /// last region added is tried first instead, and the others serve as
+    /// fallback, while smaller allocations keep the usual order. A block
+    /// that [`realloc`](struct.EspHeap.html#method.realloc) grows past the
+    /// threshold is moved accordingly, unless it is aligned like a
+    /// [DMA buffer](struct.EspHeap.html#method.alloc_dma), but only among the
+    /// regions with the capabilities of its own. Regions with different
+    /// [core costs](struct.EspHeap.html#method.set_region_core_affinity)
+    /// are still tried cheapest first.
+    ///
+    /// It defaults to `usize::MAX`, which leaves every allocation in the
+    /// usual order.
+    pub fn set_large_alloc_threshold(&self, bytes: usize) {
+        self.large_threshold.store(bytes, Ordering::Relaxed);
+    }
+
+    fn is_large(&self, size: usize) -> bool {
+        size >= self.large_threshold.load(Ordering::Relaxed)
+    }
+
+    /// Makes every allocation of more than `bytes` fail
+    ///
+    /// A safety valve against a single runaway allocation, like a
+    /// `Vec::with_capacity` driven by a corrupted length field, taking most
+    /// of the heap with it. Such an allocation returns null without touching
+    /// the regions, and so does a
+    /// [`realloc`](struct.EspHeap.html#method.realloc) growing a block past
+    /// the cap, which leaves the block alone. The cap is independent of any
+    /// other limit and applies to every region.
+    ///
+    /// The out-of-memory handler set with
+    /// [`set_oom_handler`](struct.EspHeap.html#method.set_oom_handler) is
+    /// still called. It tells these failures apart from a full heap by the
+    /// size of the layout, which is above
+    /// [`max_alloc_size`](struct.EspHeap.html#method.max_alloc_size), and
+    /// there is no point in returning [`OomAction::Retry`] for them.
+    ///
+    /// It defaults to `usize::MAX`, which lets every allocation through.
+    pub fn set_max_alloc_size(&self, bytes: usize) {
+        self.max_alloc_size.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns the cap set with
+    /// [`set_max_alloc_size`](struct.EspHeap.html#method.set_max_alloc_size)
+    pub fn max_alloc_size(&self) -> usize {
+        self.max_alloc_size.load(Ordering::Relaxed)
+    }
+
+    fn is_oversized(&self, size: usize) -> bool {
+        size > self.max_alloc_size.load(Ordering::Relaxed)
+    }
+
+    /// Allocates a DMA buffer that can safely be used with cache maintenance
+    ///
+    /// The buffer is aligned to the cache line size and its length is rounded
+    /// up to a multiple of it, so invalidating or writing back the buffer's
+    /// cache lines never touches neighbouring allocations. It is only
+    /// allocated from regions that are both [`MemoryCapability::DMA`] and
+    /// [`MemoryCapability::EXTERNAL`] (external memory is accessed through the
+    /// cache).
+    ///
+    /// The returned slice has the rounded length. Free it with
+    /// [`free_dma_cached`](struct.EspHeap.html#method.free_dma_cached).
+    pub fn alloc_dma_cached(&self, len: usize) -> Option<NonNull<[u8]>> {
+        let line = self.cache_line_size.load(Ordering::Relaxed);
+        let size = len.max(1).checked_add(line - 1)? & !(line - 1);
+        let layout = Layout::from_size_align(size, line).ok()?;
+
+        let ptr = self.alloc_from(MemoryCapability::DMA | MemoryCapability::EXTERNAL, layout);
+        NonNull::new(ptr::slice_from_raw_parts_mut(ptr, size))
+    }
+
+    /// Frees a buffer returned by
+    /// [`alloc_dma_cached`](struct.EspHeap.html#method.alloc_dma_cached)
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must have been returned by `alloc_dma_cached` on this heap and
+    /// must not be used afterwards.
+    pub unsafe fn free_dma_cached(&self, buffer: NonNull<[u8]>) {
+        let line = self.cache_line_size.load(Ordering::Relaxed);
+        let layout = Layout::from_size_align_unchecked(buffer.len(), line);
+        self.dealloc(buffer.as_ptr() as *mut u8, layout);
+    }
+
+    /// Sets the alignment of
+    /// [`alloc_dma`](struct.EspHeap.html#method.alloc_dma) buffers
+    ///
+    /// `bytes` must be a power of two, like 64 for engines that need buffers
+    /// aligned to 64 byte cache lines. Change it before allocating any DMA
+    /// buffers.
+    pub fn set_dma_alignment(&self, bytes: usize) {
+        assert!(
+            bytes.is_power_of_two(),
+            "DMA alignment must be a power of two"
+        );
+        self.dma_alignment.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns the layout [`alloc_dma`](struct.EspHeap.html#method.alloc_dma)
+    /// allocates a buffer of `size` bytes with, which it is freed and
+    /// reallocated with
+    ///
+    /// Returns `None` if `size` is too big to form a layout.
+    pub fn dma_layout(&self, size: usize) -> Option<Layout> {
+        Layout::from_size_align(size, self.dma_alignment.load(Ordering::Relaxed)).ok()
+    }
+
+    /// Allocates a buffer DMA can reach
+    ///
+    /// The buffer is aligned to the DMA alignment, see
+    /// [`set_dma_alignment`](struct.EspHeap.html#method.set_dma_alignment),
+    /// and only allocated from regions that are both
+    /// [`MemoryCapability::DMA`] and [`MemoryCapability::INTERNAL`], since
+    /// the DMA engines of some chips can't reach external memory. Returns
+    /// null if none of them has room, rather than falling back to PSRAM.
+    ///
+    /// Free the buffer with `GlobalAlloc::dealloc`, and resize it with
+    /// `GlobalAlloc::realloc`, passing
+    /// [`dma_layout`](struct.EspHeap.html#method.dma_layout)`(size)`. A buffer
+    /// that doesn't fit where it is moves to another region with the
+    /// capabilities of its own, even if it grows past the
+    /// [large allocation threshold](struct.EspHeap.html#method.set_large_alloc_threshold).
+    pub fn alloc_dma(&self, size: usize) -> *mut u8 {
+        match self.dma_layout(size) {
+            Some(layout) => {
+                self.alloc_from(MemoryCapability::DMA | MemoryCapability::INTERNAL, layout)
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Makes every allocation in the given region occupy whole cache lines
+    ///
+    /// Meant for cached external memory: the size and alignment of each
+    /// allocation from the region are rounded up to the cache line size, so
+    /// no two allocations ever share a line and cache maintenance on one
+    /// buffer can't corrupt another. The cost of the rounding is reported by
+    /// [`cache_line_padding`](struct.EspHeap.html#method.cache_line_padding).
+    ///
+    /// This must be set before anything is allocated from the region, and not
+    /// changed afterwards. It has no effect on non-existent regions.
+    pub fn set_isolate_cache_lines(&self, region: RegionId, isolate: bool) {
+        let line = self.cache_line_size.load(Ordering::Relaxed);
+        self.locked(|cs| {
+            if let Some(region) = self.heap.borrow(cs).borrow_mut().get_mut(region.index) {
+                region.cache_line = if isolate { line } else { 0 };
+            }
+        });
+    }
+
+    /// Keeps the top `bytes` of the given region clear of allocations
+    ///
+    /// Some DMA engines read a few bytes past the end of a buffer. With a
+    /// tail guard, no allocation from the region ends closer than `bytes` to
+    /// its top, so such over-reads stay within the region instead of hitting
+    /// whatever memory follows it. Allocations that would reach into the
+    /// guard are served from other regions instead, if possible. Allocations
+    /// already living in the guard zone are not affected.
+    ///
+    /// Passing `0` removes the guard. It has no effect on non-existent
+    /// regions.
+    pub fn set_dma_tail_guard(&self, region: RegionId, bytes: usize) {
+        self.locked(|cs| {
+            if let Some(region) = self.heap.borrow(cs).borrow_mut().get_mut(region.index) {
+                region.tail_guard = bytes;
+            }
+        });
+    }
+
+    /// Returns the number of bytes the live allocations of the given region
+    /// spend on cache line padding
+    pub fn cache_line_padding(&self, region: RegionId) -> usize {
+        if !self.is_initialized() {
+            return 0;
+        }
+
+        self.locked(|cs| {
+            self.heap
+                .borrow(cs)
+                .borrow()
+                .get(region.index)
+                .map_or(0, |region| region.padding)
+        })
+    }
+
+    /// Returns the crate version and the configuration this heap runs with
+    ///
+    /// Useful to tell from the field how a device was built and set up.
+    pub fn config_summary(&self) -> ConfigSummary {
+        self.locked(|cs| {
+            let regions = self.heap.borrow(cs).borrow();
+            let initialized = || regions.iter().filter(|region| region.is_initialized());
+
+            ConfigSummary {
+                version: env!("CARGO_PKG_VERSION"),
+                regions: initialized().count(),
+                max_regions: MAX_REGIONS,
+                cache_line_size: self.cache_line_size.load(Ordering::Relaxed),
+                dma_alignment: self.dma_alignment.load(Ordering::Relaxed),
+                isolated_regions: initialized()
+                    .filter(|region| region.cache_line != 0)
+                    .count(),
+                stack_reserve: regions[STACK_REGION].reserved,
+                missing_regions: regions
+                    .iter()
+                    .filter(|region| {
+                        matches!(
+                            region.status,
+                            RegionStatus::Expected | RegionStatus::Unavailable(_)
+                        )
+                    })
+                    .count(),
+                alloc_hooks: cfg!(feature = "alloc-hooks"),
+                defmt_failures: cfg!(feature = "defmt-failures"),
+                free_cache: cfg!(feature = "free-cache"),
+                heap_guard: cfg!(feature = "heap-guard"),
+                isr_guard: cfg!(feature = "isr-guard"),
+                multicore: cfg!(feature = "multicore"),
+                oom_handler: cfg!(feature = "oom-handler"),
+                poison: cfg!(feature = "poison"),
+                quarantine: cfg!(feature = "quarantine"),
+                registry: cfg!(feature = "registry"),
+                stats: cfg!(feature = "stats"),
+                trace: cfg!(feature = "trace"),
+                validate_layout: cfg!(feature = "validate-layout"),
+            }
+        })
+    }
+
+    /// Sets the clock used to timestamp allocation failures (the `stats`
+    /// feature) and live allocations (the `registry` feature)
+    ///
+    /// `now` is called inside a critical section whenever an allocation
+    /// fails or is recorded, so it must be cheap and must not use the heap.
+    /// Any monotonic time base works, like the uptime in milliseconds.
+    #[cfg(any(feature = "stats", feature = "registry"))]
+    pub fn set_timestamp_source(&self, now: fn() -> u64) {
+        self.locked(|cs| self.clock.borrow(cs).set(Some(now)));
+    }
+
+    /// Returns the current time, if a clock is set.
+    #[cfg(any(feature = "stats", feature = "registry"))]
+    fn now(&self, cs: CriticalSection<'_>) -> Option<u64> {
+        self.clock.borrow(cs).get().map(|now| now())
+    }
+
+    /// Decides how allocations for `size` bytes may be served, which must
+    /// happen before entering the critical section.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn context(&self, size: usize) -> Context {
+        #[cfg(feature = "isr-guard")]
+        let in_isr = self.isr_guard.check();
+        #[cfg(feature = "isr-guard")]
+        if in_isr == Some(IsrPolicy::Panic) {
+            fail!(
+                "allocation from an interrupt handler",
+                "Allocation of {size} bytes from an interrupt handler"
+            );
+        }
+
+        let context = Context {
+            #[cfg(feature = "isr-guard")]
+            in_isr,
+            headroom: false,
+            early: false,
+            core: self.core_source.current(),
+            large: false,
+            region: None,
+        };
+        self.resize_context(context, size)
+    }
+
+    /// Adapts `context` to an allocation of `size` bytes, which can happen
+    /// inside the critical section.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn resize_context(&self, context: Context, size: usize) -> Context {
+        Context {
+            early: self.early.applies(size),
+            large: self.is_large(size),
+            ..context
+        }
+    }
+
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn allocate(&self, capabilities: MemoryCapability, layout: Layout) -> Option<Allocation> {
+        self.allocate_with(self.context(layout.size()), capabilities, layout)
+    }
+
+    /// Allocates in critical sections of its own, one per region tried,
+    /// giving the out-of-memory handler a chance to make room if that fails.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn allocate_with(
+        &self,
+        context: Context,
+        capabilities: MemoryCapability,
+        layout: Layout,
+    ) -> Option<Allocation> {
+        // Each region is searched in a critical section of its own, so a
+        // failed search lets interrupts in before the next region is tried.
+        let attempt = |fallback: bool| {
+            let mut admitted = false;
+            let mut tried = 0;
+            loop {
+                let result = self.locked(|cs| {
+                    let mut regions = self.heap.borrow(cs).borrow_mut();
+                    let regions = &mut regions[..];
+                    #[cfg(esp_alloc_dual_core)]
+                    let _entered = self.core_guard.enter();
+                    let allocation = if !admitted && !self.admit_locked(cs, context, layout) {
+                        None
+                    } else {
+                        admitted = true;
+                        // Regions that can't take it are passed over without
+                        // letting go of the lock.
+                        let next = candidates(regions, context).skip(tried).find(|&index| {
+                            tried += 1;
+                            could_serve(&regions[index], context, capabilities, layout)
+                        });
+                        match next {
+                            Some(index) => Some((index, regions[index].allocate(layout)?)),
+                            None => {
+                                self.allocate_fallback(cs, regions, context, capabilities, layout)
+                            }
+                        }
+                    };
+
+                    Some(match allocation {
+                        Some((region, allocation)) => Ok(self.finish_allocation(
+                            cs,
+                            regions,
+                            region,
+                            allocation,
+                            capabilities,
+                            layout,
+                        )),
+                        None => {
+                            let handler = if fallback {
+                                self.enter_oom_handler(cs)
+                            } else {
+                                None
+                            };
+                            if handler.is_none() {
+                                self.record_failed(cs, regions, context, capabilities, layout);
+                            }
+                            Err(handler)
+                        }
+                    })
+                });
+                if let Some(result) = result {
+                    return result;
+                }
+            }
+        };
+
+        let allocation = match attempt(context.fallback()) {
+            Ok(allocation) => Some(allocation),
+            Err(Some(handler)) => match self.run_oom_handler(handler, layout) {
+                OomAction::Retry => attempt(false).ok(),
+                OomAction::Fail => {
+                    self.locked(|cs| {
+                        let mut regions = self.heap.borrow(cs).borrow_mut();
+                        self.record_failed(cs, &mut regions[..], context, capabilities, layout);
+                    });
+                    None
+                }
+            },
+            Err(None) => None,
+        };
+        self.report_allocation(
+            layout,
+            allocation
+                .as_ref()
+                .map_or(ptr::null_mut(), |allocation| allocation.ptr.as_ptr()),
+        );
+        self.indicate_usage();
+        allocation
+    }
+
+    /// Reports an allocation of `layout` that returned `ptr`, or null if it
+    /// failed, to the hooks or the log, which is what follows every
+    /// allocation once it has left the critical section.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn report_allocation(&self, layout: Layout, ptr: *mut u8) {
+        #[cfg(feature = "defmt-failures")]
+        if ptr.is_null() {
+            self.log_failure(layout);
+        }
+        #[cfg(feature = "alloc-hooks")]
+        if !ptr.is_null() {
+            self.alloc_hooks.allocated(ptr, layout.size());
+        }
+        #[cfg(not(any(feature = "defmt-failures", feature = "alloc-hooks")))]
+        let _ = (layout, ptr);
+    }
+
+    /// Counts and traces an allocation that failed for good, after the
+    /// out-of-memory handler had its chance, so that retries aren't counted
+    /// twice.
+    fn record_failed(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &mut [Region],
+        context: Context,
+        capabilities: MemoryCapability,
+        layout: Layout,
+    ) {
+        #[cfg(feature = "trace")]
+        self.trace_alloc(cs, regions, layout, None);
+        #[cfg(feature = "stats")]
+        self.record_failure(cs, regions, context.region, capabilities, layout.size());
+        #[cfg(not(feature = "stats"))]
+        let _ = (context, capabilities);
+        #[cfg(not(any(feature = "trace", feature = "stats")))]
+        let _ = (cs, regions, layout);
+    }
+
+    fn used_locked(&self, cs: CriticalSection<'_>) -> usize {
+        self.heap
+            .borrow(cs)
+            .borrow()
+            .iter()
+            .map(|region| region.used())
+            .sum()
+    }
+
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn allocate_locked(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &mut [Region],
+        context: Context,
+        capabilities: MemoryCapability,
+        layout: Layout,
+    ) -> Option<Allocation> {
+        let admitted = self.admit_locked(cs, context, layout);
+        let allocation = if admitted {
+            allocate_in(regions, context, capabilities, layout)
+        } else {
+            None
+        };
+        #[cfg(feature = "quarantine")]
+        let allocation = allocation.or_else(|| {
+            if !admitted {
+                return None;
+            }
+            self.allocate_quarantined(cs, regions, context, capabilities, layout)
+        });
+        let (region, allocation) = allocation?;
+        Some(self.finish_allocation(cs, regions, region, allocation, capabilities, layout))
+    }
+
+    /// Checks and counts an allocation before any region is searched,
+    /// returning whether it may go ahead.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn admit_locked(&self, cs: CriticalSection<'_>, context: Context, layout: Layout) -> bool {
+        #[cfg(feature = "validate-layout")]
+        validate_layout("Allocation", layout);
+
+        #[cfg(feature = "isr-guard")]
+        if context.in_isr.is_some() {
+            let count = self.isr_allocations.borrow(cs);
+            count.set(count.get() + 1);
+        }
+
+        !self.is_oversized(layout.size()) && (!context.early || self.early.admit(cs, layout))
+    }
+
+    /// Takes what the regions can't serve from their headroom or, after
+    /// releasing the quarantine, from the blocks that were held back, which
+    /// is what follows once every region was searched.
+    fn allocate_fallback(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &mut [Region],
+        context: Context,
+        capabilities: MemoryCapability,
+        layout: Layout,
+    ) -> Option<(usize, Allocation)> {
+        let allocation = if context.headroom {
+            allocate_in_headroom(regions, context, capabilities, layout)
+        } else {
+            None
+        };
+        #[cfg(feature = "quarantine")]
+        let allocation = allocation
+            .or_else(|| self.allocate_quarantined(cs, regions, context, capabilities, layout));
+        #[cfg(not(feature = "quarantine"))]
+        let _ = cs;
+        allocation
+    }
+
+    /// Releases the quarantine and tries again, if that could help.
+    #[cfg(feature = "quarantine")]
+    fn allocate_quarantined(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &mut [Region],
+        context: Context,
+        capabilities: MemoryCapability,
+        layout: Layout,
+    ) -> Option<(usize, Allocation)> {
+        let mut quarantine = self.quarantine.borrow(cs).borrow_mut();
+        if !context.fallback() || quarantine.is_empty() || !could_fit(regions, capabilities, layout)
+        {
+            return None;
+        }
+        let blocks = core::iter::from_fn(|| quarantine.pop());
+        // SAFETY: quarantined blocks are live allocations.
+        unsafe { self.release_quarantined(cs, regions, blocks) };
+        allocate_in(regions, context, capabilities, layout)
+    }
+
+    /// Does the accounting for a block just allocated from `region`.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn finish_allocation(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &mut [Region],
+        region: usize,
+        allocation: Allocation,
+        capabilities: MemoryCapability,
+        layout: Layout,
+    ) -> Allocation {
+        let largest = self.largest_allocation.borrow(cs);
+        largest.set(largest.get().max(layout.size()));
+        let free = regions[region].heap.free();
+        regions[region].min_free = regions[region].min_free.min(free);
+        #[cfg(feature = "stats")]
+        self.record_allocation(cs, regions, layout.size());
+        let sequence = self.sequence.borrow(cs);
+        sequence.set(sequence.get().wrapping_add(1));
+
+        #[cfg(feature = "trace")]
+        self.trace_alloc(cs, regions, layout, Some((region, allocation.ptr)));
+        #[cfg(feature = "registry")]
+        self.registry
+            .borrow(cs)
+            .borrow_mut()
+            .insert(registry::Entry {
+                ptr: allocation.ptr.as_ptr() as usize,
+                layout,
+                capabilities,
+                region,
+                created: self.now(cs),
+            });
+        #[cfg(not(any(feature = "trace", feature = "registry")))]
+        let _ = region;
+        #[cfg(not(feature = "registry"))]
+        let _ = capabilities;
+
+        #[cfg(feature = "poison")]
+        let allocation = {
+            let mut allocation = allocation;
+            // Retained blocks may come back with contents from before sleep.
+            let retained = regions[region]
+                .capabilities
+                .contains(MemoryCapability::RTC_RETAINED);
+            // SAFETY: the block is at least `layout.size()` bytes.
+            if !retained
+                && unsafe {
+                    self.poison(allocation.ptr.as_ptr(), layout.size(), poison::ALLOC_POISON)
+                }
+            {
+                allocation.dirty = allocation.dirty.max(layout.size());
+            }
+            allocation
+        };
+
+        allocation
+    }
+
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    fn alloc_from(&self, capabilities: MemoryCapability, layout: Layout) -> *mut u8 {
+        match self.allocate(capabilities, layout) {
+            Some(allocation) => allocation.ptr.as_ptr(),
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Resizes a block to `new_size` bytes, in place if possible and in a
+    /// region with `capabilities` otherwise, returning null and leaving the
+    /// block alone on failure.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation made with `layout`, and `new_size`
+    /// must form a valid layout with its alignment.
+    unsafe fn realloc_from(
+        &self,
+        capabilities: MemoryCapability,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        match self.resize_in_place(ptr, layout, new_size) {
+            Ok(()) => ptr,
+            Err(_) => self.move_block(capabilities, ptr, layout, new_size),
+        }
+    }
+
+    /// Resizes a block without moving it, in a critical section of its own.
+    ///
+    /// Returns the capabilities of the block's region if that isn't possible,
+    /// which a moved block is allocated with so that it stays in the same
+    /// kind of memory.
+    ///
+    /// # Safety
+    ///
+    /// See [`realloc_from`](Self::realloc_from).
+    unsafe fn resize_in_place(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<(), MemoryCapability> {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        // Resizing from interrupt handlers or during early boot goes through
+        // the checks of a regular allocation.
+        let context = self.context(new_size);
+        #[cfg(feature = "isr-guard")]
+        let guarded = context.in_isr.is_some();
+        #[cfg(not(feature = "isr-guard"))]
+        let guarded = false;
+
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            #[cfg(esp_alloc_dual_core)]
+            let _entered = self.core_guard.enter();
+
+            let Some(index) = region_of(&regions[..], ptr) else {
+                return Err(MemoryCapability::empty());
+            };
+            let region = &mut regions[index];
+            if guarded || context.early || self.is_oversized(new_size) {
+                return Err(region.capabilities);
+            }
+            // A block growing past the large allocation threshold moves to
+            // where large allocations go first, but among the regions of the
+            // same kind as its own, since it may have been allocated with
+            // their capabilities. DMA buffers stay where they are if they can.
+            if context.large
+                && !self.is_large(layout.size())
+                && layout.align() < self.dma_alignment.load(Ordering::Relaxed)
+            {
+                return Err(region.capabilities);
+            }
+            // The tail is poisoned before it is freed. A block that has to
+            // move instead is left alone, in case the move fails.
+            #[cfg(feature = "poison")]
+            if new_size < layout.size() && region.shrinks_in_place(layout, new_layout) {
+                self.poison(
+                    ptr.add(new_size),
+                    layout.size() - new_size,
+                    poison::FREE_POISON,
+                );
+            }
+            if !region.resize_in_place(NonNull::new_unchecked(ptr), layout, new_layout) {
+                return Err(region.capabilities);
+            }
+            #[cfg(feature = "poison")]
+            if new_size > layout.size() {
+                self.poison(
+                    ptr.add(layout.size()),
+                    new_size - layout.size(),
+                    poison::ALLOC_POISON,
+                );
+            }
+
+            let free = region.heap.free();
+            region.min_free = region.min_free.min(free);
+            let largest = self.largest_allocation.borrow(cs);
+            largest.set(largest.get().max(new_size));
+            #[cfg(feature = "stats")]
+            self.record_usage(cs, &regions[..]);
+
+            #[cfg(feature = "trace")]
+            {
+                self.trace_dealloc(cs, &mut regions[..], layout, ptr);
+                // SAFETY: `ptr` is a live allocation.
+                let served = Some((index, NonNull::new_unchecked(ptr)));
+                self.trace_alloc(cs, &mut regions[..], new_layout, served);
+            }
+            #[cfg(feature = "registry")]
+            self.registry
+                .borrow(cs)
+                .borrow_mut()
+                .resize(ptr as usize, new_layout);
+            Ok(())
+        })?;
+
+        #[cfg(feature = "alloc-hooks")]
+        {
+            self.alloc_hooks.freeing(ptr, layout.size());
+            self.alloc_hooks.allocated(ptr, new_size);
+        }
+        self.indicate_usage();
+        Ok(())
+    }
+
+    /// Moves a block to a new block of `new_size` bytes from a region with
+    /// `capabilities`, returning null and leaving the block alone on failure.
+    ///
+    /// # Safety
+    ///
+    /// See [`realloc_from`](Self::realloc_from).
+    unsafe fn move_block(
+        &self,
+        capabilities: MemoryCapability,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new = self.alloc_from(capabilities, new_layout);
+        if !new.is_null() {
+            ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new
+    }
+}
+
+unsafe impl GlobalAlloc for EspHeap {
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "panic-uninit-alloc")]
+        if !self.is_initialized() {
+            uninitialized(layout);
+        }
+        self.alloc_from(MemoryCapability::empty(), layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "panic-uninit-alloc")]
+        if !self.is_initialized() {
+            uninitialized(layout);
+        }
+        match self.allocate(MemoryCapability::empty(), layout) {
+            Some(allocation) => {
+                let ptr = allocation.ptr.as_ptr();
+                ptr.write_bytes(0, allocation.dirty.min(layout.size()));
+                ptr
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match self.resize_in_place(ptr, layout, new_size) {
+            Ok(()) => ptr,
+            Err(capabilities) => self.move_block(capabilities, ptr, layout, new_size),
+        }
+    }
+
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "validate-layout")]
+        validate_layout("Deallocation", layout);
+
+        #[cfg(feature = "isr-guard")]
+        let in_isr = self.isr_guard.check();
+        #[cfg(feature = "isr-guard")]
+        if in_isr == Some(IsrPolicy::Panic) {
+            fail!(
+                "deallocation from an interrupt handler",
+                "Deallocation of {} bytes from an interrupt handler",
+                layout.size()
+            );
+        }
+
+        // Quarantined blocks are only released, and checked, much later.
+        #[cfg(all(feature = "heap-guard", feature = "quarantine"))]
+        guard::check(NonNull::new_unchecked(ptr), layout);
+
+        #[cfg(feature = "alloc-hooks")]
+        self.alloc_hooks.freeing(ptr, layout.size());
+        #[cfg(feature = "poison")]
+        #[cfg_attr(not(feature = "quarantine"), allow(unused_variables))]
+        let poisoned = self.poison(ptr, layout.size(), poison::FREE_POISON);
+
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            #[cfg(esp_alloc_dual_core)]
+            let _entered = self.core_guard.enter();
+
+            #[cfg(feature = "isr-guard")]
+            if in_isr.is_some() {
+                let count = self.isr_allocations.borrow(cs);
+                count.set(count.get() + 1);
+            }
+
+            #[cfg(feature = "stats")]
+            self.record_deallocation(cs);
+            #[cfg(feature = "trace")]
+            self.trace_dealloc(cs, &mut regions[..], layout, ptr);
+            #[cfg(feature = "registry")]
+            self.registry.borrow(cs).borrow_mut().remove(ptr as usize);
+
+            #[cfg(feature = "quarantine")]
+            {
+                #[cfg(not(feature = "poison"))]
+                let poisoned = false;
+                let mut quarantine = self.quarantine.borrow(cs).borrow_mut();
+                let evicted = quarantine.push(ptr, layout, poisoned);
+                let blocks = evicted
+                    .into_iter()
+                    .chain(core::iter::from_fn(|| quarantine.pop_excess()));
+                self.release_quarantined(cs, &mut regions[..], blocks);
+            }
+
+            #[cfg(not(feature = "quarantine"))]
+            release(&mut regions[..], ptr, layout);
+        });
+        self.indicate_usage();
+    }
+}
+
+/// Panics on an allocation before any memory was added to the heap.
+///
+/// Returning null would end up in the generic allocation error handler, with
+/// nothing pointing at the missing initialization.
+#[cfg(feature = "panic-uninit-alloc")]
+#[cold]
+#[inline(never)]
+fn uninitialized(layout: Layout) -> ! {
+    #[cfg(not(feature = "verbose-errors"))]
+    let _ = layout;
+    fail!(
+        "esp-alloc: allocation before the heap was initialized",
+        "esp-alloc: allocation of {} bytes before the heap was initialized, \
+         call `EspHeap::init` or `EspHeap::add_region` first",
+        layout.size()
+    );
+}
+
+/// Panics if `layout` breaks the invariants `Layout` is supposed to uphold,
+/// which unsafe code can get around.
+#[cfg(feature = "validate-layout")]
+fn validate_layout(operation: &str, layout: Layout) {
+    let (size, align) = (layout.size(), layout.align());
+    #[cfg(not(feature = "verbose-errors"))]
+    let _ = operation;
+    if !align.is_power_of_two() {
+        fail!(
+            "invalid layout alignment",
+            "{operation} with an invalid layout: alignment {align} is not a power of two"
+        );
+    }
+    if size > isize::MAX as usize - (align - 1) {
+        fail!(
+            "invalid layout size",
+            "{operation} with an invalid layout: size {size} overflows when rounded up to alignment {align}"
+        );
+    }
+}
+
+/// Allocates from the first region with `capabilities` that can serve
+/// `layout`, returning the index of that region with the block. Regions are
+/// tried cheapest first for the allocating core, if it is known.
+///
+/// Regions that couldn't serve `layout` even if they were empty are skipped
+/// without searching their free list, so oversized requests fall through to
+/// the regions that are big enough, or fail right away. The headroom of a
+/// region is only used if `context` allows it and no region can serve
+/// `layout` without it.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+fn allocate_in(
+    regions: &mut [Region],
+    context: Context,
+    capabilities: MemoryCapability,
+    layout: Layout,
+) -> Option<(usize, Allocation)> {
+    let allocation = candidates(regions, context).find_map(|index| {
+        let allocation = allocate_from(&mut regions[index], context, capabilities, layout)?;
+        Some((index, allocation))
+    });
+    if allocation.is_some() || !context.headroom {
+        return allocation;
+    }
+    allocate_in_headroom(regions, context, capabilities, layout)
+}
+
+/// Returns the indices of the regions an allocation tries, in order.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+fn candidates(regions: &[Region], context: Context) -> impl Iterator<Item = usize> + Clone {
+    let order = affinity::region_order(regions, context.core, context.large);
+    order
+        .into_iter()
+        .take(regions.len())
+        .filter(move |&index| context.region.map_or(true, |only| index == only))
+}
+
+/// Returns whether `region` may serve an allocation with `capabilities`.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+fn serves(region: &Region, context: Context, capabilities: MemoryCapability) -> bool {
+    // Asked for by id, a retained region serves anything.
+    match context.region {
+        Some(_) => region.capabilities.contains(capabilities),
+        None => region.serves(capabilities),
+    }
+}
+
+/// Allocates from `region` outside of its headroom, if it serves the
+/// allocation and could fit it.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+fn allocate_from(
+    region: &mut Region,
+    context: Context,
+    capabilities: MemoryCapability,
+    layout: Layout,
+) -> Option<Allocation> {
+    if !could_serve(region, context, capabilities, layout) {
+        return None;
+    }
+    region.allocate(layout)
+}
+
+/// Returns whether `region` serves an allocation with `capabilities` and
+/// could fit `layout`, which is worth a search.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+fn could_serve(
+    region: &Region,
+    context: Context,
+    capabilities: MemoryCapability,
+    layout: Layout,
+) -> bool {
+    serves(region, context, capabilities) && region.could_fit(layout)
+}
+
+/// Allocates from the headroom of the first region with `capabilities` that
+/// can serve `layout`.
+fn allocate_in_headroom(
+    regions: &mut [Region],
+    context: Context,
+    capabilities: MemoryCapability,
+    layout: Layout,
+) -> Option<(usize, Allocation)> {
+    candidates(regions, context).find_map(|index| {
+        let region = &mut regions[index];
+        if !serves(region, context, capabilities) {
+            return None;
+        }
+        Some((index, region.allocate_in_headroom(layout)?))
+    })
+}
+
+/// Returns whether any region with `capabilities` could ever serve `layout`.
+#[cfg(feature = "quarantine")]
+fn could_fit(regions: &[Region], capabilities: MemoryCapability, layout: Layout) -> bool {
+    regions
+        .iter()
+        .any(|region| region.serves(capabilities) && region.could_fit(layout))
+}
+
+/// Panics if `[heap_bottom, heap_bottom + size)` overlaps the memory of any
+/// of `regions`.
+fn check_overlap(regions: &[Region], heap_bottom: *mut u8, size: usize) {
+    let (bottom, top) = (
+        heap_bottom as usize,
+        (heap_bottom as usize).saturating_add(size),
+    );
+    for (index, region) in regions.iter().enumerate() {
+        if region.is_initialized()
+            && bottom < region.top() as usize
+            && (region.heap.bottom() as usize) < top
+        {
+            #[cfg(not(feature = "verbose-errors"))]
+            let _ = index;
+            fail!(
+                "heap region overlaps another",
+                "Heap region {:#x}..{:#x} overlaps {}",
+                bottom,
+                top,
+                region.id(index)
+            );
+        }
+    }
+}
+
+/// Returns `layout` padded to whole cache lines of `line` bytes, or as it is
+/// if `line` is `0`.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+fn isolate(layout: Layout, line: usize) -> Option<Layout> {
+    if line == 0 {
+        return Some(layout);
+    }
+
+    let size = layout.size().max(1).checked_add(line - 1)? & !(line - 1);
+    Layout::from_size_align(size, layout.align().max(line)).ok()
+}
+
+/// Returns the number of bytes a block for `layout` takes before cache line
+/// padding, which is more than its size with the `heap-guard` feature.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+fn guarded_size(layout: Layout) -> usize {
+    #[cfg(feature = "heap-guard")]
+    return guard::outer_size(layout);
+    #[cfg(not(feature = "heap-guard"))]
+    return layout.size();
+}
+
+/// Returns the allocation for `layout` in a block of `size` bytes at
+/// `block`, of which the first `dirty` bytes may not be zero.
+///
+/// # Safety
+///
+/// `block` must be valid for writes of `size` bytes, which must be enough
+/// for `layout`.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+unsafe fn allocation(block: NonNull<u8>, layout: Layout, size: usize, dirty: usize) -> Allocation {
+    // The block proper is exactly as big as requested, behind its header.
+    #[cfg(feature = "heap-guard")]
+    let (block, size, dirty) = {
+        let _ = size;
+        let front = guard::front(layout);
+        (
+            guard::arm(block, layout),
+            layout.size(),
+            dirty.saturating_sub(front).min(layout.size()),
+        )
+    };
+    #[cfg(not(feature = "heap-guard"))]
+    let _ = layout;
+    #[cfg(not(feature = "nightly"))]
+    let _ = size;
+
+    Allocation {
+        ptr: block,
+        #[cfg(feature = "nightly")]
+        size,
+        dirty,
+    }
+}
+
+/// Hands a block back to the region it was allocated from.
+///
+/// A pointer outside of every region is never handed to a region's free
+/// list: builds with debug assertions panic on it, others leave it alone.
+///
+/// # Safety
+///
+/// `ptr` must be a live allocation made with `layout`.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+unsafe fn release(regions: &mut [Region], ptr: *mut u8, layout: Layout) {
+    match region_of(regions, ptr) {
+        Some(index) => regions[index].deallocate(NonNull::new_unchecked(ptr), layout),
+        #[cfg(debug_assertions)]
+        None => fail!(
+            "deallocation outside of the heap",
+            "Deallocation of {} bytes at {:p}, which is in no heap region",
+            layout.size(),
+            ptr
+        ),
+        #[cfg(not(debug_assertions))]
+        None => {}
+    }
+}
+
+/// Returns the index of the region `ptr` belongs to.
+#[cfg_attr(feature = "inline-hot-path", inline(always))]
+fn region_of(regions: &[Region], ptr: *mut u8) -> Option<usize> {
+    regions.iter().position(|region| region.contains(ptr))
+}
+
+/// # Safety
+///
+/// `ptr` must not be null.
+#[cfg(feature = "nightly")]
+unsafe fn slice(ptr: *mut u8, len: usize) -> NonNull<[u8]> {
+    NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(ptr, len))
+}
+
+/// Allocations through the `Allocator` trait go to any region, like those
+/// through `GlobalAlloc`, and report the full size of their block. Growing
+/// and shrinking move the block.
+///
+/// A second heap can hold memory the global allocator shouldn't hand out,
+/// like PSRAM for large buffers:
+///
+/// ```
+/// #![feature(allocator_api)]
+/// # extern crate alloc;
+/// use alloc::vec::Vec;
+///
+/// use esp_alloc::{EspHeap, MemoryCapability};
+///
+/// static PSRAM: EspHeap = EspHeap::empty();
+/// # static mut MEMORY: [u64; 512] = [0; 512];
+///
+/// # let (psram_start, psram_size) = (unsafe { MEMORY.as_mut_ptr().cast() }, 4096);
+/// unsafe { PSRAM.add_region(psram_start, psram_size, MemoryCapability::EXTERNAL) };
+///
+/// let mut frame: Vec<u8, &EspHeap> = Vec::with_capacity_in(1024, &PSRAM);
+/// frame.resize(2048, 0);
+/// assert!(PSRAM.used() >= frame.capacity());
+/// ```
+#[cfg(feature = "nightly")]
+unsafe impl Allocator for EspHeap {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            // SAFETY: the alignment is never zero.
+            return Ok(unsafe { slice(layout.align() as *mut u8, 0) });
+        }
+
+        let allocation = self
+            .allocate(MemoryCapability::empty(), layout)
+            .ok_or(AllocError)?;
+        Ok(unsafe { slice(allocation.ptr.as_ptr(), allocation.size) })
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return Allocator::allocate(self, layout);
+        }
+
+        let allocation = self
+            .allocate(MemoryCapability::empty(), layout)
+            .ok_or(AllocError)?;
+        // SAFETY: the first `dirty` bytes are part of the new allocation; the
+        // rest of it is known to be zero already.
+        unsafe { allocation.ptr.as_ptr().write_bytes(0, allocation.dirty) };
+        Ok(unsafe { slice(allocation.ptr.as_ptr(), allocation.size) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.dealloc(ptr.as_ptr(), layout);
+        }
+    }
 }
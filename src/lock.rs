@@ -0,0 +1,285 @@
+//! How heap operations are kept apart
+//!
+//! By default every operation runs in a critical section, which masks
+//! interrupts for as long as it takes, including the search of the free
+//! list of a region. An allocation searches each region it tries in a
+//! critical section of its own, so a failed search in one region lets
+//! interrupts in before the next one is tried, and the time interrupts stay
+//! masked is bounded by the search of one region.
+//! [`set_lock`](struct.EspHeap.html#method.set_lock) replaces the critical
+//! section with a lock of the application's choosing, like a
+//! scheduler-aware mutex or a priority-ceiling lock that leaves higher
+//! priority interrupts unmasked.
+//!
+//! On the dual-core ESP32 and ESP32-S3, the `critical-section`
+//! implementation in use may only mask interrupts on the current core. With
+//! the `multicore` feature, every critical section on the heap also holds a
+//! spinlock, which keeps the other core out. The lock is taken after
+//! interrupts are masked and released before they are unmasked, so its
+//! holder never waits for an interrupt handler, and the other core spins for
+//! at most one critical section.
+//!
+//! Taking the spinlock is an `Acquire` and releasing it a `Release`
+//! operation: everything one core writes to the heap while holding the lock
+//! is visible to the other core once it has taken the lock in turn. Masking
+//! interrupts only orders accesses on the current core, so this is what
+//! makes the free lists safe to share.
+
+#[cfg(feature = "multicore")]
+use core::sync::atomic::AtomicBool;
+use core::{
+    mem,
+    sync::atomic::{compiler_fence, AtomicUsize, Ordering},
+};
+
+use critical_section::CriticalSection;
+
+use crate::EspHeap;
+
+/// A lock that runs the closure passed to it while keeping every other user
+/// of the heap out, see
+/// [`set_lock`](struct.EspHeap.html#method.set_lock)
+pub type HeapLock = fn(&mut dyn FnMut());
+
+/// Like a [`HeapLock`], but runs the closure only if the lock can be taken
+/// without waiting, returning whether it did
+pub type TryHeapLock = fn(&mut dyn FnMut()) -> bool;
+
+/// The locks set with `set_lock`, readable without entering a critical
+/// section
+pub(crate) struct CustomLock {
+    /// The lock as a `HeapLock`, `0` if none is set
+    lock: AtomicUsize,
+    /// The lock as a `TryHeapLock`, set along with `lock`
+    try_lock: AtomicUsize,
+}
+
+impl CustomLock {
+    pub(crate) const fn new() -> Self {
+        Self {
+            lock: AtomicUsize::new(0),
+            try_lock: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes over the lock of `other`.
+    pub(crate) fn reset(&self, other: Self) {
+        self.lock.store(other.lock.into_inner(), Ordering::Relaxed);
+        self.try_lock
+            .store(other.try_lock.into_inner(), Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    fn get(&self) -> Option<HeapLock> {
+        let lock = self.lock.load(Ordering::Relaxed);
+        if lock == 0 {
+            return None;
+        }
+
+        // SAFETY: non-zero values are only ever stored from a `HeapLock`.
+        Some(unsafe { mem::transmute::<usize, HeapLock>(lock) })
+    }
+
+    #[inline(always)]
+    fn get_try(&self) -> Option<TryHeapLock> {
+        let lock = self.try_lock.load(Ordering::Relaxed);
+        if lock == 0 {
+            return None;
+        }
+
+        // SAFETY: non-zero values are only ever stored from a `TryHeapLock`.
+        Some(unsafe { mem::transmute::<usize, TryHeapLock>(lock) })
+    }
+}
+
+#[cfg(all(feature = "multicore", not(target_has_atomic = "8")))]
+compile_error!("the `multicore` feature needs compare-and-swap atomics, which this target lacks");
+
+/// A lock word taken with compare-and-swap
+#[cfg(feature = "multicore")]
+pub(crate) struct SpinLock {
+    locked: AtomicBool,
+}
+
+#[cfg(feature = "multicore")]
+impl SpinLock {
+    pub(crate) const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    /// Spins until the lock is taken.
+    #[inline]
+    fn lock(&self) -> Held<'_> {
+        while !self.try_take() {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        Held(self)
+    }
+
+    /// Takes the lock if it is free.
+    #[inline]
+    fn try_lock(&self) -> Option<Held<'_>> {
+        self.try_take().then_some(Held(self))
+    }
+
+    #[inline]
+    fn try_take(&self) -> bool {
+        self.locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+#[cfg(feature = "multicore")]
+struct Held<'a>(&'a SpinLock);
+
+#[cfg(feature = "multicore")]
+impl Drop for Held<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.locked.store(false, Ordering::Release);
+    }
+}
+
+impl EspHeap {
+    /// Runs heap operations under `lock` instead of a critical section
+    ///
+    /// `lock` gets a closure that performs one operation on the heap, like
+    /// the search of the free list of a region, and must call it exactly
+    /// once while holding the lock. With a lock that doesn't mask interrupts,
+    /// allocations from thread context no longer delay interrupt handlers.
+    ///
+    /// `try_lock` is used by
+    /// [`try_alloc`](struct.EspHeap.html#method.try_alloc), which must not
+    /// wait: it calls the closure exactly once if it can take the lock right
+    /// away and returns `true`, or returns `false` without calling it.
+    ///
+    /// An allocation takes the lock once for each region it searches and
+    /// releases it in between, so it is held for as long as the search of
+    /// one region. Only once every region failed does it search them again
+    /// under one lock, for the headroom and the blocks in quarantine. The
+    /// out-of-memory handler runs outside of it.
+    ///
+    /// With the `multicore` feature the spinlock is still taken inside
+    /// `lock`.
+    ///
+    /// # Safety
+    ///
+    /// `lock` and `try_lock` must keep every other use of the heap out while
+    /// the closure runs, from other tasks, from interrupt handlers that use
+    /// the heap and from the other core, make the writes of one holder
+    /// visible to the next, and must not use the heap themselves. They must
+    /// be set before the heap is used, or while nothing uses it.
+    pub unsafe fn set_lock(&self, lock: HeapLock, try_lock: TryHeapLock) {
+        self.custom_lock
+            .lock
+            .store(lock as usize, Ordering::Relaxed);
+        self.custom_lock
+            .try_lock
+            .store(try_lock as usize, Ordering::Relaxed);
+    }
+
+    /// Returns to running heap operations in critical sections
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the heap concurrently.
+    pub unsafe fn clear_lock(&self) {
+        self.custom_lock.lock.store(0, Ordering::Relaxed);
+        self.custom_lock.try_lock.store(0, Ordering::Relaxed);
+    }
+
+    /// Runs `f` in a critical section, or under the lock set with
+    /// `set_lock`, that with the `multicore` feature also keeps the other
+    /// core out.
+    ///
+    /// The lock isn't reentrant: `f` must not call anything that takes it
+    /// again.
+    #[inline(always)]
+    pub(crate) fn locked<R>(&self, f: impl FnOnce(CriticalSection<'_>) -> R) -> R {
+        let Some(lock) = self.custom_lock.get() else {
+            return critical_section::with(|cs| {
+                #[cfg(feature = "multicore")]
+                let _held = self.spin.lock();
+                f(cs)
+            });
+        };
+        run_under(lock, || {
+            // SAFETY: the lock keeps everything else that could use the heap
+            // out, which is all the token stands for here.
+            let cs = unsafe { CriticalSection::new() };
+            #[cfg(feature = "multicore")]
+            let _held = self.spin.lock();
+            f(cs)
+        })
+    }
+
+    /// Like [`locked`](Self::locked), but returns `None` instead of waiting
+    /// for the lock set with `set_lock` or for the other core.
+    #[inline(always)]
+    pub(crate) fn try_locked<R>(&self, f: impl FnOnce(CriticalSection<'_>) -> R) -> Option<R> {
+        let Some(try_lock) = self.custom_lock.get_try() else {
+            return critical_section::with(|cs| {
+                #[cfg(feature = "multicore")]
+                let _held = self.spin.try_lock()?;
+                Some(f(cs))
+            });
+        };
+        try_run_under(try_lock, || {
+            // SAFETY: as in `locked`.
+            let cs = unsafe { CriticalSection::new() };
+            #[cfg(feature = "multicore")]
+            let _held = self.spin.try_lock()?;
+            Some(f(cs))
+        })
+        .flatten()
+    }
+}
+
+/// Runs `f` under `lock`.
+///
+/// # Panics
+///
+/// Panics if `lock` doesn't call its closure.
+fn run_under<R>(lock: HeapLock, f: impl FnOnce() -> R) -> R {
+    let mut f = Some(f);
+    let mut result = None;
+    lock(&mut || {
+        if let Some(f) = f.take() {
+            compiler_fence(Ordering::SeqCst);
+            result = Some(f());
+            compiler_fence(Ordering::SeqCst);
+        }
+    });
+    match result {
+        Some(result) => result,
+        None => panic!("heap lock didn't run the operation"),
+    }
+}
+
+/// Runs `f` under `try_lock`, returning `None` if the lock was taken.
+///
+/// # Panics
+///
+/// Panics if `try_lock` reports that it took the lock without calling its
+/// closure.
+fn try_run_under<R>(try_lock: TryHeapLock, f: impl FnOnce() -> R) -> Option<R> {
+    let mut f = Some(f);
+    let mut result = None;
+    let taken = try_lock(&mut || {
+        if let Some(f) = f.take() {
+            compiler_fence(Ordering::SeqCst);
+            result = Some(f());
+            compiler_fence(Ordering::SeqCst);
+        }
+    });
+    match result {
+        Some(result) => Some(result),
+        None if taken => panic!("heap lock didn't run the operation"),
+        None => None,
+    }
+}
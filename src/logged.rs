@@ -0,0 +1,115 @@
+//! An allocator that logs every operation over `defmt`
+
+#[cfg(feature = "nightly")]
+use core::alloc::{AllocError, Allocator};
+use core::alloc::{GlobalAlloc, Layout};
+#[cfg(feature = "nightly")]
+use core::ptr::NonNull;
+
+use crate::EspHeap;
+
+/// An [`EspHeap`] that logs each allocation and deallocation
+///
+/// Every operation is passed on to the heap unchanged and, in builds with
+/// debug assertions, logged at trace level with its size, alignment and
+/// address. This shows the order in which a crate allocates without a
+/// debugger attached. In release builds the wrapper only forwards, so it
+/// can stay in place as the global allocator:
+///
+/// ```ignore
+/// static HEAP: EspHeap = EspHeap::empty();
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Logged<'static> = Logged(&HEAP);
+/// ```
+#[derive(Clone, Copy)]
+pub struct Logged<'a>(pub &'a EspHeap);
+
+unsafe impl GlobalAlloc for Logged<'_> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc(layout);
+        #[cfg(debug_assertions)]
+        defmt::trace!(
+            "alloc size={=usize} align={=usize} -> {=usize:#x}",
+            layout.size(),
+            layout.align(),
+            ptr as usize
+        );
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc_zeroed(layout);
+        #[cfg(debug_assertions)]
+        defmt::trace!(
+            "alloc_zeroed size={=usize} align={=usize} -> {=usize:#x}",
+            layout.size(),
+            layout.align(),
+            ptr as usize
+        );
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(debug_assertions)]
+        defmt::trace!(
+            "dealloc {=usize:#x} size={=usize} align={=usize}",
+            ptr as usize,
+            layout.size(),
+            layout.align()
+        );
+        self.0.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.0.realloc(ptr, layout, new_size);
+        #[cfg(debug_assertions)]
+        defmt::trace!(
+            "realloc {=usize:#x} size={=usize} align={=usize} new_size={=usize} -> {=usize:#x}",
+            ptr as usize,
+            layout.size(),
+            layout.align(),
+            new_size,
+            new_ptr as usize
+        );
+        new_ptr
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl Allocator for Logged<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let result = Allocator::allocate(self.0, layout);
+        #[cfg(debug_assertions)]
+        defmt::trace!(
+            "allocate size={=usize} align={=usize} -> {=usize:#x}",
+            layout.size(),
+            layout.align(),
+            result.map_or(0, |ptr| ptr.as_ptr() as *mut u8 as usize)
+        );
+        result
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let result = Allocator::allocate_zeroed(self.0, layout);
+        #[cfg(debug_assertions)]
+        defmt::trace!(
+            "allocate_zeroed size={=usize} align={=usize} -> {=usize:#x}",
+            layout.size(),
+            layout.align(),
+            result.map_or(0, |ptr| ptr.as_ptr() as *mut u8 as usize)
+        );
+        result
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        #[cfg(debug_assertions)]
+        defmt::trace!(
+            "deallocate {=usize:#x} size={=usize} align={=usize}",
+            ptr.as_ptr() as usize,
+            layout.size(),
+            layout.align()
+        );
+        Allocator::deallocate(self.0, ptr, layout);
+    }
+}
@@ -3,26 +3,149 @@
 /// Create a heap allocator providing a heap of the given size in bytes
 ///
 /// You can only have ONE allocator at most
+///
+/// Evaluates to the [`RegionId`](struct.RegionId.html) of the heap's region.
+/// A size too small to allocate from fails the build.
+///
+/// # Declaring the heap as an item
+///
+/// The `size:` form is used outside of any function instead. It declares the
+/// global allocator as `ALLOCATOR`, a static buffer of the given size for its
+/// memory, and a safe `fn init_heap() -> RegionId` that adds the buffer to
+/// the heap and must be called before anything allocates. An optional
+/// `section:` places the buffer in a linker section, like `.dram2_uninit`
+/// or RTC RAM. Calling `init_heap` a second time panics.
+///
+/// ```ignore
+/// esp_alloc::heap_allocator!(size: 64 * 1024, section: ".dram2_uninit");
+///
+/// fn main() {
+///     init_heap();
+/// }
+/// ```
 #[macro_export]
 macro_rules! heap_allocator {
+    (size: $size:expr $(, section: $section:literal)? $(,)?) => {
+        #[global_allocator]
+        static ALLOCATOR: $crate::EspHeap = $crate::EspHeap::empty();
+
+        /// Adds the memory of the heap declared with `esp_alloc::heap_allocator!`
+        ///
+        /// # Panics
+        ///
+        /// Panics if called more than once.
+        fn init_heap() -> $crate::RegionId {
+            $crate::__heap_buffer!(ALLOCATOR, "init_heap", $size $(, $section)?)
+        }
+    };
     ($size:expr) => {{
+        $crate::check_memory_map!($crate::HeapRegion::buffer(
+            $size,
+            $crate::MemoryCapability::empty()
+        ));
+
         #[global_allocator]
         static ALLOCATOR: $crate::EspHeap = $crate::EspHeap::empty();
         static mut HEAP: core::mem::MaybeUninit<[u8; $size]> = core::mem::MaybeUninit::uninit();
 
+        unsafe { ALLOCATOR.init(HEAP.as_mut_ptr() as *mut u8, $size) }
+    }};
+}
+
+/// Declare a second region for the heap declared with
+/// [`heap_allocator!`](macro.heap_allocator.html)
+///
+/// Used outside of any function next to the `size:` form of
+/// [`heap_allocator!`](macro.heap_allocator.html), whose `ALLOCATOR` it adds
+/// to. It declares a static buffer of the given size, placed in the linker
+/// section given, and a safe `fn init_heap2() -> RegionId` that adds it as a
+/// region of its own. Regions are used in the order they were added, so
+/// call it after `init_heap` to keep the first heap the preferred one.
+/// Calling `init_heap2` a second time panics.
+///
+/// ```ignore
+/// esp_alloc::heap_allocator!(size: 64 * 1024);
+/// esp_alloc::heap2_allocator!(size: 32 * 1024, section: ".dram2_uninit");
+///
+/// fn main() {
+///     init_heap();
+///     init_heap2();
+/// }
+/// ```
+#[macro_export]
+macro_rules! heap2_allocator {
+    (size: $size:expr $(, section: $section:literal)? $(,)?) => {
+        /// Adds the memory of the region declared with
+        /// `esp_alloc::heap2_allocator!`
+        ///
+        /// # Panics
+        ///
+        /// Panics if called more than once.
+        fn init_heap2() -> $crate::RegionId {
+            $crate::__heap_buffer!(ALLOCATOR, "init_heap2", $size $(, $section)?)
+        }
+    };
+}
+
+/// Declares a heap buffer and adds it to `$heap` once.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __heap_buffer {
+    ($heap:ident, $function:literal, $size:expr $(, $section:literal)?) => {{
+        $crate::check_memory_map!($crate::HeapRegion::buffer(
+            $size,
+            $crate::MemoryCapability::empty()
+        ));
+
+        #[repr(C, align(8))]
+        struct Buffer(core::mem::MaybeUninit<[u8; $size]>);
+
+        $(#[link_section = $section])?
+        static mut BUFFER: Buffer = Buffer(core::mem::MaybeUninit::uninit());
+        static ADDED: $crate::macros::Once = $crate::macros::Once::new();
+
+        assert!(ADDED.claim(), concat!($function, " called more than once"));
+        // SAFETY: the buffer is only ever added here, and only once.
         unsafe {
-            ALLOCATOR.init(HEAP.as_mut_ptr() as *mut u8, $size);
+            $heap.add_region(
+                core::ptr::addr_of_mut!(BUFFER) as *mut u8,
+                $size,
+                $crate::MemoryCapability::empty(),
+            )
         }
     }};
 }
 
+/// A flag that can be set once, for the buffers declared by the macros.
+#[doc(hidden)]
+pub struct Once(critical_section::Mutex<core::cell::Cell<bool>>);
+
+impl Once {
+    pub const fn new() -> Self {
+        Self(critical_section::Mutex::new(core::cell::Cell::new(false)))
+    }
+
+    /// Sets the flag, returning `false` if it was already set.
+    pub fn claim(&self) -> bool {
+        critical_section::with(|cs| !self.0.borrow(cs).replace(true))
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Create a heap allocator backed by PSRAM
 ///
 /// You can only have ONE allocator at most. You need a SoC which supports PSRAM and activate the feature to enable it.
 /// You need to pass the PSRAM peripheral and the psram module path.
 ///
+/// Evaluates to the [`RegionId`](struct.RegionId.html) of the PSRAM region.
+///
 /// # Usage
-/// ```no_run
+/// ```ignore
 /// esp_alloc::psram_allocator!(peripherals.PSRAM, hal::psram);
 /// ```
 #[macro_export]
@@ -32,9 +155,163 @@ macro_rules! psram_allocator {
         static ALLOCATOR: $crate::EspHeap = $crate::EspHeap::empty();
 
         use $psram_module as _psram;
+        $crate::check_memory_map!($crate::HeapRegion::buffer(
+            _psram::PSRAM_BYTES,
+            $crate::MemoryCapability::EXTERNAL
+        ));
+
         _psram::init_psram($peripheral);
         unsafe {
-            ALLOCATOR.init(_psram::psram_vaddr_start() as *mut u8, _psram::PSRAM_BYTES);
+            ALLOCATOR.add_region(
+                _psram::psram_vaddr_start() as *mut u8,
+                _psram::PSRAM_BYTES,
+                $crate::MemoryCapability::EXTERNAL,
+            )
         }
     }};
 }
+
+/// Register all free RAM described by linker symbols as heap regions
+///
+/// Each range is given as a pair of linker symbols marking its start and
+/// end, followed by the capabilities of its memory. The first range is the
+/// one next to the stack and gets `stack_reserve` bytes reserved at its top.
+/// Ranges that are empty, too small or overlapping are skipped; see
+/// [`EspHeap::configure_from_layout`](struct.EspHeap.html#method.configure_from_layout).
+///
+/// Evaluates to the [`LayoutSummary`](struct.LayoutSummary.html) of what
+/// was registered.
+///
+/// # Usage
+/// ```ignore
+/// let summary = esp_alloc::configure_from_layout!(
+///     ALLOCATOR,
+///     stack_reserve: 8 * 1024,
+///     _heap_start.._stack_end => esp_alloc::MemoryCapability::INTERNAL,
+///     _dram2_start.._dram2_end => esp_alloc::MemoryCapability::INTERNAL,
+/// );
+/// ```
+#[macro_export]
+macro_rules! configure_from_layout {
+    ($heap:expr, stack_reserve: $reserve:expr, $($start:ident .. $end:ident => $caps:expr),+ $(,)?) => {{
+        unsafe {
+            let ranges = [$({
+                extern "C" {
+                    static $start: u8;
+                    static $end: u8;
+                }
+                $crate::MemoryRange::new(
+                    core::ptr::addr_of!($start) as *mut u8,
+                    core::ptr::addr_of!($end) as *mut u8,
+                    $caps,
+                )
+            }),+];
+            $heap.configure_from_layout(&ranges, $reserve)
+        }
+    }};
+}
+
+/// Add the heap ranges the linker script leaves free
+///
+/// Declares the linker symbols `_heap_start` and `_heap_end` and adds the
+/// memory between them as a region of `$heap`, with
+/// [`EspHeap::init_from_symbols`](struct.EspHeap.html#method.init_from_symbols).
+/// Stable Rust can't check whether a symbol exists, so a second range, like
+/// the second DRAM bank, is added by naming its symbols; linking fails if
+/// they are missing. Each range becomes a region of its own and must only
+/// be added once.
+///
+/// Evaluates to the [`RegionId`](struct.RegionId.html) of the first region.
+///
+/// # Usage
+/// ```no_run
+/// static ALLOCATOR: esp_alloc::EspHeap = esp_alloc::EspHeap::empty();
+/// # #[no_mangle]
+/// # static mut _heap_start: [u8; 1024] = [0; 1024];
+/// # #[no_mangle]
+/// # static mut _heap_end: u8 = 0;
+/// # #[no_mangle]
+/// # static mut _heap2_start: [u8; 1024] = [0; 1024];
+/// # #[no_mangle]
+/// # static mut _heap2_end: u8 = 0;
+///
+/// fn init() {
+///     esp_alloc::init_heap_from_linker!(ALLOCATOR);
+/// }
+///
+/// fn init_with_second_bank() {
+///     esp_alloc::init_heap_from_linker!(ALLOCATOR, _heap2_start.._heap2_end);
+/// }
+/// # init(); init_with_second_bank();
+/// ```
+#[macro_export]
+macro_rules! init_heap_from_linker {
+    (@range $heap:expr, $start:ident .. $end:ident) => {{
+        extern "C" {
+            static $start: u8;
+            static $end: u8;
+        }
+        // SAFETY: the linker script leaves the range to the heap.
+        unsafe {
+            $heap.init_from_symbols(
+                core::ptr::addr_of!($start) as *mut u8,
+                core::ptr::addr_of!($end) as *mut u8,
+            )
+        }
+    }};
+    ($heap:expr $(, $start:ident .. $end:ident)? $(,)?) => {{
+        let id = $crate::init_heap_from_linker!(@range $heap, _heap_start.._heap_end);
+        $($crate::init_heap_from_linker!(@range $heap, $start..$end);)?
+        id
+    }};
+}
+
+/// Checks a memory map at compile time
+///
+/// Takes the map's [`HeapRegion`](struct.HeapRegion.html)s and fails the
+/// build with a readable message if [`validate`](fn.validate.html) rejects
+/// them.
+///
+/// # Usage
+/// ```ignore
+/// esp_alloc::check_memory_map!(
+///     esp_alloc::HeapRegion::buffer(64 * 1024, esp_alloc::MemoryCapability::INTERNAL),
+///     esp_alloc::HeapRegion::fixed(0x3c00_0000, 2 * 1024 * 1024, esp_alloc::MemoryCapability::EXTERNAL),
+/// );
+/// ```
+#[macro_export]
+macro_rules! check_memory_map {
+    ($($region:expr),+ $(,)?) => {
+        const _: () = match $crate::validate(&[$($region),+]) {
+            Ok(()) => (),
+            Err(message) => panic!("{}", message),
+        };
+    };
+}
+
+/// Make [`EspHeap::handle_alloc_error`](struct.EspHeap.html#method.handle_alloc_error)
+/// of `$heap` the allocation error handler (the `oom-handler` feature)
+///
+/// Used outside of any function, in a binary on nightly that enables
+/// `#![feature(alloc_error_handler)]`: allocation errors then hand the state
+/// of the heap to the sink set with
+/// [`set_oom_sink`](struct.EspHeap.html#method.set_oom_sink) before they
+/// panic. Applications that define their own handler just don't use the
+/// macro, so enabling the feature never gets in their way.
+///
+/// ```ignore
+/// #![feature(alloc_error_handler)]
+///
+/// esp_alloc::heap_allocator!(size: 64 * 1024);
+/// esp_alloc::alloc_error_handler!(ALLOCATOR);
+/// ```
+#[cfg(feature = "oom-handler")]
+#[macro_export]
+macro_rules! alloc_error_handler {
+    ($heap:expr) => {
+        #[alloc_error_handler]
+        fn __esp_alloc_error_handler(layout: core::alloc::Layout) -> ! {
+            $heap.handle_alloc_error(layout)
+        }
+    };
+}
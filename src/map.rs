@@ -0,0 +1,122 @@
+//! ASCII map of a region's memory
+
+use core::fmt;
+
+use crate::{holes, EspHeap, RegionId};
+
+/// Turns the free blocks of a region, visited in address order, into a line
+/// of `#` and `.` characters, without buffering anything.
+struct MapWriter<'w, W: fmt::Write> {
+    w: &'w mut W,
+    size: usize,
+    width: usize,
+    /// Column being filled
+    column: usize,
+    /// Free bytes seen in the current column
+    free: usize,
+    result: fmt::Result,
+}
+
+impl<W: fmt::Write> MapWriter<'_, W> {
+    /// Returns the offsets of the bytes the current column stands for, which
+    /// are at least one.
+    fn bounds(&self) -> (usize, usize) {
+        let offset =
+            |column: usize| (column as u64 * self.size as u64 / self.width as u64) as usize;
+        let start = offset(self.column);
+        (start, offset(self.column + 1).max(start + 1))
+    }
+
+    /// Finishes the current column: free if at least half of it is.
+    fn flush(&mut self) {
+        let (start, end) = self.bounds();
+        let c = if self.free * 2 >= end - start {
+            '.'
+        } else {
+            '#'
+        };
+        if self.result.is_ok() {
+            self.result = self.w.write_char(c);
+        }
+        self.column += 1;
+        self.free = 0;
+    }
+
+    /// Adds the free block at the given offsets, which must come after every
+    /// block added before.
+    fn add_free(&mut self, start: usize, end: usize) {
+        while self.column < self.width {
+            let (column_start, column_end) = self.bounds();
+            if start >= column_end {
+                self.flush();
+                continue;
+            }
+
+            self.free += end.min(column_end).saturating_sub(start.max(column_start));
+            if end < column_end {
+                break;
+            }
+            self.flush();
+        }
+    }
+
+    fn finish(mut self) -> fmt::Result {
+        while self.column < self.width {
+            self.flush();
+        }
+        self.result
+    }
+}
+
+impl EspHeap {
+    /// Draws the given region as a line of `width` characters, `#` for used
+    /// and `.` for free memory
+    ///
+    /// Each character stands for an equal share of the region and shows
+    /// whether at least half of it is free, which gives a quick picture of
+    /// fragmentation, for instance on a serial console. Blocks in the free
+    /// cache or the quarantine count as used. No line break is written, and
+    /// nothing at all for an uninitialized or non-existent region.
+    ///
+    /// The map is drawn from inside the region's critical section while its
+    /// free list is walked, which is `O(n²)` in the number of free blocks, so
+    /// `w` must not use the heap and this shouldn't be called from latency
+    /// sensitive code.
+    pub fn render_map(
+        &self,
+        region: RegionId,
+        width: usize,
+        w: &mut impl fmt::Write,
+    ) -> fmt::Result {
+        if !self.is_initialized() || width == 0 {
+            return Ok(());
+        }
+
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            let region = match regions.get_mut(region.index) {
+                Some(region) if region.is_initialized() => region,
+                _ => return Ok(()),
+            };
+
+            // Walking writes into the free blocks.
+            region.untouched = region.heap.top() as usize;
+            region.replay = None;
+
+            let bottom = region.heap.bottom() as usize;
+            let mut map = MapWriter {
+                w,
+                size: region.size(),
+                width,
+                column: 0,
+                free: 0,
+                result: Ok(()),
+            };
+            holes::walk(&mut region.heap, |addr, size| {
+                let start = addr as usize - bottom;
+                map.add_free(start, start + size);
+            });
+            map.finish()
+        })
+    }
+}
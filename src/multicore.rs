@@ -0,0 +1,77 @@
+//! Detection of critical sections that don't keep the other core out
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::EspHeap;
+
+/// Notices both cores working on the regions at once, which only happens if
+/// the `critical-section` implementation in use is a single-core one.
+///
+/// The check is a plain load and store, as not all targets have atomic
+/// read-modify-write operations: cores entering at exactly the same time
+/// slip through, but a broken setup is caught soon under load.
+pub(crate) struct CoreGuard {
+    inside: AtomicBool,
+    disabled: AtomicBool,
+}
+
+impl CoreGuard {
+    pub(crate) const fn new() -> Self {
+        Self {
+            inside: AtomicBool::new(false),
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes over the state of `other`.
+    pub(crate) fn reset(&self, other: Self) {
+        self.inside
+            .store(other.inside.into_inner(), Ordering::Relaxed);
+        self.disabled
+            .store(other.disabled.into_inner(), Ordering::Relaxed);
+    }
+
+    /// Marks the regions as in use until the returned value is dropped.
+    ///
+    /// This must be called in a critical section, with the regions borrowed.
+    #[inline]
+    pub(crate) fn enter(&self) -> Entered<'_> {
+        if self.inside.load(Ordering::Acquire) && !self.disabled.load(Ordering::Relaxed) {
+            fail!(
+                "heap entered by two cores at once",
+                "Heap entered by two cores at once: the critical-section implementation in use \
+                 doesn't exclude the other core, enable the `multicore` feature"
+            );
+        }
+        self.inside.store(true, Ordering::Release);
+        Entered(self)
+    }
+}
+
+pub(crate) struct Entered<'a>(&'a CoreGuard);
+
+impl Drop for Entered<'_> {
+    fn drop(&mut self) {
+        self.0.inside.store(false, Ordering::Release);
+    }
+}
+
+impl EspHeap {
+    /// Stops checking that the critical section keeps the other core out of
+    /// the heap
+    ///
+    /// On dual-core chips, allocations and deallocations check that the
+    /// other core isn't working on the heap at the same time, and panic if
+    /// it is: the `critical-section` implementation in use then only masks
+    /// interrupts, and the free lists would be corrupted sooner or later.
+    /// The `multicore` feature fixes that with a spinlock. The check costs a
+    /// load and two stores per operation.
+    ///
+    /// # Safety
+    ///
+    /// The heap must only ever be used from one core, or with a
+    /// `critical-section` implementation that excludes the other core.
+    pub unsafe fn assume_single_core(&self) {
+        self.core_guard.disabled.store(true, Ordering::Relaxed);
+    }
+}
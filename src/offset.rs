@@ -0,0 +1,189 @@
+//! Allocation in memory that isn't mapped into the address space
+
+use core::{alloc::Layout, cell::RefCell};
+
+use critical_section::Mutex;
+
+/// The number of free ranges an [`OffsetHeap`] tracks by default
+pub const DEFAULT_FREE_RANGES: usize = 32;
+
+/// A heap that hands out offsets instead of pointers
+///
+/// Meant for storage that can't be accessed through pointers, like RAM behind
+/// an SPI expander: the heap only manages an abstract address space of a given
+/// size, and the application performs the actual transfers to and from the
+/// offsets it returns. Alignment is relative to offset `0`.
+///
+/// Since the free list can't live in the managed memory itself, it is kept in
+/// the heap as up to `N` free ranges. An allocation that would need another
+/// free range when all `N` are in use fails, and a freed block that can't be
+/// recorded is lost until the heap is reinitialized (see
+/// [`lost`](struct.OffsetHeap.html#method.lost)).
+pub struct OffsetHeap<const N: usize = DEFAULT_FREE_RANGES> {
+    state: Mutex<RefCell<FreeRanges<N>>>,
+}
+
+/// Free `(offset, size)` ranges, sorted by offset and never adjacent
+struct FreeRanges<const N: usize> {
+    ranges: [(usize, usize); N],
+    len: usize,
+    size: usize,
+    used: usize,
+    lost: usize,
+}
+
+impl<const N: usize> FreeRanges<N> {
+    fn insert(&mut self, index: usize, range: (usize, usize)) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.ranges.copy_within(index..self.len, index + 1);
+        self.ranges[index] = range;
+        self.len += 1;
+        true
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.ranges.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+    }
+
+    fn allocate(&mut self, size: usize, align: usize) -> Option<usize> {
+        for index in 0..self.len {
+            let (start, len) = self.ranges[index];
+            let end = start + len;
+            let offset = match start.checked_add(align - 1) {
+                Some(offset) => offset & !(align - 1),
+                None => continue,
+            };
+            if offset > end || end - offset < size {
+                continue;
+            }
+
+            let rest = (offset + size, end - offset - size);
+            match (offset > start, rest.1 > 0) {
+                (false, false) => self.remove(index),
+                (false, true) => self.ranges[index] = rest,
+                (true, false) => self.ranges[index].1 = offset - start,
+                (true, true) => {
+                    if !self.insert(index + 1, rest) {
+                        continue;
+                    }
+                    self.ranges[index].1 = offset - start;
+                }
+            }
+
+            self.used += size;
+            return Some(offset);
+        }
+
+        None
+    }
+
+    fn deallocate(&mut self, offset: usize, size: usize) {
+        let index = self.ranges[..self.len].partition_point(|&(start, _)| start < offset);
+        let merges_prev = index > 0 && {
+            let (start, len) = self.ranges[index - 1];
+            start + len == offset
+        };
+        let merges_next = index < self.len && self.ranges[index].0 == offset + size;
+
+        match (merges_prev, merges_next) {
+            (true, true) => {
+                self.ranges[index - 1].1 += size + self.ranges[index].1;
+                self.remove(index);
+            }
+            (true, false) => self.ranges[index - 1].1 += size,
+            (false, true) => self.ranges[index] = (offset, size + self.ranges[index].1),
+            (false, false) => {
+                if !self.insert(index, (offset, size)) {
+                    self.lost += size;
+                }
+            }
+        }
+
+        self.used -= size;
+    }
+}
+
+impl<const N: usize> OffsetHeap<N> {
+    /// Create a new UNINITIALIZED offset heap
+    ///
+    /// Nothing can be allocated until
+    /// [`init`](struct.OffsetHeap.html#method.init) is called.
+    pub const fn empty() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(FreeRanges {
+                ranges: [(0, 0); N],
+                len: 0,
+                size: 0,
+                used: 0,
+                lost: 0,
+            })),
+        }
+    }
+
+    /// Makes the offsets `[0, size)` available for allocation
+    ///
+    /// This forgets about everything allocated before, so all offsets handed
+    /// out previously must be dead.
+    pub fn init(&self, size: usize) {
+        critical_section::with(|cs| {
+            let mut state = self.state.borrow(cs).borrow_mut();
+            state.len = 0;
+            state.size = size;
+            state.used = 0;
+            state.lost = 0;
+            if size > 0 {
+                state.insert(0, (0, size));
+            }
+        });
+    }
+
+    /// Allocates a block of `layout.size()` bytes, returning its offset
+    ///
+    /// Zero-sized layouts allocate a single byte so that every offset is
+    /// unique.
+    pub fn allocate(&self, layout: Layout) -> Option<usize> {
+        critical_section::with(|cs| {
+            self.state
+                .borrow(cs)
+                .borrow_mut()
+                .allocate(layout.size().max(1), layout.align())
+        })
+    }
+
+    /// Frees a block returned by
+    /// [`allocate`](struct.OffsetHeap.html#method.allocate)
+    ///
+    /// `layout` must be the one the block was allocated with. Freeing an
+    /// offset that isn't allocated corrupts the heap's bookkeeping, but
+    /// never memory, as the heap doesn't access any.
+    pub fn deallocate(&self, offset: usize, layout: Layout) {
+        critical_section::with(|cs| {
+            self.state
+                .borrow(cs)
+                .borrow_mut()
+                .deallocate(offset, layout.size().max(1));
+        });
+    }
+
+    /// Returns the number of bytes in use.
+    pub fn used(&self) -> usize {
+        critical_section::with(|cs| self.state.borrow(cs).borrow().used)
+    }
+
+    /// Returns the number of bytes available.
+    pub fn free(&self) -> usize {
+        critical_section::with(|cs| {
+            let state = self.state.borrow(cs).borrow();
+            state.size - state.used - state.lost
+        })
+    }
+
+    /// Returns the number of freed bytes that couldn't be recorded because
+    /// all free ranges were in use
+    pub fn lost(&self) -> usize {
+        critical_section::with(|cs| self.state.borrow(cs).borrow().lost)
+    }
+}
@@ -0,0 +1,129 @@
+//! Handling of allocations that fail for lack of memory
+
+use core::alloc::Layout;
+
+use critical_section::CriticalSection;
+
+use crate::{EspHeap, HeapStats};
+
+/// What the allocator should do after the out-of-memory handler ran, see
+/// [`set_oom_handler`](struct.EspHeap.html#method.set_oom_handler)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OomAction {
+    /// The handler made room: try the allocation once more
+    Retry,
+    /// Give up and return null
+    Fail,
+}
+
+/// Called with the layout of an allocation that failed
+pub(crate) type OomHandler = fn(Layout, &HeapStats) -> OomAction;
+
+#[derive(Clone, Copy)]
+pub(crate) struct OomState {
+    handler: Option<OomHandler>,
+    /// Whether the handler is running, during which it isn't called again
+    running: bool,
+}
+
+impl OomState {
+    pub(crate) const fn new() -> Self {
+        Self {
+            handler: None,
+            running: false,
+        }
+    }
+}
+
+impl EspHeap {
+    /// Calls `handler` whenever an allocation fails for lack of memory
+    ///
+    /// The handler gets the layout of the failed allocation and the usage of
+    /// the heap right after it failed, see
+    /// [`stats`](struct.EspHeap.html#method.stats). It can just log them, or
+    /// free memory, like caches the application can rebuild, and return
+    /// [`OomAction::Retry`] to have the allocation tried once more. With
+    /// [`OomAction::Fail`] the allocation returns null, so `try_reserve`
+    /// and the like see the failure as usual.
+    ///
+    /// The handler runs outside of the critical section, so it may use the
+    /// heap itself. Allocations that fail while it runs, its own or those of
+    /// the other core, return null without calling it again, so a handler
+    /// that allocates can't recurse.
+    ///
+    /// The handler isn't called for allocations that stay inside a critical
+    /// section, like those of
+    /// [`alloc_batch`](struct.EspHeap.html#method.alloc_batch) and budgets,
+    /// nor from interrupt handlers that may only try the regions (see
+    /// [`IsrPolicy::TryOnly`](enum.IsrPolicy.html#variant.TryOnly)).
+    ///
+    /// With the `defmt-failures` feature, an allocation that still fails is
+    /// logged once the handler has returned.
+    pub fn set_oom_handler(&self, handler: fn(Layout, &HeapStats) -> OomAction) {
+        self.locked(|cs| {
+            let cell = self.oom_handler.borrow(cs);
+            cell.set(OomState {
+                handler: Some(handler),
+                ..cell.get()
+            });
+        });
+    }
+
+    /// Stops calling the out-of-memory handler
+    pub fn clear_oom_handler(&self) {
+        self.locked(|cs| {
+            let cell = self.oom_handler.borrow(cs);
+            cell.set(OomState {
+                handler: None,
+                ..cell.get()
+            });
+        });
+    }
+
+    /// Returns the handler to call for a failed allocation, unless it is
+    /// already running, and notes that it runs.
+    pub(crate) fn enter_oom_handler(&self, cs: CriticalSection<'_>) -> Option<OomHandler> {
+        let cell = self.oom_handler.borrow(cs);
+        let state = cell.get();
+        if state.running {
+            return None;
+        }
+        let handler = state.handler?;
+        cell.set(OomState {
+            running: true,
+            ..state
+        });
+        Some(handler)
+    }
+
+    /// Runs a handler returned by `enter_oom_handler`.
+    ///
+    /// This must be called outside of a critical section.
+    pub(crate) fn run_oom_handler(&self, handler: OomHandler, layout: Layout) -> OomAction {
+        let action = handler(layout, &self.stats());
+        self.locked(|cs| {
+            let cell = self.oom_handler.borrow(cs);
+            cell.set(OomState {
+                running: false,
+                ..cell.get()
+            });
+        });
+        action
+    }
+
+    /// Logs an allocation that returns null, after the out-of-memory handler
+    /// had its go, with what every region has left.
+    ///
+    /// This must be called outside of a critical section.
+    #[cfg(feature = "defmt-failures")]
+    pub(crate) fn log_failure(&self, layout: Layout) {
+        let free = self.usage(false).regions.map(|region| region.free);
+        defmt::warn!(
+            "esp-alloc: allocation of {=usize} bytes aligned to {=usize} failed, free bytes per region: {}",
+            layout.size(),
+            layout.align(),
+            free
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Known patterns in allocated and freed blocks, to expose uninitialized
+//! reads and use-after-free
+
+use core::sync::atomic::Ordering;
+
+use crate::EspHeap;
+
+/// What every allocated block is filled with before it is handed out
+pub const ALLOC_POISON: u8 = 0xAA;
+
+/// What every block is filled with before it is freed
+pub const FREE_POISON: u8 = 0xDD;
+
+/// Blocks bigger than this aren't poisoned until
+/// [`set_poison_limit`](struct.EspHeap.html#method.set_poison_limit) is
+/// called
+pub const DEFAULT_POISON_LIMIT: usize = 16 * 1024;
+
+impl EspHeap {
+    /// Sets the size of the largest block that is poisoned
+    ///
+    /// With the `poison` feature, every allocated block is filled with
+    /// [`ALLOC_POISON`] and every freed one with [`FREE_POISON`], so that
+    /// reads of uninitialized or freed memory show up as a recognizable
+    /// pattern instead of plausible values. Only the requested size is
+    /// filled, not the allocator's own data around it, which overwrites the
+    /// start of a freed block again. Filling large buffers, like frame
+    /// buffers in PSRAM, can dominate startup, so blocks bigger than `bytes`
+    /// are left alone; it defaults to [`DEFAULT_POISON_LIMIT`].
+    pub fn set_poison_limit(&self, bytes: usize) {
+        self.poison_limit.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Fills `len` bytes at `ptr` with `pattern` unless that is more than
+    /// the limit, returning whether it did.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes of `len` bytes.
+    #[cfg_attr(feature = "inline-hot-path", inline(always))]
+    pub(crate) unsafe fn poison(&self, ptr: *mut u8, len: usize, pattern: u8) -> bool {
+        if len > self.poison_limit.load(Ordering::Relaxed) {
+            return false;
+        }
+        ptr.write_bytes(pattern, len);
+        true
+    }
+}
@@ -0,0 +1,63 @@
+//! The types for inspecting a heap, in one place
+//!
+//! Crates that wrap or monitor an [`EspHeap`] can glob-import this module
+//! instead of picking individual items from the crate root. Everything in
+//! here is covered by the following guarantees, so that growing the
+//! diagnostics isn't a breaking change:
+//!
+//! - Reports, statistics and hook arguments are `#[non_exhaustive]`. Fields
+//!   may be added in minor releases, so other crates read their fields but
+//!   don't build or destructure them exhaustively.
+//! - Error and event enums are `#[non_exhaustive]` as well, and variants may
+//!   be added in minor releases. Match them with a wildcard arm.
+//! - Descriptions passed into the heap, like [`RegionDescriptor`] and
+//!   [`HeapRegion`], are `#[non_exhaustive]` too. They are built with their
+//!   constructors, which keep their signatures when fields are added.
+//!
+//! Items of optional features are only exported with their feature.
+//!
+//! ```
+//! use esp_alloc::prelude::*;
+//!
+//! fn log_heap(heap: &EspHeap) {
+//!     let summary: ConfigSummary = heap.config_summary();
+//!     let _ = (summary.version, summary.regions);
+//!     for config in heap.region_configs() {
+//!         let RegionConfig { id, size, .. } = config;
+//!         let _ = (id, size);
+//!     }
+//!     for budget in heap.budgets() {
+//!         let _ = (budget.name, budget.used);
+//!     }
+//!     for missing in heap.missing_regions() {
+//!         let _ = missing.reason;
+//!     }
+//! }
+//!
+//! fn describe(error: BudgetError) -> &'static str {
+//!     match error {
+//!         BudgetError::OutOfMemory => "heap full",
+//!         _ => "other",
+//!     }
+//! }
+//!
+//! let _ = (log_heap, describe);
+//! ```
+
+pub use crate::{
+    BudgetError, BudgetUsage, CoalesceReport, ConfigSummary, ConfiguredRegion, EspHeap, HeapRegion,
+    HeapStats, LayoutSummary, MemoryCapability, MemoryRange, MissingRegion, OomAction,
+    RegionConfig, RegionDescriptor, RegionId, RegionStats, TryAllocError, WatchHit, MAX_REGIONS,
+    STATS_BYTES_LEN, STATS_FORMAT_VERSION,
+};
+
+#[cfg(feature = "heap-guard")]
+pub use crate::CorruptionInfo;
+#[cfg(feature = "isr-guard")]
+pub use crate::IsrPolicy;
+#[cfg(feature = "oom-handler")]
+pub use crate::OomSink;
+#[cfg(feature = "free-cache")]
+pub use crate::{AllocStrategy, FreeCacheStats};
+#[cfg(feature = "trace")]
+pub use crate::{LiveSummary, ReplayReport, SizeClass, TraceError, TraceEvent, TraceOp};
@@ -0,0 +1,185 @@
+//! Delayed reuse of freed blocks, to catch late use-after-free
+
+use core::alloc::Layout;
+
+use critical_section::CriticalSection;
+
+use crate::{release, EspHeap, Region};
+
+/// Maximum number of blocks the quarantine can hold
+pub const QUARANTINE_CAPACITY: usize = 16;
+
+const DEFAULT_MAX_BYTES: usize = 4096;
+
+const EMPTY_SLOT: (usize, Layout, bool) = (0, Layout::new::<u8>(), false);
+
+/// A freed block, its layout and whether it was filled with `FREE_POISON`
+pub(crate) type Quarantined = (*mut u8, Layout, bool);
+
+/// A FIFO of freed blocks that haven't been handed back to their region yet
+pub(crate) struct Quarantine {
+    blocks: [(usize, Layout, bool); QUARANTINE_CAPACITY],
+    head: usize,
+    len: usize,
+    pub(crate) bytes: usize,
+    max_blocks: usize,
+    max_bytes: usize,
+}
+
+impl Quarantine {
+    pub(crate) const fn new() -> Self {
+        Self {
+            blocks: [EMPTY_SLOT; QUARANTINE_CAPACITY],
+            head: 0,
+            len: 0,
+            bytes: 0,
+            max_blocks: QUARANTINE_CAPACITY,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+
+    /// Adds a freed block, evicting the oldest one if there is no room left.
+    pub(crate) fn push(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        poisoned: bool,
+    ) -> Option<Quarantined> {
+        let evicted = if self.len == QUARANTINE_CAPACITY {
+            self.pop()
+        } else {
+            None
+        };
+
+        self.blocks[(self.head + self.len) % QUARANTINE_CAPACITY] =
+            (ptr as usize, layout, poisoned);
+        self.len += 1;
+        self.bytes += layout.size();
+
+        evicted
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Removes the oldest block.
+    pub(crate) fn pop(&mut self) -> Option<Quarantined> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let (ptr, layout, poisoned) = self.blocks[self.head];
+        self.head = (self.head + 1) % QUARANTINE_CAPACITY;
+        self.len -= 1;
+        self.bytes -= layout.size();
+
+        Some((ptr as *mut u8, layout, poisoned))
+    }
+
+    /// Removes the oldest block if the quarantine exceeds its limits.
+    pub(crate) fn pop_excess(&mut self) -> Option<Quarantined> {
+        if self.len > self.max_blocks || self.bytes > self.max_bytes {
+            self.pop()
+        } else {
+            None
+        }
+    }
+}
+
+impl EspHeap {
+    /// Limits how many freed blocks, and how many bytes in total, are held
+    /// back from reuse
+    ///
+    /// Freed blocks spend some time in a FIFO quarantine before they are
+    /// really freed, which widens the window in which a late write to freed
+    /// memory can be noticed. Blocks leave the quarantine once it exceeds
+    /// either limit, or all at once when an allocation would fail otherwise.
+    /// Whenever blocks leave, the
+    /// [watched ranges](struct.EspHeap.html#method.watch_range) are checked,
+    /// and with the `poison` feature, a block that no longer holds
+    /// [`FREE_POISON`](constant.FREE_POISON.html) panics with where it was
+    /// written to.
+    ///
+    /// `blocks` is capped at [`QUARANTINE_CAPACITY`]; `0` disables the
+    /// quarantine. The defaults are [`QUARANTINE_CAPACITY`] blocks and 4096
+    /// bytes.
+    pub fn set_quarantine_limits(&self, blocks: usize, bytes: usize) {
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            let mut quarantine = self.quarantine.borrow(cs).borrow_mut();
+            quarantine.max_blocks = blocks.min(QUARANTINE_CAPACITY);
+            quarantine.max_bytes = bytes;
+
+            let excess = core::iter::from_fn(|| quarantine.pop_excess());
+            // SAFETY: quarantined blocks are live allocations.
+            unsafe { self.release_quarantined(cs, &mut regions[..], excess) };
+        });
+    }
+
+    /// Frees all quarantined blocks
+    pub fn flush_quarantine(&self) {
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            let mut quarantine = self.quarantine.borrow(cs).borrow_mut();
+            let blocks = core::iter::from_fn(|| quarantine.pop());
+            // SAFETY: quarantined blocks are live allocations.
+            unsafe { self.release_quarantined(cs, &mut regions[..], blocks) };
+        });
+    }
+
+    /// Returns the number of bytes currently held in quarantine
+    pub fn quarantined(&self) -> usize {
+        self.locked(|cs| self.quarantine.borrow(cs).borrow().bytes)
+    }
+
+    /// Hands blocks leaving the quarantine back to their region, after
+    /// checking that nothing wrote to them since they were freed, then
+    /// checks the watched ranges if any block left.
+    ///
+    /// # Safety
+    ///
+    /// The blocks must have been quarantined by `dealloc`.
+    pub(crate) unsafe fn release_quarantined(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &mut [Region],
+        blocks: impl Iterator<Item = Quarantined>,
+    ) {
+        let mut released = false;
+        for (ptr, layout, poisoned) in blocks {
+            #[cfg(feature = "poison")]
+            if poisoned {
+                check_poison(ptr, layout);
+            }
+            #[cfg(not(feature = "poison"))]
+            let _ = poisoned;
+            release(regions, ptr, layout);
+            released = true;
+        }
+        if released {
+            self.check_watches_locked(cs);
+        }
+    }
+}
+
+/// Panics if the block at `ptr` doesn't hold `FREE_POISON` any more.
+///
+/// # Safety
+///
+/// The block must be readable.
+#[cfg(feature = "poison")]
+unsafe fn check_poison(ptr: *mut u8, layout: Layout) {
+    let changed = (0..layout.size()).find(|&offset| *ptr.add(offset) != crate::FREE_POISON);
+    if let Some(offset) = changed {
+        #[cfg(not(feature = "verbose-errors"))]
+        let _ = offset;
+        fail!(
+            "write to freed memory",
+            "Write to the freed block of {} bytes at {:p}, {} bytes in",
+            layout.size(),
+            ptr,
+            offset
+        );
+    }
+}
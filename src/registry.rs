@@ -0,0 +1,369 @@
+//! Registry of live allocations, for moving them between regions
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    slice,
+};
+
+use crate::{Context, EspHeap, MemoryCapability, Region, RegionId};
+
+/// Maximum number of live allocations the registry can track
+pub const REGISTRY_CAPACITY: usize = 64;
+
+/// A zero-initialized block on an [`EspHeap`] that stays in its registry
+/// for as long as it lives
+///
+/// Created by
+/// [`alloc_tracked_raii`](struct.EspHeap.html#method.alloc_tracked_raii).
+/// The block is freed, and so leaves the registry, when the wrapper is
+/// dropped. It derefs to its bytes.
+pub struct TrackedAlloc<'a> {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    heap: &'a EspHeap,
+}
+
+// SAFETY: the wrapper owns its block, and the heap is `Sync`.
+unsafe impl Send for TrackedAlloc<'_> {}
+// SAFETY: as above.
+unsafe impl Sync for TrackedAlloc<'_> {}
+
+impl TrackedAlloc<'_> {
+    /// Returns the address of the block
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// Returns the layout the block was allocated with
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+}
+
+impl Deref for TrackedAlloc<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the block is initialized and lives as long as the wrapper.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for TrackedAlloc<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above, and the wrapper is borrowed mutably.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for TrackedAlloc<'_> {
+    fn drop(&mut self) {
+        if self.layout.size() != 0 {
+            // SAFETY: the block was allocated from `heap` with this layout.
+            unsafe { self.heap.dealloc(self.ptr.as_ptr(), self.layout) };
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Entry {
+    pub(crate) ptr: usize,
+    pub(crate) layout: Layout,
+    /// Capabilities the allocation was requested with
+    pub(crate) capabilities: MemoryCapability,
+    /// Index of the region the allocation lives in
+    pub(crate) region: usize,
+    /// When the allocation was made, if a clock was set
+    pub(crate) created: Option<u64>,
+}
+
+pub(crate) struct Registry {
+    entries: [Option<Entry>; REGISTRY_CAPACITY],
+    /// Live allocations that didn't fit into the registry
+    untracked: usize,
+}
+
+impl Registry {
+    pub(crate) const fn new() -> Self {
+        Self {
+            entries: [None; REGISTRY_CAPACITY],
+            untracked: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, entry: Entry) {
+        match self.entries.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(entry),
+            None => self.untracked += 1,
+        }
+    }
+
+    pub(crate) fn remove(&mut self, ptr: usize) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(entry) if entry.ptr == ptr))
+        {
+            Some(slot) => *slot = None,
+            None => self.untracked = self.untracked.saturating_sub(1),
+        }
+    }
+
+    /// Records that the allocation at `ptr` was resized in place.
+    pub(crate) fn resize(&mut self, ptr: usize, layout: Layout) {
+        for entry in self.entries.iter_mut().flatten() {
+            if entry.ptr == ptr {
+                entry.layout = layout;
+            }
+        }
+    }
+
+    fn get(&self, ptr: usize) -> Option<Entry> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|entry| entry.ptr == ptr)
+            .copied()
+    }
+
+    /// Records that the allocation at `new` is a copy of the one at `old`,
+    /// which it takes the capabilities and the age of.
+    fn inherit(&mut self, old: usize, new: usize) {
+        let Some(old) = self.get(old) else {
+            return;
+        };
+        for entry in self.entries.iter_mut().flatten() {
+            if entry.ptr == new {
+                entry.capabilities = old.capabilities;
+                entry.created = old.created;
+            }
+        }
+    }
+}
+
+/// Returns the index of the region that should rather hold `entry`.
+///
+/// A region qualifies if it has the capabilities the allocation asked for
+/// and would still be less full than the current region after the move.
+/// Of those, the one with the most free bytes is suggested.
+fn better_region(regions: &[Region], entry: &Entry) -> Option<usize> {
+    let (size, current) = (entry.layout.size() as u64, entry.region);
+    let from = &regions[current];
+    let (from_used, from_size) = (from.used() as u64, from.size() as u64);
+
+    regions
+        .iter()
+        .enumerate()
+        .filter(|&(index, region)| {
+            index != current
+                && region.is_initialized()
+                && region.serves(entry.capabilities)
+                && (region.used() as u64 + size) * from_size < from_used * region.size() as u64
+        })
+        .max_by_key(|(_, region)| region.heap.free())
+        .map(|(index, _)| index)
+}
+
+impl EspHeap {
+    /// Allocates a zero-initialized block that is freed when the returned
+    /// wrapper is dropped
+    ///
+    /// Like every allocation, the block is recorded in the registry, so it
+    /// shows up in
+    /// [`tracked_allocations`](struct.EspHeap.html#method.tracked_allocations)
+    /// while it lives; unlike a raw allocation, it can't be leaked by
+    /// forgetting to free it. Returns `None` if no region has room.
+    ///
+    /// The wrapper keeps the address it was created with, so the callback of
+    /// [`rebalance`](struct.EspHeap.html#method.rebalance) must not approve
+    /// moving the block.
+    pub fn alloc_tracked_raii(&self, layout: Layout) -> Option<TrackedAlloc<'_>> {
+        let ptr = if layout.size() == 0 {
+            // SAFETY: the alignment is never zero.
+            unsafe { NonNull::new_unchecked(layout.align() as *mut u8) }
+        } else {
+            // SAFETY: the layout isn't zero-sized.
+            NonNull::new(unsafe { self.alloc_zeroed(layout) })?
+        };
+        Some(TrackedAlloc {
+            ptr,
+            layout,
+            heap: self,
+        })
+    }
+
+    /// Calls `f` with the address, size and age of every tracked allocation
+    /// made at least `age` ago
+    ///
+    /// Ages are measured with the clock given to
+    /// [`set_timestamp_source`](struct.EspHeap.html#method.set_timestamp_source),
+    /// so allocations made before it was set aren't reported. Allocations that
+    /// pile up among the old ones are the signature of a leak, as opposed to
+    /// the churn of short-lived ones. `f` runs outside of the critical
+    /// section.
+    pub fn allocations_older_than(&self, age: u64, mut f: impl FnMut(usize, usize, u64)) {
+        let Some(now) = self.locked(|cs| self.now(cs)) else {
+            return;
+        };
+
+        for index in 0..REGISTRY_CAPACITY {
+            let entry = self.locked(|cs| self.registry.borrow(cs).borrow().entries[index]);
+            if let Some(Entry {
+                ptr,
+                layout,
+                created: Some(created),
+                ..
+            }) = entry
+            {
+                let entry_age = now.saturating_sub(created);
+                if entry_age >= age {
+                    f(ptr, layout.size(), entry_age);
+                }
+            }
+        }
+    }
+
+    /// Returns the number of live allocations tracked by the registry
+    pub fn tracked_allocations(&self) -> usize {
+        self.locked(|cs| {
+            self.registry
+                .borrow(cs)
+                .borrow()
+                .entries
+                .iter()
+                .flatten()
+                .count()
+        })
+    }
+
+    /// Returns the approximate number of live allocations the registry had
+    /// no room for
+    ///
+    /// These are never offered by
+    /// [`rebalance`](struct.EspHeap.html#method.rebalance).
+    pub fn untracked_allocations(&self) -> usize {
+        self.locked(|cs| self.registry.borrow(cs).borrow().untracked)
+    }
+
+    /// Allocates a block for a copy of the allocation at `ptr` in the region
+    /// with the given index, and copies it, returning the copy. In the
+    /// registry, the copy is the same allocation as the original.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation made with `layout` from this heap.
+    unsafe fn copy_to(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        region: usize,
+        capabilities: MemoryCapability,
+    ) -> Option<NonNull<u8>> {
+        let context = Context {
+            region: Some(region),
+            ..self.context(layout.size())
+        };
+        let new = self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            #[cfg(esp_alloc_dual_core)]
+            let _entered = self.core_guard.enter();
+            let Some(allocation) =
+                self.allocate_locked(cs, &mut regions[..], context, capabilities, layout)
+            else {
+                self.record_failed(cs, &mut regions[..], context, capabilities, layout);
+                return None;
+            };
+            let new = allocation.ptr;
+
+            ptr::copy_nonoverlapping(ptr.as_ptr(), new.as_ptr(), layout.size());
+            self.registry
+                .borrow(cs)
+                .borrow_mut()
+                .inherit(ptr.as_ptr() as usize, new.as_ptr() as usize);
+            Some(new)
+        })?;
+        self.report_allocation(layout, new.as_ptr());
+        Some(new)
+    }
+
+    /// Moves an allocation into the given region
+    ///
+    /// The contents are copied to a new block in `region` and the old block
+    /// is freed, both like any other allocation and deallocation, so they
+    /// show up in the statistics, the trace and the allocation hooks. Returns
+    /// the new location, or `None` if `region` can't hold the allocation, in
+    /// which case nothing changes.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation made with `layout` from this heap, and
+    /// any reference to it must be updated to the returned location, which
+    /// takes the old one's place.
+    pub unsafe fn migrate(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        region: RegionId,
+    ) -> Option<NonNull<u8>> {
+        let new = self.copy_to(ptr, layout, region.index, MemoryCapability::empty())?;
+        self.dealloc(ptr.as_ptr(), layout);
+        Some(new)
+    }
+
+    /// Offers to move tracked allocations out of crowded regions
+    ///
+    /// For every allocation in the registry that would leave its region less
+    /// full in another region with the capabilities it was allocated with,
+    /// its contents are copied to a new block in the emptiest such region.
+    /// `f` is then called with the allocation's current location, its layout
+    /// and the address of the copy. If `f` returns `true`, it must have
+    /// updated every reference to the allocation to the new address, and the
+    /// old block is freed; otherwise the copy is freed again. Like with
+    /// [`migrate`](struct.EspHeap.html#method.migrate), these are
+    /// allocations and deallocations like any other.
+    ///
+    /// `f` gets the address of the copy rather than the region suggested
+    /// for it, as that is what it has to update the references with. The
+    /// region follows from the address, see
+    /// [`region_configs`](struct.EspHeap.html#method.region_configs).
+    ///
+    /// Returns the number of allocations that were moved. Allocations made
+    /// while this runs, the copies included, aren't offered.
+    ///
+    /// # Safety
+    ///
+    /// No allocation may be freed while this runs, and an approved
+    /// allocation must not be used through its old location afterwards.
+    pub unsafe fn rebalance(&self, mut f: impl FnMut(NonNull<u8>, Layout, usize) -> bool) -> usize {
+        let tracked = self.locked(|cs| {
+            let registry = self.registry.borrow(cs).borrow();
+            registry.entries.map(|entry| entry.map(|entry| entry.ptr))
+        });
+        let mut moved = 0;
+
+        for ptr in tracked.into_iter().flatten() {
+            let candidate = self.locked(|cs| {
+                let entry = self.registry.borrow(cs).borrow().get(ptr)?;
+                let regions = self.heap.borrow(cs).borrow();
+                Some((entry, better_region(&regions[..], &entry)?))
+            });
+            let Some((entry, target)) = candidate else {
+                continue;
+            };
+
+            let old = NonNull::new_unchecked(ptr as *mut u8);
+            let Some(new) = self.copy_to(old, entry.layout, target, entry.capabilities) else {
+                continue;
+            };
+            let approved = f(old, entry.layout, new.as_ptr() as usize);
+
+            let freed = if approved { old } else { new };
+            self.dealloc(freed.as_ptr(), entry.layout);
+            moved += usize::from(approved);
+        }
+
+        moved
+    }
+}
@@ -0,0 +1,173 @@
+//! The heap's configuration, for boot logs
+
+use core::fmt;
+
+use crate::{EspHeap, MemoryCapability, RegionId, RegionStatus, MAX_REGIONS};
+
+/// How an available region is set up, see
+/// [`region_configs`](struct.EspHeap.html#method.region_configs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct RegionConfig {
+    /// The region
+    pub id: RegionId,
+    /// Address of the first byte the region allocates from
+    pub bottom: usize,
+    /// Number of bytes the region allocates from
+    pub size: usize,
+    /// Capabilities of the region
+    pub capabilities: MemoryCapability,
+    /// Bytes reserved for stack growth at the top of the region
+    pub stack_reserve: usize,
+    /// Bytes kept clear at the top of the region for DMA over-reads
+    pub tail_guard: usize,
+    /// Contiguous bytes kept back for allocations that may use the headroom
+    pub headroom: usize,
+    /// Cache line allocations are isolated to, `0` if they aren't
+    pub cache_line: usize,
+    /// Capacity of the free-block cache (the `free-cache` feature), `0` if
+    /// the region allocates first-fit only
+    pub free_cache: usize,
+    /// Whether the region's memory was declared zero-filled
+    pub zeroed: bool,
+}
+
+impl EspHeap {
+    /// Returns the configuration of every available region, in the order
+    /// they were added
+    pub fn region_configs(&self) -> impl Iterator<Item = RegionConfig> {
+        let mut configs = [None; MAX_REGIONS];
+        self.locked(|cs| {
+            let regions = self.heap.borrow(cs).borrow();
+            for (index, region) in regions.iter().enumerate() {
+                if region.status != RegionStatus::Available {
+                    continue;
+                }
+                configs[index] = Some(RegionConfig {
+                    id: region.id(index),
+                    bottom: region.heap.bottom() as usize,
+                    size: region.size(),
+                    capabilities: region.capabilities,
+                    stack_reserve: region.reserved,
+                    tail_guard: region.tail_guard,
+                    headroom: region.headroom,
+                    cache_line: region.cache_line,
+                    #[cfg(feature = "free-cache")]
+                    free_cache: region.cache.capacity,
+                    #[cfg(not(feature = "free-cache"))]
+                    free_cache: 0,
+                    zeroed: region.zeroed,
+                });
+            }
+        });
+        configs.into_iter().flatten()
+    }
+
+    /// Writes a description of the heap's configuration to `w`
+    ///
+    /// The first line names the crate version, the number of regions, the
+    /// cache line size and the enabled debugging features. It is followed by
+    /// a line for every available region with its address range,
+    /// capabilities and any non-default settings, and one for every expected
+    /// region that is missing. Meant to be logged once at the end of boot,
+    /// so the memory configuration of a device in the field is on record.
+    ///
+    /// Nothing is allocated, and no critical section is held while writing,
+    /// so `w` may be a slow serial port.
+    pub fn boot_report(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        let summary = self.config_summary();
+        write!(
+            w,
+            "esp-alloc {}: {} of {} regions, cache line {} bytes, DMA alignment {} bytes, features:",
+            summary.version,
+            summary.regions,
+            summary.max_regions,
+            summary.cache_line_size,
+            summary.dma_alignment
+        )?;
+        let features = [
+            (summary.defmt_failures, "defmt-failures"),
+            (summary.free_cache, "free-cache"),
+            (summary.heap_guard, "heap-guard"),
+            (summary.isr_guard, "isr-guard"),
+            (summary.multicore, "multicore"),
+            (summary.oom_handler, "oom-handler"),
+            (summary.poison, "poison"),
+            (summary.quarantine, "quarantine"),
+            (summary.registry, "registry"),
+            (summary.stats, "stats"),
+            (summary.trace, "trace"),
+            (summary.validate_layout, "validate-layout"),
+        ];
+        let mut none = true;
+        for (_, name) in features.iter().filter(|(enabled, _)| *enabled) {
+            write!(w, " {name}")?;
+            none = false;
+        }
+        if none {
+            w.write_str(" none")?;
+        }
+        w.write_char('\n')?;
+
+        for config in self.region_configs() {
+            write!(
+                w,
+                "{}: {:#x}..{:#x}, {} bytes, ",
+                config.id,
+                config.bottom,
+                config.bottom + config.size,
+                config.size
+            )?;
+            write_capabilities(w, config.capabilities)?;
+            let settings = [
+                (config.stack_reserve, "stack reserve"),
+                (config.tail_guard, "tail guard"),
+                (config.headroom, "headroom"),
+                (config.cache_line, "cache line isolation"),
+            ];
+            for (bytes, name) in settings.iter().filter(|(bytes, _)| *bytes != 0) {
+                write!(w, ", {name} {bytes}")?;
+            }
+            if config.free_cache != 0 {
+                write!(w, ", free cache of {} blocks", config.free_cache)?;
+            }
+            if config.zeroed {
+                w.write_str(", zeroed")?;
+            }
+            w.write_char('\n')?;
+        }
+
+        for missing in self.missing_regions() {
+            write!(w, "{}: missing, ", missing.region)?;
+            write_capabilities(w, missing.capabilities)?;
+            match missing.reason {
+                Some(reason) => writeln!(w, ", {reason}")?,
+                None => writeln!(w, ", not probed yet")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn write_capabilities(w: &mut impl fmt::Write, capabilities: MemoryCapability) -> fmt::Result {
+    let names = [
+        (MemoryCapability::INTERNAL, "INTERNAL"),
+        (MemoryCapability::EXTERNAL, "EXTERNAL"),
+        (MemoryCapability::DMA, "DMA"),
+        (MemoryCapability::RTC_RETAINED, "RTC_RETAINED"),
+    ];
+    let mut separator = "";
+    for (_, name) in names
+        .iter()
+        .filter(|(capability, _)| capabilities.contains(*capability))
+    {
+        write!(w, "{separator}{name}")?;
+        separator = "|";
+    }
+    if separator.is_empty() {
+        w.write_str("no capabilities")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//! Memory whose contents survive deep sleep
+//!
+//! Regions added with [`MemoryCapability::RTC_RETAINED`] only serve
+//! allocations that ask for that capability, so blocks that don't need to
+//! survive never use up RTC memory, and retained blocks never end up in
+//! memory that loses power.
+//!
+//! The heap itself starts over on every wake, but the allocator is
+//! deterministic: a fresh region asked for the same layouts in the same order
+//! hands out the same addresses. What gets in the way of the data is the
+//! allocator's bookkeeping, which lives in the free memory: adding a region
+//! writes a hole header at its bottom, and every allocation writes one right
+//! behind its block, into what used to be the next block. A region re-added
+//! with [`reinit_retained_preserving`](struct.EspHeap.html#method.reinit_retained_preserving)
+//! saves the bytes under each header before it's written and puts them back
+//! once the block they belong to is handed out again.
+
+use core::{alloc::Layout, ptr, ptr::NonNull};
+
+use crate::{holes, EspHeap, MemoryCapability, Region, RegionId};
+
+/// How far a region re-added with `reinit_retained_preserving` has got in
+/// handing out its blocks again
+#[derive(Clone, Copy)]
+pub(crate) struct Replay {
+    /// Address of the region's only hole, where the next block starts
+    cursor: usize,
+    /// What the header of that hole has overwritten
+    saved: [u8; holes::MIN_BLOCK],
+}
+
+impl Region {
+    /// Allocates `layout`, as returned by `region_layout`, from a region that
+    /// is handing out its blocks again, and puts back what the allocator had
+    /// overwritten at the start of the block.
+    ///
+    /// The replay ends with the first block that needs a gap in front of it
+    /// or fills the region, and whenever the block doesn't land where it is
+    /// expected.
+    pub(crate) fn allocate_replayed(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let Replay { cursor, saved } = self.replay?;
+        let start = if cursor % layout.align() == 0 {
+            cursor
+        } else {
+            // Where the allocator places a block behind a gap.
+            (cursor + holes::MIN_BLOCK + layout.align() - 1) & !(layout.align() - 1)
+        };
+        let end = start.saturating_add(holes::block_size(layout.size()));
+        let hole_end = self.heap.bottom() as usize + self.heap.size();
+
+        // Unless the block fills the hole, the header of what is left of it
+        // goes right behind the block.
+        let behind = end.saturating_add(holes::MIN_BLOCK) <= hole_end;
+        let mut next = [0; holes::MIN_BLOCK];
+        if behind {
+            // SAFETY: the bytes are in the region's hole.
+            unsafe { ptr::copy_nonoverlapping(end as *const u8, next.as_mut_ptr(), next.len()) };
+        }
+
+        let ptr = self.heap.allocate_first_fit(layout).ok()?;
+        self.replay = None;
+        if ptr.as_ptr() as usize != start {
+            return Some(ptr);
+        }
+        if start == cursor {
+            // SAFETY: every block is at least `MIN_BLOCK` bytes, and it was
+            // just allocated.
+            unsafe { ptr::copy_nonoverlapping(saved.as_ptr(), ptr.as_ptr(), saved.len()) };
+            if behind {
+                self.replay = Some(Replay {
+                    cursor: end,
+                    saved: next,
+                });
+            }
+        }
+        Some(ptr)
+    }
+}
+
+impl EspHeap {
+    /// Allocates memory that keeps its contents in deep sleep
+    ///
+    /// Only regions added with [`MemoryCapability::RTC_RETAINED`] serve the
+    /// allocation. There is no fallback to memory that loses power: returns
+    /// null if those regions are full, or if there are none. The block is
+    /// freed with `GlobalAlloc::dealloc`.
+    ///
+    /// To get the block back after wake, see
+    /// [`reinit_retained_preserving`](struct.EspHeap.html#method.reinit_retained_preserving).
+    pub fn alloc_retained(&self, layout: Layout) -> *mut u8 {
+        self.alloc_caps(MemoryCapability::RTC_RETAINED, layout)
+    }
+
+    /// Adds RTC memory that kept its contents in deep sleep, so that the
+    /// blocks allocated from it before come back intact
+    ///
+    /// The region is added with [`MemoryCapability::RTC_RETAINED`], like with
+    /// [`add_region`](struct.EspHeap.html#method.add_region). Added with the
+    /// same memory as before sleep, as the first region with the capability,
+    /// and asked for the same layouts in the same order with
+    /// [`alloc_retained`](struct.EspHeap.html#method.alloc_retained), it
+    /// hands out every block at its old address with its old contents.
+    ///
+    /// Use it on a cold boot as well, which hands out the same addresses. The
+    /// blocks then hold whatever the memory held at power-up, so keep a magic
+    /// number or a checksum in them to tell the two apart.
+    ///
+    /// Until all blocks are back, the region has to be left alone. Freeing or
+    /// resizing a block in it, or anything that walks its free list, like
+    /// [`coalesce`](struct.EspHeap.html#method.coalesce), ends the replay,
+    /// and so does a block with an alignment above the word size that doesn't
+    /// start at one. The blocks allocated after that still get their old
+    /// addresses, but their first bytes may be lost.
+    ///
+    /// ```
+    /// use core::alloc::Layout;
+    ///
+    /// use esp_alloc::EspHeap;
+    ///
+    /// struct State {
+    ///     magic: u32,
+    ///     wakes: u32,
+    /// }
+    ///
+    /// const MAGIC: u32 = 0x5ee_d5ee;
+    ///
+    /// # static mut RTC_MEMORY: [u64; 128] = [0; 128];
+    /// # let (rtc_start, rtc_size) = (unsafe { RTC_MEMORY.as_mut_ptr().cast() }, 1024);
+    /// for _boot in 0..3 {
+    ///     // Every boot and wake starts with a fresh heap.
+    ///     let heap = EspHeap::empty();
+    ///     unsafe { heap.reinit_retained_preserving(rtc_start, rtc_size) };
+    ///
+    ///     let state = heap.alloc_retained(Layout::new::<State>()).cast::<State>();
+    ///     let state = unsafe { &mut *state };
+    ///     if state.magic != MAGIC {
+    ///         // Cold boot: the memory holds garbage.
+    ///         *state = State { magic: MAGIC, wakes: 0 };
+    ///     } else {
+    ///         state.wakes += 1;
+    ///     }
+    ///     // Deep sleep...
+    /// }
+    /// # let heap = EspHeap::empty();
+    /// # unsafe { heap.reinit_retained_preserving(rtc_start, rtc_size) };
+    /// # let state = unsafe { &*heap.alloc_retained(Layout::new::<State>()).cast::<State>() };
+    /// # assert_eq!(state.wakes, 2);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics like `add_region`.
+    ///
+    /// # Safety
+    ///
+    /// Like for `add_region`, the memory in `[bottom, bottom + size)` must be
+    /// valid for the entire program and not used for anything else, and
+    /// `size > 0`. Until the blocks are allocated again, their contents must
+    /// not be accessed through anything but the pointers
+    /// `alloc_retained` returns for them.
+    pub unsafe fn reinit_retained_preserving(&self, bottom: *mut u8, size: usize) -> RegionId {
+        // Where the allocator puts the header of the region's first hole.
+        let cursor = (bottom as usize + holes::BLOCK_ALIGN - 1) & !(holes::BLOCK_ALIGN - 1);
+        let mut saved = [0; holes::MIN_BLOCK];
+        if cursor.saturating_add(holes::MIN_BLOCK) <= (bottom as usize).saturating_add(size) {
+            ptr::copy_nonoverlapping(cursor as *const u8, saved.as_mut_ptr(), saved.len());
+        }
+
+        let id = self.add_region(bottom, size, MemoryCapability::RTC_RETAINED);
+        self.locked(|cs| {
+            self.heap.borrow(cs).borrow_mut()[id.index].replay = Some(Replay { cursor, saved });
+        });
+        id
+    }
+}
@@ -0,0 +1,63 @@
+//! Heaps with a bounded lifetime, for tests
+
+use core::{marker::PhantomData, ops::Deref};
+
+use crate::{EspHeap, MemoryCapability, RegionId};
+
+/// An [`EspHeap`] over a borrowed buffer that checks for leaks when dropped
+///
+/// Meant for tests: each test gets a pristine heap over its own buffer, and
+/// dropping the heap with allocations still live panics, naming the number
+/// of leaked bytes. With the `std` feature the check is skipped while the
+/// thread is already panicking, as a failed assertion usually leaves blocks
+/// allocated and a second panic would abort the test run. The heap derefs to [`EspHeap`], so it can be used like
+/// any other heap (except as the global allocator, which must be a static).
+pub struct ScopedHeap<'a> {
+    heap: EspHeap,
+    buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> ScopedHeap<'a> {
+    /// Creates a heap with `buffer` as its only region
+    pub fn new(buffer: &'a mut [u8], capabilities: MemoryCapability) -> Self {
+        let scoped = Self {
+            heap: EspHeap::empty(),
+            buffer: PhantomData,
+        };
+        scoped.add_buffer(buffer, capabilities);
+        scoped
+    }
+
+    /// Adds `buffer` as another region
+    pub fn add_buffer(&self, buffer: &'a mut [u8], capabilities: MemoryCapability) -> RegionId {
+        // SAFETY: the buffer is borrowed for as long as the heap lives.
+        unsafe {
+            self.heap
+                .add_region(buffer.as_mut_ptr(), buffer.len(), capabilities)
+        }
+    }
+}
+
+impl Deref for ScopedHeap<'_> {
+    type Target = EspHeap;
+
+    fn deref(&self) -> &EspHeap {
+        &self.heap
+    }
+}
+
+impl Drop for ScopedHeap<'_> {
+    fn drop(&mut self) {
+        #[cfg(feature = "std")]
+        if std::thread::panicking() {
+            return;
+        }
+        let live = self.heap.live_bytes();
+        if live != 0 {
+            fail!(
+                "ScopedHeap dropped with live allocations",
+                "ScopedHeap dropped with {live} bytes still allocated"
+            );
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Allocation statistics
+
+use critical_section::CriticalSection;
+
+use crate::{holes, EspHeap, MemoryCapability, Region, RegionId, RegionStatus, MAX_REGIONS};
+
+pub(crate) struct Counters {
+    pub(crate) allocations: usize,
+    pub(crate) deallocations: usize,
+    pub(crate) bytes_requested: usize,
+    pub(crate) failed_allocations: usize,
+    pub(crate) region_failures: [usize; MAX_REGIONS],
+    last_failure: Option<u64>,
+    contended_allocations: usize,
+    used_peak: usize,
+    largest_failure: usize,
+}
+
+impl Counters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            allocations: 0,
+            deallocations: 0,
+            bytes_requested: 0,
+            failed_allocations: 0,
+            region_failures: [0; MAX_REGIONS],
+            last_failure: None,
+            contended_allocations: 0,
+            used_peak: 0,
+            largest_failure: 0,
+        }
+    }
+}
+
+impl EspHeap {
+    /// Returns the number of successful allocations
+    ///
+    /// A block that [`realloc`](struct.EspHeap.html#method.realloc) moves
+    /// counts as an allocation and a deallocation, one it resizes in place
+    /// as neither. With
+    /// [`deallocation_count`](struct.EspHeap.html#method.deallocation_count)
+    /// this is a cheap leak detector: if the difference keeps growing over a
+    /// long run, something doesn't free its memory.
+    pub fn allocation_count(&self) -> usize {
+        self.locked(|cs| self.counters.borrow(cs).borrow().allocations)
+    }
+
+    /// Returns the number of deallocations
+    pub fn deallocation_count(&self) -> usize {
+        self.locked(|cs| self.counters.borrow(cs).borrow().deallocations)
+    }
+
+    /// Returns the sum of the sizes of all successful allocations
+    ///
+    /// This is what was asked for, without the padding and rounding of the
+    /// backing allocator.
+    pub fn bytes_requested(&self) -> usize {
+        self.locked(|cs| self.counters.borrow(cs).borrow().bytes_requested)
+    }
+
+    /// Returns the number of allocations that failed
+    pub fn failed_allocations(&self) -> usize {
+        self.locked(|cs| self.counters.borrow(cs).borrow().failed_allocations)
+    }
+
+    /// Returns the number of failed allocations the given region could
+    /// have served
+    ///
+    /// A failed allocation counts for every added region with the requested
+    /// capabilities, which tells whether it was internal RAM or PSRAM that
+    /// ran dry, or only for the region given to
+    /// [`alloc_in_region`](struct.EspHeap.html#method.alloc_in_region).
+    /// Returns `0` for a region that doesn't exist.
+    pub fn region_failed_allocations(&self, region: RegionId) -> usize {
+        self.locked(|cs| {
+            let counters = self.counters.borrow(cs).borrow();
+            counters
+                .region_failures
+                .get(region.index)
+                .copied()
+                .unwrap_or(0)
+        })
+    }
+
+    /// Restarts the allocation, deallocation and failure counts and the
+    /// bytes requested from zero
+    ///
+    /// The peak usage and the largest failure are kept, as are the time of
+    /// the last failure and the contention count.
+    pub fn reset_counters(&self) {
+        self.locked(|cs| {
+            let mut counters = self.counters.borrow(cs).borrow_mut();
+            counters.allocations = 0;
+            counters.deallocations = 0;
+            counters.bytes_requested = 0;
+            counters.failed_allocations = 0;
+            counters.region_failures = [0; MAX_REGIONS];
+        });
+    }
+
+    /// Returns the time of the most recent failed allocation
+    ///
+    /// The time is taken from the source given to
+    /// [`set_timestamp_source`](struct.EspHeap.html#method.set_timestamp_source).
+    /// Returns `None` if no allocation has failed since it was set.
+    pub fn last_failure_time(&self) -> Option<u64> {
+        self.locked(|cs| self.counters.borrow(cs).borrow().last_failure)
+    }
+
+    /// Returns the number of times
+    /// [`try_alloc`](struct.EspHeap.html#method.try_alloc) found the heap in
+    /// use
+    ///
+    /// Every attempt of
+    /// [`try_alloc_bounded`](struct.EspHeap.html#method.try_alloc_bounded)
+    /// counts. With the `multicore` feature, attempts that found the other
+    /// core in the heap don't, as counting them would mean waiting for it.
+    pub fn contended_allocations(&self) -> usize {
+        self.locked(|cs| self.counters.borrow(cs).borrow().contended_allocations)
+    }
+
+    /// Returns the most bytes that have been in use at once
+    ///
+    /// Like [`used`](struct.EspHeap.html#method.used) this includes the
+    /// backing allocator's rounding of every block. It is updated on every
+    /// successful allocation.
+    pub fn used_peak(&self) -> usize {
+        self.locked(|cs| self.counters.borrow(cs).borrow().used_peak)
+    }
+
+    /// Returns the size of the largest allocation that failed
+    pub fn largest_failed_allocation(&self) -> usize {
+        self.locked(|cs| self.counters.borrow(cs).borrow().largest_failure)
+    }
+
+    /// Returns the heap size that would likely have avoided every failed
+    /// allocation so far
+    ///
+    /// Meant for sizing the heap after a representative run: the heap needs
+    /// room for the most it ever held at once, see
+    /// [`used_peak`](struct.EspHeap.html#method.used_peak), plus the largest
+    /// request that didn't fit, see
+    /// [`largest_failed_allocation`](struct.EspHeap.html#method.largest_failed_allocation).
+    /// An eighth of that is added on top for alignment padding and
+    /// fragmentation, and the result is rounded up to a kilobyte.
+    ///
+    /// This is a heuristic, not a guarantee: the failed request may have come
+    /// at a lower usage than the peak, in which case the figure is generous,
+    /// and a fragmented heap may still fail with this much memory. Without
+    /// any failures it is the peak with the margin, which is what the heap
+    /// needs to repeat the run.
+    pub fn recommended_size(&self) -> usize {
+        let (peak, failure) = self.locked(|cs| {
+            let counters = self.counters.borrow(cs).borrow();
+            (counters.used_peak, counters.largest_failure)
+        });
+        let failure = if failure == 0 {
+            0
+        } else {
+            holes::block_size(failure)
+        };
+        let needed = peak.saturating_add(failure);
+        needed.saturating_add(needed / 8).saturating_add(1023) & !1023
+    }
+
+    pub(crate) fn record_allocation(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &[Region],
+        size: usize,
+    ) {
+        self.record_usage(cs, regions);
+        let mut counters = self.counters.borrow(cs).borrow_mut();
+        counters.allocations += 1;
+        counters.bytes_requested = counters.bytes_requested.wrapping_add(size);
+    }
+
+    pub(crate) fn record_deallocation(&self, cs: CriticalSection<'_>) {
+        self.counters.borrow(cs).borrow_mut().deallocations += 1;
+    }
+
+    pub(crate) fn record_usage(&self, cs: CriticalSection<'_>, regions: &[Region]) {
+        let used = regions.iter().map(|region| region.used()).sum();
+        let mut counters = self.counters.borrow(cs).borrow_mut();
+        counters.used_peak = counters.used_peak.max(used);
+    }
+
+    pub(crate) fn record_contention(&self, cs: CriticalSection<'_>) {
+        self.counters.borrow(cs).borrow_mut().contended_allocations += 1;
+    }
+
+    pub(crate) fn record_failure(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &[Region],
+        only: Option<usize>,
+        capabilities: MemoryCapability,
+        size: usize,
+    ) {
+        let now = self.now(cs);
+        let mut counters = self.counters.borrow(cs).borrow_mut();
+        counters.failed_allocations += 1;
+        for (index, region) in regions.iter().enumerate() {
+            if region.status == RegionStatus::Available
+                && region.serves(capabilities)
+                && only.map_or(true, |only| index == only)
+            {
+                counters.region_failures[index] += 1;
+            }
+        }
+        counters.largest_failure = counters.largest_failure.max(size);
+        if now.is_some() {
+            counters.last_failure = now;
+        }
+    }
+}
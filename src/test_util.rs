@@ -0,0 +1,67 @@
+//! Helpers for testing code that deals with fragmentation
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+
+use crate::EspHeap;
+
+impl EspHeap {
+    /// Fragments the heap in a reproducible way
+    ///
+    /// Allocates pairs of `block`-sized blocks and frees the first block of
+    /// every pair, which leaves a free list of equally sized gaps, each one
+    /// pinned in place by the live block allocated right after it. One pair
+    /// is made per entry of `kept`, which receives the live blocks; the
+    /// caller frees them with `block` to clean up.
+    ///
+    /// Returns the number of gaps created, which is less than `kept.len()` if
+    /// the heap ran out of memory. The remaining entries are set to null.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block` is zero-sized.
+    pub fn fragment_for_test(&self, block: Layout, kept: &mut [*mut u8]) -> usize {
+        assert!(
+            block.size() != 0,
+            "fragment_for_test needs non-empty blocks"
+        );
+
+        // The gaps are chained through their first word until they're freed.
+        let mut last_gap: *mut u8 = ptr::null_mut();
+        let mut gaps = 0;
+        kept.fill(ptr::null_mut());
+        for slot in kept.iter_mut() {
+            // SAFETY: `block` is not zero-sized.
+            let gap = unsafe { self.alloc(block) };
+            if gap.is_null() {
+                break;
+            }
+            // SAFETY: the gap is ours until it is freed below, and the
+            // backing allocator never hands out less than two words.
+            unsafe { (gap as *mut *mut u8).write_unaligned(last_gap) };
+            last_gap = gap;
+
+            // SAFETY: as above.
+            let live = unsafe { self.alloc(block) };
+            if live.is_null() {
+                break;
+            }
+            *slot = live;
+            gaps += 1;
+        }
+
+        while !last_gap.is_null() {
+            // SAFETY: every gap was allocated with `block` and links to the
+            // one before it.
+            unsafe {
+                let gap = last_gap;
+                last_gap = (gap as *const *mut u8).read_unaligned();
+                self.dealloc(gap, block);
+            }
+        }
+
+        gaps
+    }
+}
@@ -0,0 +1,610 @@
+//! Binary allocation traces and their replay
+//!
+//! Every event is encoded as one byte for the operation, one byte for the
+//! base-2 logarithm of the alignment, then the size and the address as
+//! unsigned LEB128. The address of a failed allocation is `0`.
+//!
+//! Hooks that want more than the binary format, like the region that served
+//! an allocation, receive a [`TraceEvent`] instead.
+//!
+//! Without a host to stream to, the latest events can be kept on the device
+//! in a ring buffer of `TraceEvent`s that the application provides, and
+//! folded into a [`LiveSummary`] of the allocations that haven't been freed.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt, iter,
+    ptr::NonNull,
+};
+
+use critical_section::CriticalSection;
+
+use crate::{region_of, EspHeap, Region, RegionId};
+
+/// The longest encoding of a single event
+pub const MAX_EVENT_SIZE: usize = 2 + 2 * LEB128_MAX;
+
+/// Number of live allocations [`replay`] can keep track of
+pub const REPLAY_SLOTS: usize = 256;
+
+/// Number of size classes in a [`LiveSummary`]
+pub const SIZE_CLASSES: usize = 12;
+
+/// Upper bound of the smallest size class
+const SMALLEST_CLASS: usize = 16;
+
+const LEB128_MAX: usize = (usize::BITS as usize + 6) / 7;
+
+/// Receives each encoded event, see
+/// [`set_trace_sink`](struct.EspHeap.html#method.set_trace_sink)
+pub(crate) type TraceSink = fn(&[u8]);
+
+/// Receives each event, see
+/// [`set_trace_hook`](struct.EspHeap.html#method.set_trace_hook)
+pub(crate) type TraceHook = fn(&TraceEvent);
+
+const OP_ALLOC: u8 = 0;
+const OP_DEALLOC: u8 = 1;
+
+/// The kind of a [`TraceEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum TraceOp {
+    /// An allocation, successful or not
+    Alloc,
+    /// A deallocation
+    Dealloc,
+}
+
+/// An allocation or deallocation, as passed to the hook set with
+/// [`set_trace_hook`](struct.EspHeap.html#method.set_trace_hook)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TraceEvent {
+    /// Whether this is an allocation or a deallocation
+    pub op: TraceOp,
+    /// The layout the operation was requested with
+    pub layout: Layout,
+    /// Address of the block, `0` if the allocation failed
+    pub address: usize,
+    /// The region that served the allocation or that the block was returned
+    /// to, `None` if the allocation failed or no region holds the address
+    pub region: Option<RegionId>,
+    /// Number of successful allocations made from the heap so far, including
+    /// this one
+    pub sequence: usize,
+    /// Number of events of the same kind `region` has seen so far, including
+    /// this one, `0` if there is no region
+    pub region_sequence: usize,
+}
+
+impl TraceEvent {
+    /// A placeholder to fill the buffer passed to
+    /// [`set_trace_buffer`](struct.EspHeap.html#method.set_trace_buffer)
+    /// with
+    pub const EMPTY: Self = Self {
+        op: TraceOp::Alloc,
+        layout: Layout::new::<u8>(),
+        address: 0,
+        region: None,
+        sequence: 0,
+        region_sequence: 0,
+    };
+}
+
+/// The live allocations of one size class of a [`LiveSummary`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct SizeClass {
+    /// Largest requested size in the class, `usize::MAX` for the last one
+    pub max_size: usize,
+    /// Number of live allocations in the class
+    pub count: usize,
+    /// Sum of their requested sizes
+    pub bytes: usize,
+}
+
+/// The allocations in the trace buffer that haven't been freed, by size
+///
+/// Returned by [`live_summary`](struct.EspHeap.html#method.live_summary).
+/// Displays as one line per size class that has live allocations, so two
+/// summaries dumped a while apart can be diffed to see which sizes keep
+/// growing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct LiveSummary {
+    /// Live allocations by requested size: up to 16 bytes, up to 32 bytes
+    /// and so on in powers of two, with everything above 16 KiB in the last
+    /// class
+    pub classes: [SizeClass; SIZE_CLASSES],
+    /// Number of events in the buffer
+    pub events: usize,
+    /// Events lost to the ring buffer wrapping around since it was set
+    pub overwritten: u64,
+    /// Frees of blocks allocated before the oldest event in the buffer
+    pub unmatched_frees: usize,
+}
+
+impl LiveSummary {
+    fn new() -> Self {
+        let mut classes = [SizeClass::default(); SIZE_CLASSES];
+        for (index, class) in classes.iter_mut().enumerate() {
+            class.max_size = SMALLEST_CLASS << index;
+        }
+        classes[SIZE_CLASSES - 1].max_size = usize::MAX;
+        Self {
+            classes,
+            events: 0,
+            overwritten: 0,
+            unmatched_frees: 0,
+        }
+    }
+
+    fn add(&mut self, size: usize) {
+        let class = self
+            .classes
+            .iter_mut()
+            .find(|class| size <= class.max_size)
+            .unwrap();
+        class.count += 1;
+        class.bytes += size;
+    }
+}
+
+impl fmt::Display for LiveSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "live allocations in the last {} events ({} overwritten, {} unmatched frees):",
+            self.events, self.overwritten, self.unmatched_frees
+        )?;
+        for class in self.classes.iter().filter(|class| class.count != 0) {
+            if class.max_size == usize::MAX {
+                write!(f, "  larger: ")?;
+            } else {
+                write!(f, "  <= {}: ", class.max_size)?;
+            }
+            writeln!(f, "{} blocks, {} bytes", class.count, class.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// The last events, kept in the buffer set with `set_trace_buffer`
+pub(crate) struct TraceRing {
+    events: Option<&'static mut [TraceEvent]>,
+    /// Number of events recorded since the buffer was set
+    recorded: u64,
+}
+
+impl TraceRing {
+    pub(crate) const fn new() -> Self {
+        Self {
+            events: None,
+            recorded: 0,
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.events.as_ref().map_or(0, |events| events.len() as u64)
+    }
+
+    fn record(&mut self, event: TraceEvent) {
+        let capacity = self.capacity();
+        if let Some(events) = self.events.as_mut().filter(|_| capacity != 0) {
+            events[(self.recorded % capacity) as usize] = event;
+            self.recorded += 1;
+        }
+    }
+
+    /// Returns the number of the oldest event still in the buffer.
+    fn oldest(&self) -> u64 {
+        self.recorded.saturating_sub(self.capacity())
+    }
+
+    /// Returns the event with the given number, if it is in the buffer.
+    fn get(&self, number: u64) -> Option<TraceEvent> {
+        let events = self.events.as_ref()?;
+        (self.oldest() <= number && number < self.recorded)
+            .then(|| events[(number % events.len() as u64) as usize])
+    }
+
+    /// Returns the events in the buffer, oldest first.
+    fn iter(&self) -> impl Iterator<Item = &TraceEvent> + Clone {
+        let (oldest, newest) = match &self.events {
+            // Once wrapped around, the oldest event is the next to overwrite.
+            Some(events) if self.recorded >= events.len() as u64 => {
+                let (newest, oldest) =
+                    events.split_at((self.recorded % events.len() as u64) as usize);
+                (oldest, newest)
+            }
+            Some(events) => (&events[..self.recorded as usize], &events[..0]),
+            None => (&[][..], &[][..]),
+        };
+        oldest.iter().chain(newest)
+    }
+}
+
+/// Why a trace couldn't be replayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum TraceError {
+    /// The trace ends in the middle of an event
+    Truncated,
+    /// An event at the given offset is not valid
+    Invalid(usize),
+    /// More than [`REPLAY_SLOTS`] allocations were live at once
+    TooManyLive,
+}
+
+/// What happened during a [`replay`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct ReplayReport {
+    /// Number of events replayed
+    pub events: usize,
+    /// Allocations that failed on the device
+    pub recorded_failures: usize,
+    /// Allocations that succeeded on the device but failed in the replay
+    pub new_failures: usize,
+    /// Allocations that failed on the device but succeeded in the replay
+    pub new_successes: usize,
+    /// Deallocations of addresses that weren't allocated in the trace, like
+    /// allocations made before recording started, or whose allocation failed
+    /// in the replay
+    pub unknown_frees: usize,
+}
+
+struct Encoder {
+    buffer: [u8; MAX_EVENT_SIZE],
+    len: usize,
+}
+
+impl Encoder {
+    fn new(op: u8, layout: Layout) -> Self {
+        let mut encoder = Self {
+            buffer: [0; MAX_EVENT_SIZE],
+            len: 2,
+        };
+        encoder.buffer[0] = op;
+        encoder.buffer[1] = layout.align().trailing_zeros() as u8;
+        encoder.push(layout.size());
+        encoder
+    }
+
+    fn push(&mut self, mut value: usize) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+                return;
+            }
+            self.buffer[self.len] = byte | 0x80;
+            self.len += 1;
+        }
+    }
+}
+
+struct Decoder<'a> {
+    trace: &'a [u8],
+    offset: usize,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Result<u8, TraceError> {
+        let byte = *self.trace.get(self.offset).ok_or(TraceError::Truncated)?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn value(&mut self) -> Result<usize, TraceError> {
+        let start = self.offset;
+        let mut value = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.byte()?;
+            value |= usize::from(byte & 0x7f)
+                .checked_shl(shift)
+                .ok_or(TraceError::Invalid(start))?;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(TraceError::Invalid(start))
+    }
+
+    /// Returns the next event as `(op, layout, address)`.
+    fn event(&mut self) -> Result<(u8, Layout, usize), TraceError> {
+        let start = self.offset;
+        let op = self.byte()?;
+        let align = 1usize
+            .checked_shl(u32::from(self.byte()?))
+            .ok_or(TraceError::Invalid(start))?;
+        let size = self.value()?;
+        let address = self.value()?;
+
+        let layout =
+            Layout::from_size_align(size, align).map_err(|_| TraceError::Invalid(start))?;
+        match op {
+            OP_ALLOC | OP_DEALLOC => Ok((op, layout, address)),
+            _ => Err(TraceError::Invalid(start)),
+        }
+    }
+}
+
+impl EspHeap {
+    /// Streams every allocation and deallocation to `sink` in the binary
+    /// trace format understood by [`replay`]
+    ///
+    /// Each call of `sink` receives exactly one event. It runs inside a
+    /// critical section and must not use the heap, so it should only copy
+    /// the bytes somewhere, like a ring buffer drained by a logging task.
+    pub fn set_trace_sink(&self, sink: TraceSink) {
+        self.locked(|cs| self.trace_sink.borrow(cs).set(Some(sink)));
+    }
+
+    /// Stops streaming the trace
+    pub fn clear_trace_sink(&self) {
+        self.locked(|cs| self.trace_sink.borrow(cs).set(None));
+    }
+
+    /// Passes every allocation and deallocation to `hook`, along with the
+    /// region the allocator routed it to
+    ///
+    /// Unlike the binary trace, the events tell which region served an
+    /// allocation and which one a freed block went back to, so offline
+    /// analysis doesn't have to know the memory map. The hook is independent
+    /// of the trace sink, and both may be set at once. It runs inside a
+    /// critical section and must not use the heap.
+    pub fn set_trace_hook(&self, hook: TraceHook) {
+        self.locked(|cs| self.trace_hook.borrow(cs).set(Some(hook)));
+    }
+
+    /// Stops passing events to the trace hook
+    pub fn clear_trace_hook(&self) {
+        self.locked(|cs| self.trace_hook.borrow(cs).set(None));
+    }
+
+    /// Keeps the latest allocations and deallocations in `buffer`
+    ///
+    /// Once `buffer` is full, every event overwrites the oldest one. The heap
+    /// never allocates for its own tracing, and recording an event only
+    /// stores it into the buffer, in the critical section of the operation.
+    /// Read the events with
+    /// [`trace_events`](struct.EspHeap.html#method.trace_events), or fold
+    /// them with [`live_summary`](struct.EspHeap.html#method.live_summary).
+    /// Replaces any buffer set before.
+    ///
+    /// ```
+    /// use esp_alloc::{EspHeap, TraceEvent};
+    ///
+    /// static HEAP: EspHeap = EspHeap::empty();
+    /// static mut EVENTS: [TraceEvent; 256] = [TraceEvent::EMPTY; 256];
+    ///
+    /// HEAP.set_trace_buffer(unsafe { &mut *core::ptr::addr_of_mut!(EVENTS) });
+    /// ```
+    pub fn set_trace_buffer(&self, buffer: &'static mut [TraceEvent]) {
+        self.locked(|cs| {
+            self.trace_ring.borrow(cs).replace(TraceRing {
+                events: Some(buffer),
+                recorded: 0,
+            })
+        });
+    }
+
+    /// Stops recording events into the trace buffer, handing it back
+    pub fn clear_trace_buffer(&self) -> Option<&'static mut [TraceEvent]> {
+        self.locked(|cs| self.trace_ring.borrow(cs).replace(TraceRing::new()).events)
+    }
+
+    /// Returns the events in the trace buffer, oldest first
+    ///
+    /// The iterator ends with the last event recorded when it was created.
+    /// It reads one event at a time in a critical section of its own, so the
+    /// heap keeps working while it is consumed, and events overwritten in the
+    /// meantime are skipped.
+    pub fn trace_events(&self) -> impl Iterator<Item = TraceEvent> + '_ {
+        let (mut next, end) = self.locked(|cs| {
+            let ring = self.trace_ring.borrow(cs).borrow();
+            (ring.oldest(), ring.recorded)
+        });
+        iter::from_fn(move || {
+            self.locked(|cs| {
+                let ring = self.trace_ring.borrow(cs).borrow();
+                next = next.max(ring.oldest());
+                if next >= end {
+                    return None;
+                }
+                let event = ring.get(next)?;
+                next += 1;
+                Some(event)
+            })
+        })
+    }
+
+    /// Folds the events in the trace buffer into the allocations that
+    /// haven't been freed since, grouped by size
+    ///
+    /// An allocation counts as live unless a later event in the buffer frees
+    /// it, so with a buffer that wrapped around this is what the latest
+    /// events left allocated. A slow leak shows up as a size class that
+    /// keeps growing from one summary to the next.
+    ///
+    /// The events are matched in a critical section, which takes time
+    /// quadratic in the size of the buffer, so this shouldn't be called from
+    /// latency sensitive code.
+    pub fn live_summary(&self) -> LiveSummary {
+        self.locked(|cs| {
+            let ring = self.trace_ring.borrow(cs).borrow();
+            let mut summary = LiveSummary::new();
+            summary.overwritten = ring.oldest();
+            let mut events = ring.iter();
+            while let Some(event) = events.next() {
+                summary.events += 1;
+                let same_block = |other: &&TraceEvent| other.address == event.address;
+                match event.op {
+                    TraceOp::Alloc if event.address != 0 => {
+                        let freed = events
+                            .clone()
+                            .filter(same_block)
+                            .any(|later| later.op == TraceOp::Dealloc);
+                        if !freed {
+                            summary.add(event.layout.size());
+                        }
+                    }
+                    TraceOp::Dealloc => {
+                        let allocated = ring
+                            .iter()
+                            .take(summary.events - 1)
+                            .filter(same_block)
+                            .any(|earlier| earlier.op == TraceOp::Alloc);
+                        if !allocated {
+                            summary.unmatched_frees += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            summary
+        })
+    }
+
+    /// Traces an allocation, served by the region with the given index unless
+    /// it failed.
+    pub(crate) fn trace_alloc(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &mut [Region],
+        layout: Layout,
+        served: Option<(usize, NonNull<u8>)>,
+    ) {
+        let (region, address) = match served {
+            Some((index, ptr)) => {
+                regions[index].allocations = regions[index].allocations.wrapping_add(1);
+                (Some(index), ptr.as_ptr() as usize)
+            }
+            None => (None, 0),
+        };
+        self.trace(cs, regions, OP_ALLOC, layout, address, region);
+    }
+
+    pub(crate) fn trace_dealloc(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &mut [Region],
+        layout: Layout,
+        ptr: *mut u8,
+    ) {
+        let region = region_of(regions, ptr);
+        if let Some(index) = region {
+            regions[index].deallocations = regions[index].deallocations.wrapping_add(1);
+        }
+        self.trace(cs, regions, OP_DEALLOC, layout, ptr as usize, region);
+    }
+
+    fn trace(
+        &self,
+        cs: CriticalSection<'_>,
+        regions: &[Region],
+        op: u8,
+        layout: Layout,
+        address: usize,
+        region: Option<usize>,
+    ) {
+        if let Some(sink) = self.trace_sink.borrow(cs).get() {
+            let mut encoder = Encoder::new(op, layout);
+            encoder.push(address);
+            sink(&encoder.buffer[..encoder.len]);
+        }
+
+        let hook = self.trace_hook.borrow(cs).get();
+        let mut ring = self.trace_ring.borrow(cs).borrow_mut();
+        if hook.is_none() && ring.events.is_none() {
+            return;
+        }
+
+        let (op, counter): (_, fn(&Region) -> usize) = match op {
+            OP_ALLOC => (TraceOp::Alloc, |region| region.allocations),
+            _ => (TraceOp::Dealloc, |region| region.deallocations),
+        };
+        let event = TraceEvent {
+            op,
+            layout,
+            address,
+            region: region.map(|index| regions[index].id(index)),
+            sequence: self.sequence.borrow(cs).get(),
+            region_sequence: region.map_or(0, |index| counter(&regions[index])),
+        };
+        ring.record(event);
+        drop(ring);
+        if let Some(hook) = hook {
+            hook(&event);
+        }
+    }
+}
+
+/// Re-executes a recorded trace against `heap`
+///
+/// Addresses from the trace are mapped to the blocks allocated during the
+/// replay, so a trace captured on a device can reproduce its sequence of
+/// operations on a host-side heap, for instance one set up with a different
+/// configuration to test a fix. Allocations still live at the end of the
+/// trace are left allocated, so the heap's final state can be inspected, and
+/// so are the ones live when the replay stops on an error.
+pub fn replay(trace: &[u8], heap: &EspHeap) -> Result<ReplayReport, TraceError> {
+    let mut live: [Option<(usize, *mut u8)>; REPLAY_SLOTS] = [None; REPLAY_SLOTS];
+    let mut report = ReplayReport::default();
+    let mut decoder = Decoder { trace, offset: 0 };
+
+    while decoder.offset < trace.len() {
+        let (op, layout, address) = decoder.event()?;
+        report.events += 1;
+
+        match op {
+            OP_ALLOC => {
+                // SAFETY: `layout` is a valid layout and the block is tracked
+                // in `live` until the trace frees it.
+                let ptr = unsafe { heap.alloc(layout) };
+                match (address == 0, ptr.is_null()) {
+                    (true, true) => report.recorded_failures += 1,
+                    (true, false) => {
+                        report.recorded_failures += 1;
+                        report.new_successes += 1;
+                        // SAFETY: `ptr` was just allocated with `layout`.
+                        unsafe { heap.dealloc(ptr, layout) };
+                    }
+                    (false, true) => report.new_failures += 1,
+                    (false, false) => match live.iter_mut().find(|slot| slot.is_none()) {
+                        Some(slot) => *slot = Some((address, ptr)),
+                        None => {
+                            // SAFETY: `ptr` was just allocated with `layout`
+                            // and is not tracked anywhere.
+                            unsafe { heap.dealloc(ptr, layout) };
+                            return Err(TraceError::TooManyLive);
+                        }
+                    },
+                }
+            }
+            _ => match live
+                .iter_mut()
+                .find(|slot| matches!(slot, Some((recorded, _)) if *recorded == address))
+            {
+                Some(slot) => {
+                    let (_, ptr) = slot.take().unwrap();
+                    // SAFETY: `ptr` was allocated by the replay with the
+                    // layout the trace frees it with.
+                    unsafe { heap.dealloc(ptr, layout) };
+                }
+                None => report.unknown_frees += 1,
+            },
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,436 @@
+//! Usage of every region, for debugging exhaustion
+
+use core::fmt;
+
+use critical_section::CriticalSection;
+
+#[cfg(feature = "heap-guard")]
+use crate::guard;
+use crate::{
+    holes, BudgetUsage, EspHeap, Region, RegionId, RegionStatus, MAX_BUDGETS, MAX_REGIONS,
+};
+
+/// Usage of a single region, see
+/// [`stats`](struct.EspHeap.html#method.stats)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct RegionStats {
+    /// The region
+    pub id: RegionId,
+    /// Whether the region's memory has been added; all other fields are
+    /// zero if it hasn't
+    pub initialized: bool,
+    /// Address of the first byte the region allocates from
+    pub bottom: usize,
+    /// Address one past the last byte the region allocates from
+    pub top: usize,
+    /// Number of bytes the region allocates from
+    pub size: usize,
+    /// Bytes in use, like [`used`](struct.EspHeap.html#method.used)
+    pub used: usize,
+    /// Bytes available, like [`free`](struct.EspHeap.html#method.free)
+    pub free: usize,
+    /// Fewest bytes that were available after an allocation, see
+    /// [`min_free`](struct.EspHeap.html#method.min_free)
+    pub min_free: usize,
+    /// Number of separate blocks the free bytes are split into, see
+    /// [`free_block_count`](struct.EspHeap.html#method.free_block_count)
+    pub free_blocks: usize,
+    /// Largest allocation the region can serve, see
+    /// [`largest_free_block`](struct.EspHeap.html#method.largest_free_block)
+    pub largest_free_block: usize,
+    /// How much of the free space is outside the largest free block, in
+    /// thousandths, see
+    /// [`fragmentation`](struct.EspHeap.html#method.fragmentation)
+    pub fragmentation: u16,
+    /// Failed allocations the region could have served, see
+    /// [`region_failed_allocations`](struct.EspHeap.html#method.region_failed_allocations)
+    /// (the `stats` feature)
+    #[cfg(feature = "stats")]
+    pub failed_allocations: usize,
+}
+
+/// Usage of every region of a heap, see
+/// [`stats`](struct.EspHeap.html#method.stats)
+///
+/// Displays as one line with the totals followed by one line per region and
+/// one per budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct HeapStats {
+    /// Every region slot, in the order the regions were added
+    pub regions: [RegionStats; MAX_REGIONS],
+    /// Bytes all regions allocate from
+    pub size: usize,
+    /// Bytes in use in all regions
+    pub used: usize,
+    /// Bytes requested by all live allocations, see
+    /// [`live_bytes`](struct.EspHeap.html#method.live_bytes)
+    pub live_bytes: usize,
+    /// Bytes available in all regions
+    pub free: usize,
+    /// Sum of the watermarks of all regions
+    pub min_free: usize,
+    /// Largest allocation any region can serve, see
+    /// [`max_free_block`](struct.EspHeap.html#method.max_free_block)
+    pub largest_free_block: usize,
+    /// Every budget, in the order they were created, see
+    /// [`budgets`](struct.EspHeap.html#method.budgets)
+    pub budgets: [Option<BudgetUsage>; MAX_BUDGETS],
+    /// Successful allocations, see
+    /// [`allocation_count`](struct.EspHeap.html#method.allocation_count)
+    /// (the `stats` feature)
+    #[cfg(feature = "stats")]
+    pub allocations: usize,
+    /// Deallocations, see
+    /// [`deallocation_count`](struct.EspHeap.html#method.deallocation_count)
+    /// (the `stats` feature)
+    #[cfg(feature = "stats")]
+    pub deallocations: usize,
+    /// Failed allocations, see
+    /// [`failed_allocations`](struct.EspHeap.html#method.failed_allocations)
+    /// (the `stats` feature)
+    #[cfg(feature = "stats")]
+    pub failed_allocations: usize,
+    /// Bytes of all successful allocations, see
+    /// [`bytes_requested`](struct.EspHeap.html#method.bytes_requested)
+    /// (the `stats` feature)
+    #[cfg(feature = "stats")]
+    pub bytes_requested: usize,
+}
+
+impl HeapStats {
+    fn empty() -> Self {
+        Self {
+            regions: core::array::from_fn(|index| RegionStats {
+                id: RegionId { index, name: "" },
+                initialized: false,
+                bottom: 0,
+                top: 0,
+                size: 0,
+                used: 0,
+                free: 0,
+                min_free: 0,
+                free_blocks: 0,
+                largest_free_block: 0,
+                fragmentation: 0,
+                #[cfg(feature = "stats")]
+                failed_allocations: 0,
+            }),
+            size: 0,
+            used: 0,
+            live_bytes: 0,
+            free: 0,
+            min_free: 0,
+            largest_free_block: 0,
+            budgets: [None; MAX_BUDGETS],
+            #[cfg(feature = "stats")]
+            allocations: 0,
+            #[cfg(feature = "stats")]
+            deallocations: 0,
+            #[cfg(feature = "stats")]
+            failed_allocations: 0,
+            #[cfg(feature = "stats")]
+            bytes_requested: 0,
+        }
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "heap: {} of {} bytes used, {} live, {} free, at least {} free, \
+             largest free block {}",
+            self.used,
+            self.size,
+            self.live_bytes,
+            self.free,
+            self.min_free,
+            self.largest_free_block
+        )?;
+        for region in &self.regions {
+            if !region.initialized {
+                writeln!(f, "{}: not initialized", region.id)?;
+                continue;
+            }
+            writeln!(
+                f,
+                "{}: {:#x}..{:#x}, {} of {} bytes used, {} free, at least {} free, \
+                 {} free blocks, largest {}, {}.{}% fragmented",
+                region.id,
+                region.bottom,
+                region.top,
+                region.used,
+                region.size,
+                region.free,
+                region.min_free,
+                region.free_blocks,
+                region.largest_free_block,
+                region.fragmentation / 10,
+                region.fragmentation % 10
+            )?;
+        }
+        for budget in self.budgets.iter().flatten() {
+            writeln!(
+                f,
+                "budget {}: {} of {} bytes used",
+                budget.name, budget.used, budget.max_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns how much of `free` bytes is outside the largest free block of
+/// `largest` bytes, in thousandths.
+fn fragmentation(free: usize, largest: usize) -> u16 {
+    if free == 0 {
+        return 0;
+    }
+    // Both are at most the size of the address space, which overflows a
+    // 32-bit product.
+    (1000 - largest as u64 * 1000 / free as u64) as u16
+}
+
+/// The free blocks of a region, see `Region::free_blocks`
+pub(crate) struct FreeBlocks {
+    /// Number of free blocks, with adjacent holes counted as one
+    pub(crate) count: usize,
+    /// Size of the largest of them, in bytes
+    pub(crate) largest: usize,
+    /// Largest allocation they can serve, as returned by
+    /// `EspHeap::largest_free_block`
+    pub(crate) largest_fit: usize,
+}
+
+impl Region {
+    /// Walks the free list of the region, with adjacent holes counted as one
+    /// block like [`coalesce`](EspHeap::coalesce) reports them.
+    ///
+    /// The region must be initialized. A region that is handing out its
+    /// retained blocks again has a single hole, at its end, which isn't
+    /// walked so the replay goes on.
+    pub(crate) fn free_blocks(&mut self) -> FreeBlocks {
+        // Isolated allocations are whole lines, aligned to a line.
+        let align = self.cache_line.max(holes::BLOCK_ALIGN);
+        let limit = self.limit();
+        let mut blocks = FreeBlocks {
+            count: 0,
+            largest: 0,
+            largest_fit: 0,
+        };
+
+        let free = self.heap.free();
+        if self.replay.is_some() {
+            if free > 0 {
+                let hole = self.heap.bottom() as usize + self.heap.size() - free;
+                blocks = FreeBlocks {
+                    count: 1,
+                    largest: free,
+                    largest_fit: holes::largest_fit(hole, free, align, limit),
+                };
+            }
+        } else {
+            // Walking writes into the free blocks.
+            self.untouched = self.heap.top() as usize;
+
+            let mut run_end = core::ptr::null_mut();
+            let mut run_size = 0;
+            holes::walk(&mut self.heap, |addr, size| {
+                if addr == run_end {
+                    run_size += size;
+                } else {
+                    blocks.count += 1;
+                    run_size = size;
+                }
+                run_end = addr.wrapping_add(size);
+                blocks.largest = blocks.largest.max(run_size);
+                blocks.largest_fit =
+                    blocks
+                        .largest_fit
+                        .max(holes::largest_fit(addr as usize, size, align, limit));
+            });
+        }
+
+        #[cfg(feature = "heap-guard")]
+        {
+            blocks.largest_fit = blocks.largest_fit.saturating_sub(guard::OVERHEAD);
+        }
+        blocks
+    }
+}
+
+impl EspHeap {
+    /// Returns the usage of every region along with the totals
+    ///
+    /// The totals can look healthy while a single region is exhausted, like
+    /// internal RAM next to a mostly empty PSRAM, which the per-region
+    /// figures show. They are all taken in one critical section, so they
+    /// are consistent with each other.
+    ///
+    /// The free block count and the fragmentation of each region come from
+    /// walking its free list, which takes time quadratic in the number of
+    /// free blocks, all in that critical section. See
+    /// [`used`](struct.EspHeap.html#method.used) and
+    /// [`free`](struct.EspHeap.html#method.free) for figures that don't walk
+    /// anything.
+    pub fn stats(&self) -> HeapStats {
+        self.usage(true)
+    }
+
+    /// Returns the usage of every region, leaving out the free block counts
+    /// and the fragmentation unless `walk` is set.
+    pub(crate) fn usage(&self, walk: bool) -> HeapStats {
+        if !self.is_initialized() {
+            return HeapStats::empty();
+        }
+        self.locked(|cs| self.usage_locked(cs, walk))
+    }
+
+    pub(crate) fn usage_locked(&self, cs: CriticalSection<'_>, walk: bool) -> HeapStats {
+        let mut stats = HeapStats::empty();
+        for (entry, budget) in stats
+            .budgets
+            .iter_mut()
+            .zip(self.budgets.borrow(cs).borrow().iter())
+        {
+            *entry = budget.map(|budget| budget.usage());
+        }
+        #[cfg(feature = "stats")]
+        {
+            let counters = self.counters.borrow(cs).borrow();
+            stats.allocations = counters.allocations;
+            stats.deallocations = counters.deallocations;
+            stats.failed_allocations = counters.failed_allocations;
+            stats.bytes_requested = counters.bytes_requested;
+            for (entry, failures) in stats.regions.iter_mut().zip(counters.region_failures) {
+                entry.failed_allocations = failures;
+            }
+        }
+
+        let mut regions = self.heap.borrow(cs).borrow_mut();
+        for (index, region) in regions.iter_mut().enumerate() {
+            stats.live_bytes += region.live;
+            let entry = &mut stats.regions[index];
+            entry.id = region.id(index);
+            if region.status != RegionStatus::Available {
+                continue;
+            }
+            if walk {
+                let blocks = region.free_blocks();
+                entry.free_blocks = blocks.count;
+                entry.largest_free_block = blocks.largest_fit;
+                entry.fragmentation = fragmentation(region.heap.free(), blocks.largest);
+            }
+            *entry = RegionStats {
+                initialized: true,
+                bottom: region.heap.bottom() as usize,
+                top: region.top() as usize,
+                size: region.size(),
+                used: region.used(),
+                free: region.heap.free(),
+                min_free: region.min_free,
+                ..*entry
+            };
+        }
+        // Quarantined blocks are still counted by their region.
+        #[cfg(feature = "quarantine")]
+        {
+            stats.live_bytes -= self.quarantine.borrow(cs).borrow().bytes;
+        }
+
+        for region in &stats.regions {
+            stats.size += region.size;
+            stats.used += region.used;
+            stats.free += region.free;
+            stats.min_free += region.min_free;
+            stats.largest_free_block = stats.largest_free_block.max(region.largest_free_block);
+        }
+        stats
+    }
+
+    /// Returns the fewest bytes that have been available since the regions
+    /// were added or [`reset_min_free`](struct.EspHeap.html#method.reset_min_free)
+    ///
+    /// The counterpart of ESP-IDF's `heap_caps_get_minimum_free_size`, for
+    /// sizing the memory layout: the free bytes of every region are noted
+    /// after each allocation from it, in the same critical section, and this
+    /// is the sum of each region's lowest figure. As the regions can have
+    /// reached their lows at different times, the heap as a whole may never
+    /// have been this full. [`stats`](struct.EspHeap.html#method.stats)
+    /// reports the watermark of each region.
+    pub fn min_free(&self) -> usize {
+        self.usage(false).min_free
+    }
+
+    /// Restarts the watermarks of
+    /// [`min_free`](struct.EspHeap.html#method.min_free) from the bytes
+    /// available now
+    ///
+    /// Meant for measuring the watermark of one phase of the application.
+    pub fn reset_min_free(&self) {
+        self.locked(|cs| {
+            for region in self.heap.borrow(cs).borrow_mut().iter_mut() {
+                region.min_free = region.heap.free();
+            }
+        });
+    }
+
+    /// Returns the number of separate blocks the free bytes of the given
+    /// region are split into
+    ///
+    /// One on a region with nothing allocated, and rising as the free space
+    /// gets split up between live allocations. Free blocks that are adjacent
+    /// count as one, as they serve allocations like a single block. A region
+    /// that isn't initialized has none.
+    ///
+    /// This walks the region's free list in a critical section, which takes
+    /// time quadratic in the number of free blocks, and so does
+    /// [`fragmentation`](struct.EspHeap.html#method.fragmentation).
+    pub fn free_block_count(&self, region: RegionId) -> usize {
+        self.walk_free_blocks(region).0
+    }
+
+    /// Returns how much of the free space of the given region is outside
+    /// its largest free block, in thousandths
+    ///
+    /// This is `1 - largest free block / free bytes`: `0` while the free
+    /// space is a single block, and approaching `1000` as it is shredded
+    /// into small ones, when allocations start failing even though
+    /// [`free`](struct.EspHeap.html#method.free) reports plenty of memory.
+    /// Logged periodically, for instance through
+    /// [`stats`](struct.EspHeap.html#method.stats), it shows the trend and
+    /// whether a mitigation like pooling helped. A region that isn't
+    /// initialized, or that is full, reports `0`.
+    ///
+    /// The largest free block here is the raw size of the backing
+    /// allocator's hole, without the overheads
+    /// [`largest_free_block`](struct.EspHeap.html#method.largest_free_block)
+    /// takes into account.
+    pub fn fragmentation(&self, region: RegionId) -> u16 {
+        let (_, largest, free) = self.walk_free_blocks(region);
+        fragmentation(free, largest)
+    }
+
+    /// Returns the number of free blocks of `region`, the size of the
+    /// largest and the free bytes, all zero if it isn't initialized.
+    fn walk_free_blocks(&self, region: RegionId) -> (usize, usize, usize) {
+        if !self.is_initialized() {
+            return (0, 0, 0);
+        }
+
+        self.locked(|cs| {
+            let mut regions = self.heap.borrow(cs).borrow_mut();
+            match regions.get_mut(region.index) {
+                Some(region) if region.is_initialized() => {
+                    let blocks = region.free_blocks();
+                    (blocks.count, blocks.largest, region.heap.free())
+                }
+                _ => (0, 0, 0),
+            }
+        })
+    }
+}
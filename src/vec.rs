@@ -0,0 +1,202 @@
+//! A growable array placed in memory with given capabilities
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    slice,
+};
+
+use crate::{EspHeap, MemoryCapability};
+
+/// A growable array on an [`EspHeap`] whose buffer always stays in a region
+/// with the requested capabilities
+///
+/// The stable-channel counterpart of `Vec::new_in`: the buffer and all its
+/// reallocations are served from regions with the given capabilities, and
+/// returned to the same heap on drop. Indexing and slice methods are
+/// available through `Deref<Target = [T]>`.
+pub struct EspVec<'a, T> {
+    ptr: NonNull<T>,
+    len: usize,
+    capacity: usize,
+    heap: &'a EspHeap,
+    capabilities: MemoryCapability,
+    _owns: PhantomData<T>,
+}
+
+// SAFETY: the vector owns its elements, and the heap is `Sync`.
+unsafe impl<T: Send> Send for EspVec<'_, T> {}
+// SAFETY: as above.
+unsafe impl<T: Sync> Sync for EspVec<'_, T> {}
+
+impl<'a, T> EspVec<'a, T> {
+    /// Creates an empty vector that allocates from regions of `heap` with
+    /// `capabilities`
+    ///
+    /// Nothing is allocated until the first element is pushed.
+    pub const fn new_caps(heap: &'a EspHeap, capabilities: MemoryCapability) -> Self {
+        Self {
+            ptr: NonNull::dangling(),
+            len: 0,
+            capacity: if core::mem::size_of::<T>() == 0 {
+                usize::MAX
+            } else {
+                0
+            },
+            heap,
+            capabilities,
+            _owns: PhantomData,
+        }
+    }
+
+    /// Returns the number of elements
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if there are no elements
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of elements the vector can hold without
+    /// reallocating
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the elements as a slice
+    pub fn as_slice(&self) -> &[T] {
+        // SAFETY: the first `len` elements are initialized.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Returns the elements as a mutable slice
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        // SAFETY: as above, and the vector is borrowed mutably.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Makes room for at least `additional` more elements
+    ///
+    /// Returns `false` if the buffer couldn't be grown, in which case the
+    /// vector is unchanged.
+    pub fn reserve(&mut self, additional: usize) -> bool {
+        let required = match self.len.checked_add(additional) {
+            Some(required) => required,
+            None => return false,
+        };
+        if required <= self.capacity {
+            return true;
+        }
+
+        let capacity = required.max(self.capacity.saturating_mul(2)).max(4);
+        let layout = match Layout::array::<T>(capacity) {
+            Ok(layout) => layout,
+            Err(_) => return false,
+        };
+
+        let ptr = if self.capacity == 0 {
+            self.heap.alloc_from(self.capabilities, layout)
+        } else {
+            // SAFETY: the buffer was allocated from `heap` with the current
+            // capacity's layout.
+            unsafe {
+                self.heap.realloc_from(
+                    self.capabilities,
+                    self.ptr.as_ptr().cast(),
+                    self.layout(),
+                    layout.size(),
+                )
+            }
+        };
+
+        match NonNull::new(ptr) {
+            Some(ptr) => {
+                self.ptr = ptr.cast();
+                self.capacity = capacity;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Appends an element
+    ///
+    /// Gives `value` back if the buffer had to grow and couldn't.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.len == self.capacity && !self.reserve(1) {
+            return Err(value);
+        }
+
+        // SAFETY: `len < capacity`, so the slot is part of the buffer.
+        unsafe { self.ptr.as_ptr().add(self.len).write(value) };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the last element and returns it
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+        // SAFETY: the element was initialized and is no longer part of the
+        // vector.
+        Some(unsafe { self.ptr.as_ptr().add(self.len).read() })
+    }
+
+    /// Removes all elements, keeping the buffer
+    pub fn clear(&mut self) {
+        let elements = ptr::slice_from_raw_parts_mut(self.ptr.as_ptr(), self.len);
+        self.len = 0;
+        // SAFETY: the elements were initialized and are no longer part of
+        // the vector.
+        unsafe { elements.drop_in_place() };
+    }
+
+    fn layout(&self) -> Layout {
+        // SAFETY: a buffer of this capacity has been allocated, so its layout
+        // is valid.
+        unsafe {
+            Layout::from_size_align_unchecked(
+                core::mem::size_of::<T>() * self.capacity,
+                core::mem::align_of::<T>(),
+            )
+        }
+    }
+}
+
+impl<T> Deref for EspVec<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T> DerefMut for EspVec<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for EspVec<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_slice(), f)
+    }
+}
+
+impl<T> Drop for EspVec<'_, T> {
+    fn drop(&mut self) {
+        self.clear();
+        if core::mem::size_of::<T>() != 0 && self.capacity != 0 {
+            // SAFETY: the buffer was allocated from `heap` with this layout.
+            unsafe { self.heap.dealloc(self.ptr.as_ptr().cast(), self.layout()) };
+        }
+    }
+}
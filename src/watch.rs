@@ -0,0 +1,171 @@
+//! Software watchpoints on memory ranges
+
+use critical_section::CriticalSection;
+
+use crate::EspHeap;
+
+/// The maximum number of ranges that can be watched at a time
+pub const MAX_WATCHES: usize = 4;
+
+/// A change of a watched range, passed to the handler given to
+/// [`watch_range`](struct.EspHeap.html#method.watch_range)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct WatchHit {
+    /// Address of the watched range
+    pub start: usize,
+    /// Length of the watched range in bytes
+    pub len: usize,
+    /// Allocation sequence number when the range was last seen unchanged
+    pub last_seen: usize,
+    /// Allocation sequence number when the change was noticed
+    pub noticed: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Watch {
+    start: usize,
+    len: usize,
+    checksum: u32,
+    last_seen: usize,
+    handler: fn(WatchHit),
+}
+
+pub(crate) struct Watches {
+    watches: [Option<Watch>; MAX_WATCHES],
+    /// Called with every new range, to program a hardware watchpoint
+    hardware: Option<fn(usize, usize)>,
+}
+
+impl Watches {
+    pub(crate) const fn new() -> Self {
+        Self {
+            watches: [None; MAX_WATCHES],
+            hardware: None,
+        }
+    }
+
+    /// Compares every watched range against its last contents, reporting the
+    /// ones that changed. Returns the number of changed ranges.
+    pub(crate) fn check(&mut self, sequence: usize) -> usize {
+        let mut hits = 0;
+        for watch in self.watches.iter_mut().flatten() {
+            // SAFETY: the range was promised to stay readable by `watch_range`.
+            let checksum = unsafe { checksum(watch.start, watch.len) };
+            if checksum != watch.checksum {
+                (watch.handler)(WatchHit {
+                    start: watch.start,
+                    len: watch.len,
+                    last_seen: watch.last_seen,
+                    noticed: sequence,
+                });
+                watch.checksum = checksum;
+                hits += 1;
+            }
+            watch.last_seen = sequence;
+        }
+        hits
+    }
+}
+
+/// FNV-1a over the bytes of a range
+///
+/// # Safety
+///
+/// `[start, start + len)` must be readable.
+unsafe fn checksum(start: usize, len: usize) -> u32 {
+    let mut hash = 0x811c_9dc5_u32;
+    for offset in 0..len {
+        let byte = ((start + offset) as *const u8).read_volatile();
+        hash = (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+impl EspHeap {
+    /// Watches a memory range for unexpected changes
+    ///
+    /// The contents of the range are recorded now and compared whenever the
+    /// heap's debug checks run: whenever blocks leave the quarantine, where
+    /// they are checked for the poison pattern, on every
+    /// [`check_integrity`](struct.EspHeap.html#method.check_integrity) and on
+    /// every call to [`check_watches`](struct.EspHeap.html#method.check_watches).
+    /// `handler` is called, inside a critical section, with the allocation
+    /// sequence numbers between which a change happened, which narrows down
+    /// who wrote it. The new contents are then recorded so every change is
+    /// reported once. The handler must not use the heap.
+    ///
+    /// Returns `false` if [`MAX_WATCHES`] ranges are already watched.
+    ///
+    /// # Safety
+    ///
+    /// The range must stay readable until it is unwatched.
+    pub unsafe fn watch_range(&self, start: *const u8, len: usize, handler: fn(WatchHit)) -> bool {
+        let start = start as usize;
+        self.locked(|cs| {
+            let mut watches = self.watches.borrow(cs).borrow_mut();
+            let sequence = self.sequence.borrow(cs).get();
+            let hardware = watches.hardware;
+            let slot = match watches.watches.iter_mut().find(|watch| watch.is_none()) {
+                Some(slot) => slot,
+                None => return false,
+            };
+            *slot = Some(Watch {
+                start,
+                len,
+                checksum: checksum(start, len),
+                last_seen: sequence,
+                handler,
+            });
+
+            if let Some(program) = hardware {
+                program(start, len);
+            }
+            true
+        })
+    }
+
+    /// Stops watching the range starting at `start`
+    pub fn unwatch_range(&self, start: *const u8) {
+        self.locked(|cs| {
+            let mut watches = self.watches.borrow(cs).borrow_mut();
+            for slot in watches.watches.iter_mut() {
+                if matches!(slot, Some(watch) if watch.start == start as usize) {
+                    *slot = None;
+                }
+            }
+        });
+    }
+
+    /// Registers a function that programs a hardware watchpoint
+    ///
+    /// On chips where the HAL exposes the debug watchpoint registers,
+    /// `program` is called with the address and length of every range passed
+    /// to [`watch_range`](struct.EspHeap.html#method.watch_range) afterwards,
+    /// so the offending write traps right away instead of being noticed by
+    /// the next software check.
+    pub fn set_watchpoint_hook(&self, program: fn(usize, usize)) {
+        self.locked(|cs| {
+            self.watches.borrow(cs).borrow_mut().hardware = Some(program);
+        });
+    }
+
+    /// Checks all watched ranges now, returning the number that changed
+    pub fn check_watches(&self) -> usize {
+        self.locked(|cs| self.check_watches_locked(cs))
+    }
+
+    pub(crate) fn check_watches_locked(&self, cs: CriticalSection<'_>) -> usize {
+        let sequence = self.sequence.borrow(cs).get();
+        self.watches.borrow(cs).borrow_mut().check(sequence)
+    }
+
+    /// Returns the number of allocations made so far
+    ///
+    /// Every allocation gets the next number, which is what
+    /// [`WatchHit`] reports.
+    pub fn allocation_sequence(&self) -> usize {
+        self.locked(|cs| self.sequence.borrow(cs).get())
+    }
+}
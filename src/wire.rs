@@ -0,0 +1,100 @@
+//! Heap statistics in a fixed binary format
+
+use crate::{EspHeap, RegionStatus, MAX_REGIONS};
+
+/// Version of the format written by
+/// [`stats_bytes`](struct.EspHeap.html#method.stats_bytes)
+pub const STATS_FORMAT_VERSION: u8 = 1;
+
+/// Length of the snapshot written by
+/// [`stats_bytes`](struct.EspHeap.html#method.stats_bytes)
+pub const STATS_BYTES_LEN: usize = HEADER_LEN + MAX_REGIONS * REGION_LEN;
+
+const HEADER_LEN: usize = 32;
+const REGION_LEN: usize = 16;
+
+struct Writer {
+    bytes: [u8; STATS_BYTES_LEN],
+    offset: usize,
+}
+
+impl Writer {
+    fn put(&mut self, value: usize) {
+        let value = u32::try_from(value).unwrap_or(u32::MAX);
+        self.bytes[self.offset..self.offset + 4].copy_from_slice(&value.to_le_bytes());
+        self.offset += 4;
+    }
+}
+
+impl EspHeap {
+    /// Returns a snapshot of the heap statistics in a stable binary format
+    ///
+    /// Meant for host tools that decode heap telemetry from a byte stream.
+    /// All values are little-endian `u32`s, saturated if they don't fit, at
+    /// these offsets:
+    ///
+    /// | Offset | Contents                                                  |
+    /// |--------|-----------------------------------------------------------|
+    /// | 0      | Format version, a byte: [`STATS_FORMAT_VERSION`]          |
+    /// | 1      | Number of available regions, a byte                       |
+    /// | 2      | Flags, a byte: bit 0 is set if failures are counted       |
+    /// | 3      | Number of region records, a byte: [`MAX_REGIONS`]        |
+    /// | 4      | [`used`](struct.EspHeap.html#method.used)                 |
+    /// | 8      | [`free`](struct.EspHeap.html#method.free)                 |
+    /// | 12     | [`live_bytes`](struct.EspHeap.html#method.live_bytes)     |
+    /// | 16     | [`free_general`](struct.EspHeap.html#method.free_general) |
+    /// | 20     | [`largest_allocation`](struct.EspHeap.html#method.largest_allocation) |
+    /// | 24     | Failed allocations, `0` unless counted (the `stats` feature) |
+    /// | 28     | [`allocation_sequence`](struct.EspHeap.html#method.allocation_sequence) |
+    /// | 32     | The region records, 16 bytes each                         |
+    ///
+    /// A region record holds the region's bottom address, size, used bytes
+    /// and free bytes, in that order. Records are in the order the regions
+    /// were added; slots without an available region are all zeros.
+    ///
+    /// New versions of the format only ever append fields and bump the
+    /// version, so a decoder can read any newer snapshot as far as it knows
+    /// the format, and firmware and host tools can evolve independently.
+    pub fn stats_bytes(&self) -> [u8; STATS_BYTES_LEN] {
+        let mut writer = Writer {
+            bytes: [0; STATS_BYTES_LEN],
+            offset: 4,
+        };
+
+        // Everything is read in one critical section, so the snapshot is
+        // consistent.
+        self.locked(|cs| {
+            let stats = self.usage_locked(cs, false);
+            writer.put(stats.used);
+            writer.put(stats.free);
+            writer.put(stats.live_bytes);
+            writer.put(self.free_general_locked(cs));
+            writer.put(self.largest_allocation.borrow(cs).get());
+            #[cfg(feature = "stats")]
+            writer.put(stats.failed_allocations);
+            #[cfg(not(feature = "stats"))]
+            writer.put(0);
+            writer.put(self.sequence.borrow(cs).get());
+
+            let regions = self.heap.borrow(cs).borrow();
+            let mut count = 0;
+            for region in regions.iter() {
+                if region.status == RegionStatus::Available {
+                    writer.put(region.heap.bottom() as usize);
+                    writer.put(region.size());
+                    writer.put(region.used());
+                    writer.put(region.heap.free());
+                    count += 1;
+                } else {
+                    writer.offset += REGION_LEN;
+                }
+            }
+            writer.bytes[1] = count;
+        });
+
+        writer.bytes[0] = STATS_FORMAT_VERSION;
+        writer.bytes[2] = u8::from(cfg!(feature = "stats"));
+        writer.bytes[3] = MAX_REGIONS as u8;
+        writer.bytes
+    }
+}
@@ -0,0 +1,65 @@
+//! Preference for the regions a core reaches fastest
+
+mod common;
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+};
+
+use common::{add, region_of};
+use esp_alloc::{EspHeap, MemoryCapability, MAX_CORES};
+
+thread_local! {
+    static CORE: Cell<usize> = const { Cell::new(0) };
+}
+
+fn current_core() -> usize {
+    CORE.with(Cell::get)
+}
+
+fn alloc_on(heap: &EspHeap, core: usize, layout: Layout) -> *mut u8 {
+    CORE.with(|current| current.set(core));
+    unsafe { heap.alloc(layout) }
+}
+
+#[test]
+fn tries_the_cheapest_regions_first() {
+    let heap = EspHeap::empty();
+    let (first, _) = add(&heap, 4096, MemoryCapability::INTERNAL);
+    let (second, _) = add(&heap, 4096, MemoryCapability::INTERNAL);
+    add(&heap, 4096, MemoryCapability::INTERNAL);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    // Without a source the costs are ignored.
+    heap.set_region_core_affinity(first, 0, 9);
+    heap.set_region_core_affinity(first, 1, 9);
+    heap.set_region_core_affinity(second, 1, 3);
+    let ptr = alloc_on(&heap, 0, layout);
+    assert_eq!(region_of(&heap, ptr), Some(0));
+    unsafe { heap.dealloc(ptr, layout) };
+
+    heap.set_core_id_source(current_core);
+    // Equally cheap regions go in the order they were added.
+    let ptr = alloc_on(&heap, 0, layout);
+    assert_eq!(region_of(&heap, ptr), Some(1));
+    unsafe { heap.dealloc(ptr, layout) };
+    let ptr = alloc_on(&heap, 1, layout);
+    assert_eq!(region_of(&heap, ptr), Some(2));
+    unsafe { heap.dealloc(ptr, layout) };
+    // Unknown cores ignore the costs.
+    let ptr = alloc_on(&heap, MAX_CORES, layout);
+    assert_eq!(region_of(&heap, ptr), Some(0));
+    unsafe { heap.dealloc(ptr, layout) };
+
+    // Costlier regions are the fallback.
+    let big = Layout::from_size_align(3000, 8).unwrap();
+    let blocks: Vec<_> = (0..3).map(|_| alloc_on(&heap, 1, big)).collect();
+    let regions: Vec<_> = blocks.iter().map(|ptr| region_of(&heap, *ptr)).collect();
+    assert_eq!(regions, [Some(2), Some(1), Some(0)]);
+    for ptr in blocks {
+        unsafe { heap.dealloc(ptr, big) };
+    }
+
+    assert_eq!(heap.used(), 0);
+}
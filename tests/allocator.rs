@@ -0,0 +1,98 @@
+//! The `Allocator` trait (the `nightly` feature)
+
+#![cfg(feature = "nightly")]
+#![feature(allocator_api)]
+
+mod common;
+
+use core::alloc::{Allocator, Layout};
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability};
+
+#[test]
+fn backs_collections() {
+    let heap = EspHeap::empty();
+    add(&heap, 16 * 1024, MemoryCapability::empty());
+
+    let mut bytes: Vec<u8, &EspHeap> = Vec::new_in(&heap);
+    for byte in 0..4000u32 {
+        bytes.push(byte as u8);
+    }
+    let mut words: Vec<u32, &EspHeap> = Vec::with_capacity_in(100, &heap);
+    words.extend(0..100);
+    assert!(heap.live_bytes() >= 4000 + 400);
+    assert!(bytes.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+
+    bytes.shrink_to_fit();
+    drop(bytes);
+    assert_eq!(words.iter().sum::<u32>(), 4950);
+    drop(words);
+    assert_eq!((heap.used(), heap.live_bytes()), (0, 0));
+}
+
+#[test]
+fn zeroes_reused_memory() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let layout = Layout::from_size_align(256, 8).unwrap();
+
+    let block = heap.allocate(layout).unwrap();
+    assert!(block.len() >= layout.size());
+    unsafe { block.cast::<u8>().as_ptr().write_bytes(0xee, block.len()) };
+    unsafe { heap.deallocate(block.cast(), layout) };
+
+    let zeroed = heap.allocate_zeroed(layout).unwrap();
+    let bytes = unsafe { zeroed.as_ref() };
+    assert!(bytes.iter().all(|&byte| byte == 0));
+    unsafe { heap.deallocate(zeroed.cast(), layout) };
+}
+
+#[test]
+fn handles_zero_sized_allocations() {
+    let heap = EspHeap::empty();
+    add(&heap, 1024, MemoryCapability::empty());
+    let layout = Layout::from_size_align(0, 16).unwrap();
+
+    let block = heap.allocate(layout).unwrap();
+    assert_eq!(block.len(), 0);
+    assert_eq!(block.cast::<u8>().as_ptr() as usize % 16, 0);
+    unsafe { heap.deallocate(block.cast(), layout) };
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn keeps_budgets() {
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    let budget = heap.budget("frames", 1024);
+
+    let mut frame: Vec<u8, _> = Vec::with_capacity_in(512, budget);
+    frame.resize(512, 1);
+    assert_eq!(budget.usage().used, 512);
+    assert!(frame.try_reserve_exact(1024).is_err());
+    drop(frame);
+    assert_eq!(budget.usage().used, 0);
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+#[cfg(feature = "defmt")]
+fn forwards_through_logged() {
+    use esp_alloc::Logged;
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let mut bytes: Vec<u8, Logged<'_>> = Vec::new_in(Logged(&heap));
+    bytes.extend(0..200);
+    assert!(heap.live_bytes() >= 200);
+    let zeroed = Logged(&heap)
+        .allocate_zeroed(Layout::new::<[u8; 64]>())
+        .unwrap();
+    assert!(unsafe { zeroed.as_ref() }.iter().all(|&byte| byte == 0));
+
+    unsafe { Logged(&heap).deallocate(zeroed.cast(), Layout::new::<[u8; 64]>()) };
+    assert!(bytes.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+    drop(bytes);
+    assert_eq!(heap.used(), 0);
+}
@@ -0,0 +1,113 @@
+//! Many allocations in one critical section
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::{add, region_of};
+use esp_alloc::{EarlyPolicy, EspHeap, MemoryCapability};
+
+fn layout(size: usize, align: usize) -> Layout {
+    Layout::from_size_align(size, align).unwrap()
+}
+
+#[test]
+fn allocates_every_layout_it_can() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let layouts = [
+        layout(10, 1),
+        layout(100, 32),
+        layout(8192, 8),
+        layout(1, 64),
+    ];
+    let mut out = [core::ptr::null_mut(); 4];
+    heap.alloc_batch(&layouts, &mut out);
+
+    assert!(out[2].is_null());
+    let mut blocks: Vec<_> = layouts
+        .iter()
+        .zip(out)
+        .filter(|(_, ptr)| !ptr.is_null())
+        .collect();
+    assert_eq!(blocks.len(), 3);
+    assert!(blocks
+        .iter()
+        .all(|(layout, ptr)| *ptr as usize % layout.align() == 0));
+    blocks.sort_by_key(|(_, ptr)| *ptr as usize);
+    assert!(blocks
+        .windows(2)
+        .all(|pair| pair[0].1 as usize + pair[0].0.size() <= pair[1].1 as usize));
+    assert_eq!(heap.live_bytes(), 111);
+
+    for (layout, ptr) in blocks {
+        unsafe { heap.dealloc(ptr, *layout) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn restricts_each_layout_on_its_own() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::INTERNAL);
+    add(&heap, 8192, MemoryCapability::EXTERNAL);
+    heap.set_early_limit(256, EarlyPolicy::Deny);
+    heap.set_large_alloc_threshold(1024);
+
+    // Together the layouts are above both limits, each of the small ones is
+    // below them.
+    let layouts = [
+        layout(200, 8),
+        layout(200, 8),
+        layout(2000, 8),
+        layout(200, 8),
+    ];
+    let mut out = [core::ptr::null_mut(); 4];
+    heap.alloc_batch(&layouts, &mut out);
+    assert!(out[2].is_null());
+    for index in [0, 1, 3] {
+        assert_eq!(region_of(&heap, out[index]), Some(0));
+    }
+
+    heap.finalize();
+    let mut large = [core::ptr::null_mut(); 1];
+    heap.alloc_batch(&layouts[2..3], &mut large);
+    assert_eq!(region_of(&heap, large[0]), Some(1));
+
+    for (layout, ptr) in layouts.iter().zip(out).chain([(&layouts[2], large[0])]) {
+        if !ptr.is_null() {
+            unsafe { heap.dealloc(ptr, *layout) };
+        }
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+#[cfg(feature = "alloc-hooks")]
+fn reports_each_allocation_to_the_hooks() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+    fn on_alloc(_: *mut u8, size: usize) {
+        ALLOCATED.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn on_free(_: *mut u8, size: usize) {
+        ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_alloc_hooks(on_alloc, on_free);
+    let layouts = [layout(10, 1), layout(8192, 8), layout(100, 4)];
+    let mut out = [core::ptr::null_mut(); 3];
+    heap.alloc_batch(&layouts, &mut out);
+    assert_eq!(ALLOCATED.load(Ordering::Relaxed), 110);
+
+    unsafe {
+        heap.dealloc(out[0], layouts[0]);
+        heap.dealloc(out[2], layouts[2]);
+    }
+    assert_eq!(ALLOCATED.load(Ordering::Relaxed), 0);
+}
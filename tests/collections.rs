@@ -0,0 +1,110 @@
+//! Boxes and vectors placed in regions with given capabilities
+
+mod common;
+
+use std::{cell::Cell, rc::Rc};
+
+use common::{add, region_of};
+use esp_alloc::{EspBox, EspHeap, EspVec, MemoryCapability};
+
+/// Counts its drops.
+struct Tracked(Rc<Cell<usize>>);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+#[test]
+fn grows_a_vector_in_regions_with_its_capabilities() {
+    let heap = EspHeap::empty();
+    add(&heap, 8 * 1024, MemoryCapability::empty());
+    add(&heap, 8 * 1024, MemoryCapability::DMA);
+
+    let mut vec = EspVec::new_caps(&heap, MemoryCapability::DMA);
+    assert!(vec.is_empty());
+    assert_eq!(heap.used(), 0);
+    for value in 0..100u32 {
+        vec.push(value).unwrap();
+        assert_eq!(region_of(&heap, vec.as_ptr() as *mut u8), Some(1));
+    }
+    assert_eq!(vec.len(), 100);
+    assert!(vec.capacity() >= 100);
+    assert_eq!(vec[42], 42);
+    assert_eq!(vec.as_slice().iter().sum::<u32>(), 99 * 100 / 2);
+
+    vec.as_mut_slice()[0] = 1000;
+    vec[1] = 2000;
+    assert_eq!(&vec[..3], &[1000, 2000, 2]);
+    assert_eq!(vec.pop(), Some(99));
+    assert_eq!(vec.len(), 99);
+
+    vec.clear();
+    assert!(vec.is_empty());
+    assert_eq!(vec.pop(), None);
+    drop(vec);
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn gives_the_element_back_if_the_vector_cannot_grow() {
+    let heap = EspHeap::empty();
+    add(&heap, 1024, MemoryCapability::empty());
+
+    // No region has the capabilities.
+    let mut vec = EspVec::new_caps(&heap, MemoryCapability::DMA);
+    assert_eq!(vec.push(7u8), Err(7));
+    assert!(!vec.reserve(1));
+
+    let mut vec = EspVec::<u8>::new_caps(&heap, MemoryCapability::empty());
+    assert!(!vec.reserve(4096));
+    assert!(vec.reserve(16));
+    assert!(vec.capacity() >= 16);
+    drop(vec);
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn drops_the_elements_of_a_vector() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let drops = Rc::new(Cell::new(0));
+
+    let mut vec = EspVec::new_caps(&heap, MemoryCapability::empty());
+    for _ in 0..5 {
+        assert!(vec.push(Tracked(drops.clone())).is_ok());
+    }
+    drop(vec.pop());
+    assert_eq!(drops.get(), 1);
+    drop(vec);
+    assert_eq!(drops.get(), 5);
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn boxes_a_value_in_a_region_with_its_capabilities() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    add(&heap, 4096, MemoryCapability::DMA);
+
+    let mut boxed = EspBox::new_caps(&heap, MemoryCapability::DMA, [7u32; 16]).unwrap();
+    let ptr = &*boxed as *const [u32; 16] as *mut u8;
+    assert_eq!(region_of(&heap, ptr), Some(1));
+    assert!(heap.used() >= 64);
+    assert_eq!(boxed[3], 7);
+    boxed[3] = 8;
+    assert_eq!(EspBox::into_inner(boxed)[..4], [7, 7, 7, 8]);
+    assert_eq!(heap.used(), 0);
+
+    let drops = Rc::new(Cell::new(0));
+    let boxed = EspBox::new_caps(&heap, MemoryCapability::empty(), Tracked(drops.clone()));
+    assert!(boxed.is_ok());
+    drop(boxed);
+    assert_eq!(drops.get(), 1);
+    assert_eq!(heap.used(), 0);
+
+    // No region has room, so the value comes back.
+    let value = EspBox::new_caps(&heap, MemoryCapability::DMA, [0u8; 8192]);
+    assert!(matches!(value, Err(value) if value.len() == 8192));
+}
@@ -0,0 +1,67 @@
+//! Helpers shared by the host tests
+
+#![allow(dead_code)]
+
+use std::alloc::Layout;
+
+use esp_alloc::{EspHeap, MemoryCapability, RegionId};
+
+/// Returns `size` bytes of zeroed memory, aligned to 64 bytes.
+///
+/// The memory is leaked, so it outlives every heap it is added to.
+pub fn memory(size: usize) -> *mut u8 {
+    let layout = Layout::from_size_align(size, 64).unwrap();
+    // SAFETY: every test region is bigger than zero bytes.
+    let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+    assert!(!ptr.is_null());
+    ptr
+}
+
+/// Adds `size` bytes of fresh memory to `heap` as a region with
+/// `capabilities`, returning its id and its memory.
+pub fn add(heap: &EspHeap, size: usize, capabilities: MemoryCapability) -> (RegionId, *mut u8) {
+    let bottom = memory(size);
+    // SAFETY: the memory is leaked and used for nothing else.
+    let id = unsafe { heap.add_region(bottom, size, capabilities) };
+    // The tests expect freed blocks back on the free list right away.
+    #[cfg(feature = "quarantine")]
+    heap.set_quarantine_limits(0, 0);
+    (id, bottom)
+}
+
+/// Returns the index of the region of `heap` that `ptr` lies in.
+pub fn region_of(heap: &EspHeap, ptr: *mut u8) -> Option<usize> {
+    let address = ptr as usize;
+    heap.region_configs()
+        .find(|region| region.bottom <= address && address < region.bottom + region.size)
+        .map(|region| region.id.index())
+}
+
+/// A reproducible stream of pseudo-random numbers.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    /// Returns a number in `0..bound`.
+    pub fn below(&mut self, bound: usize) -> usize {
+        // xorshift64*
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as usize % bound
+    }
+}
+
+/// Returns the message of a caught panic.
+pub fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload
+            .downcast::<&str>()
+            .map(|message| message.to_string())
+            .unwrap_or_default(),
+    }
+}
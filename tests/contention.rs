@@ -0,0 +1,82 @@
+//! Allocation that gives up instead of waiting for the heap
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+use common::add;
+use esp_alloc::{EarlyPolicy, EspHeap, MemoryCapability, TryAllocError};
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+#[test]
+fn fails_while_the_heap_is_in_use() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static RESULTS: Mutex<Vec<Result<(), TryAllocError>>> = Mutex::new(Vec::new());
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+
+    fn spin() {
+        SPINS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The hook runs in the middle of an allocation, like an interrupt
+    // handler that preempted it would.
+    fn hook(_: Layout) {
+        let mut results = RESULTS.lock().unwrap();
+        results.push(HEAP.try_alloc(layout(16)).map(drop));
+        results.push(HEAP.try_alloc_bounded(layout(16), 3, spin).map(drop));
+    }
+
+    add(&HEAP, 4096, MemoryCapability::empty());
+    HEAP.set_early_limit(512, EarlyPolicy::Report);
+    HEAP.set_early_hook(hook);
+
+    let ptr = unsafe { HEAP.alloc(layout(1000)) };
+    assert!(!ptr.is_null());
+    assert_eq!(
+        *RESULTS.lock().unwrap(),
+        [Err(TryAllocError::Contended), Err(TryAllocError::Contended)]
+    );
+    assert_eq!(SPINS.load(Ordering::Relaxed), 2);
+    #[cfg(all(feature = "stats", not(feature = "multicore")))]
+    assert_eq!(HEAP.contended_allocations(), 4);
+    assert_eq!(HEAP.live_bytes(), 1000);
+
+    unsafe { HEAP.dealloc(ptr, layout(1000)) };
+    assert_eq!(HEAP.used(), 0);
+}
+
+#[test]
+fn allocates_when_the_heap_is_free() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+
+    let ptr = heap.try_alloc(layout(100)).unwrap();
+    assert_eq!(heap.live_bytes(), 100);
+    assert_eq!(
+        heap.try_alloc(layout(8192)),
+        Err(TryAllocError::OutOfMemory)
+    );
+    assert_eq!(TryAllocError::OutOfMemory.to_string(), "out of memory");
+    assert_eq!(TryAllocError::Contended.to_string(), "heap in use");
+    // Zero-sized allocations never touch the heap.
+    assert_eq!(heap.try_alloc(layout(0)).unwrap().as_ptr() as usize, 8);
+
+    // Running out of memory is never retried.
+    fn never() {
+        unreachable!();
+    }
+    assert_eq!(
+        heap.try_alloc_bounded(layout(8192), 5, never),
+        Err(TryAllocError::OutOfMemory)
+    );
+
+    unsafe { heap.dealloc(ptr.as_ptr(), layout(100)) };
+    assert_eq!(heap.used(), 0);
+}
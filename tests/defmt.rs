@@ -0,0 +1,81 @@
+//! `defmt` support, and logging of failed allocations over it
+
+#![cfg(feature = "defmt")]
+
+mod common;
+
+use esp_alloc::{
+    ConfigSummary, HeapStats, MemoryCapability, OomAction, RegionConfig, RegionId, RegionStats,
+};
+
+fn assert_format<T: defmt::Format>() {}
+
+#[test]
+fn formats_the_stats_types() {
+    assert_format::<HeapStats>();
+    assert_format::<RegionStats>();
+    assert_format::<RegionId>();
+    assert_format::<ConfigSummary>();
+    assert_format::<RegionConfig>();
+    assert_format::<MemoryCapability>();
+    assert_format::<OomAction>();
+}
+
+#[test]
+#[cfg(feature = "defmt-failures")]
+fn logs_failed_allocations_after_the_oom_handler() {
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use esp_alloc::EspHeap;
+
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+    fn handler(_: Layout, _: &HeapStats) -> OomAction {
+        HANDLED.fetch_add(1, Ordering::Relaxed);
+        OomAction::Fail
+    }
+
+    let heap = EspHeap::empty();
+    common::add(&heap, 1024, MemoryCapability::INTERNAL);
+    heap.set_oom_handler(handler);
+    assert!(heap.config_summary().defmt_failures);
+
+    // Logging happens outside the critical section, after the handler.
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    assert!(unsafe { heap.alloc(layout) }.is_null());
+    assert_eq!(HANDLED.load(Ordering::Relaxed), 1);
+    let ptr = unsafe { heap.alloc(Layout::from_size_align(64, 8).unwrap()) };
+    assert!(!ptr.is_null());
+}
+
+#[test]
+fn forwards_every_operation_through_logged() {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    use esp_alloc::{EspHeap, Logged};
+
+    let heap = EspHeap::empty();
+    common::add(&heap, 4096, MemoryCapability::empty());
+    let logged = Logged(&heap);
+    let layout = Layout::from_size_align(100, 8).unwrap();
+
+    let ptr = unsafe { logged.alloc_zeroed(layout) };
+    assert!(!ptr.is_null());
+    assert!((0..100).all(|offset| unsafe { ptr.add(offset).read() } == 0));
+    unsafe { ptr.write_bytes(0x5a, 100) };
+    assert_eq!(heap.live_bytes(), 100);
+
+    let grown = unsafe { logged.realloc(ptr, layout, 300) };
+    assert!((0..100).all(|offset| unsafe { grown.add(offset).read() } == 0x5a));
+    assert_eq!(heap.live_bytes(), 300);
+    let other = unsafe { logged.alloc(layout) };
+    assert!(!other.is_null());
+    assert!(unsafe { logged.alloc(Layout::from_size_align(8192, 8).unwrap()) }.is_null());
+
+    unsafe {
+        logged.dealloc(grown, Layout::from_size_align(300, 8).unwrap());
+        logged.dealloc(other, layout);
+    }
+    assert_eq!((heap.used(), heap.live_bytes()), (0, 0));
+}
@@ -0,0 +1,302 @@
+//! Random workloads checked against a model of what must hold
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::collections::BTreeMap;
+
+use common::{add, region_of, Rng};
+use esp_alloc::{EspHeap, MemoryCapability, RegionId, MAX_REGIONS};
+
+/// Smallest block the backing allocator hands out, which is also the
+/// smallest remainder it splits off a free block
+const MIN_BLOCK: usize = 2 * core::mem::size_of::<usize>();
+
+struct Block {
+    layout: Layout,
+    fill: u8,
+    /// The region that served the block
+    region: usize,
+}
+
+/// What the heap must look like after the operations so far
+#[derive(Default)]
+struct Model {
+    blocks: BTreeMap<usize, Block>,
+}
+
+impl Model {
+    /// Records a block the heap handed out, which must not overlap any other.
+    fn insert(&mut self, seed: u64, ptr: *mut u8, block: Block) {
+        let start = ptr as usize;
+        let end = start + block.layout.size();
+        let before = self.blocks.range(..=start).next_back();
+        assert!(
+            before.map_or(true, |(addr, other)| addr + other.layout.size() <= start),
+            "seed {seed}: block at {ptr:p} overlaps the one at {:#x}",
+            before.unwrap().0
+        );
+        let after = self.blocks.range(start..).next();
+        assert!(
+            after.map_or(true, |(addr, _)| end <= *addr),
+            "seed {seed}: block at {ptr:p} overlaps the one at {:#x}",
+            after.unwrap().0
+        );
+        self.blocks.insert(start, block);
+    }
+
+    fn live(&self, region: Option<usize>) -> usize {
+        self.blocks
+            .values()
+            .filter(|block| region.map_or(true, |region| block.region == region))
+            .map(|block| block.layout.size())
+            .sum()
+    }
+
+    fn pick(&self, rng: &mut Rng) -> usize {
+        *self
+            .blocks
+            .keys()
+            .nth(rng.below(self.blocks.len()))
+            .unwrap()
+    }
+}
+
+fn intact(ptr: usize, block: &Block) -> bool {
+    let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, block.layout.size()) };
+    bytes.iter().all(|&byte| byte == block.fill)
+}
+
+fn random_layout(rng: &mut Rng) -> Layout {
+    let size = match rng.below(10) {
+        0 => 1 + rng.below(2048),
+        1..=3 => 1 + rng.below(256),
+        _ => 1 + rng.below(48),
+    };
+    Layout::from_size_align(size, 1 << rng.below(7)).unwrap()
+}
+
+/// Checks that a failed allocation of `layout` really had nowhere to go: a
+/// size that leaves at least a minimum block of any region's largest free
+/// block would have fit.
+fn check_failure(seed: u64, heap: &EspHeap, regions: &[RegionId], layout: Layout) {
+    if layout.align() > core::mem::align_of::<usize>() {
+        return;
+    }
+    for &region in regions {
+        let largest = heap.largest_free_block(region);
+        assert!(
+            layout.size() + MIN_BLOCK > largest,
+            "seed {seed}: {layout:?} failed with {largest} bytes free in {region}"
+        );
+    }
+}
+
+/// Returns the used bytes of every region, from the binary snapshot, which
+/// doesn't walk the free lists.
+fn region_used(heap: &EspHeap) -> Vec<usize> {
+    let bytes = heap.stats_bytes();
+    let word = |offset: usize| u32::from_le_bytes(bytes[offset..][..4].try_into().unwrap());
+    (0..MAX_REGIONS)
+        .map(|index| word(32 + 16 * index + 8) as usize)
+        .collect()
+}
+
+fn check(seed: u64, heap: &EspHeap, regions: &[RegionId], model: &Model) {
+    for (&ptr, block) in &model.blocks {
+        assert_eq!(
+            ptr % block.layout.align(),
+            0,
+            "seed {seed}: block at {ptr:#x} is misaligned"
+        );
+        assert_eq!(
+            region_of(heap, ptr as *mut u8),
+            Some(block.region),
+            "seed {seed}: block at {ptr:#x} left its region"
+        );
+        let top = heap.region_top(regions[block.region]).unwrap();
+        assert!(
+            ptr + block.layout.size() <= top as usize,
+            "seed {seed}: block at {ptr:#x} reaches past the top of its region"
+        );
+        assert!(
+            intact(ptr, block),
+            "seed {seed}: block at {ptr:#x} was overwritten"
+        );
+    }
+
+    let used = region_used(heap);
+    for region in regions {
+        let live = model.live(Some(region.index()));
+        let used = used[region.index()];
+        #[cfg(feature = "free-cache")]
+        let used = used - heap.free_cache_stats(*region).bytes;
+        assert!(
+            used >= live,
+            "seed {seed}: {region} uses {used} bytes for {live} live"
+        );
+        if live == 0 {
+            assert_eq!(
+                used, 0,
+                "seed {seed}: {region} is empty but uses {used} bytes"
+            );
+        }
+    }
+    assert_eq!(heap.live_bytes(), model.live(None), "seed {seed}");
+}
+
+/// Frees the block at `ptr` and checks that it went back to the region that
+/// served it, and nowhere else.
+fn free(seed: u64, heap: &EspHeap, ptr: usize, block: &Block) {
+    #[cfg(feature = "free-cache")]
+    let region = heap.region_id_by_index(block.region).unwrap();
+    #[cfg(feature = "free-cache")]
+    let cached = heap.free_cache_stats(region).blocks;
+    let before = region_used(heap);
+    unsafe { heap.dealloc(ptr as *mut u8, block.layout) };
+    let after = region_used(heap);
+
+    // A block parked in the free cache still counts as used.
+    #[cfg(feature = "free-cache")]
+    let returned = heap.free_cache_stats(region).blocks <= cached;
+    #[cfg(not(feature = "free-cache"))]
+    let returned = true;
+    for (index, (before, after)) in before.into_iter().zip(after).enumerate() {
+        if index != block.region {
+            assert_eq!(
+                before, after,
+                "seed {seed}: freeing {ptr:#x} changed region {index}"
+            );
+        } else if returned {
+            assert!(
+                after < before,
+                "seed {seed}: freeing {ptr:#x} didn't return it to region {index}"
+            );
+        }
+    }
+}
+
+fn run(seed: u64, configure: fn(&EspHeap, RegionId)) {
+    let heap = EspHeap::empty();
+    let (internal, _) = add(&heap, 16 * 1024, MemoryCapability::INTERNAL);
+    let (external, _) = add(&heap, 24 * 1024, MemoryCapability::EXTERNAL);
+    let regions = [internal, external];
+    configure(&heap, internal);
+    configure(&heap, external);
+    let total = heap.free();
+
+    let mut rng = Rng::new(seed);
+    let mut model = Model::default();
+    for step in 0..2000 {
+        match rng.below(8) {
+            0..=3 => {
+                let layout = random_layout(&mut rng);
+                let ptr = if rng.below(4) == 0 {
+                    unsafe { heap.alloc_zeroed(layout) }
+                } else {
+                    unsafe { heap.alloc(layout) }
+                };
+                if ptr.is_null() {
+                    check_failure(seed, &heap, &regions, layout);
+                    continue;
+                }
+                let fill = step as u8;
+                unsafe { ptr.write_bytes(fill, layout.size()) };
+                let region = region_of(&heap, ptr)
+                    .unwrap_or_else(|| panic!("seed {seed}: block at {ptr:p} outside of the heap"));
+                model.insert(
+                    seed,
+                    ptr,
+                    Block {
+                        layout,
+                        fill,
+                        region,
+                    },
+                );
+            }
+            4..=5 if !model.blocks.is_empty() => {
+                let ptr = model.pick(&mut rng);
+                let block = model.blocks.remove(&ptr).unwrap();
+                free(seed, &heap, ptr, &block);
+            }
+            6..=7 if !model.blocks.is_empty() => {
+                let old = model.pick(&mut rng);
+                let block = model.blocks.remove(&old).unwrap();
+                let size = random_layout(&mut rng).size();
+                let ptr = unsafe { heap.realloc(old as *mut u8, block.layout, size) };
+                if ptr.is_null() {
+                    // The block keeps the capabilities of its region.
+                    let layout = Layout::from_size_align(size, block.layout.align()).unwrap();
+                    check_failure(seed, &heap, &regions[block.region..][..1], layout);
+                    model.blocks.insert(old, block);
+                    continue;
+                }
+                // The part both sizes have in common moved along.
+                let kept = size.min(block.layout.size());
+                let moved = unsafe { std::slice::from_raw_parts(ptr, kept) };
+                assert!(
+                    moved.iter().all(|&byte| byte == block.fill),
+                    "seed {seed}: reallocating {old:#x} lost its contents"
+                );
+                unsafe { ptr.write_bytes(block.fill, size) };
+                let region = region_of(&heap, ptr)
+                    .unwrap_or_else(|| panic!("seed {seed}: block at {ptr:p} outside of the heap"));
+                let layout = Layout::from_size_align(size, block.layout.align()).unwrap();
+                model.insert(
+                    seed,
+                    ptr,
+                    Block {
+                        layout,
+                        region,
+                        ..block
+                    },
+                );
+            }
+            _ => {}
+        }
+        if step % 16 == 0 {
+            check(seed, &heap, &regions, &model);
+        }
+    }
+    check(seed, &heap, &regions, &model);
+
+    for (ptr, block) in core::mem::take(&mut model.blocks) {
+        free(seed, &heap, ptr, &block);
+    }
+    #[cfg(feature = "quarantine")]
+    heap.flush_quarantine();
+    #[cfg(feature = "free-cache")]
+    for region in regions {
+        heap.flush_free_cache(region);
+    }
+    assert_eq!(
+        (heap.used(), heap.free(), heap.live_bytes()),
+        (0, total, 0),
+        "seed {seed}"
+    );
+}
+
+#[test]
+fn keeps_blocks_apart_and_intact() {
+    for seed in 1..=8 {
+        run(seed, |_, _| {});
+    }
+}
+
+#[test]
+fn keeps_blocks_apart_with_isolated_cache_lines() {
+    for seed in 1..=4 {
+        run(seed, |heap, region| {
+            heap.set_cache_line_size(32);
+            heap.set_isolate_cache_lines(region, true);
+        });
+    }
+}
+
+#[test]
+#[cfg(feature = "free-cache")]
+fn keeps_blocks_apart_with_a_free_cache() {
+    for seed in 1..=4 {
+        run(seed, |heap, region| heap.set_free_cache(region, 8));
+    }
+}
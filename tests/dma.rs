@@ -0,0 +1,216 @@
+//! DMA buffers, which must stay in internal DMA-capable memory
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::{add, region_of};
+use esp_alloc::{EspHeap, MemoryCapability, DEFAULT_CACHE_LINE_SIZE, DEFAULT_DMA_ALIGNMENT};
+
+fn heap_with_psram() -> EspHeap {
+    let heap = EspHeap::empty();
+    add(
+        &heap,
+        2048,
+        MemoryCapability::INTERNAL | MemoryCapability::DMA,
+    );
+    add(&heap, 1024, MemoryCapability::INTERNAL);
+    add(&heap, 16 * 1024, MemoryCapability::EXTERNAL);
+    heap
+}
+
+#[test]
+fn places_aligned_buffers_in_internal_dma_memory_only() {
+    let heap = heap_with_psram();
+    assert_eq!(DEFAULT_DMA_ALIGNMENT, 32);
+
+    let mut buffers = Vec::new();
+    for size in [1, 40, 100, 333].into_iter().cycle() {
+        let ptr = heap.alloc_dma(size);
+        if ptr.is_null() {
+            break;
+        }
+        assert_eq!(ptr as usize % 32, 0);
+        assert_eq!(region_of(&heap, ptr), Some(0));
+        buffers.push((ptr, size));
+    }
+    assert!(buffers.len() > 4);
+
+    // Nearly full, the region still serves what fits, and PSRAM is never
+    // used.
+    let largest = (0..2048).rev().find(|&size| {
+        let ptr = heap.alloc_dma(size);
+        if !ptr.is_null() {
+            buffers.push((ptr, size));
+        }
+        !ptr.is_null()
+    });
+    assert!(largest.is_some());
+    assert!(heap.alloc_dma(64).is_null());
+    let stats = heap.stats();
+    assert_eq!((stats.regions[1].used, stats.regions[2].used), (0, 0));
+
+    for (ptr, size) in buffers {
+        unsafe { heap.dealloc(ptr, heap.dma_layout(size).unwrap()) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn places_cached_buffers_in_external_dma_memory_only() {
+    let heap = EspHeap::empty();
+    add(
+        &heap,
+        4096,
+        MemoryCapability::INTERNAL | MemoryCapability::DMA,
+    );
+    add(&heap, 4096, MemoryCapability::EXTERNAL);
+    add(
+        &heap,
+        4096,
+        MemoryCapability::EXTERNAL | MemoryCapability::DMA,
+    );
+    assert_eq!(DEFAULT_CACHE_LINE_SIZE, 32);
+
+    let mut buffers = Vec::new();
+    for (len, rounded) in [(1, 32), (32, 32), (33, 64), (100, 128)] {
+        let buffer = heap.alloc_dma_cached(len).unwrap();
+        assert_eq!(buffer.len(), rounded);
+        assert_eq!(buffer.as_ptr() as *mut u8 as usize % 32, 0);
+        assert_eq!(region_of(&heap, buffer.as_ptr() as *mut u8), Some(2));
+        buffers.push(buffer);
+    }
+
+    heap.set_cache_line_size(64);
+    let buffer = heap.alloc_dma_cached(65).unwrap();
+    assert_eq!(buffer.len(), 128);
+    assert_eq!(buffer.as_ptr() as *mut u8 as usize % 64, 0);
+    assert_eq!(region_of(&heap, buffer.as_ptr() as *mut u8), Some(2));
+    unsafe { heap.free_dma_cached(buffer) };
+    heap.set_cache_line_size(32);
+
+    // The other regions have room for it, but can't serve it.
+    assert!(heap.alloc_dma_cached(4096).is_none());
+    let stats = heap.stats();
+    assert_eq!((stats.regions[0].used, stats.regions[1].used), (0, 0));
+
+    for buffer in buffers {
+        unsafe { heap.free_dma_cached(buffer) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn honors_a_custom_dma_alignment() {
+    let heap = heap_with_psram();
+    heap.set_dma_alignment(64);
+    assert_eq!(heap.config_summary().dma_alignment, 64);
+
+    let blocks: Vec<_> = (0..4).map(|_| heap.alloc_dma(24)).collect();
+    for &ptr in &blocks {
+        assert_eq!(ptr as usize % 64, 0);
+        assert_eq!(region_of(&heap, ptr), Some(0));
+    }
+    for ptr in blocks {
+        unsafe { heap.dealloc(ptr, heap.dma_layout(24).unwrap()) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn keeps_reallocated_buffers_in_dma_memory() {
+    let heap = heap_with_psram();
+    heap.set_large_alloc_threshold(512);
+
+    let ptr = heap.alloc_dma(100);
+    unsafe { ptr.write_bytes(0x5a, 100) };
+    // A neighbour, so the buffer has to move to grow.
+    let neighbour = heap.alloc_dma(100);
+
+    let grown = unsafe { heap.realloc(ptr, heap.dma_layout(100).unwrap(), 1000) };
+    assert!(!grown.is_null());
+    assert_eq!(grown as usize % 32, 0);
+    assert_eq!(region_of(&heap, grown), Some(0));
+    assert!((0..100).all(|offset| unsafe { grown.add(offset).read() } == 0x5a));
+
+    // Growing past what DMA memory has fails rather than moving to PSRAM.
+    let layout = heap.dma_layout(1000).unwrap();
+    assert!(unsafe { heap.realloc(grown, layout, 4000) }.is_null());
+
+    // So do ordinary blocks growing past the threshold, which may have been
+    // allocated with the capabilities of their region.
+    let ordinary = Layout::from_size_align(100, 4).unwrap();
+    let block = unsafe { heap.alloc(ordinary) };
+    assert_eq!(region_of(&heap, block), Some(0));
+    let moved = unsafe { heap.realloc(block, ordinary, 600) };
+    assert_eq!(region_of(&heap, moved), Some(0));
+
+    unsafe {
+        heap.dealloc(moved, Layout::from_size_align(600, 4).unwrap());
+        heap.dealloc(grown, layout);
+        heap.dealloc(neighbour, heap.dma_layout(100).unwrap());
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn gives_each_allocation_its_own_cache_lines() {
+    let heap = EspHeap::empty();
+    let (region, _) = add(&heap, 16 * 1024, MemoryCapability::EXTERNAL);
+    heap.set_cache_line_size(32);
+    heap.set_isolate_cache_lines(region, true);
+
+    let layout = Layout::from_size_align(5, 1).unwrap();
+    let mut lines: Vec<_> = (0..16)
+        .map(|_| unsafe { heap.alloc(layout) } as usize)
+        .collect();
+    assert!(lines.iter().all(|&addr| addr != 0));
+    lines.sort_unstable();
+    // The canaries of the `heap-guard` feature share the lines of their
+    // block, which starts on a line of its own either way.
+    assert!(lines
+        .windows(2)
+        .all(|pair| (pair[0] + layout.size() - 1) / 32 < pair[1] / 32));
+    #[cfg(not(feature = "heap-guard"))]
+    {
+        assert!(lines.iter().all(|addr| addr % 32 == 0));
+        assert_eq!(heap.cache_line_padding(region), 16 * (32 - 5));
+    }
+
+    for addr in lines {
+        unsafe { heap.dealloc(addr as *mut u8, layout) };
+    }
+    assert_eq!((heap.used(), heap.cache_line_padding(region)), (0, 0));
+}
+
+#[test]
+fn keeps_allocations_out_of_the_tail_guard() {
+    let heap = EspHeap::empty();
+    let (guarded, bottom) = add(&heap, 4096, MemoryCapability::INTERNAL);
+    add(&heap, 4096, MemoryCapability::INTERNAL);
+    let free = heap.free_general();
+    heap.set_dma_tail_guard(guarded, 1024);
+    assert_eq!(heap.free_general(), free - 1024);
+
+    // What would reach into the guard goes to the other region.
+    let large = Layout::from_size_align(3500, 8).unwrap();
+    let ptr = unsafe { heap.alloc(large) };
+    assert_eq!(region_of(&heap, ptr), Some(1));
+    let blocks: Vec<_> = (0..8)
+        .map(|_| unsafe { heap.alloc(Layout::from_size_align(256, 8).unwrap()) })
+        .collect();
+    assert!(blocks.iter().any(|ptr| region_of(&heap, *ptr) == Some(0)));
+    let limit = bottom as usize + 4096 - 1024;
+    assert!(blocks
+        .iter()
+        .filter(|ptr| !ptr.is_null())
+        .all(|ptr| region_of(&heap, *ptr) != Some(0) || *ptr as usize + 256 <= limit));
+    assert!(unsafe { heap.alloc(Layout::from_size_align(3500, 8).unwrap()) }.is_null());
+
+    heap.set_dma_tail_guard(guarded, 0);
+    unsafe { heap.dealloc(ptr, large) };
+    for ptr in blocks {
+        unsafe { heap.dealloc(ptr, Layout::from_size_align(256, 8).unwrap()) };
+    }
+    assert_eq!((heap.used(), heap.free_general()), (0, free));
+}
@@ -0,0 +1,62 @@
+//! Restrictions on large allocations during early boot
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::sync::Mutex;
+
+use common::add;
+use esp_alloc::{EarlyPolicy, EspHeap, MemoryCapability};
+
+#[test]
+fn denies_large_allocations_until_finalized() {
+    static SEEN: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    fn seen(layout: Layout) {
+        SEEN.lock().unwrap().push(layout.size());
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_early_limit(256, EarlyPolicy::Deny);
+    heap.set_early_hook(seen);
+    let small = Layout::from_size_align(256, 4).unwrap();
+    let large = Layout::from_size_align(257, 4).unwrap();
+
+    let ptr = unsafe { heap.alloc(small) };
+    assert!(!ptr.is_null());
+    assert!(unsafe { heap.alloc(large) }.is_null());
+    assert_eq!(heap.early_allocations(), 1);
+    assert_eq!(*SEEN.lock().unwrap(), [257]);
+
+    heap.finalize();
+    let late = unsafe { heap.alloc(large) };
+    assert!(!late.is_null());
+    assert_eq!(heap.early_allocations(), 1);
+    assert_eq!(SEEN.lock().unwrap().len(), 1);
+
+    unsafe {
+        heap.dealloc(ptr, small);
+        heap.dealloc(late, large);
+    }
+}
+
+#[test]
+fn reports_large_allocations_it_lets_through() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_early_limit(100, EarlyPolicy::Report);
+    let layout = Layout::from_size_align(900, 4).unwrap();
+
+    let blocks: Vec<_> = (0..3).map(|_| unsafe { heap.alloc(layout) }).collect();
+    assert!(blocks.iter().all(|ptr| !ptr.is_null()));
+    assert_eq!(heap.early_allocations(), 3);
+    heap.finalize();
+    let ptr = unsafe { heap.alloc(layout) };
+    assert_eq!(heap.early_allocations(), 3);
+
+    for ptr in blocks.into_iter().chain([ptr]) {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.used(), 0);
+}
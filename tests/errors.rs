@@ -0,0 +1,104 @@
+//! Panic messages, detailed with the `verbose-errors` feature and short
+//! without it
+
+mod common;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use common::{add, memory, panic_message};
+use esp_alloc::{EspHeap, MemoryCapability, MAX_BUDGETS, MAX_REGIONS};
+
+/// Returns the message `f` panics with.
+fn panic_of(f: impl FnOnce()) -> String {
+    panic_message(catch_unwind(AssertUnwindSafe(f)).unwrap_err())
+}
+
+fn expected(terse: &str, verbose: String) -> String {
+    if cfg!(feature = "verbose-errors") {
+        verbose
+    } else {
+        terse.to_string()
+    }
+}
+
+#[test]
+fn names_the_limits_that_were_exceeded() {
+    let heap = EspHeap::empty();
+    for _ in 0..MAX_REGIONS {
+        add(&heap, 1024, MemoryCapability::empty());
+    }
+    let message = panic_of(|| {
+        add(&heap, 1024, MemoryCapability::empty());
+    });
+    assert_eq!(
+        message,
+        expected(
+            "too many heap regions",
+            format!("Exceeded the maximum of {MAX_REGIONS} heap regions")
+        )
+    );
+
+    for _ in 0..MAX_BUDGETS {
+        heap.budget("budget", 64);
+    }
+    let message = panic_of(|| {
+        heap.budget("one too many", 64);
+    });
+    assert_eq!(
+        message,
+        expected(
+            "too many budgets",
+            format!("Exceeded the maximum of {MAX_BUDGETS} budgets")
+        )
+    );
+}
+
+#[test]
+fn names_the_regions_involved() {
+    let heap = EspHeap::empty();
+    let (region, bottom) = add(&heap, 1024, MemoryCapability::empty());
+
+    let overlapping = bottom as usize + 512;
+    let message = panic_of(|| unsafe {
+        heap.add_region(overlapping as *mut u8, 1024, MemoryCapability::empty());
+    });
+    assert_eq!(
+        message,
+        expected(
+            "heap region overlaps another",
+            format!(
+                "Heap region {overlapping:#x}..{:#x} overlaps region 0",
+                overlapping + 1024
+            )
+        )
+    );
+
+    let message = panic_of(|| unsafe { heap.mark_available(region, memory(1024), 1024) });
+    assert_eq!(
+        message,
+        expected(
+            "not an expected region",
+            "region 0 is not an expected region".to_string()
+        )
+    );
+
+    // Only debug builds check where a freed block lies.
+    #[cfg(debug_assertions)]
+    {
+        use core::alloc::{GlobalAlloc, Layout};
+
+        let mut local = 0u64;
+        let ptr = &mut local as *mut u64 as *mut u8;
+        let message = panic_of(|| unsafe { heap.dealloc(ptr, Layout::new::<u64>()) });
+        // Quarantined blocks have their canaries checked first.
+        if !cfg!(all(feature = "heap-guard", feature = "quarantine")) {
+            assert_eq!(
+                message,
+                expected(
+                    "deallocation outside of the heap",
+                    format!("Deallocation of 8 bytes at {ptr:p}, which is in no heap region")
+                )
+            );
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Growing a region with the memory right behind it
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use common::{memory, panic_message};
+use esp_alloc::{EspHeap, MemoryCapability};
+
+#[test]
+fn serves_a_failing_allocation_after_extending() {
+    let heap = EspHeap::empty();
+    let memory = memory(4096);
+    let region = unsafe { heap.add_region(memory, 2048, MemoryCapability::empty()) };
+    #[cfg(feature = "quarantine")]
+    heap.set_quarantine_limits(0, 0);
+
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let mut blocks = Vec::new();
+    loop {
+        let ptr = unsafe { heap.alloc(small) };
+        if ptr.is_null() {
+            break;
+        }
+        blocks.push(ptr);
+    }
+    let big = Layout::from_size_align(1024, 8).unwrap();
+    assert!(unsafe { heap.alloc(big) }.is_null());
+    let free = heap.free();
+
+    assert_eq!(heap.region_top(region), Some(unsafe { memory.add(2048) }));
+    unsafe { heap.extend_region(region, 2048) };
+    assert_eq!(heap.region_top(region), Some(unsafe { memory.add(4096) }));
+
+    // Counted right away.
+    assert_eq!(heap.free(), free + 2048);
+    let stats = heap.stats().regions[region.index()];
+    assert_eq!((stats.size, stats.top), (4096, memory as usize + 4096));
+    assert!(heap.largest_free_block(region) >= big.size());
+
+    let ptr = unsafe { heap.alloc(big) };
+    assert!(!ptr.is_null());
+    assert!(ptr as usize >= memory as usize + 2048 - 64);
+    unsafe { heap.dealloc(ptr, big) };
+    for ptr in blocks {
+        unsafe { heap.dealloc(ptr, small) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn panics_on_extending_into_another_region() {
+    let heap = EspHeap::empty();
+    let memory = memory(4096);
+    let (first, second) = unsafe {
+        (
+            heap.add_region(memory, 1024, MemoryCapability::empty()),
+            heap.add_region(memory.add(2048), 2048, MemoryCapability::empty()),
+        )
+    };
+
+    // Up to the next region is fine.
+    unsafe { heap.extend_region(first, 1024) };
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe { heap.extend_region(first, 8) }));
+    let message = panic_message(result.unwrap_err());
+    assert!(message.contains("overlaps"), "{message}");
+    assert_eq!(heap.region_top(first), Some(unsafe { memory.add(2048) }));
+    assert_eq!(heap.stats().regions[second.index()].size, 2048);
+}
+
+#[test]
+fn takes_back_a_trimmed_tail() {
+    let heap = EspHeap::empty();
+    let memory = memory(4096);
+    let region = unsafe { heap.add_region(memory, 4096, MemoryCapability::empty()) };
+    let free = heap.free();
+
+    let start = unsafe { heap.shrink_region(region, 1024) }.unwrap();
+    assert_eq!(start, unsafe { memory.add(1024) });
+    assert_eq!(heap.free(), free - 3072);
+
+    unsafe { heap.extend_region(region, 3072) };
+    assert_eq!(heap.free(), free);
+    let layout = Layout::from_size_align(3500, 8).unwrap();
+    let ptr = unsafe { heap.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { heap.dealloc(ptr, layout) };
+}
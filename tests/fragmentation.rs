@@ -0,0 +1,82 @@
+//! Fragmented heaps (the `test-util` feature)
+
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability};
+
+/// Fragments all of `heap` into gaps of `gap` bytes, returning the live
+/// blocks between them.
+fn fragment(heap: &EspHeap, gap: Layout) -> Vec<*mut u8> {
+    let mut kept = vec![core::ptr::null_mut(); 1024];
+    let gaps = heap.fragment_for_test(gap, &mut kept);
+    assert!(gaps > 4 && gaps < kept.len());
+    kept.truncate(gaps);
+    kept
+}
+
+#[test]
+fn fits_a_gap_of_the_worst_case_overhead() {
+    for (size, align) in [(1, 1), (24, 4), (100, 8), (100, 16), (200, 32), (50, 64)] {
+        let heap = EspHeap::empty();
+        let (region, _) = add(&heap, 32 * 1024, MemoryCapability::empty());
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let overhead = heap.worst_case_overhead(region, layout);
+        assert!(overhead < 256, "{layout:?}");
+
+        let gap = Layout::from_size_align(size + overhead, 1).unwrap();
+        let kept = fragment(&heap, gap);
+
+        // Only the gaps and a little slack at the end are left, and every
+        // gap has to do.
+        let mut blocks = Vec::new();
+        loop {
+            let ptr = unsafe { heap.alloc(layout) };
+            if ptr.is_null() {
+                break;
+            }
+            assert_eq!(ptr as usize % align, 0);
+            blocks.push(ptr);
+        }
+        assert!(blocks.len() >= kept.len(), "{layout:?}");
+
+        for ptr in blocks {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        for ptr in kept {
+            unsafe { heap.dealloc(ptr, gap) };
+        }
+        assert_eq!(heap.used(), 0);
+    }
+}
+
+#[test]
+fn reports_the_gaps() {
+    let heap = EspHeap::empty();
+    let (region, _) = add(&heap, 16 * 1024, MemoryCapability::empty());
+    let gap = Layout::from_size_align(200, 8).unwrap();
+    let kept = fragment(&heap, gap);
+
+    let report = heap.coalesce(region);
+    assert_eq!(report.merges, 0);
+    assert!(report.free_blocks >= kept.len());
+    assert!(report.largest_free_block < 2 * gap.size() + 64);
+    let largest = heap.largest_free_block(region);
+    assert!(largest >= gap.size() && largest <= report.largest_free_block);
+
+    // Freeing every other live block merges three gaps into one.
+    for ptr in kept.iter().step_by(2) {
+        unsafe { heap.dealloc(*ptr, gap) };
+    }
+    assert!(heap.largest_free_block(region) >= 3 * gap.size());
+    for ptr in kept.iter().skip(1).step_by(2) {
+        unsafe { heap.dealloc(*ptr, gap) };
+    }
+    let report = heap.coalesce(region);
+    assert_eq!((report.merges, report.free_blocks), (0, 1));
+    assert_eq!(heap.used(), 0);
+}
@@ -0,0 +1,72 @@
+//! The per-region cache of freed blocks (the `free-cache` feature)
+
+#![cfg(feature = "free-cache")]
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::add;
+use esp_alloc::{AllocStrategy, EspHeap, MemoryCapability, FREE_CACHE_CAPACITY};
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 4).unwrap()
+}
+
+#[test]
+fn counts_hits_and_misses() {
+    let heap = EspHeap::empty();
+    let (region, _) = add(&heap, 4096, MemoryCapability::empty());
+    heap.set_free_cache(region, 2);
+
+    let small = unsafe { heap.alloc(layout(64)) };
+    unsafe { heap.dealloc(small, layout(64)) };
+    let stats = heap.free_cache_stats(region);
+    assert_eq!((stats.hits, stats.misses, stats.blocks), (0, 1, 1));
+    assert!(stats.bytes >= 64);
+
+    // The same size class gets the cached block back, another one doesn't.
+    assert_eq!(unsafe { heap.alloc(layout(60)) }, small);
+    let large = unsafe { heap.alloc(layout(256)) };
+    assert_ne!(large, small);
+    let stats = heap.free_cache_stats(region);
+    assert_eq!((stats.hits, stats.misses, stats.blocks), (1, 2, 0));
+
+    // Beyond its capacity freed blocks go to the free list.
+    let other = unsafe { heap.alloc(layout(32)) };
+    unsafe {
+        heap.dealloc(small, layout(60));
+        heap.dealloc(large, layout(256));
+        heap.dealloc(other, layout(32));
+    }
+    assert_eq!(heap.free_cache_stats(region).blocks, 2);
+    heap.flush_free_cache(region);
+    let stats = heap.free_cache_stats(region);
+    assert_eq!((stats.hits, stats.blocks, stats.bytes), (1, 0, 0));
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn gives_cached_blocks_back_when_memory_runs_out() {
+    let heap = EspHeap::empty();
+    let (region, _) = add(&heap, 4096, MemoryCapability::empty());
+    heap.set_alloc_strategy(region, AllocStrategy::RecentlyFreed);
+    let blocks: Vec<_> = (0..FREE_CACHE_CAPACITY)
+        .map(|_| unsafe { heap.alloc(layout(200)) })
+        .collect();
+    for ptr in blocks {
+        unsafe { heap.dealloc(ptr, layout(200)) };
+    }
+    assert_eq!(heap.free_cache_stats(region).blocks, FREE_CACHE_CAPACITY);
+
+    let ptr = unsafe { heap.alloc(layout(3500)) };
+    assert!(!ptr.is_null());
+    assert_eq!(heap.free_cache_stats(region).blocks, 0);
+    unsafe { heap.dealloc(ptr, layout(3500)) };
+    assert_eq!(heap.free_cache_stats(region).blocks, 1);
+
+    // Shrinking the cache returns what no longer fits.
+    heap.set_alloc_strategy(region, AllocStrategy::FirstFit);
+    assert_eq!(heap.free_cache_stats(region).blocks, 0);
+    assert_eq!(heap.used(), 0);
+}
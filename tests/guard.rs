@@ -0,0 +1,108 @@
+//! Canaries around blocks (the `heap-guard` feature)
+
+#![cfg(feature = "heap-guard")]
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use common::{add, region_of};
+use esp_alloc::{EspHeap, MemoryCapability};
+
+#[test]
+fn keeps_the_requested_alignment() {
+    let heap = EspHeap::empty();
+    add(&heap, 16 * 1024, MemoryCapability::empty());
+
+    let mut blocks = Vec::new();
+    for align in [1, 4, 8, 16, 32, 64] {
+        for size in [1, 13, 64, 250] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = unsafe { heap.alloc(layout) };
+            assert_eq!(ptr as usize % align, 0, "{layout:?}");
+            unsafe { ptr.write_bytes(0xff, size) };
+            blocks.push((ptr, layout));
+        }
+    }
+    assert_eq!(heap.check_integrity(), Ok(()));
+    for (ptr, layout) in blocks {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn finds_an_overrun_in_the_right_region() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::INTERNAL);
+    let (external, _) = add(&heap, 4096, MemoryCapability::EXTERNAL);
+    let layout = Layout::from_size_align(24, 4).unwrap();
+
+    let internal = unsafe { heap.alloc(layout) };
+    let blocks: Vec<_> = (0..4)
+        .map(|_| heap.alloc_caps(MemoryCapability::EXTERNAL, layout))
+        .collect();
+    assert!(blocks
+        .iter()
+        .all(|&ptr| region_of(&heap, ptr) == Some(external.index())));
+    assert_eq!(heap.check_integrity(), Ok(()));
+
+    // One byte too many.
+    unsafe { blocks[2].add(layout.size()).write(0) };
+    let corruption = heap.check_integrity().unwrap_err();
+    assert_eq!(corruption.region, external);
+    assert_eq!(corruption.address, blocks[2] as usize);
+    assert_eq!(corruption.size, layout.size());
+
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe {
+        heap.dealloc(blocks[2], layout)
+    }));
+    assert!(result.is_err());
+
+    // The others are fine.
+    unsafe {
+        heap.dealloc(internal, layout);
+        heap.dealloc(blocks[0], layout);
+    }
+}
+
+#[test]
+fn finds_an_underrun() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let layout = Layout::from_size_align(32, 8).unwrap();
+
+    let ptr = unsafe { heap.alloc(layout) };
+    unsafe { ptr.sub(1).write(0xff) };
+    assert!(heap.check_integrity().is_err());
+}
+
+#[test]
+fn moves_the_back_canary_on_realloc() {
+    let heap = EspHeap::empty();
+    add(&heap, 16 * 1024, MemoryCapability::empty());
+    let layout = Layout::from_size_align(100, 4).unwrap();
+
+    let ptr = unsafe { heap.alloc(layout) };
+    let grown = unsafe { heap.realloc(ptr, layout, 1000) };
+    assert_eq!(grown, ptr);
+    unsafe { grown.write_bytes(0x77, 1000) };
+    assert_eq!(heap.check_integrity(), Ok(()));
+
+    let shrunk = unsafe { heap.realloc(grown, Layout::from_size_align(1000, 4).unwrap(), 10) };
+    unsafe { shrunk.write_bytes(0x77, 10) };
+    assert_eq!(heap.check_integrity(), Ok(()));
+
+    let pin = unsafe { heap.alloc(layout) };
+    let moved = unsafe { heap.realloc(shrunk, Layout::from_size_align(10, 4).unwrap(), 4000) };
+    assert_ne!(moved, shrunk);
+    unsafe { moved.write_bytes(0x77, 4000) };
+    assert_eq!(heap.check_integrity(), Ok(()));
+
+    unsafe {
+        heap.dealloc(moved, Layout::from_size_align(4000, 4).unwrap());
+        heap.dealloc(pin, layout);
+    }
+    assert_eq!(heap.used(), 0);
+}
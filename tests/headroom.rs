@@ -0,0 +1,58 @@
+//! Contiguous memory kept back for allocations that must not fail
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability};
+
+#[test]
+fn keeps_the_headroom_for_allocations_that_ask_for_it() {
+    let heap = EspHeap::empty();
+    let (region, bottom) = add(&heap, 8 * 1024, MemoryCapability::empty());
+    assert!(heap.set_headroom(region, 2048));
+    let reserved = heap.used();
+    assert!(reserved >= 2048);
+
+    // Fill the rest of the region and free every other block, which leaves
+    // no free block that could serve a large allocation.
+    let small = Layout::from_size_align(256, 8).unwrap();
+    let mut blocks = Vec::new();
+    loop {
+        let ptr = unsafe { heap.alloc(small) };
+        if ptr.is_null() {
+            break;
+        }
+        blocks.push(ptr);
+    }
+    assert!(blocks.len() > 4);
+    let (freed, kept): (Vec<_>, Vec<_>) = blocks.iter().enumerate().partition(|(i, _)| i % 2 == 0);
+    for (_, &ptr) in freed {
+        unsafe { heap.dealloc(ptr, small) };
+    }
+
+    let large = Layout::from_size_align(1024, 8).unwrap();
+    assert!(unsafe { heap.alloc(large) }.is_null());
+    let ptr = heap.alloc_from_headroom(MemoryCapability::empty(), large);
+    assert!(!ptr.is_null());
+    let headroom = bottom as usize..bottom as usize + reserved;
+    assert!(headroom.contains(&(ptr as usize)));
+    unsafe { ptr.write_bytes(0x44, large.size()) };
+    // What it left of the headroom stays out of reach.
+    assert!(unsafe { heap.alloc(large) }.is_null());
+
+    // Once freed, the headroom is reserved again as a whole.
+    unsafe { heap.dealloc(ptr, large) };
+    assert!(unsafe { heap.alloc(large) }.is_null());
+    let again = heap.alloc_from_headroom(MemoryCapability::empty(), large);
+    assert!(headroom.contains(&(again as usize)));
+    unsafe { heap.dealloc(again, large) };
+
+    for (_, &ptr) in kept {
+        unsafe { heap.dealloc(ptr, small) };
+    }
+    assert_eq!(heap.used(), reserved);
+    assert!(heap.set_headroom(region, 0));
+    assert_eq!(heap.used(), 0);
+}
@@ -0,0 +1,74 @@
+//! Reporting allocations to external heap tracing (the `alloc-hooks` feature)
+
+#![cfg(feature = "alloc-hooks")]
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::sync::Mutex;
+
+use common::add;
+use esp_alloc::{BudgetError, EspHeap, MemoryCapability, TryAllocError};
+
+#[test]
+fn reports_the_allocations_that_bypass_global_alloc() {
+    static LIVE: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+    fn on_alloc(ptr: *mut u8, size: usize) {
+        LIVE.lock().unwrap().push((ptr as usize, size));
+    }
+
+    fn on_free(ptr: *mut u8, size: usize) {
+        let mut live = LIVE.lock().unwrap();
+        let index = live.iter().position(|block| *block == (ptr as usize, size));
+        live.swap_remove(index.expect("freed a block the hooks never saw"));
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_alloc_hooks(on_alloc, on_free);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let huge = Layout::from_size_align(8192, 8).unwrap();
+
+    let ptr = heap.try_alloc(layout).unwrap();
+    assert_eq!(*LIVE.lock().unwrap(), [(ptr.as_ptr() as usize, 64)]);
+    assert_eq!(heap.try_alloc(huge), Err(TryAllocError::OutOfMemory));
+    assert_eq!(LIVE.lock().unwrap().len(), 1);
+
+    let budget = heap.budget("frames", 1024);
+    let framed = budget.try_alloc(layout).unwrap();
+    assert_eq!(LIVE.lock().unwrap().len(), 2);
+    let too_big = Layout::from_size_align(2048, 8).unwrap();
+    assert_eq!(budget.try_alloc(too_big), Err(BudgetError::BudgetExceeded));
+    assert_eq!(LIVE.lock().unwrap().len(), 2);
+
+    unsafe {
+        budget.dealloc(framed, layout);
+        heap.dealloc(ptr.as_ptr(), layout);
+    }
+    assert!(LIVE.lock().unwrap().is_empty());
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn stops_reporting_once_the_hooks_are_cleared() {
+    static REPORTS: Mutex<usize> = Mutex::new(0);
+
+    fn report(_: *mut u8, _: usize) {
+        *REPORTS.lock().unwrap() += 1;
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_alloc_hooks(report, report);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let ptr = unsafe { heap.alloc(layout) };
+    assert_eq!(*REPORTS.lock().unwrap(), 1);
+    heap.clear_alloc_hooks();
+    unsafe { heap.dealloc(ptr, layout) };
+    let again = unsafe { heap.alloc(layout) };
+    assert!(!again.is_null());
+    unsafe { heap.dealloc(again, layout) };
+    assert_eq!(*REPORTS.lock().unwrap(), 1);
+}
@@ -0,0 +1,69 @@
+//! The usage indicator
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use common::memory;
+use esp_alloc::EspHeap;
+
+#[test]
+fn ignores_usage_hovering_around_a_threshold() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static LEVEL: AtomicU8 = AtomicU8::new(0);
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn callback(level: u8) {
+        LEVEL.store(level, Ordering::Relaxed);
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe { HEAP.init(memory(8192), 8192) };
+    #[cfg(feature = "quarantine")]
+    HEAP.set_quarantine_limits(0, 0);
+    assert_eq!(HEAP.usage_indicator([1024, 4096], callback), 0);
+    let layout = Layout::from_size_align(16, 4).unwrap();
+
+    let mut blocks = Vec::new();
+    while HEAP.used() < 1024 {
+        blocks.push(unsafe { HEAP.alloc(layout) });
+    }
+    assert_eq!(
+        (LEVEL.load(Ordering::Relaxed), CALLS.load(Ordering::Relaxed)),
+        (1, 1)
+    );
+
+    // Dropping just below the threshold and back doesn't count.
+    for _ in 0..50 {
+        unsafe { HEAP.dealloc(blocks.pop().unwrap(), layout) };
+        assert!(HEAP.used() < 1024);
+        blocks.push(unsafe { HEAP.alloc(layout) });
+        assert!(HEAP.used() >= 1024);
+    }
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+
+    // Well below it does.
+    while HEAP.used() >= 1024 - 1024 / 16 {
+        unsafe { HEAP.dealloc(blocks.pop().unwrap(), layout) };
+    }
+    assert_eq!(
+        (LEVEL.load(Ordering::Relaxed), CALLS.load(Ordering::Relaxed)),
+        (0, 2)
+    );
+
+    // Jumping over a level reports the final one only.
+    let big = Layout::from_size_align(5000, 4).unwrap();
+    let ptr = unsafe { HEAP.alloc(big) };
+    assert_eq!(
+        (LEVEL.load(Ordering::Relaxed), CALLS.load(Ordering::Relaxed)),
+        (2, 3)
+    );
+
+    HEAP.clear_usage_indicator();
+    unsafe { HEAP.dealloc(ptr, big) };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+    for ptr in blocks {
+        unsafe { HEAP.dealloc(ptr, layout) };
+    }
+}
@@ -0,0 +1,156 @@
+//! Adding memory to a heap, and using a heap before that
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use common::{memory, panic_message};
+use esp_alloc::{EspHeap, MemoryCapability, RegionDescriptor};
+
+#[test]
+fn allocates_after_init() {
+    let heap = EspHeap::empty();
+    assert!(!heap.is_initialized());
+    let region = unsafe { heap.init(memory(1024), 1024) };
+    assert!(heap.is_initialized());
+    assert!(heap.is_region_initialized(region));
+
+    let layout = Layout::from_size_align(100, 4).unwrap();
+    let ptr = unsafe { heap.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { heap.dealloc(ptr, layout) };
+}
+
+#[test]
+fn panics_on_a_second_init() {
+    let heap = EspHeap::empty();
+    let memory = memory(1024);
+    unsafe { heap.init(memory, 1024) };
+
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe { heap.init(memory, 1024) }));
+    assert_eq!(panic_message(result.unwrap_err()), "heap initialized twice");
+}
+
+#[test]
+fn panics_on_init_after_allocating() {
+    let heap = EspHeap::empty();
+    let memory = memory(2048);
+    unsafe { heap.init(memory, 1024) };
+    let layout = Layout::from_size_align(100, 4).unwrap();
+    let ptr = unsafe { heap.alloc(layout) };
+    assert!(!ptr.is_null());
+
+    // Even with memory of its own, a second heap can't be initialized over
+    // the first one.
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe {
+        heap.init(memory.add(1024), 1024)
+    }));
+    assert!(result.is_err());
+    unsafe { heap.dealloc(ptr, layout) };
+}
+
+#[test]
+fn panics_on_overlapping_regions() {
+    let heap = EspHeap::empty();
+    let memory = memory(4096);
+    unsafe { heap.add_region(memory, 2048, MemoryCapability::empty()) };
+
+    for (offset, size) in [(0, 2048), (1024, 2048), (2040, 100)] {
+        let result = catch_unwind(AssertUnwindSafe(|| unsafe {
+            heap.add_region(memory.add(offset), size, MemoryCapability::empty())
+        }));
+        let message = panic_message(result.unwrap_err());
+        assert!(message.contains("overlaps"), "{message}");
+    }
+
+    // Right behind the first region is fine.
+    let second = unsafe { heap.add_region(memory.add(2048), 2048, MemoryCapability::empty()) };
+    assert!(heap.is_region_initialized(second));
+}
+
+#[test]
+fn tells_expected_regions_apart_from_initialized_ones() {
+    let heap = EspHeap::empty();
+    let region = heap.register_expected(RegionDescriptor::new("psram", MemoryCapability::EXTERNAL));
+    assert!(!heap.is_region_initialized(region));
+
+    // Internal memory can still be initialized while PSRAM is pending.
+    let internal = unsafe { heap.init(memory(1024), 1024) };
+    assert!(heap.is_region_initialized(internal));
+    assert!(!heap.is_region_initialized(region));
+
+    unsafe { heap.mark_available(region, memory(4096), 4096) };
+    assert!(heap.is_region_initialized(region));
+}
+
+#[test]
+fn aligns_ranges_from_linker_symbols() {
+    let heap = EspHeap::empty();
+    let memory = memory(4096);
+    let (start, end) = unsafe { (memory.add(3), memory.add(4001)) };
+    unsafe { heap.init_from_symbols(start, end) };
+
+    let region = heap.stats().regions[0];
+    assert_eq!(region.bottom, memory as usize + 8);
+    assert_eq!(region.top, memory as usize + 4000);
+
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe {
+        heap.init_from_symbols(end, start)
+    }));
+    assert!(result.is_err());
+}
+
+#[test]
+#[cfg(feature = "panic-uninit-alloc")]
+fn panics_on_allocating_before_init() {
+    let heap = EspHeap::empty();
+    let layout = Layout::from_size_align(24, 4).unwrap();
+
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe { heap.alloc(layout) }));
+    let message = panic_message(result.unwrap_err());
+    assert!(message.starts_with("esp-alloc: allocation"), "{message}");
+    if cfg!(feature = "verbose-errors") {
+        assert!(message.contains("24 bytes"), "{message}");
+        assert!(message.contains("EspHeap::init"), "{message}");
+    }
+}
+
+#[test]
+#[cfg(not(feature = "panic-uninit-alloc"))]
+fn returns_null_when_allocating_before_init() {
+    let heap = EspHeap::empty();
+    let layout = Layout::from_size_align(24, 4).unwrap();
+    assert!(unsafe { heap.alloc(layout) }.is_null());
+    assert!(unsafe { heap.alloc_zeroed(layout) }.is_null());
+}
+
+#[test]
+fn reports_an_empty_heap_before_init_without_locking() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static TAKEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn lock(f: &mut dyn FnMut()) {
+        TAKEN.fetch_add(1, Ordering::Relaxed);
+        f();
+    }
+
+    fn try_lock(f: &mut dyn FnMut()) -> bool {
+        lock(f);
+        true
+    }
+
+    unsafe { HEAP.set_lock(lock, try_lock) };
+    HEAP.register_expected(RegionDescriptor::new("psram", MemoryCapability::EXTERNAL));
+    let taken = TAKEN.load(Ordering::Relaxed);
+    assert_eq!((HEAP.used(), HEAP.free()), (0, 0));
+    assert_eq!(TAKEN.load(Ordering::Relaxed), taken);
+
+    unsafe { HEAP.init(memory(1024), 1024) };
+    assert!(HEAP.free() > 0);
+    assert!(TAKEN.load(Ordering::Relaxed) > taken);
+    unsafe { HEAP.clear_lock() };
+}
@@ -0,0 +1,118 @@
+//! Heap use from interrupt handlers (the `isr-guard` feature)
+
+#![cfg(feature = "isr-guard")]
+
+mod common;
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+};
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use common::{add, panic_message};
+use esp_alloc::{EspHeap, HeapStats, IsrPolicy, MemoryCapability, OomAction};
+
+thread_local! {
+    static IN_ISR: Cell<bool> = const { Cell::new(false) };
+}
+
+fn in_isr() -> bool {
+    IN_ISR.with(Cell::get)
+}
+
+fn interrupt<T>(f: impl FnOnce() -> T) -> T {
+    IN_ISR.with(|flag| flag.set(true));
+    let result = f();
+    IN_ISR.with(|flag| flag.set(false));
+    result
+}
+
+#[test]
+fn counts_operations_it_allows() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_isr_guard(in_isr, IsrPolicy::Allow);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let ptr = unsafe { heap.alloc(layout) };
+    unsafe { heap.dealloc(ptr, layout) };
+    assert_eq!(heap.isr_allocations(), 0);
+
+    let ptr = interrupt(|| unsafe { heap.alloc(layout) });
+    assert!(!ptr.is_null());
+    interrupt(|| unsafe { heap.dealloc(ptr, layout) });
+    assert_eq!(heap.isr_allocations(), 2);
+
+    heap.clear_isr_guard();
+    let ptr = interrupt(|| unsafe { heap.alloc(layout) });
+    interrupt(|| unsafe { heap.dealloc(ptr, layout) });
+    assert_eq!((heap.isr_allocations(), heap.used()), (2, 0));
+}
+
+#[test]
+#[cfg(feature = "verbose-errors")]
+fn panics_on_operations_it_forbids() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_isr_guard(in_isr, IsrPolicy::Panic);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let message = catch_unwind(AssertUnwindSafe(|| {
+        interrupt(|| unsafe { heap.alloc(layout) })
+    }))
+    .map_err(panic_message)
+    .unwrap_err();
+    IN_ISR.with(|flag| flag.set(false));
+    assert_eq!(message, "Allocation of 64 bytes from an interrupt handler");
+
+    let ptr = unsafe { heap.alloc(layout) };
+    let message = catch_unwind(AssertUnwindSafe(|| {
+        interrupt(|| unsafe { heap.dealloc(ptr, layout) })
+    }))
+    .map_err(panic_message)
+    .unwrap_err();
+    IN_ISR.with(|flag| flag.set(false));
+    assert_eq!(
+        message,
+        "Deallocation of 64 bytes from an interrupt handler"
+    );
+    assert_eq!(heap.isr_allocations(), 0);
+
+    unsafe { heap.dealloc(ptr, layout) };
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn only_tries_the_regions_in_try_only_mode() {
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+    fn out_of_memory(_: Layout, _: &HeapStats) -> OomAction {
+        HANDLED.fetch_add(1, Ordering::Relaxed);
+        OomAction::Fail
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_oom_handler(out_of_memory);
+    heap.set_isr_guard(in_isr, IsrPolicy::TryOnly);
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let big = Layout::from_size_align(8192, 8).unwrap();
+
+    let ptr = interrupt(|| unsafe { heap.alloc(small) });
+    assert!(!ptr.is_null());
+    assert!(interrupt(|| unsafe { heap.alloc(big) }).is_null());
+    assert_eq!(HANDLED.load(Ordering::Relaxed), 0);
+    // Resizing is a regular allocation there, so the block moves.
+    let grown = interrupt(|| unsafe { heap.realloc(ptr, small, 128) });
+    assert!(!grown.is_null() && grown != ptr);
+    assert_eq!(heap.isr_allocations(), 4);
+
+    assert!(unsafe { heap.alloc(big) }.is_null());
+    assert_eq!(HANDLED.load(Ordering::Relaxed), 1);
+    unsafe { heap.dealloc(grown, Layout::from_size_align(128, 8).unwrap()) };
+    assert_eq!(heap.used(), 0);
+}
@@ -0,0 +1,126 @@
+//! Heap configuration from the linker's memory layout
+
+mod common;
+
+use core::alloc::Layout;
+
+use common::memory;
+use esp_alloc::{EspHeap, MemoryCapability, MemoryRange, MAX_REGIONS};
+
+core::arch::global_asm!(
+    ".pushsection .data.esp_alloc_layout, \"aw\"",
+    ".balign 64",
+    ".globl _layout_heap_start",
+    "_layout_heap_start:",
+    ".zero 4096",
+    ".globl _layout_heap_end",
+    "_layout_heap_end:",
+    ".popsection",
+);
+
+fn range(start: *mut u8, size: usize, capabilities: MemoryCapability) -> MemoryRange {
+    MemoryRange::new(start, unsafe { start.add(size) }, capabilities)
+}
+
+#[test]
+fn registers_the_viable_ranges() {
+    let heap = EspHeap::empty();
+    let (internal, external, small) = (memory(4096), memory(2048), memory(64));
+    let ranges = [
+        MemoryRange::new(
+            unsafe { internal.add(3) },
+            unsafe { internal.add(4096) },
+            MemoryCapability::INTERNAL,
+        ),
+        range(small, 0, MemoryCapability::INTERNAL),
+        range(small, 16, MemoryCapability::INTERNAL),
+        range(external, 2048, MemoryCapability::EXTERNAL),
+    ];
+    let summary = unsafe { heap.configure_from_layout(&ranges, 1024) };
+
+    let regions: Vec<_> = summary.regions.iter().flatten().collect();
+    assert_eq!(regions.len(), 2);
+    assert_eq!(regions[0].bottom, internal as usize + 8);
+    assert_eq!(regions[0].size, 4096 - 8);
+    assert_eq!(regions[0].capabilities, MemoryCapability::INTERNAL);
+    assert_eq!(regions[1].bottom, external as usize);
+    assert_eq!(regions[1].size, 2048);
+    assert_eq!(regions[1].capabilities, MemoryCapability::EXTERNAL);
+    assert_eq!((summary.rejected, summary.dropped), (2, 0));
+    assert_eq!(summary.total_size(), 4096 - 8 + 2048);
+
+    let configs: Vec<_> = heap.region_configs().collect();
+    assert_eq!(configs[0].id, regions[0].id);
+    assert_eq!(configs[0].stack_reserve, 1024);
+    assert_eq!(configs[1].stack_reserve, 0);
+}
+
+#[test]
+fn keeps_the_stack_reserve_out_of_the_first_region() {
+    let heap = EspHeap::empty();
+    let ranges = [range(memory(4096), 4096, MemoryCapability::INTERNAL)];
+    unsafe { heap.configure_from_layout(&ranges, 1024) };
+    assert_eq!(heap.free_general(), heap.free() - 1024);
+
+    let layout = |size| Layout::from_size_align(size, 8).unwrap();
+    assert!(heap
+        .alloc_caps(MemoryCapability::INTERNAL, layout(3500))
+        .is_null());
+    let ptr = heap.alloc_caps(MemoryCapability::INTERNAL, layout(2048));
+    assert!(!ptr.is_null());
+    assert!((ptr as usize + 2048) <= ranges[0].end as usize - 1024);
+}
+
+#[test]
+fn skips_ranges_that_overlap_or_cannot_hold_the_stack_reserve() {
+    let heap = EspHeap::empty();
+    let (shared, next) = (memory(4096), memory(4096));
+    let ranges = [
+        range(shared, 4096, MemoryCapability::INTERNAL),
+        range(
+            unsafe { shared.add(2048) },
+            2048,
+            MemoryCapability::INTERNAL,
+        ),
+        range(memory(1024), 1024, MemoryCapability::INTERNAL),
+        range(next, 4096, MemoryCapability::INTERNAL),
+    ];
+    let summary = unsafe { heap.configure_from_layout(&ranges, 2048) };
+
+    // Both overlapping ranges are rejected, and so is the one that is too
+    // small for the reserve, which goes to the next one instead.
+    let regions: Vec<_> = summary.regions.iter().flatten().collect();
+    assert_eq!(regions.len(), 1);
+    assert_eq!(regions[0].bottom, next as usize);
+    assert_eq!((summary.rejected, summary.dropped), (3, 0));
+    assert_eq!(heap.region_configs().next().unwrap().stack_reserve, 2048);
+}
+
+#[test]
+fn drops_ranges_beyond_the_last_region() {
+    let heap = EspHeap::empty();
+    let ranges: Vec<_> = (0..MAX_REGIONS + 2)
+        .map(|_| range(memory(1024), 1024, MemoryCapability::empty()))
+        .collect();
+    let summary = unsafe { heap.configure_from_layout(&ranges, 0) };
+
+    assert!(summary.regions.iter().all(Option::is_some));
+    assert_eq!((summary.rejected, summary.dropped), (0, 2));
+    assert_eq!(summary.total_size(), MAX_REGIONS * 1024);
+}
+
+#[test]
+fn configures_from_linker_symbols() {
+    static HEAP: EspHeap = EspHeap::empty();
+    let summary = esp_alloc::configure_from_layout!(
+        HEAP,
+        stack_reserve: 512,
+        _layout_heap_start.._layout_heap_end => MemoryCapability::INTERNAL,
+    );
+
+    let region = summary.regions[0].unwrap();
+    assert_eq!(region.size, 4096);
+    assert_eq!(region.capabilities, MemoryCapability::INTERNAL);
+    assert_eq!(summary.total_size(), 4096);
+    assert_eq!(HEAP.region_configs().next().unwrap().stack_reserve, 512);
+}
@@ -0,0 +1,247 @@
+//! Heap operations under a lock of the application's choosing
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use common::{add, panic_message};
+use esp_alloc::{EspHeap, MemoryCapability, TryAllocError};
+
+#[test]
+fn runs_every_operation_under_the_lock() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static LOCK: Mutex<()> = Mutex::new(());
+    static TAKEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn lock(f: &mut dyn FnMut()) {
+        let _guard = LOCK.lock().unwrap();
+        TAKEN.fetch_add(1, Ordering::Relaxed);
+        f();
+    }
+
+    fn try_lock(f: &mut dyn FnMut()) -> bool {
+        let Ok(_guard) = LOCK.try_lock() else {
+            return false;
+        };
+        TAKEN.fetch_add(1, Ordering::Relaxed);
+        f();
+        true
+    }
+
+    unsafe { HEAP.set_lock(lock, try_lock) };
+    add(&HEAP, 16 * 1024, MemoryCapability::empty());
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let before = TAKEN.load(Ordering::Relaxed);
+    let ptr = unsafe { HEAP.alloc(layout) };
+    assert!(!ptr.is_null());
+    assert!(TAKEN.load(Ordering::Relaxed) > before);
+    let before = TAKEN.load(Ordering::Relaxed);
+    assert_eq!(HEAP.live_bytes(), 64);
+    unsafe { HEAP.dealloc(ptr, layout) };
+    assert!(TAKEN.load(Ordering::Relaxed) >= before + 2);
+
+    // The lock alone keeps threads apart.
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || {
+                for _ in 0..2000 {
+                    let ptr = unsafe { HEAP.alloc(layout) };
+                    assert!(!ptr.is_null());
+                    unsafe { ptr.write_bytes(0x5a, layout.size()) };
+                    unsafe { HEAP.dealloc(ptr, layout) };
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert_eq!(HEAP.used(), 0);
+
+    unsafe { HEAP.clear_lock() };
+    let before = TAKEN.load(Ordering::Relaxed);
+    assert_eq!(HEAP.used(), 0);
+    assert_eq!(TAKEN.load(Ordering::Relaxed), before);
+}
+
+#[test]
+fn panics_if_the_lock_skips_the_operation() {
+    let heap = EspHeap::empty();
+    add(&heap, 1024, MemoryCapability::empty());
+
+    fn broken(_: &mut dyn FnMut()) {}
+
+    fn try_broken(_: &mut dyn FnMut()) -> bool {
+        true
+    }
+
+    unsafe { heap.set_lock(broken, try_broken) };
+    let result = catch_unwind(AssertUnwindSafe(|| heap.used()));
+    assert_eq!(
+        panic_message(result.unwrap_err()),
+        "heap lock didn't run the operation"
+    );
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    let result = catch_unwind(AssertUnwindSafe(|| heap.try_alloc(layout)));
+    assert_eq!(
+        panic_message(result.unwrap_err()),
+        "heap lock didn't run the operation"
+    );
+    unsafe { heap.clear_lock() };
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn coalesces_a_few_free_blocks_at_a_time() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static TAKEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn lock(f: &mut dyn FnMut()) {
+        TAKEN.fetch_add(1, Ordering::Relaxed);
+        f();
+    }
+
+    fn try_lock(f: &mut dyn FnMut()) -> bool {
+        lock(f);
+        true
+    }
+
+    unsafe { HEAP.set_lock(lock, try_lock) };
+    let (region, _) = add(&HEAP, 16 * 1024, MemoryCapability::empty());
+    #[cfg(feature = "quarantine")]
+    HEAP.set_quarantine_limits(0, 0);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let blocks: Vec<_> = (0..64).map(|_| unsafe { HEAP.alloc(layout) }).collect();
+    assert!(blocks.iter().all(|ptr| !ptr.is_null()));
+    for ptr in blocks.iter().step_by(2) {
+        unsafe { HEAP.dealloc(*ptr, layout) };
+    }
+
+    // 32 gaps and the rest of the region behind the last block.
+    let before = TAKEN.load(Ordering::Relaxed);
+    let report = HEAP.coalesce(region);
+    assert_eq!((report.merges, report.free_blocks), (0, 33));
+    assert!(TAKEN.load(Ordering::Relaxed) - before > 4);
+    assert_eq!(report.free_blocks, HEAP.free_block_count(region));
+
+    for ptr in blocks.iter().skip(1).step_by(2) {
+        unsafe { HEAP.dealloc(*ptr, layout) };
+    }
+    let report = HEAP.coalesce(region);
+    assert_eq!((report.merges, report.free_blocks), (0, 1));
+    assert_eq!(report.largest_free_block, HEAP.free());
+    unsafe { HEAP.clear_lock() };
+}
+
+#[test]
+fn takes_the_lock_once_for_each_region_searched() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static TAKEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn lock(f: &mut dyn FnMut()) {
+        TAKEN.fetch_add(1, Ordering::Relaxed);
+        f();
+    }
+
+    fn try_lock(f: &mut dyn FnMut()) -> bool {
+        lock(f);
+        true
+    }
+
+    unsafe { HEAP.set_lock(lock, try_lock) };
+    add(&HEAP, 4096, MemoryCapability::empty());
+    add(&HEAP, 8192, MemoryCapability::empty());
+    let taken = |f: &mut dyn FnMut()| {
+        let before = TAKEN.load(Ordering::Relaxed);
+        f();
+        TAKEN.load(Ordering::Relaxed) - before
+    };
+    let layout = |size| Layout::from_size_align(size, 8).unwrap();
+    // Logging a failure looks at the regions once the allocation is done.
+    let logged = usize::from(cfg!(feature = "defmt-failures"));
+
+    let mut blocks = Vec::new();
+    for size in [64, 3072] {
+        assert_eq!(
+            taken(&mut || blocks.push((unsafe { HEAP.alloc(layout(size)) }, layout(size)))),
+            1
+        );
+    }
+    // The search of the first region fails, and the lock is let go before
+    // the second one is searched.
+    assert_eq!(
+        taken(&mut || blocks.push((unsafe { HEAP.alloc(layout(2048)) }, layout(2048)))),
+        2
+    );
+    // The first region is too small to be searched at all, and once the
+    // second one failed the lock is taken once more for the fallbacks.
+    assert_eq!(
+        taken(&mut || assert!(unsafe { HEAP.alloc(layout(7000)) }.is_null())),
+        2 + logged
+    );
+    // Nothing is worth a search.
+    assert_eq!(
+        taken(&mut || assert!(unsafe { HEAP.alloc(layout(16 * 1024)) }.is_null())),
+        1 + logged
+    );
+
+    for (ptr, layout) in blocks {
+        assert!(!ptr.is_null());
+        unsafe { HEAP.dealloc(ptr, layout) };
+    }
+    assert_eq!(HEAP.used(), 0);
+    unsafe { HEAP.clear_lock() };
+}
+
+#[test]
+fn fails_to_try_while_the_lock_is_held() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static LOCK: Mutex<()> = Mutex::new(());
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+
+    fn lock(f: &mut dyn FnMut()) {
+        let _guard = LOCK.lock().unwrap();
+        f();
+    }
+
+    fn try_lock(f: &mut dyn FnMut()) -> bool {
+        let Ok(_guard) = LOCK.try_lock() else {
+            return false;
+        };
+        f();
+        true
+    }
+
+    fn spin() {
+        SPINS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe { HEAP.set_lock(lock, try_lock) };
+    add(&HEAP, 4096, MemoryCapability::empty());
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    // An interrupt handler would find the lock taken by the code it
+    // preempted, and must not wait for it.
+    let guard = LOCK.lock().unwrap();
+    assert_eq!(HEAP.try_alloc(layout), Err(TryAllocError::Contended));
+    assert_eq!(
+        HEAP.try_alloc_bounded(layout, 3, spin),
+        Err(TryAllocError::Contended)
+    );
+    assert_eq!(SPINS.load(Ordering::Relaxed), 2);
+    drop(guard);
+
+    let ptr = HEAP.try_alloc(layout).unwrap();
+    unsafe { HEAP.dealloc(ptr.as_ptr(), layout) };
+    assert_eq!(HEAP.used(), 0);
+    unsafe { HEAP.clear_lock() };
+}
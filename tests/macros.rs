@@ -0,0 +1,32 @@
+//! Heap buffers declared by the allocator macros
+
+mod common;
+
+use std::panic::catch_unwind;
+
+use common::panic_message;
+use esp_alloc::{macros::Once, EspHeap};
+
+static ALLOCATOR: EspHeap = EspHeap::empty();
+
+esp_alloc::heap2_allocator!(size: 4096);
+
+#[test]
+fn claims_a_flag_only_once() {
+    let once = Once::new();
+    assert!(once.claim());
+    assert!(!once.claim());
+    assert!(!once.claim());
+}
+
+#[test]
+fn adds_the_declared_buffer_only_once() {
+    let region = init_heap2();
+    let config = ALLOCATOR.region_configs().next().unwrap();
+    assert_eq!(config.id, region);
+    assert_eq!(config.size, 4096);
+
+    let message = panic_message(catch_unwind(init_heap2).unwrap_err());
+    assert_eq!(message, "init_heap2 called more than once");
+    assert_eq!(ALLOCATOR.region_configs().count(), 1);
+}
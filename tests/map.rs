@@ -0,0 +1,43 @@
+//! Drawing the free and used memory of a region
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability};
+
+fn map(heap: &EspHeap, index: usize, width: usize) -> String {
+    let mut line = String::new();
+    heap.render_map(heap.stats().regions[index].id, width, &mut line)
+        .unwrap();
+    line
+}
+
+#[test]
+fn draws_used_and_free_memory() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    assert_eq!(map(&heap, 0, 8), "........");
+
+    let half = Layout::from_size_align(2048, 8).unwrap();
+    let quarter = Layout::from_size_align(1024, 8).unwrap();
+    let first = unsafe { heap.alloc(half) };
+    assert_eq!(map(&heap, 0, 8), "####....");
+    let second = unsafe { heap.alloc(quarter) };
+    assert_eq!(map(&heap, 0, 4), "###.");
+    unsafe { heap.dealloc(first, half) };
+    assert_eq!(map(&heap, 0, 8), "....##..");
+
+    unsafe { heap.dealloc(second, quarter) };
+    assert_eq!(map(&heap, 0, 8), "........");
+}
+
+#[test]
+fn draws_nothing_without_memory() {
+    let heap = EspHeap::empty();
+    assert_eq!(map(&heap, 0, 8), "");
+    add(&heap, 4096, MemoryCapability::empty());
+    assert_eq!(map(&heap, 0, 0), "");
+    assert_eq!(map(&heap, 1, 8), "");
+}
@@ -0,0 +1,55 @@
+//! The check that catches both cores in the heap at once
+//!
+//! The check is only built for dual-core chips. On the host, run these with
+//! `RUSTFLAGS="--cfg esp_alloc_dual_core"`.
+
+#![cfg(esp_alloc_dual_core)]
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::thread;
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability};
+
+/// Allocates and frees from `heap` on two threads at once.
+fn share(heap: &'static EspHeap) {
+    let workers: Vec<_> = (0..2)
+        .map(|_| {
+            thread::spawn(move || {
+                let layout = Layout::from_size_align(64, 8).unwrap();
+                for _ in 0..2000 {
+                    let ptr = unsafe { heap.alloc(layout) };
+                    assert!(!ptr.is_null());
+                    unsafe { heap.dealloc(ptr, layout) };
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn lets_in_cores_the_critical_section_keeps_apart() {
+    static HEAP: EspHeap = EspHeap::empty();
+    add(&HEAP, 4096, MemoryCapability::empty());
+    share(&HEAP);
+}
+
+#[test]
+fn serves_allocations_as_before_once_assumed_single_core() {
+    static HEAP: EspHeap = EspHeap::empty();
+    add(&HEAP, 4096, MemoryCapability::empty());
+    unsafe { HEAP.assume_single_core() };
+
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let ptr = unsafe { HEAP.alloc(layout) };
+    assert!(!ptr.is_null());
+    assert!(HEAP.used() >= 1024);
+    unsafe { HEAP.dealloc(ptr, layout) };
+    share(&HEAP);
+}
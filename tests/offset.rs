@@ -0,0 +1,69 @@
+//! Offsets in memory that isn't mapped into the address space
+
+use core::alloc::Layout;
+
+use esp_alloc::OffsetHeap;
+
+fn layout(size: usize, align: usize) -> Layout {
+    Layout::from_size_align(size, align).unwrap()
+}
+
+#[test]
+fn hands_out_aligned_offsets() {
+    let heap: OffsetHeap = OffsetHeap::empty();
+    assert_eq!(heap.allocate(layout(8, 1)), None);
+    heap.init(1000);
+
+    assert_eq!(heap.allocate(layout(10, 1)), Some(0));
+    assert_eq!(heap.allocate(layout(8, 16)), Some(16));
+    assert_eq!(heap.allocate(layout(6, 1)), Some(10));
+    // Zero-sized blocks still get an offset of their own.
+    let first = heap.allocate(layout(0, 1)).unwrap();
+    let second = heap.allocate(layout(0, 1)).unwrap();
+    assert_ne!(first, second);
+    assert_eq!((heap.used(), heap.free()), (26, 974));
+
+    assert_eq!(heap.allocate(layout(1000, 1)), None);
+    assert_eq!(heap.lost(), 0);
+}
+
+#[test]
+fn merges_freed_neighbours() {
+    let heap: OffsetHeap = OffsetHeap::empty();
+    heap.init(300);
+    let blocks: Vec<_> = (0..3)
+        .map(|_| heap.allocate(layout(100, 1)).unwrap())
+        .collect();
+    assert_eq!(blocks, [0, 100, 200]);
+    assert_eq!(heap.free(), 0);
+
+    heap.deallocate(0, layout(100, 1));
+    heap.deallocate(200, layout(100, 1));
+    assert_eq!(heap.allocate(layout(150, 1)), None);
+    heap.deallocate(100, layout(100, 1));
+
+    assert_eq!((heap.used(), heap.free()), (0, 300));
+    assert_eq!(heap.allocate(layout(300, 1)), Some(0));
+}
+
+#[test]
+fn accounts_for_frees_it_can_not_record() {
+    let heap: OffsetHeap<2> = OffsetHeap::empty();
+    heap.init(100);
+    for offset in [0, 10, 20, 30] {
+        assert_eq!(heap.allocate(layout(10, 1)), Some(offset));
+    }
+
+    // Two free ranges, and a third one that has nowhere to go.
+    heap.deallocate(0, layout(10, 1));
+    heap.deallocate(20, layout(10, 1));
+    assert_eq!(heap.lost(), 10);
+    assert_eq!((heap.used(), heap.free()), (20, 70));
+
+    // An allocation that would split a range in three fails as well.
+    assert_eq!(heap.allocate(layout(12, 64)), None);
+    assert_eq!(heap.allocate(layout(36, 64)), Some(64));
+
+    heap.init(100);
+    assert_eq!((heap.used(), heap.free(), heap.lost()), (0, 100, 0));
+}
@@ -0,0 +1,220 @@
+//! The out-of-memory handler
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use common::memory;
+use esp_alloc::{EspHeap, HeapStats, OomAction};
+
+const BLOCK: Layout = unsafe { Layout::from_size_align_unchecked(256, 8) };
+
+/// Allocates `BLOCK`s until the heap is full.
+fn fill(heap: &EspHeap) -> Vec<*mut u8> {
+    let mut blocks = Vec::new();
+    loop {
+        let ptr = unsafe { heap.alloc(BLOCK) };
+        if ptr.is_null() {
+            break blocks;
+        }
+        blocks.push(ptr);
+    }
+}
+
+#[test]
+fn retries_after_the_handler_made_room() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static SPARE: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+
+    fn handler(layout: Layout, stats: &HeapStats) -> OomAction {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(layout, BLOCK);
+        assert!(stats.free < BLOCK.size() * 2);
+
+        // The handler may allocate: this one fails without calling it again.
+        let nested = unsafe { HEAP.alloc(BLOCK) };
+        assert!(nested.is_null());
+
+        let spare = SPARE.swap(core::ptr::null_mut(), Ordering::Relaxed);
+        if spare.is_null() {
+            return OomAction::Fail;
+        }
+        unsafe { HEAP.dealloc(spare, BLOCK) };
+        OomAction::Retry
+    }
+
+    unsafe { HEAP.init(memory(4096), 4096) };
+    let mut blocks = fill(&HEAP);
+    SPARE.store(blocks.pop().unwrap(), Ordering::Relaxed);
+    HEAP.set_oom_handler(handler);
+
+    let ptr = unsafe { HEAP.alloc(BLOCK) };
+    assert!(!ptr.is_null());
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    blocks.push(ptr);
+
+    // Nothing left to free: the allocation fails after one more call.
+    assert!(unsafe { HEAP.alloc(BLOCK) }.is_null());
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+
+    HEAP.clear_oom_handler();
+    assert!(unsafe { HEAP.alloc(BLOCK) }.is_null());
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn is_called_again_after_it_returned() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn handler(_: Layout, _: &HeapStats) -> OomAction {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        OomAction::Fail
+    }
+
+    unsafe { HEAP.init(memory(2048), 2048) };
+    let blocks = fill(&HEAP);
+    HEAP.set_oom_handler(handler);
+    for calls in 1..=3 {
+        assert!(unsafe { HEAP.alloc(BLOCK) }.is_null());
+        assert_eq!(CALLS.load(Ordering::Relaxed), calls);
+    }
+    for ptr in blocks {
+        unsafe { HEAP.dealloc(ptr, BLOCK) };
+    }
+}
+
+#[test]
+#[cfg(feature = "stats")]
+fn counts_each_failure_once() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static RETRY: AtomicUsize = AtomicUsize::new(1);
+
+    fn handler(_: Layout, _: &HeapStats) -> OomAction {
+        if RETRY.load(Ordering::Relaxed) == 1 {
+            OomAction::Retry
+        } else {
+            OomAction::Fail
+        }
+    }
+
+    unsafe { HEAP.init(memory(2048), 2048) };
+    let blocks = fill(&HEAP);
+    assert_eq!(HEAP.failed_allocations(), 1);
+    HEAP.set_oom_handler(handler);
+
+    // A retry that fails again is still one failed allocation.
+    assert!(unsafe { HEAP.alloc(BLOCK) }.is_null());
+    assert_eq!(HEAP.failed_allocations(), 2);
+    RETRY.store(0, Ordering::Relaxed);
+    assert!(unsafe { HEAP.alloc(BLOCK) }.is_null());
+    assert_eq!(HEAP.failed_allocations(), 3);
+    assert_eq!(HEAP.largest_failed_allocation(), BLOCK.size());
+
+    for ptr in blocks {
+        unsafe { HEAP.dealloc(ptr, BLOCK) };
+    }
+}
+
+#[test]
+fn rejects_allocations_above_the_maximum_size() {
+    static HEAP: EspHeap = EspHeap::empty();
+    static OVERSIZED: AtomicUsize = AtomicUsize::new(0);
+
+    fn handler(layout: Layout, _: &HeapStats) -> OomAction {
+        if layout.size() > HEAP.max_alloc_size() {
+            OVERSIZED.fetch_add(1, Ordering::Relaxed);
+        }
+        OomAction::Fail
+    }
+
+    unsafe { HEAP.init(memory(8192), 8192) };
+    #[cfg(feature = "quarantine")]
+    HEAP.set_quarantine_limits(0, 0);
+    assert_eq!(HEAP.max_alloc_size(), usize::MAX);
+    HEAP.set_max_alloc_size(1024);
+    HEAP.set_oom_handler(handler);
+
+    let fits = Layout::from_size_align(1024, 8).unwrap();
+    let too_big = Layout::from_size_align(1025, 8).unwrap();
+    let ptr = unsafe { HEAP.alloc(fits) };
+    assert!(!ptr.is_null());
+    assert!(unsafe { HEAP.alloc(too_big) }.is_null());
+    assert_eq!(OVERSIZED.load(Ordering::Relaxed), 1);
+
+    let mut out = [core::ptr::null_mut(); 2];
+    HEAP.alloc_batch(&[BLOCK, too_big], &mut out);
+    assert!(!out[0].is_null());
+    assert!(out[1].is_null());
+
+    // Growing past the cap fails and leaves the block alone.
+    unsafe { ptr.write_bytes(0xa5, fits.size()) };
+    assert!(unsafe { HEAP.realloc(ptr, fits, 4096) }.is_null());
+    assert!((0..fits.size()).all(|offset| unsafe { ptr.add(offset).read() } == 0xa5));
+    assert_eq!(OVERSIZED.load(Ordering::Relaxed), 2);
+
+    HEAP.set_max_alloc_size(usize::MAX);
+    let ptr = unsafe { HEAP.realloc(ptr, fits, 4096) };
+    assert!(!ptr.is_null());
+    unsafe { HEAP.dealloc(ptr, Layout::from_size_align(4096, 8).unwrap()) };
+    unsafe { HEAP.dealloc(out[0], BLOCK) };
+    assert_eq!(HEAP.used(), 0);
+}
+
+#[test]
+#[cfg(feature = "oom-handler")]
+fn reports_the_heap_to_the_sink_before_panicking() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::Mutex;
+
+    use common::panic_message;
+
+    static HEAP: EspHeap = EspHeap::empty();
+    static REPORTS: Mutex<Vec<(HeapStats, Layout)>> = Mutex::new(Vec::new());
+
+    fn sink(stats: &HeapStats, layout: Layout) {
+        // The heap's lock is free again: this neither deadlocks nor calls
+        // the sink twice.
+        assert_eq!(HEAP.free(), stats.free);
+        assert!(unsafe { HEAP.alloc(layout) }.is_null());
+        REPORTS.lock().unwrap().push((*stats, layout));
+    }
+
+    fn handler(layout: Layout, _: &HeapStats) -> OomAction {
+        HEAP.handle_alloc_error(layout)
+    }
+
+    unsafe { HEAP.init(memory(4096), 4096) };
+    let _blocks = fill(&HEAP);
+    HEAP.set_oom_sink(sink);
+
+    let result = catch_unwind(AssertUnwindSafe(|| HEAP.handle_alloc_error(BLOCK)));
+    let message = panic_message(result.unwrap_err());
+    if cfg!(feature = "verbose-errors") {
+        assert!(message.contains("256 bytes"), "{message}");
+    }
+    {
+        let reports = REPORTS.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        let (stats, layout) = reports[0];
+        assert_eq!(layout, BLOCK);
+        assert!(stats.free < BLOCK.size());
+        assert!(stats.largest_free_block < BLOCK.size());
+        assert!(stats.regions[0].initialized);
+        assert_eq!(stats.min_free, HEAP.min_free());
+    }
+
+    // On stable, the out-of-memory handler gets there as well.
+    HEAP.set_oom_handler(handler);
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe { HEAP.alloc(BLOCK) }));
+    assert!(result.is_err());
+    assert_eq!(REPORTS.lock().unwrap().len(), 2);
+
+    // Without the sink, the error still panics, but reports nothing.
+    HEAP.clear_oom_sink();
+    let result = catch_unwind(AssertUnwindSafe(|| HEAP.handle_alloc_error(BLOCK)));
+    assert!(result.is_err());
+    assert_eq!(REPORTS.lock().unwrap().len(), 2);
+}
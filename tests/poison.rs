@@ -0,0 +1,72 @@
+//! Poisoned blocks (the `poison` feature)
+
+#![cfg(feature = "poison")]
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability, ALLOC_POISON, FREE_POISON};
+
+fn bytes<'a>(ptr: *mut u8, range: core::ops::Range<usize>) -> &'a [u8] {
+    unsafe { core::slice::from_raw_parts(ptr.add(range.start), range.len()) }
+}
+
+#[test]
+fn fills_allocated_and_freed_blocks() {
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    let layout = Layout::from_size_align(128, 4).unwrap();
+
+    let ptr = unsafe { heap.alloc(layout) };
+    assert!(bytes(ptr, 0..128).iter().all(|&byte| byte == ALLOC_POISON));
+    unsafe { ptr.write_bytes(0x42, 128) };
+    let pin = unsafe { heap.alloc(layout) };
+    unsafe { heap.dealloc(ptr, layout) };
+
+    // Something else is allocated, somewhere else.
+    let other = Layout::from_size_align(512, 4).unwrap();
+    let elsewhere = unsafe { heap.alloc(other) };
+    assert!(elsewhere.is_null() || elsewhere != ptr);
+
+    // The allocator keeps its own data at the start of the freed block.
+    assert!(bytes(ptr, 16..128).iter().all(|&byte| byte == FREE_POISON));
+
+    unsafe {
+        heap.dealloc(elsewhere, other);
+        heap.dealloc(pin, layout);
+    }
+}
+
+#[test]
+fn still_zeroes_zeroed_allocations() {
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    let layout = Layout::from_size_align(256, 8).unwrap();
+
+    for _ in 0..3 {
+        let ptr = unsafe { heap.alloc_zeroed(layout) };
+        assert!(bytes(ptr, 0..256).iter().all(|&byte| byte == 0));
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn leaves_large_blocks_alone() {
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    heap.set_poison_limit(64);
+
+    let small = Layout::from_size_align(64, 4).unwrap();
+    let large = Layout::from_size_align(65, 4).unwrap();
+    let a = unsafe { heap.alloc(small) };
+    let b = unsafe { heap.alloc(large) };
+    assert!(bytes(a, 0..64).iter().all(|&byte| byte == ALLOC_POISON));
+    // Fresh memory, apart from the allocator's data at the start.
+    assert!(bytes(b, 16..65).iter().all(|&byte| byte == 0));
+    unsafe {
+        heap.dealloc(a, small);
+        heap.dealloc(b, large);
+    }
+}
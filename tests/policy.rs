@@ -0,0 +1,190 @@
+//! Which region an allocation is served from
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::{add, region_of};
+use esp_alloc::{EspHeap, MemoryCapability};
+
+#[test]
+fn sends_large_allocations_to_the_last_region() {
+    let heap = EspHeap::empty();
+    let (internal, _) = add(&heap, 16 * 1024, MemoryCapability::INTERNAL);
+    let (external, _) = add(&heap, 256 * 1024, MemoryCapability::EXTERNAL);
+    let large = Layout::from_size_align(4096, 4).unwrap();
+    let small = Layout::from_size_align(32, 4).unwrap();
+
+    let before = unsafe { heap.alloc(large) };
+    assert_eq!(region_of(&heap, before), Some(internal.index()));
+    unsafe { heap.dealloc(before, large) };
+
+    heap.set_large_alloc_threshold(1024);
+    let mut blocks = Vec::new();
+    for _ in 0..8 {
+        let ptr = unsafe { heap.alloc(large) };
+        assert_eq!(region_of(&heap, ptr), Some(external.index()));
+        blocks.push((ptr, large));
+        let ptr = unsafe { heap.alloc(small) };
+        assert_eq!(region_of(&heap, ptr), Some(internal.index()));
+        blocks.push((ptr, small));
+    }
+    assert_eq!(heap.available_for(large), Some(external));
+    assert_eq!(heap.available_for(small), Some(internal));
+
+    // Growing past the threshold keeps a block in its kind of memory.
+    let (ptr, layout) = blocks.pop().unwrap();
+    let grown = unsafe { heap.realloc(ptr, layout, 2048) };
+    assert_eq!(region_of(&heap, grown), Some(internal.index()));
+    blocks.push((grown, Layout::from_size_align(2048, 4).unwrap()));
+
+    for (ptr, layout) in blocks {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn falls_back_to_the_first_region_for_large_allocations() {
+    let heap = EspHeap::empty();
+    let (internal, _) = add(&heap, 16 * 1024, MemoryCapability::INTERNAL);
+    add(&heap, 4 * 1024, MemoryCapability::EXTERNAL);
+    heap.set_large_alloc_threshold(1024);
+    let large = Layout::from_size_align(6000, 4).unwrap();
+
+    let ptr = unsafe { heap.alloc(large) };
+    assert_eq!(region_of(&heap, ptr), Some(internal.index()));
+    unsafe { heap.dealloc(ptr, large) };
+}
+
+#[test]
+fn allocates_from_one_region_only() {
+    let heap = EspHeap::empty();
+    let (first, _) = add(&heap, 2048, MemoryCapability::INTERNAL);
+    let (second, _) = add(&heap, 8192, MemoryCapability::EXTERNAL);
+    let layout = Layout::from_size_align(128, 4).unwrap();
+
+    let mut blocks = Vec::new();
+    loop {
+        let ptr = heap.alloc_in_region(first, layout);
+        if ptr.is_null() {
+            break;
+        }
+        assert_eq!(region_of(&heap, ptr), Some(first.index()));
+        blocks.push(ptr);
+    }
+    assert!(!blocks.is_empty());
+
+    // The first region is full, but a plain allocation falls back.
+    let ptr = unsafe { heap.alloc(layout) };
+    assert_eq!(region_of(&heap, ptr), Some(second.index()));
+    blocks.push(ptr);
+    assert!(heap.alloc_internal(layout).is_null());
+    let ptr = heap.alloc_external(layout);
+    assert_eq!(region_of(&heap, ptr), Some(second.index()));
+    blocks.push(ptr);
+
+    // A region without memory yet serves nothing.
+    let expected = heap.register_expected(esp_alloc::RegionDescriptor::new(
+        "psram",
+        MemoryCapability::EXTERNAL,
+    ));
+    assert!(heap.alloc_in_region(expected, layout).is_null());
+
+    for ptr in blocks {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn skips_regions_without_the_capabilities() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::INTERNAL);
+    let (dma, _) = add(
+        &heap,
+        4096,
+        MemoryCapability::INTERNAL | MemoryCapability::DMA,
+    );
+    let layout = Layout::from_size_align(64, 4).unwrap();
+
+    let ptr = heap.alloc_caps(MemoryCapability::DMA, layout);
+    assert_eq!(region_of(&heap, ptr), Some(dma.index()));
+    assert!(heap
+        .alloc_caps(MemoryCapability::EXTERNAL, layout)
+        .is_null());
+    unsafe { heap.dealloc(ptr, layout) };
+}
+
+#[test]
+fn knows_when_alignment_leaves_no_room() {
+    let heap = EspHeap::empty();
+    let memory = Layout::from_size_align(1024, 1024).unwrap();
+    let bottom = unsafe { std::alloc::alloc_zeroed(memory) };
+    let region = unsafe { heap.add_region(bottom, 1024, MemoryCapability::empty()) };
+    #[cfg(feature = "quarantine")]
+    heap.set_quarantine_limits(0, 0);
+    let first = Layout::from_size_align(16, 4).unwrap();
+    let ptr = unsafe { heap.alloc(first) };
+
+    // The free block is big enough, but not from an address this aligned.
+    let aligned = Layout::from_size_align(600, 512).unwrap();
+    assert!(heap.largest_free_block(region) >= aligned.size());
+    assert_eq!(heap.available_for(aligned), None);
+    assert!(unsafe { heap.alloc(aligned) }.is_null());
+
+    unsafe { heap.dealloc(ptr, first) };
+    // The canary in front of a guarded block needs room below the aligned
+    // address, which the region doesn't have.
+    if cfg!(feature = "heap-guard") {
+        assert_eq!(heap.available_for(aligned), None);
+    } else {
+        assert_eq!(heap.available_for(aligned), Some(region));
+        let ptr = unsafe { heap.alloc(aligned) };
+        assert_eq!(ptr, bottom);
+        unsafe { heap.dealloc(ptr, aligned) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn answers_like_the_next_allocation() {
+    for seed in 0..16 {
+        let heap = EspHeap::empty();
+        add(&heap, 2048, MemoryCapability::INTERNAL);
+        add(&heap, 4096, MemoryCapability::EXTERNAL);
+        heap.set_large_alloc_threshold(512);
+        let mut rng = common::Rng::new(seed);
+        let mut blocks = Vec::new();
+
+        for step in 0..400 {
+            if !blocks.is_empty() && rng.below(3) == 0 {
+                let (ptr, layout) = blocks.swap_remove(rng.below(blocks.len()));
+                unsafe { heap.dealloc(ptr, layout) };
+                continue;
+            }
+            let layout = Layout::from_size_align(1 + rng.below(768), 1 << rng.below(9)).unwrap();
+            let available = heap.available_for(layout);
+            let ptr = unsafe { heap.alloc(layout) };
+            let served = (!ptr.is_null()).then(|| region_of(&heap, ptr).unwrap());
+            // A block from the free cache may serve what the free lists can't.
+            #[cfg(feature = "free-cache")]
+            if available.is_none() {
+                continue;
+            }
+            assert_eq!(
+                available.map(|id| id.index()),
+                served,
+                "seed {seed}, step {step}, {layout:?}"
+            );
+            if !ptr.is_null() {
+                blocks.push((ptr, layout));
+            }
+        }
+
+        for (ptr, layout) in blocks {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        assert_eq!(heap.used(), 0, "seed {seed}");
+    }
+}
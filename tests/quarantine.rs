@@ -0,0 +1,133 @@
+//! Delayed reuse of freed blocks (the `quarantine` feature)
+
+#![cfg(feature = "quarantine")]
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability, WatchHit};
+
+#[test]
+fn holds_freed_blocks_back() {
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    heap.set_quarantine_limits(2, 4096);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let first = unsafe { heap.alloc(layout) };
+    unsafe { heap.dealloc(first, layout) };
+    assert_eq!(heap.quarantined(), 64);
+    let second = unsafe { heap.alloc(layout) };
+    assert_ne!(second, first);
+
+    // A third block pushes the first one out.
+    let third = unsafe { heap.alloc(layout) };
+    unsafe {
+        heap.dealloc(second, layout);
+        heap.dealloc(third, layout);
+    }
+    assert_eq!(heap.quarantined(), 128);
+
+    heap.flush_quarantine();
+    assert_eq!((heap.quarantined(), heap.used()), (0, 0));
+}
+
+#[test]
+fn releases_blocks_before_failing() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_quarantine_limits(16, 4096);
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+
+    let first = unsafe { heap.alloc(layout) };
+    let second = unsafe { heap.alloc(layout) };
+    unsafe {
+        heap.dealloc(first, layout);
+        heap.dealloc(second, layout);
+    }
+    assert_eq!(heap.quarantined(), 2048);
+
+    let big = Layout::from_size_align(3000, 8).unwrap();
+    let ptr = unsafe { heap.alloc(big) };
+    assert!(!ptr.is_null());
+    assert_eq!(heap.quarantined(), 0);
+    unsafe { heap.dealloc(ptr, big) };
+}
+
+#[test]
+fn checks_watches_when_blocks_leave() {
+    static HITS: AtomicUsize = AtomicUsize::new(0);
+
+    fn hit(_: WatchHit) {
+        HITS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    heap.set_quarantine_limits(1, 4096);
+    let watched = Box::leak(Box::new([0u8; 16]));
+    assert!(unsafe { heap.watch_range(watched.as_ptr(), 16, hit) });
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    unsafe { watched.as_mut_ptr().write_volatile(1) };
+    let first = unsafe { heap.alloc(layout) };
+    let second = unsafe { heap.alloc(layout) };
+    unsafe { heap.dealloc(first, layout) };
+    assert_eq!(HITS.load(Ordering::Relaxed), 0);
+
+    // The first block leaves as the second one comes in.
+    unsafe { heap.dealloc(second, layout) };
+    assert_eq!(HITS.load(Ordering::Relaxed), 1);
+
+    unsafe { watched.as_mut_ptr().write_volatile(2) };
+    heap.flush_quarantine();
+    assert_eq!(HITS.load(Ordering::Relaxed), 2);
+    heap.flush_quarantine();
+    assert_eq!(HITS.load(Ordering::Relaxed), 2);
+    heap.unwatch_range(watched.as_ptr());
+}
+
+#[test]
+#[cfg(all(feature = "poison", feature = "verbose-errors"))]
+fn panics_on_a_write_to_a_quarantined_block() {
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    heap.set_quarantine_limits(4, 4096);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let ptr = unsafe { heap.alloc(layout) };
+    unsafe { heap.dealloc(ptr, layout) };
+    unsafe { ptr.add(40).write(0x42) };
+
+    let message = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        heap.flush_quarantine();
+    }))
+    .map_err(common::panic_message)
+    .unwrap_err();
+    assert_eq!(
+        message,
+        format!("Write to the freed block of 64 bytes at {ptr:p}, 40 bytes in")
+    );
+}
+
+#[test]
+#[cfg(feature = "poison")]
+fn releases_intact_blocks() {
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    heap.set_quarantine_limits(4, 4096);
+    heap.set_poison_limit(32);
+
+    // Blocks that weren't poisoned aren't checked either.
+    for size in [16, 64] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = unsafe { heap.alloc(layout) };
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    heap.set_poison_limit(4096);
+    heap.flush_quarantine();
+    assert_eq!(heap.used(), 0);
+}
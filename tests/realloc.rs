@@ -0,0 +1,218 @@
+//! Resizing blocks, in place where possible
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::{add, region_of};
+use esp_alloc::{EspHeap, MemoryCapability};
+
+#[test]
+fn grows_in_place_when_the_space_behind_is_free() {
+    let heap = EspHeap::empty();
+    add(&heap, 128 * 1024, MemoryCapability::empty());
+
+    let mut layout = Layout::from_size_align(1024, 4).unwrap();
+    let mut ptr = unsafe { heap.alloc(layout) };
+    unsafe { ptr.write_bytes(0x11, layout.size()) };
+    let (mut grows, mut moves) = (0, 0);
+    while layout.size() < 64 * 1024 {
+        let new_size = layout.size() + 1024;
+        let new = unsafe { heap.realloc(ptr, layout, new_size) };
+        assert!(!new.is_null());
+        grows += 1;
+        if new != ptr {
+            moves += 1;
+        }
+        let contents = unsafe { core::slice::from_raw_parts(new, layout.size()) };
+        assert!(contents.iter().all(|&byte| byte == 0x11));
+        unsafe {
+            new.add(layout.size())
+                .write_bytes(0x11, new_size - layout.size())
+        };
+        ptr = new;
+        layout = Layout::from_size_align(new_size, 4).unwrap();
+    }
+    assert_eq!(grows, 63);
+    assert_eq!(moves, 0);
+    assert_eq!(heap.live_bytes(), 64 * 1024);
+
+    unsafe { heap.dealloc(ptr, layout) };
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn moves_blocks_that_cannot_grow_in_place() {
+    let heap = EspHeap::empty();
+    add(&heap, 8 * 1024, MemoryCapability::empty());
+    let layout = Layout::from_size_align(256, 8).unwrap();
+
+    let ptr = unsafe { heap.alloc(layout) };
+    let pin = unsafe { heap.alloc(layout) };
+    for offset in 0..layout.size() {
+        unsafe { ptr.add(offset).write(offset as u8) };
+    }
+
+    let new = unsafe { heap.realloc(ptr, layout, 1024) };
+    assert!(!new.is_null());
+    assert_ne!(new, ptr);
+    for offset in 0..layout.size() {
+        assert_eq!(unsafe { new.add(offset).read() }, offset as u8);
+    }
+    assert_eq!(heap.live_bytes(), 1024 + layout.size());
+
+    unsafe { heap.dealloc(new, Layout::from_size_align(1024, 8).unwrap()) };
+    unsafe { heap.dealloc(pin, layout) };
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn grows_into_the_hole_behind_only() {
+    let heap = EspHeap::empty();
+    add(&heap, 8 * 1024, MemoryCapability::empty());
+    let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+    let front = unsafe { heap.alloc(layout(64)) };
+    let ptr = unsafe { heap.alloc(layout(256)) };
+    let behind = unsafe { heap.alloc(layout(512)) };
+    let pin = unsafe { heap.alloc(layout(256)) };
+    unsafe {
+        ptr.write_bytes(0x22, 256);
+        heap.dealloc(front, layout(64));
+        heap.dealloc(behind, layout(512));
+    }
+
+    // The hole in front is too small, so the one behind serves the growth.
+    let grown = unsafe { heap.realloc(ptr, layout(256), 512) };
+    assert_eq!(grown, ptr);
+    unsafe { grown.add(256).write_bytes(0x22, 256) };
+
+    // Nothing is left behind it, so the block moves.
+    let moved = unsafe { heap.realloc(grown, layout(512), 1024) };
+    assert!(!moved.is_null() && moved != grown);
+    let contents = unsafe { core::slice::from_raw_parts(moved, 512) };
+    assert!(contents.iter().all(|&byte| byte == 0x22));
+    assert_eq!(heap.live_bytes(), 1024 + 256);
+
+    unsafe {
+        heap.dealloc(moved, layout(1024));
+        heap.dealloc(pin, layout(256));
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn grows_in_place_while_an_earlier_hole_is_free() {
+    let heap = EspHeap::empty();
+    add(&heap, 8 * 1024, MemoryCapability::empty());
+    let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+    let front = unsafe { heap.alloc(layout(1024)) };
+    let ptr = unsafe { heap.alloc(layout(256)) };
+    let behind = unsafe { heap.alloc(layout(512)) };
+    let pin = unsafe { heap.alloc(layout(256)) };
+    unsafe {
+        ptr.write_bytes(0x33, 256);
+        heap.dealloc(front, layout(1024));
+        heap.dealloc(behind, layout(512));
+    }
+
+    // Growing by less than a hole takes it from the hole behind all the
+    // same, as does growing by what the hole in front could serve.
+    let mut size = 256;
+    for new_size in [264, 512, 768] {
+        let grown = unsafe { heap.realloc(ptr, layout(size), new_size) };
+        assert_eq!(grown, ptr);
+        unsafe { ptr.add(size).write_bytes(0x33, new_size - size) };
+        size = new_size;
+    }
+    let contents = unsafe { core::slice::from_raw_parts(ptr, size) };
+    assert!(contents.iter().all(|&byte| byte == 0x33));
+    assert_eq!(heap.live_bytes(), 768 + 256);
+
+    // The hole in front is still there.
+    assert_eq!(unsafe { heap.alloc(layout(1024)) }, front);
+    unsafe {
+        heap.dealloc(front, layout(1024));
+        heap.dealloc(ptr, layout(size));
+        heap.dealloc(pin, layout(256));
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn shrinks_in_place_and_frees_the_tail() {
+    let heap = EspHeap::empty();
+    add(&heap, 8 * 1024, MemoryCapability::empty());
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+
+    let ptr = unsafe { heap.alloc(layout) };
+    let used = heap.used();
+    let new = unsafe { heap.realloc(ptr, layout, 1000) };
+    assert_eq!(new, ptr);
+    assert!(heap.used() <= used - 3000);
+
+    // The freed tail is available again.
+    let tail = Layout::from_size_align(2048, 8).unwrap();
+    let other = unsafe { heap.alloc(tail) };
+    assert!(!other.is_null());
+
+    unsafe { heap.dealloc(other, tail) };
+    unsafe { heap.dealloc(new, Layout::from_size_align(1000, 8).unwrap()) };
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn keeps_moved_blocks_in_the_same_kind_of_memory() {
+    let heap = EspHeap::empty();
+    add(&heap, 16 * 1024, MemoryCapability::INTERNAL);
+    add(&heap, 16 * 1024, MemoryCapability::EXTERNAL);
+    let layout = Layout::from_size_align(512, 8).unwrap();
+
+    let ptr = heap.alloc_caps(MemoryCapability::EXTERNAL, layout);
+    let pin = heap.alloc_caps(MemoryCapability::EXTERNAL, layout);
+    assert_eq!(region_of(&heap, ptr), Some(1));
+
+    // The internal region has room, but the block stays external.
+    let new = unsafe { heap.realloc(ptr, layout, 4096) };
+    assert_ne!(new, ptr);
+    assert_eq!(region_of(&heap, new), Some(1));
+
+    unsafe { heap.dealloc(new, Layout::from_size_align(4096, 8).unwrap()) };
+    unsafe { heap.dealloc(pin, layout) };
+}
+
+#[test]
+fn moves_blocks_growing_past_the_threshold_among_their_kind() {
+    let heap = EspHeap::empty();
+    let (dma, _) = add(
+        &heap,
+        8192,
+        MemoryCapability::INTERNAL | MemoryCapability::DMA,
+    );
+    let (plain, _) = add(&heap, 4096, MemoryCapability::empty());
+    let (external, _) = add(&heap, 8192, MemoryCapability::EXTERNAL);
+    heap.set_large_alloc_threshold(1024);
+    let small = Layout::from_size_align(100, 4).unwrap();
+    let large = Layout::from_size_align(2000, 4).unwrap();
+
+    // A block from a region without capabilities goes wherever large
+    // allocations go first.
+    let ptr = heap.alloc_in_region(plain, small);
+    let grown = unsafe { heap.realloc(ptr, small, large.size()) };
+    assert_eq!(region_of(&heap, grown), Some(external.index()));
+    unsafe { heap.dealloc(grown, large) };
+
+    // One that may have been allocated with capabilities keeps them.
+    let ptr = heap.alloc_caps(MemoryCapability::DMA, small);
+    assert_eq!(region_of(&heap, ptr), Some(dma.index()));
+    let pin = heap.alloc_caps(MemoryCapability::DMA, small);
+    let grown = unsafe { heap.realloc(ptr, small, large.size()) };
+    assert_eq!(region_of(&heap, grown), Some(dma.index()));
+
+    unsafe {
+        heap.dealloc(grown, large);
+        heap.dealloc(pin, small);
+    }
+    assert_eq!(heap.used(), 0);
+}
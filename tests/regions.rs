@@ -0,0 +1,337 @@
+//! Routing between regions, alignment, exhaustion and accounting
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::{add, memory, region_of};
+use esp_alloc::{EspHeap, MemoryCapability, RegionDescriptor};
+
+#[test]
+fn falls_back_to_the_next_region() {
+    let heap = EspHeap::empty();
+    add(&heap, 1024, MemoryCapability::empty());
+    add(&heap, 8192, MemoryCapability::empty());
+    let layout = Layout::from_size_align(128, 4).unwrap();
+
+    let mut blocks = Vec::new();
+    loop {
+        let ptr = unsafe { heap.alloc(layout) };
+        assert!(!ptr.is_null());
+        blocks.push(ptr);
+        if region_of(&heap, ptr) == Some(1) {
+            break;
+        }
+    }
+    let (first, rest) = blocks.split_last().unwrap();
+    assert!(rest.len() > 1);
+    assert!(rest.iter().all(|&ptr| region_of(&heap, ptr) == Some(0)));
+
+    // Freed room in the first region is preferred again.
+    unsafe { heap.dealloc(rest[0], layout) };
+    let ptr = unsafe { heap.alloc(layout) };
+    assert_eq!(region_of(&heap, ptr), Some(0));
+
+    for &ptr in rest[1..].iter().chain([first, &ptr]) {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.live_bytes(), 0);
+}
+
+#[test]
+fn routes_deallocations_at_the_boundary_of_adjacent_regions() {
+    let heap = EspHeap::empty();
+    let memory = memory(4096);
+    let (first, second) = unsafe {
+        (
+            heap.add_region(memory, 2048, MemoryCapability::empty()),
+            heap.add_region(memory.add(2048), 2048, MemoryCapability::empty()),
+        )
+    };
+    #[cfg(feature = "quarantine")]
+    heap.set_quarantine_limits(0, 0);
+    let layout = Layout::from_size_align(64, 4).unwrap();
+
+    let mut blocks = Vec::new();
+    let boundary = loop {
+        let ptr = unsafe { heap.alloc(layout) };
+        assert!(!ptr.is_null());
+        if region_of(&heap, ptr) == Some(second.index()) {
+            break ptr;
+        }
+        blocks.push(ptr);
+    };
+    // The first block of the second region, behind any canaries.
+    assert!(boundary as usize - (memory as usize + 2048) < 64);
+
+    let before = heap.stats();
+    unsafe { heap.dealloc(boundary, layout) };
+    let after = heap.stats();
+    assert_eq!(after.regions[first.index()], before.regions[first.index()]);
+    assert!(after.regions[second.index()].used < before.regions[second.index()].used);
+    assert_eq!(
+        after.regions[second.index()].free,
+        after.regions[second.index()].size
+    );
+
+    for ptr in blocks {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn panics_on_deallocating_a_foreign_pointer() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use common::panic_message;
+
+    let heap = EspHeap::empty();
+    add(&heap, 1024, MemoryCapability::empty());
+    let mut local = [0u8; 16];
+
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe {
+        heap.dealloc(local.as_mut_ptr(), Layout::new::<[u8; 16]>())
+    }));
+    let message = panic_message(result.unwrap_err());
+    // Quarantined blocks have their canaries checked first.
+    if cfg!(all(feature = "heap-guard", feature = "quarantine")) {
+        return;
+    }
+    let expected = if cfg!(feature = "verbose-errors") {
+        "which is in no heap region"
+    } else {
+        "deallocation outside of the heap"
+    };
+    assert!(message.contains(expected), "{message}");
+}
+
+#[test]
+fn honors_alignments_up_to_64_bytes() {
+    let heap = EspHeap::empty();
+    add(&heap, 16 * 1024, MemoryCapability::empty());
+
+    for shift in 0..=6 {
+        let align = 1 << shift;
+        let mut blocks = Vec::new();
+        for size in [1, 7, 24, 100, 333] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = unsafe { heap.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0, "{layout:?}");
+            unsafe { ptr.write_bytes(0x5a, size) };
+            blocks.push((ptr, layout));
+        }
+        for (ptr, layout) in blocks {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn grows_the_stack_reserve_to_the_high_water_mark() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    add(&heap, 4096, MemoryCapability::empty());
+    let reserve = |heap: &EspHeap| heap.region_configs().next().unwrap().stack_reserve;
+
+    heap.set_reserved_for_stack(512);
+    heap.note_stack_high_water(1024);
+    assert_eq!(reserve(&heap), 1024);
+    // A lower mark never shrinks it.
+    heap.note_stack_high_water(256);
+    assert_eq!(reserve(&heap), 1024);
+
+    // What would reach into the reserve goes to the next region.
+    let layout = Layout::from_size_align(3500, 8).unwrap();
+    let ptr = unsafe { heap.alloc(layout) };
+    assert_eq!(region_of(&heap, ptr), Some(1));
+    unsafe { heap.dealloc(ptr, layout) };
+
+    heap.set_reserved_for_stack(0);
+    assert_eq!(reserve(&heap), 0);
+    let ptr = unsafe { heap.alloc(layout) };
+    assert_eq!(region_of(&heap, ptr), Some(0));
+    unsafe { heap.dealloc(ptr, layout) };
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn recovers_from_exhaustion() {
+    let heap = EspHeap::empty();
+    add(&heap, 2048, MemoryCapability::empty());
+    add(&heap, 2048, MemoryCapability::empty());
+    let layout = Layout::from_size_align(96, 8).unwrap();
+
+    let fill = |heap: &EspHeap| {
+        let mut blocks = Vec::new();
+        loop {
+            let ptr = unsafe { heap.alloc(layout) };
+            if ptr.is_null() {
+                break blocks;
+            }
+            blocks.push(ptr);
+        }
+    };
+
+    let blocks = fill(&heap);
+    assert!(blocks.iter().any(|&ptr| region_of(&heap, ptr) == Some(1)));
+    assert!(heap.free() < 2 * layout.size());
+    for &ptr in &blocks {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.used(), 0);
+
+    let again = fill(&heap);
+    assert_eq!(again.len(), blocks.len());
+    for ptr in again {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn accounts_for_used_and_free_bytes() {
+    let heap = EspHeap::empty();
+    assert_eq!((heap.used(), heap.free(), heap.live_bytes()), (0, 0, 0));
+
+    add(&heap, 4096, MemoryCapability::empty());
+    add(&heap, 8192, MemoryCapability::empty());
+    let total = heap.free();
+    assert_eq!(heap.used(), 0);
+    assert!(total <= 4096 + 8192 && total > 4096 + 8000);
+
+    let layouts = [
+        Layout::from_size_align(10, 1).unwrap(),
+        Layout::from_size_align(100, 4).unwrap(),
+        Layout::from_size_align(3000, 8).unwrap(),
+        Layout::from_size_align(2000, 16).unwrap(),
+    ];
+    let mut blocks = Vec::new();
+    for layout in layouts {
+        let ptr = unsafe { heap.alloc(layout) };
+        assert!(!ptr.is_null());
+        blocks.push((ptr, layout));
+
+        let requested: usize = blocks.iter().map(|(_, layout)| layout.size()).sum();
+        assert_eq!(heap.live_bytes(), requested);
+        assert!(heap.used() >= requested);
+        assert_eq!(heap.used() + heap.free(), total);
+    }
+    let stats = heap.stats();
+    assert_eq!(stats.used, stats.regions[0].used + stats.regions[1].used);
+    assert_eq!(stats.free, stats.regions[0].free + stats.regions[1].free);
+
+    for (ptr, layout) in blocks {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!((heap.used(), heap.free(), heap.live_bytes()), (0, total, 0));
+}
+
+#[test]
+fn rejects_allocations_bigger_than_any_region() {
+    let heap = EspHeap::empty();
+    let (first, _) = add(&heap, 2048, MemoryCapability::empty());
+    let (second, _) = add(&heap, 4096, MemoryCapability::empty());
+
+    let too_big_for_first = Layout::from_size_align(3000, 4).unwrap();
+    let ptr = unsafe { heap.alloc(too_big_for_first) };
+    assert_eq!(region_of(&heap, ptr), Some(second.index()));
+    unsafe { heap.dealloc(ptr, too_big_for_first) };
+
+    // Together the regions would be big enough, but blocks can't span them.
+    let too_big_for_either = Layout::from_size_align(5000, 4).unwrap();
+    assert!(unsafe { heap.alloc(too_big_for_either) }.is_null());
+    let bigger_than_all = Layout::from_size_align(10_000, 4).unwrap();
+    assert!(unsafe { heap.alloc(bigger_than_all) }.is_null());
+
+    // The largest free block is exactly what fits.
+    for region in [first, second] {
+        let largest = heap.largest_free_block(region);
+        let fits = Layout::from_size_align(largest, 8).unwrap();
+        let ptr = heap.alloc_in_region(region, fits);
+        assert!(!ptr.is_null(), "{largest} bytes in {region}");
+        unsafe { heap.dealloc(ptr, fits) };
+        let too_big = Layout::from_size_align(largest + 8, 8).unwrap();
+        assert!(heap.alloc_in_region(region, too_big).is_null());
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn starts_over_after_a_reset() {
+    let heap = EspHeap::empty();
+    let memory = memory(2048);
+    unsafe { heap.init(memory, 2048) };
+    let layout = Layout::from_size_align(100, 4).unwrap();
+    let ptr = unsafe { heap.alloc(layout) };
+    assert!(!ptr.is_null());
+
+    unsafe { heap.reset() };
+    assert!(!heap.is_initialized());
+    assert_eq!((heap.used(), heap.free()), (0, 0));
+
+    unsafe { heap.init(memory, 2048) };
+    assert_eq!(heap.used(), 0);
+    let again = unsafe { heap.alloc(layout) };
+    assert_eq!(again, ptr);
+    unsafe { heap.dealloc(again, layout) };
+}
+
+#[test]
+fn reports_expected_regions_until_they_are_available() {
+    let heap = EspHeap::empty();
+    let psram = heap.register_expected(RegionDescriptor::new("psram", MemoryCapability::EXTERNAL));
+    let flash = heap.register_expected(RegionDescriptor::new("flash", MemoryCapability::EXTERNAL));
+    let (internal, _) = add(&heap, 4096, MemoryCapability::INTERNAL);
+    assert_eq!((psram.index(), flash.index(), internal.index()), (0, 1, 2));
+
+    let missing: Vec<_> = heap.missing_regions().collect();
+    assert_eq!(missing.len(), 2);
+    assert_eq!((missing[0].region, missing[0].reason), (psram, None));
+    assert_eq!(missing[1].capabilities, MemoryCapability::EXTERNAL);
+
+    // Expected regions serve nothing.
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    assert!(heap
+        .alloc_caps(MemoryCapability::EXTERNAL, layout)
+        .is_null());
+    let ptr = unsafe { heap.alloc(layout) };
+    assert_eq!(region_of(&heap, ptr), Some(2));
+    unsafe { heap.dealloc(ptr, layout) };
+
+    heap.mark_unavailable(flash, "probe failed");
+    let size = 8192;
+    unsafe { heap.mark_available(psram, memory(size), size) };
+    let missing: Vec<_> = heap.missing_regions().collect();
+    assert_eq!(missing.len(), 1);
+    assert_eq!(
+        (missing[0].region, missing[0].reason),
+        (flash, Some("probe failed"))
+    );
+
+    let ptr = heap.alloc_caps(MemoryCapability::EXTERNAL, layout);
+    assert_eq!(region_of(&heap, ptr), Some(0));
+    unsafe { heap.dealloc(ptr, layout) };
+
+    // Only regions that are still expected can be marked unavailable.
+    heap.mark_unavailable(psram, "too late");
+    heap.mark_unavailable(internal, "too late");
+    assert_eq!(heap.missing_regions().count(), 1);
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn lists_the_ids_of_every_region() {
+    let heap = EspHeap::empty();
+    assert_eq!(heap.region_ids().count(), 0);
+    let (internal, _) = add(&heap, 4096, MemoryCapability::INTERNAL);
+    let psram = heap.register_expected(RegionDescriptor::new("psram", MemoryCapability::EXTERNAL));
+    let (dma, _) = add(&heap, 4096, MemoryCapability::DMA);
+
+    let ids: Vec<_> = heap.region_ids().collect();
+    assert_eq!(ids, [internal, psram, dma]);
+    assert_eq!(heap.region_id_by_index(1), Some(psram));
+    assert_eq!(heap.region_id_by_index(3), None);
+}
@@ -0,0 +1,280 @@
+//! Moving tracked allocations between regions (the `registry` feature)
+
+#![cfg(feature = "registry")]
+
+mod common;
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use common::{add, region_of};
+use esp_alloc::{EspHeap, MemoryCapability, REGISTRY_CAPACITY};
+
+fn filled(heap: &EspHeap, layout: Layout, byte: u8) -> NonNull<u8> {
+    let ptr = NonNull::new(unsafe { heap.alloc(layout) }).unwrap();
+    unsafe { ptr.as_ptr().write_bytes(byte, layout.size()) };
+    ptr
+}
+
+fn holds(ptr: NonNull<u8>, layout: Layout, byte: u8) -> bool {
+    (0..layout.size()).all(|offset| unsafe { ptr.as_ptr().add(offset).read() } == byte)
+}
+
+#[test]
+fn migrates_like_any_allocation() {
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn now() -> u64 {
+        NOW.load(Ordering::Relaxed)
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::INTERNAL);
+    let (external, _) = add(&heap, 8192, MemoryCapability::EXTERNAL);
+    heap.set_timestamp_source(now);
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    NOW.store(5, Ordering::Relaxed);
+    let ptr = filled(&heap, layout, 0x3c);
+    let sequence = heap.allocation_sequence();
+
+    NOW.store(100, Ordering::Relaxed);
+    let moved = unsafe { heap.migrate(ptr, layout, external) }.unwrap();
+    assert_eq!(region_of(&heap, moved.as_ptr()), Some(1));
+    assert!(holds(moved, layout, 0x3c));
+    assert_eq!(heap.allocation_sequence(), sequence + 1);
+    assert_eq!(heap.tracked_allocations(), 1);
+    let stats = heap.stats();
+    assert_eq!(stats.regions[0].used, 0);
+    assert!(stats.regions[1].min_free <= 8192 - 1000);
+    assert_eq!(stats.live_bytes, 1000);
+    #[cfg(feature = "stats")]
+    assert_eq!((heap.allocation_count(), heap.deallocation_count()), (2, 1));
+
+    // The copy is the same allocation, as old as the original.
+    let mut old = Vec::new();
+    heap.allocations_older_than(90, |ptr, size, age| old.push((ptr, size, age)));
+    assert_eq!(old, [(moved.as_ptr() as usize, 1000, 95)]);
+
+    // Nothing changes if the region has no room.
+    let big = Layout::from_size_align(6000, 8).unwrap();
+    let ptr = filled(&heap, big, 0x5a);
+    assert!(unsafe { heap.migrate(ptr, big, external) }.is_none());
+    assert!(holds(ptr, big, 0x5a));
+    assert_eq!(heap.tracked_allocations(), 2);
+
+    unsafe {
+        heap.dealloc(ptr.as_ptr(), big);
+        heap.dealloc(moved.as_ptr(), layout);
+    }
+    assert_eq!((heap.used(), heap.tracked_allocations()), (0, 0));
+}
+
+#[test]
+fn moves_the_allocations_the_callback_approves() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::INTERNAL);
+    add(&heap, 8192, MemoryCapability::INTERNAL);
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let mut blocks: Vec<_> = (0..8)
+        .map(|index| filled(&heap, layout, index as u8))
+        .collect();
+    assert!(blocks
+        .iter()
+        .all(|ptr| region_of(&heap, ptr.as_ptr()) == Some(0)));
+
+    // Every other block moves.
+    let mut offered = Vec::new();
+    let moved = unsafe {
+        heap.rebalance(|old, offered_layout, new| {
+            assert_eq!(offered_layout, layout);
+            assert_eq!(region_of(&heap, new as *mut u8), Some(1));
+            let index = blocks.iter().position(|ptr| *ptr == old).unwrap();
+            assert!(holds(
+                NonNull::new(new as *mut u8).unwrap(),
+                layout,
+                index as u8
+            ));
+            offered.push(index);
+            if index % 2 == 0 {
+                blocks[index] = NonNull::new(new as *mut u8).unwrap();
+            }
+            index % 2 == 0
+        })
+    };
+    assert!(moved > 0);
+    assert_eq!(
+        offered.iter().filter(|index| *index % 2 == 0).count(),
+        moved
+    );
+    // Each allocation is offered once.
+    let offers = offered.len();
+    offered.sort_unstable();
+    offered.dedup();
+    assert_eq!(offered.len(), offers);
+
+    for (index, ptr) in blocks.iter().enumerate() {
+        assert!(holds(*ptr, layout, index as u8));
+        let expected = if index % 2 == 0 && offered.contains(&index) {
+            1
+        } else {
+            0
+        };
+        assert_eq!(region_of(&heap, ptr.as_ptr()), Some(expected));
+    }
+    assert_eq!(heap.tracked_allocations(), 8);
+    assert_eq!(heap.live_bytes(), 8 * 256);
+
+    for ptr in blocks {
+        unsafe { heap.dealloc(ptr.as_ptr(), layout) };
+    }
+    assert_eq!((heap.used(), heap.tracked_allocations()), (0, 0));
+}
+
+#[test]
+fn counts_the_allocations_it_has_no_room_for() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let layout = Layout::from_size_align(16, 8).unwrap();
+
+    let blocks: Vec<_> = (0..REGISTRY_CAPACITY + 3)
+        .map(|_| filled(&heap, layout, 0))
+        .collect();
+    assert_eq!(heap.tracked_allocations(), REGISTRY_CAPACITY);
+    assert_eq!(heap.untracked_allocations(), 3);
+
+    // Only tracked allocations are offered.
+    let mut offered = 0;
+    unsafe {
+        heap.rebalance(|_, _, _| {
+            offered += 1;
+            false
+        })
+    };
+    assert!(offered <= REGISTRY_CAPACITY);
+
+    // Freed untracked allocations leave the count, and then tracked ones
+    // leave the registry.
+    for (freed, ptr) in blocks.iter().rev().enumerate() {
+        unsafe { heap.dealloc(ptr.as_ptr(), layout) };
+        if freed < 3 {
+            assert_eq!(heap.untracked_allocations(), 2 - freed);
+        }
+    }
+    assert_eq!(heap.untracked_allocations(), 0);
+    assert_eq!(heap.tracked_allocations(), 0);
+}
+
+#[test]
+#[cfg(feature = "alloc-hooks")]
+fn reports_moves_to_the_hooks() {
+    use std::sync::atomic::AtomicUsize;
+
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+    fn on_alloc(_: *mut u8, _: usize) {
+        LIVE.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_free(_: *mut u8, _: usize) {
+        LIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::INTERNAL);
+    let (external, _) = add(&heap, 8192, MemoryCapability::EXTERNAL);
+    heap.set_alloc_hooks(on_alloc, on_free);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = filled(&heap, layout, 1);
+    let moved = unsafe { heap.migrate(ptr, layout, external) }.unwrap();
+    assert_eq!(LIVE.load(Ordering::Relaxed), 1);
+    unsafe { heap.dealloc(moved.as_ptr(), layout) };
+    assert_eq!(LIVE.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn reports_the_allocations_that_are_old_enough() {
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn now() -> u64 {
+        NOW.load(Ordering::Relaxed)
+    }
+
+    fn older_than(heap: &EspHeap, age: u64) -> Vec<(usize, usize, u64)> {
+        let mut old = Vec::new();
+        heap.allocations_older_than(age, |ptr, size, age| old.push((ptr, size, age)));
+        old.sort_unstable();
+        old
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let layout = |size| Layout::from_size_align(size, 8).unwrap();
+    // Nothing is reported without a clock, nor what was allocated before.
+    let untimed = unsafe { heap.alloc(layout(16)) };
+    assert!(older_than(&heap, 0).is_empty());
+    heap.set_timestamp_source(now);
+
+    NOW.store(10, Ordering::Relaxed);
+    let first = unsafe { heap.alloc(layout(100)) };
+    NOW.store(25, Ordering::Relaxed);
+    let second = unsafe { heap.alloc(layout(200)) };
+    NOW.store(40, Ordering::Relaxed);
+    let third = unsafe { heap.alloc(layout(300)) };
+
+    NOW.store(50, Ordering::Relaxed);
+    let [first, second, third] = [first, second, third].map(|ptr| ptr as usize);
+    assert_eq!(older_than(&heap, 41), []);
+    assert_eq!(older_than(&heap, 40), [(first, 100, 40)]);
+    let mut expected = vec![(first, 100, 40), (second, 200, 25), (third, 300, 10)];
+    expected.sort_unstable();
+    assert_eq!(older_than(&heap, 0), expected);
+
+    unsafe { heap.dealloc(first as *mut u8, layout(100)) };
+    assert_eq!(older_than(&heap, 25), [(second, 200, 25)]);
+
+    unsafe {
+        heap.dealloc(untimed, layout(16));
+        heap.dealloc(second as *mut u8, layout(200));
+        heap.dealloc(third as *mut u8, layout(300));
+    }
+    assert!(older_than(&heap, 0).is_empty());
+}
+
+#[test]
+fn frees_a_tracked_allocation_when_it_is_dropped() {
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn now() -> u64 {
+        NOW.load(Ordering::Relaxed)
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_timestamp_source(now);
+    let layout = Layout::from_size_align(256, 16).unwrap();
+
+    let mut block = heap.alloc_tracked_raii(layout).unwrap();
+    assert_eq!(block.layout(), layout);
+    assert_eq!(block.as_ptr() as usize % 16, 0);
+    assert_eq!(heap.tracked_allocations(), 1);
+    let mut entries = Vec::new();
+    heap.allocations_older_than(0, |ptr, size, _| entries.push((ptr, size)));
+    assert_eq!(entries, [(block.as_ptr() as usize, 256)]);
+
+    // It derefs to its zeroed bytes.
+    assert_eq!(block.len(), 256);
+    assert!(block.iter().all(|&byte| byte == 0));
+    block[..4].copy_from_slice(b"esp!");
+    assert_eq!(unsafe { block.as_ptr().read() }, b'e');
+    assert!(heap.used() >= 256);
+
+    drop(block);
+    assert_eq!(heap.tracked_allocations(), 0);
+    assert_eq!(heap.used(), 0);
+    assert!(heap
+        .alloc_tracked_raii(Layout::from_size_align(8192, 8).unwrap())
+        .is_none());
+}
@@ -0,0 +1,75 @@
+//! The boot report of the heap's configuration
+
+mod common;
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability, RegionDescriptor};
+
+#[test]
+fn describes_every_region() {
+    let heap = EspHeap::empty();
+    let psram = heap.register_expected(RegionDescriptor::new("psram", MemoryCapability::EXTERNAL));
+    heap.register_expected(RegionDescriptor::new(
+        "rtc",
+        MemoryCapability::INTERNAL | MemoryCapability::RTC_RETAINED,
+    ));
+    heap.mark_unavailable(psram, "not fitted");
+    let (internal, bottom) = add(
+        &heap,
+        4096,
+        MemoryCapability::INTERNAL | MemoryCapability::DMA,
+    );
+    heap.set_dma_tail_guard(internal, 64);
+    add(&heap, 2048, MemoryCapability::empty());
+
+    let mut report = String::new();
+    heap.boot_report(&mut report).unwrap();
+    let lines: Vec<_> = report.lines().collect();
+    assert_eq!(lines.len(), 5);
+
+    let features = [
+        (cfg!(feature = "defmt-failures"), "defmt-failures"),
+        (cfg!(feature = "free-cache"), "free-cache"),
+        (cfg!(feature = "heap-guard"), "heap-guard"),
+        (cfg!(feature = "isr-guard"), "isr-guard"),
+        (cfg!(feature = "multicore"), "multicore"),
+        (cfg!(feature = "oom-handler"), "oom-handler"),
+        (cfg!(feature = "poison"), "poison"),
+        (cfg!(feature = "quarantine"), "quarantine"),
+        (cfg!(feature = "registry"), "registry"),
+        (cfg!(feature = "stats"), "stats"),
+        (cfg!(feature = "trace"), "trace"),
+        (cfg!(feature = "validate-layout"), "validate-layout"),
+    ];
+    let mut enabled: String = features
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| format!(" {name}"))
+        .collect();
+    if enabled.is_empty() {
+        enabled = " none".to_string();
+    }
+    assert_eq!(
+        lines[0],
+        format!(
+            "esp-alloc {}: 2 of 4 regions, cache line 32 bytes, DMA alignment 32 bytes, features:{enabled}",
+            env!("CARGO_PKG_VERSION")
+        )
+    );
+
+    let bottom = bottom as usize;
+    assert_eq!(
+        lines[1],
+        format!(
+            "region 2: {bottom:#x}..{:#x}, 4096 bytes, INTERNAL|DMA, tail guard 64",
+            bottom + 4096
+        )
+    );
+    assert!(lines[2].starts_with("region 3: "));
+    assert!(lines[2].ends_with(", 2048 bytes, no capabilities"));
+    assert_eq!(lines[3], "psram: missing, EXTERNAL, not fitted");
+    assert_eq!(
+        lines[4],
+        "rtc: missing, INTERNAL|RTC_RETAINED, not probed yet"
+    );
+}
@@ -0,0 +1,158 @@
+//! Retained regions, and getting their blocks back after deep sleep
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::{add, memory, region_of};
+use esp_alloc::{EspHeap, MemoryCapability};
+
+const RTC_SIZE: usize = 1024;
+
+/// Layouts of the blocks kept across sleep, in the order they are allocated.
+fn layouts() -> Vec<Layout> {
+    [(4, 4), (24, 8), (1, 1), (100, 4), (16, 8), (37, 2), (8, 8)]
+        .into_iter()
+        .map(|(size, align)| Layout::from_size_align(size, align).unwrap())
+        .collect()
+}
+
+/// Allocates every one of `layouts()` from the retained region of `heap`.
+fn allocate_retained(heap: &EspHeap) -> Vec<*mut u8> {
+    layouts()
+        .into_iter()
+        .map(|layout| {
+            let ptr = heap.alloc_retained(layout);
+            assert!(!ptr.is_null(), "{layout:?}");
+            ptr
+        })
+        .collect()
+}
+
+fn pattern(block: usize, offset: usize) -> u8 {
+    (block * 31 + offset * 7 + 1) as u8
+}
+
+/// Returns a heap that has fresh DRAM and the RTC memory at `rtc`, as on every
+/// boot or wake.
+fn boot(rtc: *mut u8) -> EspHeap {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::INTERNAL);
+    unsafe { heap.reinit_retained_preserving(rtc, RTC_SIZE) };
+    #[cfg(feature = "quarantine")]
+    heap.set_quarantine_limits(0, 0);
+    heap
+}
+
+#[test]
+fn keeps_retained_blocks_intact_across_sleep() {
+    let rtc = memory(RTC_SIZE);
+    let heap = boot(rtc);
+    let blocks = allocate_retained(&heap);
+    for (block, (&ptr, layout)) in blocks.iter().zip(layouts()).enumerate() {
+        assert_eq!(region_of(&heap, ptr), Some(1));
+        assert_eq!(ptr as usize % layout.align(), 0);
+        for offset in 0..layout.size() {
+            unsafe { ptr.add(offset).write(pattern(block, offset)) };
+        }
+    }
+
+    // Deep sleep: everything but the RTC memory is lost, and the heap
+    // starts over on wake.
+    let heap = boot(rtc);
+    let again = allocate_retained(&heap);
+    assert_eq!(again, blocks);
+    for (block, (&ptr, layout)) in again.iter().zip(layouts()).enumerate() {
+        for offset in 0..layout.size() {
+            assert_eq!(
+                unsafe { ptr.add(offset).read() },
+                pattern(block, offset),
+                "byte {offset} of {layout:?}"
+            );
+        }
+    }
+
+    // Once every block is back, the region is an ordinary retained one.
+    let extra = Layout::from_size_align(64, 8).unwrap();
+    let ptr = heap.alloc_retained(extra);
+    assert_eq!(region_of(&heap, ptr), Some(1));
+    unsafe { heap.dealloc(ptr, extra) };
+    for (ptr, layout) in again.into_iter().zip(layouts()) {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn keeps_ordinary_allocations_out_of_retained_regions() {
+    let heap = EspHeap::empty();
+    add(&heap, 2048, MemoryCapability::INTERNAL);
+    let (rtc, _) = add(&heap, RTC_SIZE, MemoryCapability::RTC_RETAINED);
+    let layout = Layout::from_size_align(64, 4).unwrap();
+
+    // Ordinary allocations fail rather than use up RTC memory.
+    let mut blocks = Vec::new();
+    loop {
+        let ptr = unsafe { heap.alloc(layout) };
+        if ptr.is_null() {
+            break;
+        }
+        assert_eq!(region_of(&heap, ptr), Some(0));
+        blocks.push((ptr, layout));
+    }
+    assert_eq!(heap.available_for(layout), None);
+
+    // Asked for explicitly, the region serves them.
+    let explicit = heap.alloc_in_region(rtc, layout);
+    assert_eq!(region_of(&heap, explicit), Some(rtc.index()));
+    blocks.push((explicit, layout));
+
+    // Retained allocations never fall back to memory that loses power.
+    let mut retained = 0;
+    loop {
+        let ptr = heap.alloc_retained(layout);
+        if ptr.is_null() {
+            break;
+        }
+        assert_eq!(region_of(&heap, ptr), Some(rtc.index()));
+        blocks.push((ptr, layout));
+        retained += 1;
+    }
+    assert!(retained > 0);
+    unsafe { heap.dealloc(blocks[0].0, layout) };
+    assert!(heap.alloc_retained(layout).is_null());
+    unsafe { heap.dealloc(blocks[1].0, layout) };
+
+    for &(ptr, layout) in &blocks[2..] {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn returns_null_without_a_retained_region() {
+    let heap = EspHeap::empty();
+    add(&heap, 2048, MemoryCapability::INTERNAL);
+    let layout = Layout::from_size_align(16, 4).unwrap();
+    assert!(heap.alloc_retained(layout).is_null());
+}
+
+#[test]
+fn hands_out_the_same_addresses_after_a_free() {
+    let rtc = memory(RTC_SIZE);
+    let heap = boot(rtc);
+    let blocks = allocate_retained(&heap);
+
+    // Freeing a block ends the replay: the addresses still come back, but
+    // nothing is said about the contents.
+    let heap = boot(rtc);
+    let layouts = layouts();
+    let first = heap.alloc_retained(layouts[0]);
+    assert_eq!(first, blocks[0]);
+    unsafe { heap.dealloc(first, layouts[0]) };
+    let first = heap.alloc_retained(layouts[0]);
+    assert_eq!(first, blocks[0]);
+    for (&ptr, &layout) in blocks[1..].iter().zip(&layouts[1..]) {
+        assert_eq!(heap.alloc_retained(layout), ptr);
+    }
+}
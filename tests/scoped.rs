@@ -0,0 +1,64 @@
+//! Heaps that check for leaks when dropped
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::panic::catch_unwind;
+
+use common::panic_message;
+use esp_alloc::{MemoryCapability, ScopedHeap};
+
+#[test]
+fn drops_quietly_without_leaks() {
+    let mut buffer = [0u8; 4096];
+    let heap = ScopedHeap::new(&mut buffer, MemoryCapability::empty());
+    let layout = Layout::from_size_align(100, 4).unwrap();
+    let ptr = unsafe { heap.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { heap.dealloc(ptr, layout) };
+    drop(heap);
+}
+
+#[test]
+fn allocates_from_every_buffer_added() {
+    let (mut internal, mut dma) = ([0u8; 2048], [0u8; 2048]);
+    let dma_range = dma.as_ptr_range();
+    let heap = ScopedHeap::new(&mut internal, MemoryCapability::INTERNAL);
+    let region = heap.add_buffer(&mut dma, MemoryCapability::DMA);
+    assert_eq!(heap.region_ids().nth(1), Some(region));
+
+    let layout = Layout::from_size_align(1024, 4).unwrap();
+    let ptr = heap.alloc_caps(MemoryCapability::DMA, layout);
+    assert!(dma_range.contains(&(ptr as *const u8)));
+    unsafe { heap.dealloc(ptr, layout) };
+    drop(heap);
+}
+
+#[test]
+fn panics_on_leaks() {
+    let message = catch_unwind(|| {
+        let mut buffer = [0u8; 4096];
+        let heap = ScopedHeap::new(&mut buffer, MemoryCapability::empty());
+        unsafe { heap.alloc(Layout::from_size_align(100, 4).unwrap()) };
+    })
+    .map_err(panic_message)
+    .unwrap_err();
+    #[cfg(feature = "verbose-errors")]
+    assert_eq!(message, "ScopedHeap dropped with 100 bytes still allocated");
+    #[cfg(not(feature = "verbose-errors"))]
+    assert_eq!(message, "ScopedHeap dropped with live allocations");
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn keeps_the_panic_it_unwinds_from() {
+    let message = catch_unwind(|| {
+        let mut buffer = [0u8; 4096];
+        let heap = ScopedHeap::new(&mut buffer, MemoryCapability::empty());
+        let ptr = unsafe { heap.alloc(Layout::from_size_align(100, 4).unwrap()) };
+        assert!(ptr.is_null(), "the test failed");
+    })
+    .map_err(panic_message)
+    .unwrap_err();
+    assert_eq!(message, "the test failed");
+}
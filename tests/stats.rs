@@ -0,0 +1,122 @@
+//! Allocation counters (the `stats` feature)
+
+#![cfg(feature = "stats")]
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use common::{add, region_of};
+use esp_alloc::{EspHeap, MemoryCapability};
+
+#[test]
+fn counts_a_mixed_workload() {
+    let heap = EspHeap::empty();
+    let (internal, _) = add(&heap, 4096, MemoryCapability::INTERNAL);
+    let (external, _) = add(&heap, 16 * 1024, MemoryCapability::EXTERNAL);
+    let small = Layout::from_size_align(100, 4).unwrap();
+
+    let a = unsafe { heap.alloc(small) };
+    let b = heap.alloc_caps(MemoryCapability::EXTERNAL, small);
+    assert_eq!(region_of(&heap, b), Some(external.index()));
+    let pin = unsafe { heap.alloc(small) };
+    assert_eq!((heap.allocation_count(), heap.deallocation_count()), (3, 0));
+    assert_eq!(heap.bytes_requested(), 300);
+
+    // Growing in place is neither an allocation nor a deallocation.
+    let b = unsafe { heap.realloc(b, small, 200) };
+    assert_eq!((heap.allocation_count(), heap.deallocation_count()), (3, 0));
+    // Moving is both.
+    let a = unsafe { heap.realloc(a, small, 2000) };
+    assert_eq!((heap.allocation_count(), heap.deallocation_count()), (4, 1));
+    assert_eq!(heap.bytes_requested(), 2300);
+
+    // Failures count for every region that could have served them.
+    let huge = Layout::from_size_align(64 * 1024, 4).unwrap();
+    assert!(unsafe { heap.alloc(huge) }.is_null());
+    let internal_only = Layout::from_size_align(8192, 4).unwrap();
+    assert!(heap
+        .alloc_caps(MemoryCapability::INTERNAL, internal_only)
+        .is_null());
+    assert_eq!(heap.failed_allocations(), 2);
+    assert_eq!(heap.region_failed_allocations(internal), 2);
+    assert_eq!(heap.region_failed_allocations(external), 1);
+    assert_eq!(heap.largest_failed_allocation(), huge.size());
+
+    let stats = heap.stats();
+    assert_eq!((stats.allocations, stats.deallocations), (4, 1));
+    assert_eq!(stats.failed_allocations, 2);
+    assert_eq!(stats.bytes_requested, 2300);
+    assert_eq!(stats.regions[internal.index()].failed_allocations, 2);
+    assert_eq!(stats.regions[external.index()].failed_allocations, 1);
+
+    unsafe {
+        heap.dealloc(a, Layout::from_size_align(2000, 4).unwrap());
+        heap.dealloc(b, Layout::from_size_align(200, 4).unwrap());
+        heap.dealloc(pin, small);
+    }
+    assert_eq!(heap.allocation_count(), heap.deallocation_count());
+
+    heap.reset_counters();
+    let stats = heap.stats();
+    assert_eq!(
+        (
+            stats.allocations,
+            stats.deallocations,
+            stats.failed_allocations
+        ),
+        (0, 0, 0)
+    );
+    assert_eq!(stats.regions[internal.index()].failed_allocations, 0);
+    assert_eq!(heap.largest_failed_allocation(), huge.size());
+}
+
+#[test]
+fn recommends_a_heap_size() {
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    let layout = Layout::from_size_align(3000, 4).unwrap();
+
+    let ptr = unsafe { heap.alloc(layout) };
+    let peak = heap.used_peak();
+    assert!(peak >= layout.size());
+    unsafe { heap.dealloc(ptr, layout) };
+    assert_eq!(heap.used_peak(), peak);
+    assert!(heap.recommended_size() >= peak && heap.recommended_size() % 1024 == 0);
+
+    let failed = Layout::from_size_align(10_000, 4).unwrap();
+    assert!(unsafe { heap.alloc(failed) }.is_null());
+    assert!(heap.recommended_size() >= peak + failed.size());
+}
+
+#[test]
+fn records_when_the_last_failure_happened() {
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn now() -> u64 {
+        NOW.load(Ordering::Relaxed)
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let huge = Layout::from_size_align(8192, 8).unwrap();
+    // Failures before the clock is set have no time.
+    assert!(unsafe { heap.alloc(huge) }.is_null());
+    assert_eq!(heap.last_failure_time(), None);
+
+    heap.set_timestamp_source(now);
+    NOW.store(10, Ordering::Relaxed);
+    assert!(unsafe { heap.alloc(huge) }.is_null());
+    NOW.store(20, Ordering::Relaxed);
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let ptr = unsafe { heap.alloc(small) };
+    assert!(!ptr.is_null());
+    assert_eq!(heap.last_failure_time(), Some(10));
+
+    NOW.store(30, Ordering::Relaxed);
+    assert!(heap.alloc_caps(MemoryCapability::DMA, small).is_null());
+    heap.reset_counters();
+    assert_eq!(heap.last_failure_time(), Some(30));
+    unsafe { heap.dealloc(ptr, small) };
+}
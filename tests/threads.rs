@@ -0,0 +1,75 @@
+//! Several threads sharing one heap, standing in for the two cores
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use common::{add, Rng};
+use esp_alloc::{EspHeap, MemoryCapability};
+
+static HEAP: EspHeap = EspHeap::empty();
+
+fn hammer(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let mut blocks: Vec<(*mut u8, Layout, u8)> = Vec::new();
+    for step in 0..5000 {
+        if blocks.len() < 32 && rng.below(3) != 0 {
+            let layout = Layout::from_size_align(1 + rng.below(300), 1 << rng.below(4)).unwrap();
+            let ptr = if rng.below(8) == 0 {
+                HEAP.try_alloc(layout)
+                    .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
+            } else {
+                unsafe { HEAP.alloc(layout) }
+            };
+            if ptr.is_null() {
+                continue;
+            }
+            let fill = (seed as u8).wrapping_mul(31).wrapping_add(step as u8);
+            unsafe { ptr.write_bytes(fill, layout.size()) };
+            blocks.push((ptr, layout, fill));
+        } else if !blocks.is_empty() {
+            let (ptr, layout, fill) = blocks.swap_remove(rng.below(blocks.len()));
+            let bytes = unsafe { std::slice::from_raw_parts(ptr, layout.size()) };
+            assert!(
+                bytes.iter().all(|&byte| byte == fill),
+                "block at {ptr:p} was overwritten"
+            );
+            unsafe { HEAP.dealloc(ptr, layout) };
+        }
+    }
+    for (ptr, layout, _) in blocks {
+        unsafe { HEAP.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn survives_allocations_from_several_threads() {
+    add(&HEAP, 32 * 1024, MemoryCapability::INTERNAL);
+    add(&HEAP, 32 * 1024, MemoryCapability::EXTERNAL);
+    let total = HEAP.free();
+
+    // Snapshots taken while the others allocate stay consistent.
+    static DONE: AtomicBool = AtomicBool::new(false);
+    let observer = thread::spawn(move || {
+        while !DONE.load(Ordering::Relaxed) {
+            let stats = HEAP.stats();
+            assert_eq!(stats.used + stats.free, total);
+            assert_eq!(stats.used, stats.regions[0].used + stats.regions[1].used);
+        }
+    });
+
+    let workers: Vec<_> = (1..=4)
+        .map(|seed| thread::spawn(move || hammer(seed)))
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    DONE.store(true, Ordering::Relaxed);
+    observer.join().unwrap();
+
+    assert_eq!((HEAP.used(), HEAP.free(), HEAP.live_bytes()), (0, total, 0));
+}
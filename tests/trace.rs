@@ -0,0 +1,194 @@
+//! The trace ring buffer, the summary of live allocations and replays
+
+#![cfg(feature = "trace")]
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::sync::Mutex;
+
+use common::add;
+use esp_alloc::{replay, EspHeap, MemoryCapability, TraceError, TraceEvent, TraceOp, REPLAY_SLOTS};
+
+fn buffer(len: usize) -> &'static mut [TraceEvent] {
+    Box::leak(vec![TraceEvent::EMPTY; len].into_boxed_slice())
+}
+
+#[test]
+fn keeps_the_latest_events() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    assert_eq!(heap.trace_events().count(), 0);
+    heap.set_trace_buffer(buffer(4));
+
+    let layouts: Vec<_> = (1..=3)
+        .map(|size| Layout::from_size_align(size * 10, 4).unwrap())
+        .collect();
+    let blocks: Vec<_> = layouts
+        .iter()
+        .map(|&layout| unsafe { heap.alloc(layout) })
+        .collect();
+    let events: Vec<_> = heap.trace_events().collect();
+    assert_eq!(events.len(), 3);
+    for ((event, &layout), &ptr) in events.iter().zip(&layouts).zip(&blocks) {
+        assert_eq!(event.op, TraceOp::Alloc);
+        assert_eq!((event.layout, event.address), (layout, ptr as usize));
+        assert_eq!(event.region.map(|region| region.index()), Some(0));
+    }
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| event.sequence)
+            .collect::<Vec<_>>(),
+        [1, 2, 3]
+    );
+
+    // Wrapping around overwrites the oldest events.
+    for (&ptr, &layout) in blocks.iter().zip(&layouts) {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    let events: Vec<_> = heap.trace_events().collect();
+    assert_eq!(events.len(), 4);
+    assert_eq!(events[0].address, blocks[2] as usize);
+    assert_eq!(events[0].op, TraceOp::Alloc);
+    assert!(events[1..].iter().all(|event| event.op == TraceOp::Dealloc));
+    assert_eq!(events[3].address, blocks[2] as usize);
+
+    let buffer = heap.clear_trace_buffer().unwrap();
+    assert_eq!(buffer.len(), 4);
+    let ptr = unsafe { heap.alloc(layouts[0]) };
+    assert_eq!(heap.trace_events().count(), 0);
+    unsafe { heap.dealloc(ptr, layouts[0]) };
+}
+
+#[test]
+fn skips_events_overwritten_while_reading() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    heap.set_trace_buffer(buffer(4));
+    let layout = Layout::from_size_align(32, 4).unwrap();
+    for _ in 0..4 {
+        let ptr = unsafe { heap.alloc(layout) };
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+
+    let mut events = heap.trace_events();
+    assert!(events.next().is_some());
+    for _ in 0..3 {
+        let ptr = unsafe { heap.alloc(layout) };
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    // The rest were overwritten, and the new ones are past the end.
+    assert_eq!(events.count(), 0);
+}
+
+#[test]
+fn summarizes_live_allocations_by_size() {
+    let heap = EspHeap::empty();
+    add(&heap, 16 * 1024, MemoryCapability::empty());
+    let layout = |size| Layout::from_size_align(size, 4).unwrap();
+    let early = unsafe { heap.alloc(layout(100)) };
+    heap.set_trace_buffer(buffer(64));
+
+    let mut leaked = Vec::new();
+    for round in 0..5 {
+        let temporary = unsafe { heap.alloc(layout(200)) };
+        leaked.push((unsafe { heap.alloc(layout(24)) }, 24));
+        if round % 2 == 0 {
+            leaked.push((unsafe { heap.alloc(layout(5000)) }, 5000));
+        }
+        unsafe { heap.dealloc(temporary, layout(200)) };
+    }
+    unsafe { heap.dealloc(early, layout(100)) };
+
+    let summary = heap.live_summary();
+    assert_eq!(summary.events, 5 * 3 + 3 + 1);
+    assert_eq!((summary.overwritten, summary.unmatched_frees), (0, 1));
+    let live: Vec<_> = summary
+        .classes
+        .iter()
+        .filter(|class| class.count != 0)
+        .map(|class| (class.max_size, class.count, class.bytes))
+        .collect();
+    assert_eq!(live, [(32, 5, 120), (8192, 3, 15_000)]);
+
+    let text = summary.to_string();
+    assert!(text.contains("<= 32: 5 blocks, 120 bytes"), "{text}");
+    assert!(text.contains("<= 8192: 3 blocks, 15000 bytes"), "{text}");
+    assert!(!text.contains("<= 256"), "{text}");
+
+    for (ptr, size) in leaked {
+        unsafe { heap.dealloc(ptr, layout(size)) };
+    }
+    let summary = heap.live_summary();
+    assert!(summary.classes.iter().all(|class| class.count == 0));
+}
+
+#[test]
+fn frees_the_block_it_cannot_track() {
+    static TRACE: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+    fn sink(event: &[u8]) {
+        TRACE.lock().unwrap().extend_from_slice(event);
+    }
+
+    let layout = Layout::from_size_align(8, 4).unwrap();
+    let recorded = EspHeap::empty();
+    add(&recorded, 16384, MemoryCapability::empty());
+    recorded.set_trace_sink(sink);
+    let blocks: Vec<_> = (0..=REPLAY_SLOTS)
+        .map(|_| unsafe { recorded.alloc(layout) })
+        .collect();
+    recorded.clear_trace_sink();
+    for ptr in blocks {
+        unsafe { recorded.dealloc(ptr, layout) };
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 16384, MemoryCapability::empty());
+    let trace = TRACE.lock().unwrap().clone();
+    assert_eq!(replay(&trace, &heap), Err(TraceError::TooManyLive));
+    // The blocks the replay tracked stay allocated, the last one doesn't.
+    assert_eq!(heap.live_bytes(), REPLAY_SLOTS * layout.size());
+}
+
+#[test]
+fn passes_each_event_to_the_hook_with_its_region() {
+    static EVENTS: Mutex<Vec<TraceEvent>> = Mutex::new(Vec::new());
+
+    fn hook(event: &TraceEvent) {
+        EVENTS.lock().unwrap().push(*event);
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 1024, MemoryCapability::INTERNAL);
+    let (dma, _) = add(&heap, 4096, MemoryCapability::DMA);
+    heap.set_trace_hook(hook);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let ptr = heap.alloc_caps(MemoryCapability::DMA, layout);
+    unsafe { heap.dealloc(ptr, layout) };
+    assert!(heap
+        .alloc_caps(MemoryCapability::EXTERNAL, layout)
+        .is_null());
+    heap.clear_trace_hook();
+    let quiet = unsafe { heap.alloc(layout) };
+    unsafe { heap.dealloc(quiet, layout) };
+
+    let events = EVENTS.lock().unwrap();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].op, TraceOp::Alloc);
+    assert_eq!(events[0].address, ptr as usize);
+    assert_eq!(events[0].region, Some(dma));
+    assert_eq!((events[0].sequence, events[0].region_sequence), (1, 1));
+    assert_eq!(events[1].op, TraceOp::Dealloc);
+    assert_eq!(
+        (events[1].address, events[1].region),
+        (ptr as usize, Some(dma))
+    );
+    assert_eq!(events[1].region_sequence, 1);
+    // A failed allocation has neither an address nor a region.
+    assert_eq!(events[2].op, TraceOp::Alloc);
+    assert_eq!((events[2].address, events[2].region), (0, None));
+    assert_eq!(events[2].layout, layout);
+}
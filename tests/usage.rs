@@ -0,0 +1,320 @@
+//! Usage figures and watermarks
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::{add, Rng};
+use esp_alloc::{EspHeap, MemoryCapability};
+
+#[test]
+fn reports_every_region() {
+    let heap = EspHeap::empty();
+    let (first, bottom) = add(&heap, 4096, MemoryCapability::INTERNAL);
+    let (second, _) = add(&heap, 8192, MemoryCapability::EXTERNAL);
+    let layout = Layout::from_size_align(3000, 8).unwrap();
+    let ptr = unsafe { heap.alloc(layout) };
+
+    let stats = heap.stats();
+    let region = stats.regions[first.index()];
+    assert!(region.initialized);
+    assert_eq!(region.bottom, bottom as usize);
+    assert_eq!(region.top, bottom as usize + 4096);
+    assert_eq!(region.used + region.free, region.size);
+    assert!(region.used >= layout.size());
+    assert_eq!(stats.regions[second.index()].used, 0);
+    assert!(!stats.regions[2].initialized);
+    assert_eq!(stats.size, 4096 + 8192);
+    assert_eq!(stats.used, heap.used());
+
+    let text = stats.to_string();
+    assert_eq!(text.lines().count(), 1 + stats.regions.len());
+    assert!(text.contains("not initialized"));
+
+    unsafe { heap.dealloc(ptr, layout) };
+}
+
+#[test]
+fn keeps_the_lowest_free_figure() {
+    let heap = EspHeap::empty();
+    add(&heap, 8192, MemoryCapability::empty());
+    add(&heap, 8192, MemoryCapability::empty());
+    assert_eq!(heap.min_free(), heap.free());
+
+    let mut rng = Rng::new(7);
+    let mut blocks = Vec::new();
+    let mut lowest = heap.free();
+    for _ in 0..2000 {
+        if blocks.is_empty() || rng.below(3) != 0 {
+            let layout = Layout::from_size_align(1 + rng.below(600), 4).unwrap();
+            let ptr = unsafe { heap.alloc(layout) };
+            if !ptr.is_null() {
+                blocks.push((ptr, layout));
+            }
+        } else {
+            let (ptr, layout) = blocks.swap_remove(rng.below(blocks.len()));
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+
+        let min_free = heap.min_free();
+        assert!(min_free <= lowest, "the watermark went up");
+        assert!(min_free <= heap.free());
+        lowest = min_free;
+        if blocks.len() > 40 {
+            for (ptr, layout) in blocks.drain(..) {
+                unsafe { heap.dealloc(ptr, layout) };
+            }
+        }
+    }
+    assert!(lowest < heap.free());
+
+    heap.reset_min_free();
+    assert_eq!(heap.min_free(), heap.free());
+    for (ptr, layout) in blocks {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn keeps_the_largest_allocation() {
+    let heap = EspHeap::empty();
+    assert_eq!(heap.largest_allocation(), 0);
+    add(&heap, 8192, MemoryCapability::empty());
+    let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+    let first = unsafe { heap.alloc(layout(100)) };
+    let second = unsafe { heap.alloc(layout(50)) };
+    assert_eq!(heap.largest_allocation(), 100);
+    // Failures don't count, growing blocks do.
+    assert!(unsafe { heap.alloc(layout(16 * 1024)) }.is_null());
+    assert_eq!(heap.largest_allocation(), 100);
+    let grown = unsafe { heap.realloc(second, layout(50), 300) };
+    assert!(!grown.is_null());
+    assert_eq!(heap.largest_allocation(), 300);
+
+    unsafe {
+        heap.dealloc(first, layout(100));
+        heap.dealloc(grown, layout(300));
+    }
+    assert_eq!(heap.largest_allocation(), 300);
+}
+
+#[test]
+fn finds_the_largest_free_block() {
+    let heap = EspHeap::empty();
+    let (first, _) = add(&heap, 2048, MemoryCapability::empty());
+    let (second, _) = add(&heap, 8192, MemoryCapability::empty());
+    assert_eq!(heap.max_free_block(), heap.largest_free_block(second));
+
+    // Pin a block in the middle of the big region.
+    let front = Layout::from_size_align(3000, 8).unwrap();
+    let pinned = Layout::from_size_align(64, 8).unwrap();
+    let a = heap.alloc_in_region(second, front);
+    let b = heap.alloc_in_region(second, pinned);
+    unsafe { heap.dealloc(a, front) };
+    let largest = heap.largest_free_block(second);
+    assert!(largest < 8192 - 3000);
+    assert!(largest > heap.largest_free_block(first));
+    assert_eq!(heap.max_free_block(), largest);
+
+    unsafe { heap.dealloc(b, pinned) };
+    assert_eq!(heap.used(), 0);
+}
+
+#[test]
+fn serves_exactly_the_largest_free_block() {
+    const WORD: usize = core::mem::size_of::<usize>();
+    const MIN_BLOCK: usize = 2 * WORD;
+
+    for seed in 0..64 {
+        let heap = EspHeap::empty();
+        let (region, _) = add(&heap, 4096, MemoryCapability::empty());
+        let mut rng = Rng::new(seed);
+        match seed % 4 {
+            1 => heap.set_reserved_for_stack(8 * rng.below(64)),
+            2 => heap.set_dma_tail_guard(region, rng.below(512)),
+            3 => heap.set_isolate_cache_lines(region, true),
+            _ => {}
+        }
+
+        // Punch holes of every size and alignment into the region.
+        let mut blocks = Vec::new();
+        for _ in 0..24 {
+            let layout = Layout::from_size_align(1 + rng.below(256), 1 << rng.below(7)).unwrap();
+            let ptr = unsafe { heap.alloc(layout) };
+            if !ptr.is_null() {
+                blocks.push((ptr, layout));
+            }
+        }
+        let mut kept = Vec::new();
+        for (ptr, layout) in blocks {
+            if rng.below(2) == 0 {
+                unsafe { heap.dealloc(ptr, layout) };
+            } else {
+                kept.push((ptr, layout));
+            }
+        }
+
+        // Nothing bigger than the largest free block fits, with any
+        // alignment up to that of `usize`. The largest and everything that
+        // leaves a remainder of at least a minimum block do.
+        let largest = heap.largest_free_block(region);
+        assert!(largest > 0, "seed {seed}");
+        for size in largest.saturating_sub(64).max(1)..=largest + 64 {
+            for align in [1, 2, 4, 8] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = heap.alloc_in_region(region, layout);
+                if size > largest {
+                    assert!(ptr.is_null(), "seed {seed}, {layout:?}");
+                } else if size <= largest - MIN_BLOCK || size > largest - WORD {
+                    assert!(!ptr.is_null(), "seed {seed}, {layout:?}");
+                }
+                if !ptr.is_null() {
+                    unsafe { heap.dealloc(ptr, layout) };
+                }
+                assert_eq!(heap.largest_free_block(region), largest, "seed {seed}");
+            }
+        }
+
+        for (ptr, layout) in kept {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        assert_eq!(heap.used(), 0);
+    }
+}
+
+#[test]
+fn measures_fragmentation() {
+    let heap = EspHeap::empty();
+    let (region, _) = add(&heap, 16 * 1024, MemoryCapability::empty());
+    #[cfg(feature = "quarantine")]
+    heap.set_quarantine_limits(0, 0);
+    assert_eq!(heap.free_block_count(region), 1);
+    assert_eq!(heap.fragmentation(region), 0);
+
+    let layout = Layout::from_size_align(64, 4).unwrap();
+    let mut blocks = Vec::new();
+    loop {
+        let ptr = unsafe { heap.alloc(layout) };
+        if ptr.is_null() {
+            break;
+        }
+        blocks.push(ptr);
+    }
+    assert!(blocks.len() > 100);
+    assert!(heap.free_block_count(region) <= 1);
+    assert_eq!(heap.fragmentation(region), 0);
+
+    // Freeing every other block leaves gaps between live blocks.
+    let mut previous = (0, 0);
+    for (index, &ptr) in blocks.iter().enumerate().step_by(2) {
+        unsafe { heap.dealloc(ptr, layout) };
+        let now = (heap.free_block_count(region), heap.fragmentation(region));
+        assert!(now.0 > previous.0, "after freeing block {index}");
+        assert!(
+            now.1 >= previous.1 || index == 0,
+            "after freeing block {index}"
+        );
+        previous = now;
+    }
+    assert!(previous.0 >= blocks.len() / 2);
+    assert!(previous.1 > 950, "{}", previous.1);
+
+    let stats = heap.stats();
+    assert_eq!(stats.regions[region.index()].free_blocks, previous.0);
+    assert_eq!(stats.regions[region.index()].fragmentation, previous.1);
+    assert!(stats.to_string().contains("free blocks"));
+
+    // Freeing the rest merges the gaps back into one block.
+    for &ptr in blocks.iter().skip(1).step_by(2) {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.free_block_count(region), 1);
+    assert_eq!(heap.fragmentation(region), 0);
+}
+
+#[test]
+fn reports_no_fragmentation_without_memory() {
+    let heap = EspHeap::empty();
+    let region = heap.stats().regions[0].id;
+    assert_eq!(heap.free_block_count(region), 0);
+    assert_eq!(heap.fragmentation(region), 0);
+
+    let (first, _) = add(&heap, 4096, MemoryCapability::empty());
+    let stats = heap.stats();
+    let unused = stats.regions[1].id;
+    assert_eq!(heap.free_block_count(unused), 0);
+    assert_eq!(heap.fragmentation(unused), 0);
+    assert_eq!(
+        (stats.regions[1].free_blocks, stats.regions[1].fragmentation),
+        (0, 0)
+    );
+    assert_eq!(stats.regions[first.index()].free_blocks, 1);
+}
+
+#[test]
+fn returns_to_the_same_live_bytes() {
+    let heap = EspHeap::empty();
+    let (internal, _) = add(&heap, 4096, MemoryCapability::INTERNAL);
+    let (external, _) = add(&heap, 8192, MemoryCapability::EXTERNAL);
+    let pinned = Layout::from_size_align(100, 4).unwrap();
+    let pin = unsafe { heap.alloc(pinned) };
+    let baseline = heap.live_bytes();
+    assert_eq!(baseline, 100);
+
+    // Fill both regions with blocks of odd sizes and alignments.
+    let mut rng = Rng::new(7);
+    let mut blocks = Vec::new();
+    let mut requested = 0;
+    loop {
+        let layout = Layout::from_size_align(1 + rng.below(300), 1 << rng.below(5)).unwrap();
+        let ptr = unsafe { heap.alloc(layout) };
+        if ptr.is_null() {
+            break;
+        }
+        requested += layout.size();
+        blocks.push((ptr, layout));
+    }
+    let stats = heap.stats();
+    assert!(stats.regions[internal.index()].used > 3000);
+    assert!(stats.regions[external.index()].used > 7000);
+    assert_eq!(stats.live_bytes, baseline + requested);
+    assert!(stats.used > stats.live_bytes);
+    assert!(stats
+        .to_string()
+        .contains(&format!("{} live", stats.live_bytes)));
+
+    for (ptr, layout) in blocks {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+    assert_eq!(heap.live_bytes(), baseline);
+    assert_eq!(heap.stats().live_bytes, baseline);
+    unsafe { heap.dealloc(pin, pinned) };
+    assert_eq!(heap.live_bytes(), 0);
+}
+
+#[test]
+fn reports_every_budget() {
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let frames = heap.budget("frames", 1024);
+    heap.budget("packets", 512);
+    let layout = Layout::from_size_align(300, 4).unwrap();
+    let ptr = frames.try_alloc(layout).unwrap();
+
+    let stats = heap.stats();
+    let budgets: Vec<_> = stats
+        .budgets
+        .iter()
+        .flatten()
+        .map(|budget| (budget.name, budget.used, budget.max_bytes))
+        .collect();
+    assert_eq!(budgets, [("frames", 300, 1024), ("packets", 0, 512)]);
+    let text = stats.to_string();
+    assert_eq!(text.lines().count(), 1 + stats.regions.len() + 2);
+    assert!(text.contains("budget frames: 300 of 1024 bytes used"));
+    assert!(text.contains("budget packets: 0 of 512 bytes used"));
+
+    unsafe { frames.dealloc(ptr, layout) };
+    assert_eq!(heap.stats().budgets[0].unwrap().used, 0);
+}
@@ -0,0 +1,107 @@
+//! Software watchpoints on memory ranges
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+use std::sync::Mutex;
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability, WatchHit, MAX_WATCHES};
+
+fn range(len: usize) -> *mut u8 {
+    Box::leak(vec![0u8; len].into_boxed_slice()).as_mut_ptr()
+}
+
+#[test]
+fn reports_a_change_once_with_the_allocations_around_it() {
+    static HITS: Mutex<Vec<WatchHit>> = Mutex::new(Vec::new());
+
+    fn hit(hit: WatchHit) {
+        HITS.lock().unwrap().push(hit);
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let watched = range(32);
+    assert!(unsafe { heap.watch_range(watched, 32, hit) });
+    assert_eq!(heap.check_watches(), 0);
+
+    let layout = Layout::from_size_align(16, 4).unwrap();
+    let before = heap.allocation_sequence();
+    let blocks: Vec<_> = (0..3).map(|_| unsafe { heap.alloc(layout) }).collect();
+    unsafe { watched.add(31).write_volatile(1) };
+    assert_eq!(heap.check_watches(), 1);
+    let hits = HITS.lock().unwrap().clone();
+    assert_eq!(hits.len(), 1);
+    assert_eq!((hits[0].start, hits[0].len), (watched as usize, 32));
+    assert_eq!((hits[0].last_seen, hits[0].noticed), (before, before + 3));
+
+    // The new contents are what the next check compares against.
+    assert_eq!(heap.check_watches(), 0);
+    heap.unwatch_range(watched);
+    unsafe { watched.write_volatile(1) };
+    assert_eq!(heap.check_watches(), 0);
+    assert_eq!(HITS.lock().unwrap().len(), 1);
+
+    for ptr in blocks {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn watches_a_limited_number_of_ranges() {
+    static PROGRAMMED: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+    fn ignore(_: WatchHit) {}
+
+    fn program(start: usize, len: usize) {
+        PROGRAMMED.lock().unwrap().push((start, len));
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let first = range(8);
+    assert!(unsafe { heap.watch_range(first, 8, ignore) });
+    heap.set_watchpoint_hook(program);
+
+    let ranges: Vec<_> = (1..MAX_WATCHES).map(|_| range(4)).collect();
+    for &start in &ranges {
+        assert!(unsafe { heap.watch_range(start, 4, ignore) });
+    }
+    let extra = range(4);
+    assert!(!unsafe { heap.watch_range(extra, 4, ignore) });
+
+    heap.unwatch_range(first);
+    assert!(unsafe { heap.watch_range(extra, 4, ignore) });
+
+    // The hook sees every range watched after it was set.
+    let expected: Vec<_> = ranges
+        .iter()
+        .chain([&extra])
+        .map(|&start| (start as usize, 4))
+        .collect();
+    assert_eq!(*PROGRAMMED.lock().unwrap(), expected);
+}
+
+#[test]
+#[cfg(feature = "heap-guard")]
+fn checks_watches_with_the_integrity_of_the_heap() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static HITS: AtomicUsize = AtomicUsize::new(0);
+
+    fn hit(_: WatchHit) {
+        HITS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let heap = EspHeap::empty();
+    add(&heap, 4096, MemoryCapability::empty());
+    let watched = range(16);
+    assert!(unsafe { heap.watch_range(watched, 16, hit) });
+
+    assert_eq!(heap.check_integrity(), Ok(()));
+    assert_eq!(HITS.load(Ordering::Relaxed), 0);
+    unsafe { watched.write_volatile(7) };
+    assert_eq!(heap.check_integrity(), Ok(()));
+    assert_eq!(HITS.load(Ordering::Relaxed), 1);
+}
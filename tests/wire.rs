@@ -0,0 +1,87 @@
+//! Heap statistics in a fixed binary format
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability, MAX_REGIONS, STATS_BYTES_LEN, STATS_FORMAT_VERSION};
+
+fn word(bytes: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+#[test]
+fn writes_every_field_at_its_offset() {
+    let heap = EspHeap::empty();
+    let (_, first) = add(&heap, 4096, MemoryCapability::INTERNAL);
+    let (_, second) = add(&heap, 2048, MemoryCapability::EXTERNAL);
+    let layout = Layout::from_size_align(100, 4).unwrap();
+    let ptr = unsafe { heap.alloc(layout) };
+    assert!(unsafe { heap.alloc(Layout::from_size_align(8192, 4).unwrap()) }.is_null());
+
+    let bytes = heap.stats_bytes();
+    assert_eq!(bytes.len(), STATS_BYTES_LEN);
+    assert_eq!(bytes[0], STATS_FORMAT_VERSION);
+    assert_eq!(bytes[1], 2);
+    assert_eq!(bytes[2], u8::from(cfg!(feature = "stats")));
+    assert_eq!(bytes[3] as usize, MAX_REGIONS);
+    assert_eq!(word(&bytes, 4), heap.used());
+    assert_eq!(word(&bytes, 8), heap.free());
+    assert_eq!(word(&bytes, 12), 100);
+    assert_eq!(word(&bytes, 16), heap.free_general());
+    assert_eq!(word(&bytes, 20), 100);
+    assert_eq!(word(&bytes, 24), usize::from(cfg!(feature = "stats")));
+    assert_eq!(word(&bytes, 28), heap.allocation_sequence());
+
+    let stats = heap.stats();
+    for (index, bottom) in [(0, first), (1, second)] {
+        let record = &bytes[32 + index * 16..];
+        let region = stats.regions[index];
+        // Host addresses don't fit and saturate.
+        assert_eq!(word(record, 0), (bottom as usize).min(u32::MAX as usize));
+        assert_eq!(word(record, 4), region.size);
+        assert_eq!(word(record, 8), region.used);
+        assert_eq!(word(record, 12), region.free);
+    }
+    assert!(bytes[32 + 2 * 16..].iter().all(|&byte| byte == 0));
+
+    unsafe { heap.dealloc(ptr, layout) };
+}
+
+#[test]
+fn writes_zeros_before_init() {
+    let heap = EspHeap::empty();
+    let bytes = heap.stats_bytes();
+    assert_eq!(bytes[0], STATS_FORMAT_VERSION);
+    assert_eq!(bytes[1], 0);
+    assert!(bytes[4..].iter().all(|&byte| byte == 0));
+}
+
+#[test]
+#[cfg(feature = "multicore")]
+fn takes_snapshots_while_other_cores_allocate() {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    static HEAP: EspHeap = EspHeap::empty();
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    add(&HEAP, 16 * 1024, MemoryCapability::empty());
+    let total = HEAP.free();
+    let worker = thread::spawn(|| {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        for _ in 0..2000 {
+            let ptr = unsafe { HEAP.alloc(layout) };
+            unsafe { HEAP.dealloc(ptr, layout) };
+        }
+        DONE.store(true, Ordering::Relaxed);
+    });
+    while !DONE.load(Ordering::Relaxed) {
+        let bytes = HEAP.stats_bytes();
+        assert_eq!(word(&bytes, 4) + word(&bytes, 8), total);
+    }
+    worker.join().unwrap();
+}
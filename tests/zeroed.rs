@@ -0,0 +1,41 @@
+//! Zeroed allocations from regions that start out cleared
+
+mod common;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use common::add;
+use esp_alloc::{EspHeap, MemoryCapability};
+
+#[test]
+fn clears_only_what_the_allocator_has_written_to() {
+    let heap = EspHeap::empty();
+    let (region, bottom) = add(&heap, 4096, MemoryCapability::empty());
+    unsafe { heap.assume_zeroed(region) };
+    // Poisoning would write to every block it hands out.
+    #[cfg(feature = "poison")]
+    heap.set_poison_limit(0);
+
+    // Break the promise behind the allocator's back, which shows what it
+    // skips clearing.
+    unsafe { bottom.add(1024).write_bytes(0xaa, 4096 - 1024) };
+
+    let layout = Layout::from_size_align(2048, 8).unwrap();
+    let ptr = unsafe { heap.alloc_zeroed(layout) };
+    assert!(!ptr.is_null());
+    let block = unsafe { core::slice::from_raw_parts_mut(ptr, layout.size()) };
+    let untouched = bottom as usize + 1024 - ptr as usize;
+    assert!(block[..untouched].iter().all(|&byte| byte == 0));
+    assert!(block[untouched..].iter().all(|&byte| byte == 0xaa));
+
+    // Memory that has been handed out is cleared when it comes back.
+    block.fill(0x55);
+    unsafe { heap.dealloc(ptr, layout) };
+    let again = unsafe { heap.alloc_zeroed(layout) };
+    assert_eq!(again, ptr);
+    let block = unsafe { core::slice::from_raw_parts(again, layout.size()) };
+    assert!(block.iter().all(|&byte| byte == 0));
+
+    unsafe { heap.dealloc(again, layout) };
+    assert_eq!(heap.used(), 0);
+}